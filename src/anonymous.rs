@@ -5,6 +5,16 @@ use anyhow::{anyhow, bail, Result};
 /// The ANONYMOUS mechanism name.
 pub const ANONYMOUS: &str = "ANONYMOUS";
 
+/// The security properties of the ANONYMOUS mechanism.
+pub const PROPERTIES: sasl::Properties = sasl::Properties {
+    plaintext: false,
+    anonymous: true,
+    channel_binding: false,
+    mutual_auth: false,
+    security_layer: false,
+    ssf: 0,
+};
+
 /// A client implementation of the ANONYMOUS authentication mechanism, as
 /// described in RFC 4505.
 pub struct AnonymousClient {
@@ -20,10 +30,14 @@ impl AnonymousClient {
 }
 
 impl sasl::Client for AnonymousClient {
-    fn start(&mut self) -> Result<(String, Vec<u8>)> {
+    fn mechanism_name(&self) -> &str {
+        ANONYMOUS
+    }
+
+    fn start(&mut self) -> Result<(String, Option<Vec<u8>>)> {
         Ok((
             ANONYMOUS.to_string(),
-            self.trace.clone().into_bytes(),
+            Some(self.trace.clone().into_bytes()),
         ))
     }
 
@@ -33,7 +47,7 @@ impl sasl::Client for AnonymousClient {
 }
 
 /// Get trace information from clients logging in anonymously.
-pub type AnonymousAuthenticator = Box<dyn Fn(&str) -> Result<()> + Send>;
+pub type AnonymousAuthenticator = Box<dyn Fn(&str) -> Result<()> + Send + Sync>;
 
 /// A server implementation of the ANONYMOUS authentication mechanism, as
 /// described in RFC 4505.
@@ -52,20 +66,33 @@ impl AnonymousServer {
 }
 
 impl sasl::Server for AnonymousServer {
-    fn next(&mut self, response: Option<&[u8]>) -> Result<(Vec<u8>, bool)> {
+    fn mechanism_name(&self) -> &str {
+        ANONYMOUS
+    }
+
+    fn next(&mut self, response: Option<&[u8]>) -> Result<sasl::ServerStep> {
         if self.done {
             bail!(sasl::ERR_UNEXPECTED_CLIENT_RESPONSE);
         }
 
         // No initial response, send an empty challenge
         if response.is_none() {
-            return Ok((Vec::new(), false));
+            return Ok(sasl::ServerStep::Challenge(Vec::new()));
         }
         let response = response.unwrap();
 
         self.done = true;
 
         (self.authenticator)(std::str::from_utf8(response)?)?;
-        Ok((Vec::new(), true))
+        Ok(sasl::ServerStep::Done { additional_data: None })
+    }
+
+    fn is_done(&self) -> bool {
+        self.done
+    }
+
+    fn reset(&mut self) -> Result<()> {
+        self.done = false;
+        Ok(())
     }
 }
\ No newline at end of file
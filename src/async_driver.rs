@@ -0,0 +1,457 @@
+//! Asynchronous drivers running a whole exchange over an `AsyncRead +
+//! AsyncWrite` stream, the counterparts of the `driver` functions. Messages
+//! are encoded by a `Codec`: base64 lines or length-prefixed frames, or a
+//! protocol-specific one.
+
+use crate::async_sasl::{AsyncClient, AsyncServer};
+use crate::driver::{ClientMessage, Outcome, ServerMessage, ERR_MECHANISM_MISMATCH, ERR_UNEXPECTED_MESSAGE};
+use crate::framing;
+use crate::sasl;
+
+use anyhow::{anyhow, bail, Result};
+use std::future::Future;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+pub const ERR_TIMEOUT: &str = "sasl: driver: exchange timed out";
+pub const ERR_CONNECTION_CLOSED: &str = "sasl: driver: connection closed";
+pub const ERR_MESSAGE_TOO_LARGE: &str = "sasl: driver: message too large";
+pub const ERR_MALFORMED_MESSAGE: &str = "sasl: driver: malformed message";
+pub const ERR_MECHANISM_NAME_TOO_LONG: &str = "sasl: driver: mechanism name too long";
+
+/// Encodes and decodes the messages of an exchange. Decoders are passed
+/// the bytes received so far and return the message at their start with
+/// the number of bytes it spans, or `None` if it is incomplete. Client
+/// messages fail to encode if the codec can't represent them.
+pub trait Codec: Send + Sync {
+    fn encode_client(&self, message: &ClientMessage) -> Result<Vec<u8>>;
+    fn decode_client(&self, input: &[u8]) -> Result<Option<(ClientMessage, usize)>>;
+    fn encode_server(&self, message: &ServerMessage) -> Vec<u8>;
+    fn decode_server(&self, input: &[u8]) -> Result<Option<(ServerMessage, usize)>>;
+}
+
+/// Base64 lines, terminated by CRLF: the client sends `AUTH <mechanism>
+/// [initial-response]`, then responses or `*` to cancel, and the server
+/// sends `+ <challenge>`, `OK [additional-data]` or `NO`.
+pub struct LineCodec {
+    max_line_length: usize,
+}
+
+/// The default maximum length of a line, as in SMTP servers.
+pub const DEFAULT_MAX_LINE_LENGTH: usize = 12288;
+
+impl Default for LineCodec {
+    fn default() -> Self {
+        Self {
+            max_line_length: DEFAULT_MAX_LINE_LENGTH,
+        }
+    }
+}
+
+impl LineCodec {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_max_line_length(mut self, max_line_length: usize) -> Self {
+        self.max_line_length = max_line_length;
+        self
+    }
+
+    fn line<'a>(&self, input: &'a [u8]) -> Result<Option<(&'a str, usize)>> {
+        match input.iter().position(|&b| b == b'\n') {
+            Some(end) if end < self.max_line_length => {
+                let line = std::str::from_utf8(&input[..end])?.trim_end_matches('\r');
+                Ok(Some((line, end + 1)))
+            }
+            None if input.len() < self.max_line_length => Ok(None),
+            _ => bail!(ERR_MESSAGE_TOO_LARGE),
+        }
+    }
+}
+
+impl Codec for LineCodec {
+    fn encode_client(&self, message: &ClientMessage) -> Result<Vec<u8>> {
+        let line = match message {
+            ClientMessage::Start { mechanism, initial_response } => match framing::encode_initial_response(initial_response.as_deref()) {
+                Some(ir) => format!("AUTH {} {}", mechanism, ir),
+                None => format!("AUTH {}", mechanism),
+            },
+            ClientMessage::Response(response) => framing::encode_response(response),
+            ClientMessage::Cancel => crate::dispatcher::CANCEL.to_string(),
+        };
+        Ok(format!("{}\r\n", line).into_bytes())
+    }
+
+    fn decode_client(&self, input: &[u8]) -> Result<Option<(ClientMessage, usize)>> {
+        let Some((line, len)) = self.line(input)? else {
+            return Ok(None);
+        };
+        let message = match line.strip_prefix("AUTH ") {
+            Some(command) => {
+                let (mechanism, ir) = match command.split_once(' ') {
+                    Some((mechanism, ir)) => (mechanism, Some(ir)),
+                    None => (command, None),
+                };
+                ClientMessage::Start {
+                    mechanism: mechanism.to_string(),
+                    initial_response: framing::decode_initial_response(ir)?,
+                }
+            }
+            None => match framing::decode_response(line)? {
+                Some(response) => ClientMessage::Response(response),
+                None => ClientMessage::Cancel,
+            },
+        };
+        Ok(Some((message, len)))
+    }
+
+    fn encode_server(&self, message: &ServerMessage) -> Vec<u8> {
+        let line = match message {
+            ServerMessage::Challenge(challenge) => framing::encode_challenge(framing::IMAP_CONTINUATION, challenge),
+            ServerMessage::Success(Some(data)) => format!("OK {}", framing::base64_encode(data)),
+            ServerMessage::Success(None) => "OK".to_string(),
+            ServerMessage::Failure => "NO".to_string(),
+        };
+        format!("{}\r\n", line).into_bytes()
+    }
+
+    fn decode_server(&self, input: &[u8]) -> Result<Option<(ServerMessage, usize)>> {
+        let Some((line, len)) = self.line(input)? else {
+            return Ok(None);
+        };
+        let message = match line {
+            "OK" => ServerMessage::Success(None),
+            "NO" => ServerMessage::Failure,
+            _ => match line.strip_prefix("OK ") {
+                Some(data) => ServerMessage::Success(Some(framing::base64_decode(data)?)),
+                None => ServerMessage::Challenge(framing::decode_challenge(framing::IMAP_CONTINUATION, line)?),
+            },
+        };
+        Ok(Some((message, len)))
+    }
+}
+
+/// Frames prefixed with their length as a 4-byte big-endian integer. The
+/// first byte of a frame is the kind of message: 0 for `Start`, followed
+/// by the length of the mechanism name as a byte, the name and, if there
+/// is an initial response, a 1 byte and the response; 1 for `Response` and
+/// 2 for `Cancel` from clients; 0 for `Challenge`, 1 for `Success` with the
+/// additional data if any, and 2 for `Failure` from servers.
+pub struct FrameCodec {
+    max_frame_length: usize,
+}
+
+/// The default maximum length of a frame.
+pub const DEFAULT_MAX_FRAME_LENGTH: usize = 65536;
+
+impl Default for FrameCodec {
+    fn default() -> Self {
+        Self {
+            max_frame_length: DEFAULT_MAX_FRAME_LENGTH,
+        }
+    }
+}
+
+impl FrameCodec {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_max_frame_length(mut self, max_frame_length: usize) -> Self {
+        self.max_frame_length = max_frame_length;
+        self
+    }
+
+    fn frame<'a>(&self, input: &'a [u8]) -> Result<Option<(u8, &'a [u8], usize)>> {
+        let Some(len) = input.get(..4) else {
+            return Ok(None);
+        };
+        let len = u32::from_be_bytes(len.try_into()?);
+        let Some(end) = usize::try_from(len).ok().filter(|&len| len <= self.max_frame_length).and_then(|len| len.checked_add(4)) else {
+            bail!(ERR_MESSAGE_TOO_LARGE);
+        };
+        match input.get(4..end) {
+            Some([kind, data @ ..]) => Ok(Some((*kind, data, end))),
+            Some([]) => bail!(ERR_MALFORMED_MESSAGE),
+            None => Ok(None),
+        }
+    }
+}
+
+fn encode_frame(kind: u8, data: &[u8]) -> Vec<u8> {
+    let mut frame = ((data.len() + 1) as u32).to_be_bytes().to_vec();
+    frame.push(kind);
+    frame.extend_from_slice(data);
+    frame
+}
+
+impl Codec for FrameCodec {
+    fn encode_client(&self, message: &ClientMessage) -> Result<Vec<u8>> {
+        Ok(match message {
+            ClientMessage::Start { mechanism, initial_response } => {
+                let name_len = u8::try_from(mechanism.len()).map_err(|_| anyhow!(ERR_MECHANISM_NAME_TOO_LONG))?;
+                let mut data = vec![name_len];
+                data.extend_from_slice(mechanism.as_bytes());
+                if let Some(ir) = initial_response {
+                    data.push(1);
+                    data.extend_from_slice(ir);
+                }
+                encode_frame(0, &data)
+            }
+            ClientMessage::Response(response) => encode_frame(1, response),
+            ClientMessage::Cancel => encode_frame(2, &[]),
+        })
+    }
+
+    fn decode_client(&self, input: &[u8]) -> Result<Option<(ClientMessage, usize)>> {
+        let Some((kind, data, len)) = self.frame(input)? else {
+            return Ok(None);
+        };
+        let message = match (kind, data) {
+            (0, [name_len, rest @ ..]) if rest.len() >= *name_len as usize => {
+                let (mechanism, ir) = rest.split_at(*name_len as usize);
+                let initial_response = match ir {
+                    [] => None,
+                    [1, ir @ ..] => Some(ir.to_vec()),
+                    _ => bail!(ERR_MALFORMED_MESSAGE),
+                };
+                ClientMessage::Start {
+                    mechanism: std::str::from_utf8(mechanism)?.to_string(),
+                    initial_response,
+                }
+            }
+            (1, response) => ClientMessage::Response(response.to_vec()),
+            (2, []) => ClientMessage::Cancel,
+            _ => bail!(ERR_MALFORMED_MESSAGE),
+        };
+        Ok(Some((message, len)))
+    }
+
+    fn encode_server(&self, message: &ServerMessage) -> Vec<u8> {
+        match message {
+            ServerMessage::Challenge(challenge) => encode_frame(0, challenge),
+            ServerMessage::Success(None) => encode_frame(1, &[]),
+            ServerMessage::Success(Some(data)) => encode_frame(1, &[&[1], &data[..]].concat()),
+            ServerMessage::Failure => encode_frame(2, &[]),
+        }
+    }
+
+    fn decode_server(&self, input: &[u8]) -> Result<Option<(ServerMessage, usize)>> {
+        let Some((kind, data, len)) = self.frame(input)? else {
+            return Ok(None);
+        };
+        let message = match (kind, data) {
+            (0, challenge) => ServerMessage::Challenge(challenge.to_vec()),
+            (1, []) => ServerMessage::Success(None),
+            (1, [1, data @ ..]) => ServerMessage::Success(Some(data.to_vec())),
+            (2, []) => ServerMessage::Failure,
+            _ => bail!(ERR_MALFORMED_MESSAGE),
+        };
+        Ok(Some((message, len)))
+    }
+}
+
+/// Runs exchanges over a stream with a codec.
+pub struct AsyncDriver<D> {
+    codec: D,
+    timeout: Option<Duration>,
+}
+
+impl<D: Codec> AsyncDriver<D> {
+    pub fn new(codec: D) -> Self {
+        Self { codec, timeout: None }
+    }
+
+    /// Sets the time allowed for a whole exchange, after which it fails
+    /// with `ERR_TIMEOUT`. The runtime must have the time driver enabled.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    async fn with_deadline<T>(&self, exchange: impl Future<Output = Result<T>>) -> Result<T> {
+        match self.timeout {
+            Some(timeout) => tokio::time::timeout(timeout, exchange).await.map_err(|_| anyhow!(ERR_TIMEOUT))?,
+            None => exchange.await,
+        }
+    }
+
+    /// Runs a client exchange. See `driver::authenticate_client`.
+    pub async fn authenticate_client<C, T>(&self, client: &mut C, stream: &mut T) -> Result<Outcome>
+    where
+        C: AsyncClient,
+        T: AsyncRead + AsyncWrite + Unpin + Send,
+    {
+        let mut transport = Transport::new(stream);
+        self.with_deadline(async {
+            let (mechanism, initial_response) = client.start().await?;
+            transport.write(&self.codec.encode_client(&ClientMessage::Start { mechanism, initial_response })?).await?;
+            loop {
+                match transport.read(|input| self.codec.decode_server(input)).await? {
+                    ServerMessage::Challenge(challenge) => match client.next(&challenge).await {
+                        Ok(response) => transport.write(&self.codec.encode_client(&ClientMessage::Response(response))?).await?,
+                        Err(err) => {
+                            let cancel = client.cancel().map_or(ClientMessage::Cancel, ClientMessage::Response);
+                            transport.write(&self.codec.encode_client(&cancel)?).await?;
+                            return match transport.read(|input| self.codec.decode_server(input)).await? {
+                                ServerMessage::Failure => Ok(Outcome::Failure(err)),
+                                _ => bail!(ERR_UNEXPECTED_MESSAGE),
+                            };
+                        }
+                    },
+                    ServerMessage::Success(data) => {
+                        return match client.finish(data.as_deref()).await {
+                            Ok(()) => Ok(Outcome::Success {
+                                security_layer: client.security_layer(),
+                            }),
+                            Err(err) => Ok(Outcome::Failure(err)),
+                        };
+                    }
+                    ServerMessage::Failure => {
+                        let err = client.failure().unwrap_or_else(|| anyhow!(sasl::ERR_AUTHENTICATION_FAILED));
+                        return Ok(Outcome::Failure(err));
+                    }
+                }
+            }
+        })
+        .await
+    }
+
+    /// Runs a server exchange. See `driver::authenticate_server`.
+    pub async fn authenticate_server<V, T>(&self, server: &mut V, stream: &mut T) -> Result<Outcome>
+    where
+        V: AsyncServer,
+        T: AsyncRead + AsyncWrite + Unpin + Send,
+    {
+        let mut transport = Transport::new(stream);
+        self.with_deadline(async {
+            let ClientMessage::Start { mechanism, initial_response } = transport.read(|input| self.codec.decode_client(input)).await? else {
+                bail!(ERR_UNEXPECTED_MESSAGE);
+            };
+            if !mechanism.eq_ignore_ascii_case(server.mechanism_name()) {
+                transport.write(&self.codec.encode_server(&ServerMessage::Failure)).await?;
+                return Ok(Outcome::Failure(anyhow!(ERR_MECHANISM_MISMATCH)));
+            }
+            let mut step = server.next(initial_response.as_deref()).await;
+            loop {
+                match step {
+                    Ok(sasl::ServerStep::Challenge(challenge)) => {
+                        transport.write(&self.codec.encode_server(&ServerMessage::Challenge(challenge))).await?;
+                        step = match transport.read(|input| self.codec.decode_client(input)).await? {
+                            ClientMessage::Response(response) => server.next(Some(&response)).await,
+                            ClientMessage::Cancel => Err(sasl::Error::Canceled.into()),
+                            ClientMessage::Start { .. } => bail!(ERR_UNEXPECTED_MESSAGE),
+                        };
+                    }
+                    Ok(sasl::ServerStep::Done { additional_data }) => {
+                        transport.write(&self.codec.encode_server(&ServerMessage::Success(additional_data))).await?;
+                        return Ok(Outcome::Success {
+                            security_layer: server.security_layer(),
+                        });
+                    }
+                    Err(err) => {
+                        transport.write(&self.codec.encode_server(&ServerMessage::Failure)).await?;
+                        return Ok(Outcome::Failure(err));
+                    }
+                }
+            }
+        })
+        .await
+    }
+}
+
+/// A stream with the bytes received but not decoded yet. Bytes following
+/// the last message of the exchange are discarded, so peers must wait for
+/// the outcome before sending anything else.
+struct Transport<'a, T> {
+    stream: &'a mut T,
+    input: Vec<u8>,
+}
+
+impl<'a, T: AsyncRead + AsyncWrite + Unpin> Transport<'a, T> {
+    fn new(stream: &'a mut T) -> Self {
+        Self { stream, input: Vec::new() }
+    }
+
+    async fn write(&mut self, data: &[u8]) -> Result<()> {
+        self.stream.write_all(data).await?;
+        Ok(self.stream.flush().await?)
+    }
+
+    async fn read<M>(&mut self, decode: impl Fn(&[u8]) -> Result<Option<(M, usize)>>) -> Result<M> {
+        loop {
+            if let Some((message, len)) = decode(&self.input)? {
+                self.input.drain(..len);
+                return Ok(message);
+            }
+            let mut buf = [0; 4096];
+            let n = self.stream.read(&mut buf).await?;
+            if n == 0 {
+                bail!(ERR_CONNECTION_CLOSED);
+            }
+            self.input.extend_from_slice(&buf[..n]);
+        }
+    }
+}
+
+#[cfg(feature = "login")]
+#[test]
+fn test_async_driver() -> Result<()> {
+    use crate::async_sasl::Inline;
+    use crate::login::{LoginClient, LoginServer};
+
+    let runtime = tokio::runtime::Builder::new_current_thread().enable_time().build()?;
+    runtime.block_on(async {
+        for codec in [0, 1] {
+            for (password, success) in [("password", true), ("wrong", false)] {
+                let (mut client_stream, mut server_stream) = tokio::io::duplex(64);
+                let server = tokio::spawn(async move {
+                    let mut server = Inline::new(LoginServer::new(Box::new(|_, p| match p {
+                        "password" => Ok(()),
+                        _ => bail!("invalid credentials"),
+                    })));
+                    match codec {
+                        0 => AsyncDriver::new(LineCodec::new()).authenticate_server(&mut server, &mut server_stream).await,
+                        _ => AsyncDriver::new(FrameCodec::new()).authenticate_server(&mut server, &mut server_stream).await,
+                    }
+                });
+                let mut client = Inline::new(LoginClient::new("username".to_string(), password.to_string()));
+                let outcome = match codec {
+                    0 => AsyncDriver::new(LineCodec::new()).authenticate_client(&mut client, &mut client_stream).await?,
+                    _ => AsyncDriver::new(FrameCodec::new()).authenticate_client(&mut client, &mut client_stream).await?,
+                };
+                if outcome.is_success() != success || server.await??.is_success() != success {
+                    bail!("Unexpected outcome with codec {} and password {}", codec, password);
+                }
+            }
+        }
+
+        let (mut client_stream, _server_stream) = tokio::io::duplex(64);
+        let mut client = Inline::new(LoginClient::new("username".to_string(), "password".to_string()));
+        let driver = AsyncDriver::new(LineCodec::new()).with_timeout(Duration::from_millis(10));
+        match driver.authenticate_client(&mut client, &mut client_stream).await {
+            Err(err) if err.to_string() == ERR_TIMEOUT => Ok(()),
+            _ => bail!("Expected the exchange to time out"),
+        }
+    })
+}
+
+#[test]
+fn test_frame_codec_limits() -> Result<()> {
+    let codec = FrameCodec::new().with_max_frame_length(usize::MAX);
+    let start = ClientMessage::Start {
+        mechanism: "X".repeat(256),
+        initial_response: None,
+    };
+    match codec.encode_client(&start) {
+        Err(err) if err.to_string() == ERR_MECHANISM_NAME_TOO_LONG => {}
+        result => bail!("Expected the mechanism name to be rejected, got {:?}", result),
+    }
+    match codec.decode_client(b"\xff\xff\xff\xff\x00") {
+        Ok(None) if usize::BITS > 32 => {}
+        Err(err) if err.to_string() == ERR_MESSAGE_TOO_LARGE => {}
+        result => bail!("Unexpected result for the longest frame: {:?}", result.map(|message| message.map(|(_, len)| len))),
+    }
+    Ok(())
+}
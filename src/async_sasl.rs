@@ -0,0 +1,443 @@
+//! Asynchronous counterparts of the `sasl::Client` and `sasl::Server`
+//! traits, for mechanisms whose steps need to await I/O (e.g. a database
+//! query or a token introspection request).
+
+use crate::sasl;
+use crate::security_layer::BoxSecurityLayer;
+
+use anyhow::{anyhow, bail, Result};
+use std::future::Future;
+use std::pin::Pin;
+
+/// A boxed future, as returned by asynchronous authenticators.
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Asynchronous client interface to perform challenge-response
+/// authentication. See `sasl::Client`.
+pub trait AsyncClient: Send {
+    /// Returns the name of the authentication mechanism.
+    fn mechanism_name(&self) -> &str;
+
+    /// Begins SASL authentication with the server. See `sasl::Client::start`.
+    fn start(&mut self) -> impl Future<Output = Result<(String, Option<Vec<u8>>)>> + Send;
+
+    /// Continues challenge-response authentication. See
+    /// `sasl::Client::next`.
+    fn next(&mut self, challenge: &[u8]) -> impl Future<Output = Result<Vec<u8>>> + Send;
+
+    /// Completes authentication after the server reported success. See
+    /// `sasl::Client::finish`.
+    fn finish(&mut self, data: Option<&[u8]>) -> impl Future<Output = Result<()>> + Send {
+        let unexpected = matches!(data, Some(data) if !data.is_empty());
+        async move {
+            if unexpected {
+                bail!(sasl::ERR_UNEXPECTED_SUCCESS_DATA);
+            }
+            Ok(())
+        }
+    }
+
+    /// Aborts the exchange. See `sasl::Client::cancel`.
+    fn cancel(&mut self) -> Option<Vec<u8>> {
+        None
+    }
+
+    /// Returns the error received from the server. See
+    /// `sasl::Client::failure`.
+    fn failure(&mut self) -> Option<anyhow::Error> {
+        None
+    }
+
+    /// Returns the negotiated security layer. See
+    /// `sasl::Client::security_layer`.
+    fn security_layer(&mut self) -> Option<BoxSecurityLayer> {
+        None
+    }
+}
+
+/// Asynchronous server interface to perform challenge-response
+/// authentication. See `sasl::Server`.
+pub trait AsyncServer: Send {
+    /// Returns the name of the authentication mechanism.
+    fn mechanism_name(&self) -> &str;
+
+    /// Begins or continues challenge-response authentication. See
+    /// `sasl::Server::next`.
+    fn next(&mut self, response: Option<&[u8]>) -> impl Future<Output = Result<sasl::ServerStep>> + Send;
+
+    /// Reports whether the exchange is over. See `sasl::Server::is_done`.
+    fn is_done(&self) -> bool {
+        false
+    }
+
+    /// Resets the server to its initial state. See `sasl::Server::reset`.
+    fn reset(&mut self) -> Result<()> {
+        bail!(sasl::ERR_RESET_UNSUPPORTED)
+    }
+
+    /// Returns the negotiated security layer. See
+    /// `sasl::Server::security_layer`.
+    fn security_layer(&mut self) -> Option<BoxSecurityLayer> {
+        None
+    }
+}
+
+/// An object-safe form of `AsyncClient`, returning boxed futures, for
+/// mechanisms which must be used as trait objects (e.g. plugins). It is
+/// implemented by every `AsyncClient`, and `BoxAsyncClient` implements
+/// `AsyncClient` in turn. Its methods are prefixed with `dyn_`, so that
+/// calls stay unambiguous when both traits are in scope.
+pub trait DynAsyncClient: Send {
+    fn dyn_mechanism_name(&self) -> &str;
+    fn dyn_start(&mut self) -> BoxFuture<'_, Result<(String, Option<Vec<u8>>)>>;
+    fn dyn_next<'a>(&'a mut self, challenge: &'a [u8]) -> BoxFuture<'a, Result<Vec<u8>>>;
+    fn dyn_finish<'a>(&'a mut self, data: Option<&'a [u8]>) -> BoxFuture<'a, Result<()>>;
+    fn dyn_cancel(&mut self) -> Option<Vec<u8>>;
+    fn dyn_failure(&mut self) -> Option<anyhow::Error>;
+    fn dyn_security_layer(&mut self) -> Option<BoxSecurityLayer>;
+}
+
+pub type BoxAsyncClient = Box<dyn DynAsyncClient>;
+
+impl<C: AsyncClient> DynAsyncClient for C {
+    fn dyn_mechanism_name(&self) -> &str {
+        AsyncClient::mechanism_name(self)
+    }
+
+    fn dyn_start(&mut self) -> BoxFuture<'_, Result<(String, Option<Vec<u8>>)>> {
+        Box::pin(AsyncClient::start(self))
+    }
+
+    fn dyn_next<'a>(&'a mut self, challenge: &'a [u8]) -> BoxFuture<'a, Result<Vec<u8>>> {
+        Box::pin(AsyncClient::next(self, challenge))
+    }
+
+    fn dyn_finish<'a>(&'a mut self, data: Option<&'a [u8]>) -> BoxFuture<'a, Result<()>> {
+        Box::pin(AsyncClient::finish(self, data))
+    }
+
+    fn dyn_cancel(&mut self) -> Option<Vec<u8>> {
+        AsyncClient::cancel(self)
+    }
+
+    fn dyn_failure(&mut self) -> Option<anyhow::Error> {
+        AsyncClient::failure(self)
+    }
+
+    fn dyn_security_layer(&mut self) -> Option<BoxSecurityLayer> {
+        AsyncClient::security_layer(self)
+    }
+}
+
+impl AsyncClient for BoxAsyncClient {
+    fn mechanism_name(&self) -> &str {
+        (**self).dyn_mechanism_name()
+    }
+
+    async fn start(&mut self) -> Result<(String, Option<Vec<u8>>)> {
+        (**self).dyn_start().await
+    }
+
+    async fn next(&mut self, challenge: &[u8]) -> Result<Vec<u8>> {
+        (**self).dyn_next(challenge).await
+    }
+
+    async fn finish(&mut self, data: Option<&[u8]>) -> Result<()> {
+        (**self).dyn_finish(data).await
+    }
+
+    fn cancel(&mut self) -> Option<Vec<u8>> {
+        (**self).dyn_cancel()
+    }
+
+    fn failure(&mut self) -> Option<anyhow::Error> {
+        (**self).dyn_failure()
+    }
+
+    fn security_layer(&mut self) -> Option<BoxSecurityLayer> {
+        (**self).dyn_security_layer()
+    }
+}
+
+/// An object-safe form of `AsyncServer`, returning boxed futures. See
+/// `DynAsyncClient`.
+pub trait DynAsyncServer: Send {
+    fn dyn_mechanism_name(&self) -> &str;
+    fn dyn_next<'a>(&'a mut self, response: Option<&'a [u8]>) -> BoxFuture<'a, Result<sasl::ServerStep>>;
+    fn dyn_is_done(&self) -> bool;
+    fn dyn_reset(&mut self) -> Result<()>;
+    fn dyn_security_layer(&mut self) -> Option<BoxSecurityLayer>;
+}
+
+pub type BoxAsyncServer = Box<dyn DynAsyncServer>;
+
+impl<S: AsyncServer> DynAsyncServer for S {
+    fn dyn_mechanism_name(&self) -> &str {
+        AsyncServer::mechanism_name(self)
+    }
+
+    fn dyn_next<'a>(&'a mut self, response: Option<&'a [u8]>) -> BoxFuture<'a, Result<sasl::ServerStep>> {
+        Box::pin(AsyncServer::next(self, response))
+    }
+
+    fn dyn_is_done(&self) -> bool {
+        AsyncServer::is_done(self)
+    }
+
+    fn dyn_reset(&mut self) -> Result<()> {
+        AsyncServer::reset(self)
+    }
+
+    fn dyn_security_layer(&mut self) -> Option<BoxSecurityLayer> {
+        AsyncServer::security_layer(self)
+    }
+}
+
+impl AsyncServer for BoxAsyncServer {
+    fn mechanism_name(&self) -> &str {
+        (**self).dyn_mechanism_name()
+    }
+
+    async fn next(&mut self, response: Option<&[u8]>) -> Result<sasl::ServerStep> {
+        (**self).dyn_next(response).await
+    }
+
+    fn is_done(&self) -> bool {
+        (**self).dyn_is_done()
+    }
+
+    fn reset(&mut self) -> Result<()> {
+        (**self).dyn_reset()
+    }
+
+    fn security_layer(&mut self) -> Option<BoxSecurityLayer> {
+        (**self).dyn_security_layer()
+    }
+}
+
+/// Adapts a synchronous client or server to the asynchronous traits by
+/// running its steps directly on the calling task. Only suitable for
+/// mechanisms which don't block, e.g. clients or servers with in-memory
+/// authenticators.
+pub struct Inline<T> {
+    inner: T,
+}
+
+impl<T> Inline<T> {
+    pub fn new(inner: T) -> Self {
+        Self { inner }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<C: sasl::Client + Send> AsyncClient for Inline<C> {
+    fn mechanism_name(&self) -> &str {
+        self.inner.mechanism_name()
+    }
+
+    async fn start(&mut self) -> Result<(String, Option<Vec<u8>>)> {
+        self.inner.start()
+    }
+
+    async fn next(&mut self, challenge: &[u8]) -> Result<Vec<u8>> {
+        self.inner.next(challenge)
+    }
+
+    async fn finish(&mut self, data: Option<&[u8]>) -> Result<()> {
+        self.inner.finish(data)
+    }
+
+    fn cancel(&mut self) -> Option<Vec<u8>> {
+        self.inner.cancel()
+    }
+
+    fn failure(&mut self) -> Option<anyhow::Error> {
+        self.inner.failure()
+    }
+
+    fn security_layer(&mut self) -> Option<BoxSecurityLayer> {
+        self.inner.security_layer()
+    }
+}
+
+impl<S: sasl::Server> AsyncServer for Inline<S> {
+    fn mechanism_name(&self) -> &str {
+        self.inner.mechanism_name()
+    }
+
+    async fn next(&mut self, response: Option<&[u8]>) -> Result<sasl::ServerStep> {
+        self.inner.next(response)
+    }
+
+    fn is_done(&self) -> bool {
+        self.inner.is_done()
+    }
+
+    fn reset(&mut self) -> Result<()> {
+        self.inner.reset()
+    }
+
+    fn security_layer(&mut self) -> Option<BoxSecurityLayer> {
+        self.inner.security_layer()
+    }
+}
+
+const ERR_LOST: &str = "sasl: mechanism lost after a panic";
+
+/// Adapts a synchronous client or server to the asynchronous traits by
+/// running each step on tokio's blocking thread pool, for mechanisms whose
+/// authenticators perform blocking I/O.
+pub struct SpawnBlocking<T> {
+    mechanism: String,
+    inner: Option<T>,
+}
+
+impl<C: sasl::Client + Send + 'static> SpawnBlocking<C> {
+    pub fn client(client: C) -> Self {
+        Self {
+            mechanism: client.mechanism_name().to_string(),
+            inner: Some(client),
+        }
+    }
+
+    async fn run_client<R, F>(&mut self, f: F) -> Result<R>
+    where
+        R: Send + 'static,
+        F: FnOnce(&mut C) -> Result<R> + Send + 'static,
+    {
+        let mut client = self.inner.take().ok_or_else(|| anyhow!(ERR_LOST))?;
+        let (client, result) = tokio::task::spawn_blocking(move || {
+            let result = f(&mut client);
+            (client, result)
+        })
+        .await?;
+        self.inner = Some(client);
+        result
+    }
+}
+
+impl<S: sasl::Server + 'static> SpawnBlocking<S> {
+    pub fn server(server: S) -> Self {
+        Self {
+            mechanism: server.mechanism_name().to_string(),
+            inner: Some(server),
+        }
+    }
+}
+
+impl<C: sasl::Client + Send + 'static> AsyncClient for SpawnBlocking<C> {
+    fn mechanism_name(&self) -> &str {
+        &self.mechanism
+    }
+
+    async fn start(&mut self) -> Result<(String, Option<Vec<u8>>)> {
+        self.run_client(|client| client.start()).await
+    }
+
+    async fn next(&mut self, challenge: &[u8]) -> Result<Vec<u8>> {
+        let challenge = challenge.to_vec();
+        self.run_client(move |client| client.next(&challenge)).await
+    }
+
+    async fn finish(&mut self, data: Option<&[u8]>) -> Result<()> {
+        let data = data.map(|data| data.to_vec());
+        self.run_client(move |client| client.finish(data.as_deref())).await
+    }
+
+    fn cancel(&mut self) -> Option<Vec<u8>> {
+        self.inner.as_mut().and_then(|client| client.cancel())
+    }
+
+    fn failure(&mut self) -> Option<anyhow::Error> {
+        self.inner.as_mut().and_then(|client| client.failure())
+    }
+
+    fn security_layer(&mut self) -> Option<BoxSecurityLayer> {
+        self.inner.as_mut().and_then(|client| client.security_layer())
+    }
+}
+
+impl<S: sasl::Server + 'static> AsyncServer for SpawnBlocking<S> {
+    fn mechanism_name(&self) -> &str {
+        &self.mechanism
+    }
+
+    async fn next(&mut self, response: Option<&[u8]>) -> Result<sasl::ServerStep> {
+        let mut server = self.inner.take().ok_or_else(|| anyhow!(ERR_LOST))?;
+        let response = response.map(|response| response.to_vec());
+        let (server, result) = tokio::task::spawn_blocking(move || {
+            let result = server.next(response.as_deref());
+            (server, result)
+        })
+        .await?;
+        self.inner = Some(server);
+        result
+    }
+
+    fn is_done(&self) -> bool {
+        self.inner.as_ref().is_some_and(|server| server.is_done())
+    }
+
+    fn reset(&mut self) -> Result<()> {
+        self.inner.as_mut().ok_or_else(|| anyhow!(ERR_LOST))?.reset()
+    }
+
+    fn security_layer(&mut self) -> Option<BoxSecurityLayer> {
+        self.inner.as_mut().and_then(|server| server.security_layer())
+    }
+}
+
+#[cfg(feature = "plain")]
+#[test]
+fn test_spawn_blocking_plain() -> Result<()> {
+    use crate::plain::{PlainClient, PlainServer};
+
+    let runtime = tokio::runtime::Builder::new_current_thread().build()?;
+    runtime.block_on(async {
+        let mut client = SpawnBlocking::client(PlainClient::new(
+            String::new(),
+            "username".to_string(),
+            "password".to_string(),
+        ));
+        let mut server = SpawnBlocking::server(PlainServer::new(Box::new(|_, username, password| {
+            std::thread::sleep(std::time::Duration::from_millis(1));
+            if username != "username" || password != "password" {
+                bail!("Invalid credentials");
+            }
+            Ok(())
+        })));
+
+        let (_, ir) = client.start().await?;
+        if !server.next(ir.as_deref()).await?.is_done() || !server.is_done() {
+            bail!("Expected authentication to be done");
+        }
+        client.finish(None).await
+    })
+}
+
+#[cfg(feature = "plain")]
+#[test]
+fn test_boxed_async_server() -> Result<()> {
+    use crate::plain::{AsyncPlainServer, PlainServer};
+
+    async fn authenticate<S: AsyncServer>(server: &mut S) -> Result<bool> {
+        Ok(server.next(Some(b"\x00username\x00password")).await?.is_done())
+    }
+
+    let runtime = tokio::runtime::Builder::new_current_thread().build()?;
+    runtime.block_on(async {
+        let mut servers: Vec<BoxAsyncServer> = vec![
+            Box::new(Inline::new(PlainServer::new(Box::new(|_, _, _| Ok(()))))),
+            Box::new(AsyncPlainServer::new(Box::new(|_, _, _| Box::pin(async { Ok(()) })))),
+        ];
+        for server in &mut servers {
+            if !authenticate(server).await? || !server.is_done() {
+                bail!("Expected authentication to be done");
+            }
+        }
+        Ok(())
+    })
+}
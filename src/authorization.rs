@@ -0,0 +1,130 @@
+//! Authorization of proxy authentication, where a client authenticated as
+//! one identity (authcid) requests to act as another (authzid).
+
+use crate::sasl::FailureReason;
+
+use anyhow::{bail, Result};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Matches any authorization identity in a `ProxyPolicy`.
+pub const ANY_IDENTITY: &str = "*";
+
+/// Decides whether an authenticated identity may act as a requested
+/// authorization identity. Servers consult it once credentials have been
+/// verified, and only when the client requested an authorization identity
+/// other than its own.
+pub trait AuthorizationPolicy: Send + Sync {
+    /// Fails, e.g. with `FailureReason::AuthorizationDenied`, if `authcid`
+    /// may not act as `authzid`.
+    fn authorize(&self, authcid: &str, authzid: &str) -> Result<()>;
+}
+
+impl<F: Fn(&str, &str) -> Result<()> + Send + Sync> AuthorizationPolicy for F {
+    fn authorize(&self, authcid: &str, authzid: &str) -> Result<()> {
+        self(authcid, authzid)
+    }
+}
+
+/// A static list of the identities each user may act as, e.g. loaded from
+/// a configuration file. Users granted `ANY_IDENTITY` may act as anyone.
+#[derive(Clone, Debug, Default)]
+pub struct ProxyPolicy {
+    grants: BTreeMap<String, BTreeSet<String>>,
+}
+
+impl ProxyPolicy {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Allows `authcid` to act as `authzid`.
+    pub fn with_grant(mut self, authcid: &str, authzid: &str) -> Self {
+        self.grants.entry(authcid.to_string()).or_default().insert(authzid.to_string());
+        self
+    }
+}
+
+impl AuthorizationPolicy for ProxyPolicy {
+    fn authorize(&self, authcid: &str, authzid: &str) -> Result<()> {
+        match self.grants.get(authcid) {
+            Some(grants) if grants.contains(authzid) || grants.contains(ANY_IDENTITY) => Ok(()),
+            _ => bail!(FailureReason::AuthorizationDenied),
+        }
+    }
+}
+
+/// Authorizes `authcid` to act as `authzid`. An empty authorization
+/// identity is the authenticated identity itself, and is always allowed.
+/// Without a policy, clients may only act as themselves.
+#[cfg(any(feature = "plain", feature = "login", feature = "external", feature = "oauthbearer"))]
+pub(crate) fn authorize(policy: Option<&dyn AuthorizationPolicy>, authcid: &str, authzid: &str) -> Result<()> {
+    if authzid.is_empty() || authzid == authcid {
+        return Ok(());
+    }
+    match policy {
+        Some(policy) => policy.authorize(authcid, authzid),
+        None => bail!(FailureReason::AuthorizationDenied),
+    }
+}
+
+#[cfg(feature = "plain")]
+#[test]
+fn test_authorization_policy() -> Result<()> {
+    use crate::plain::PlainServer;
+    use crate::sasl::Server;
+    use std::sync::Arc;
+
+    let policy = ProxyPolicy::new().with_grant("admin", ANY_IDENTITY).with_grant("support", "username");
+    let new_server = || {
+        PlainServer::new(Box::new(|identity, _, password| {
+            if !identity.is_empty() {
+                bail!("Expected the identity to be left to the policy");
+            }
+            match password {
+                "password" => Ok(()),
+                _ => bail!(FailureReason::InvalidCredentials),
+            }
+        }))
+        .with_authorization_policy(Arc::new(policy.clone()))
+    };
+
+    let cases = [
+        (&b"\x00username\x00password"[..], None),
+        (b"username\x00username\x00password", None),
+        (b"anyone\x00admin\x00password", None),
+        (b"username\x00support\x00password", None),
+        (b"admin\x00support\x00password", Some(FailureReason::AuthorizationDenied)),
+        (b"admin\x00username\x00password", Some(FailureReason::AuthorizationDenied)),
+        (b"username\x00support\x00wrong", Some(FailureReason::InvalidCredentials)),
+    ];
+    for (response, expected) in cases {
+        let mut server = new_server();
+        match (server.next(Some(response)), expected) {
+            (Ok(_), None) => {}
+            (Err(err), Some(reason)) if FailureReason::of(&err) == reason => {}
+            (result, _) => bail!("Unexpected result for {:?}: {:?}", response, result.err()),
+        }
+    }
+
+    #[cfg(feature = "oauthbearer")]
+    {
+        use crate::oauthbearer::OAuthBearerServer;
+
+        let mut server = OAuthBearerServer::from_validator(Box::new(|_| Ok("support".to_string())))
+            .with_authorization_policy(Arc::new(policy.clone()));
+        server.next(Some(b"n,a=username,\x01auth=Bearer token\x01\x01"))?;
+        if !server.is_done() || server.authentication_id() != Some("support") {
+            bail!("Expected support to act as username");
+        }
+        server.reset()?;
+        if server.next(Some(b"n,a=admin,\x01auth=Bearer token\x01\x01"))?.is_done() {
+            bail!("Expected an error challenge");
+        }
+        match server.next(Some(b"\x01")) {
+            Err(err) if FailureReason::of(&err) == FailureReason::AuthorizationDenied => {}
+            _ => bail!("Expected support not to act as admin"),
+        }
+    }
+
+    Ok(())
+}
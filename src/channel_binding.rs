@@ -0,0 +1,279 @@
+//! Channel binding data of a TLS session, as used by mechanisms binding the
+//! authentication to the underlying connection (e.g. `-PLUS` variants). It
+//! is obtained from the TLS library by the application, so that mechanisms
+//! don't depend on any of them. Helpers for TLS libraries are behind a
+//! cargo feature named after the library.
+
+#[cfg(feature = "native-tls")]
+pub mod native_tls;
+#[cfg(feature = "openssl")]
+pub mod openssl;
+#[cfg(feature = "rustls")]
+pub mod rustls;
+
+use anyhow::{anyhow, bail, Result};
+use sha2::{Digest, Sha224, Sha256, Sha384, Sha512};
+
+/// The channel binding of a connection, as described in RFC 5056.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum ChannelBinding {
+    /// No channel binding is available, e.g. on a cleartext connection.
+    #[default]
+    None,
+    /// `tls-unique` (RFC 5929), the first Finished message of the latest
+    /// handshake. Not defined for TLS 1.3.
+    TlsUnique(Vec<u8>),
+    /// `tls-server-end-point` (RFC 5929), a hash of the server certificate.
+    TlsServerEndPoint(Vec<u8>),
+    /// `tls-exporter` (RFC 9266), exported keying material. TLS 1.3 only.
+    TlsExporter(Vec<u8>),
+}
+
+pub const TLS_UNIQUE: &str = "tls-unique";
+pub const TLS_SERVER_END_POINT: &str = "tls-server-end-point";
+pub const TLS_EXPORTER: &str = "tls-exporter";
+
+/// The exporter label and length of `tls-exporter` bindings (RFC 9266).
+pub const TLS_EXPORTER_LABEL: &str = "EXPORTER-Channel-Binding";
+pub const TLS_EXPORTER_LENGTH: usize = 32;
+
+pub const ERR_NO_CHANNEL_BINDING: &str = "sasl: channel binding required but none is available";
+pub const ERR_HANDSHAKE_IN_PROGRESS: &str = "sasl: TLS handshake not complete";
+pub const ERR_TLS_UNIQUE_UNDEFINED: &str = "sasl: tls-unique is not defined for TLS 1.3";
+pub const ERR_TLS_EXPORTER_UNDEFINED: &str = "sasl: tls-exporter is only defined for TLS 1.3";
+pub const ERR_NO_PEER_CERTIFICATE: &str = "sasl: no peer certificate";
+pub const ERR_MALFORMED_CERTIFICATE: &str = "sasl: malformed certificate";
+pub const ERR_UNSUPPORTED_SIGNATURE_ALGORITHM: &str =
+    "sasl: no tls-server-end-point hash for the certificate signature algorithm";
+
+impl ChannelBinding {
+    /// Creates a channel binding from its registered type name, or returns
+    /// `None` for unknown types.
+    pub fn from_name(name: &str, data: Vec<u8>) -> Option<Self> {
+        match name {
+            TLS_UNIQUE => Some(ChannelBinding::TlsUnique(data)),
+            TLS_SERVER_END_POINT => Some(ChannelBinding::TlsServerEndPoint(data)),
+            TLS_EXPORTER => Some(ChannelBinding::TlsExporter(data)),
+            _ => None,
+        }
+    }
+
+    /// Returns the registered type name, as sent in GS2 headers.
+    pub fn name(&self) -> Option<&'static str> {
+        match self {
+            ChannelBinding::None => None,
+            ChannelBinding::TlsUnique(_) => Some(TLS_UNIQUE),
+            ChannelBinding::TlsServerEndPoint(_) => Some(TLS_SERVER_END_POINT),
+            ChannelBinding::TlsExporter(_) => Some(TLS_EXPORTER),
+        }
+    }
+
+    pub fn data(&self) -> Option<&[u8]> {
+        match self {
+            ChannelBinding::None => None,
+            ChannelBinding::TlsUnique(data)
+            | ChannelBinding::TlsServerEndPoint(data)
+            | ChannelBinding::TlsExporter(data) => Some(data),
+        }
+    }
+
+    pub fn is_none(&self) -> bool {
+        *self == ChannelBinding::None
+    }
+
+    /// Computes the `tls-server-end-point` channel binding from the DER
+    /// certificate of the server, as described in RFC 5929 section 4.1: the
+    /// certificate is hashed with the hash function of its signature
+    /// algorithm, or SHA-256 if that is MD5 or SHA-1. Certificates signed
+    /// without a separate hash function (e.g. Ed25519) are rejected.
+    pub fn tls_server_end_point(certificate: &[u8]) -> Result<Self> {
+        let data = match end_point_hash(certificate)? {
+            EndPointHash::Sha224 => Sha224::digest(certificate).to_vec(),
+            EndPointHash::Sha256 => Sha256::digest(certificate).to_vec(),
+            EndPointHash::Sha384 => Sha384::digest(certificate).to_vec(),
+            EndPointHash::Sha512 => Sha512::digest(certificate).to_vec(),
+        };
+        Ok(ChannelBinding::TlsServerEndPoint(data))
+    }
+}
+
+/// Picks the channel binding to use among the ones available on a
+/// connection: tls-exporter with TLS 1.3, tls-unique with earlier versions,
+/// and tls-server-end-point otherwise. If the types supported by the peer
+/// are known, only those are considered. Returns `ChannelBinding::None` if
+/// none fits.
+pub fn select(tls13: bool, available: &[ChannelBinding], peer_types: Option<&[&str]>) -> ChannelBinding {
+    let preferred = if tls13 { TLS_EXPORTER } else { TLS_UNIQUE };
+    [preferred, TLS_SERVER_END_POINT]
+        .into_iter()
+        .filter(|name| peer_types.is_none_or(|types| types.contains(name)))
+        .find_map(|name| available.iter().find(|binding| binding.name() == Some(name)))
+        .cloned()
+        .unwrap_or_default()
+}
+
+#[derive(Clone, Copy)]
+enum EndPointHash {
+    Sha224,
+    Sha256,
+    Sha384,
+    Sha512,
+}
+
+const OID_SHA1: &[u8] = b"\x2b\x0e\x03\x02\x1a";
+const OID_SHA224: &[u8] = b"\x60\x86\x48\x01\x65\x03\x04\x02\x04";
+const OID_SHA256: &[u8] = b"\x60\x86\x48\x01\x65\x03\x04\x02\x01";
+const OID_SHA384: &[u8] = b"\x60\x86\x48\x01\x65\x03\x04\x02\x02";
+const OID_SHA512: &[u8] = b"\x60\x86\x48\x01\x65\x03\x04\x02\x03";
+const OID_RSASSA_PSS: &[u8] = b"\x2a\x86\x48\x86\xf7\x0d\x01\x01\x0a";
+
+/// Signature algorithms and the hash function they use.
+const SIGNATURE_HASHES: &[(&[u8], EndPointHash)] = &[
+    (b"\x2a\x86\x48\x86\xf7\x0d\x01\x01\x04", EndPointHash::Sha256), // md5WithRSAEncryption
+    (b"\x2a\x86\x48\x86\xf7\x0d\x01\x01\x05", EndPointHash::Sha256), // sha1WithRSAEncryption
+    (b"\x2a\x86\x48\x86\xf7\x0d\x01\x01\x0b", EndPointHash::Sha256), // sha256WithRSAEncryption
+    (b"\x2a\x86\x48\x86\xf7\x0d\x01\x01\x0c", EndPointHash::Sha384), // sha384WithRSAEncryption
+    (b"\x2a\x86\x48\x86\xf7\x0d\x01\x01\x0d", EndPointHash::Sha512), // sha512WithRSAEncryption
+    (b"\x2a\x86\x48\x86\xf7\x0d\x01\x01\x0e", EndPointHash::Sha224), // sha224WithRSAEncryption
+    (b"\x2a\x86\x48\xce\x3d\x04\x01", EndPointHash::Sha256), // ecdsa-with-SHA1
+    (b"\x2a\x86\x48\xce\x3d\x04\x03\x01", EndPointHash::Sha224), // ecdsa-with-SHA224
+    (b"\x2a\x86\x48\xce\x3d\x04\x03\x02", EndPointHash::Sha256), // ecdsa-with-SHA256
+    (b"\x2a\x86\x48\xce\x3d\x04\x03\x03", EndPointHash::Sha384), // ecdsa-with-SHA384
+    (b"\x2a\x86\x48\xce\x3d\x04\x03\x04", EndPointHash::Sha512), // ecdsa-with-SHA512
+    (b"\x2a\x86\x48\xce\x38\x04\x03", EndPointHash::Sha256), // dsa-with-sha1
+    (b"\x60\x86\x48\x01\x65\x03\x04\x03\x01", EndPointHash::Sha224), // dsa-with-sha224
+    (b"\x60\x86\x48\x01\x65\x03\x04\x03\x02", EndPointHash::Sha256), // dsa-with-sha256
+];
+
+/// Splits the first DER element off `input`, returning its tag, its
+/// contents and the remaining input.
+fn der_element(input: &[u8]) -> Result<(u8, &[u8], &[u8])> {
+    let malformed = || anyhow!(ERR_MALFORMED_CERTIFICATE);
+    let (&tag, rest) = input.split_first().ok_or_else(malformed)?;
+    let (&len, mut rest) = rest.split_first().ok_or_else(malformed)?;
+    let len = if len < 0x80 {
+        len as usize
+    } else {
+        let n = (len & 0x7f) as usize;
+        if n == 0 || n > 4 || rest.len() < n {
+            bail!(ERR_MALFORMED_CERTIFICATE);
+        }
+        let (bytes, r) = rest.split_at(n);
+        rest = r;
+        bytes.iter().fold(0, |acc, &b| acc << 8 | b as usize)
+    };
+    if rest.len() < len {
+        bail!(ERR_MALFORMED_CERTIFICATE);
+    }
+    let (contents, rest) = rest.split_at(len);
+    Ok((tag, contents, rest))
+}
+
+/// Parses an AlgorithmIdentifier, returning the algorithm OID and the
+/// encoded parameters.
+fn algorithm_identifier(input: &[u8]) -> Result<(&[u8], &[u8])> {
+    match der_element(input)? {
+        (0x30, contents, _) => match der_element(contents)? {
+            (0x06, oid, params) => Ok((oid, params)),
+            _ => bail!(ERR_MALFORMED_CERTIFICATE),
+        },
+        _ => bail!(ERR_MALFORMED_CERTIFICATE),
+    }
+}
+
+/// Returns the tls-server-end-point hash function for a certificate.
+fn end_point_hash(certificate: &[u8]) -> Result<EndPointHash> {
+    let certificate = match der_element(certificate)? {
+        (0x30, contents, _) => contents,
+        _ => bail!(ERR_MALFORMED_CERTIFICATE),
+    };
+    let (_, _, rest) = der_element(certificate)?;
+    let (oid, params) = algorithm_identifier(rest)?;
+
+    if oid == OID_RSASSA_PSS {
+        // RSASSA-PSS-params starts with an optional [0] hashAlgorithm,
+        // defaulting to SHA-1.
+        let hash = match der_element(params)? {
+            (0x30, contents, _) if !contents.is_empty() => match der_element(contents)? {
+                (0xa0, hash, _) => algorithm_identifier(hash)?.0,
+                _ => OID_SHA1,
+            },
+            _ => OID_SHA1,
+        };
+        return match hash {
+            OID_SHA1 | OID_SHA256 => Ok(EndPointHash::Sha256),
+            OID_SHA224 => Ok(EndPointHash::Sha224),
+            OID_SHA384 => Ok(EndPointHash::Sha384),
+            OID_SHA512 => Ok(EndPointHash::Sha512),
+            _ => bail!(ERR_UNSUPPORTED_SIGNATURE_ALGORITHM),
+        };
+    }
+
+    match SIGNATURE_HASHES.iter().find(|(alg, _)| *alg == oid) {
+        Some((_, hash)) => Ok(*hash),
+        None => bail!(ERR_UNSUPPORTED_SIGNATURE_ALGORITHM),
+    }
+}
+
+#[test]
+fn test_channel_binding_names() -> Result<()> {
+    for name in [TLS_UNIQUE, TLS_SERVER_END_POINT, TLS_EXPORTER] {
+        let binding = ChannelBinding::from_name(name, b"data".to_vec());
+        if binding.as_ref().and_then(|b| b.name()) != Some(name) || binding.as_ref().and_then(|b| b.data()) != Some(b"data") {
+            bail!("Unexpected binding for {}", name);
+        }
+    }
+    if ChannelBinding::from_name("tls-unknown", Vec::new()).is_some() || ChannelBinding::default().name().is_some() {
+        bail!("Expected no binding");
+    }
+    Ok(())
+}
+
+#[test]
+fn test_select() -> Result<()> {
+    let unique = ChannelBinding::TlsUnique(b"unique".to_vec());
+    let end_point = ChannelBinding::TlsServerEndPoint(b"end-point".to_vec());
+    let exporter = ChannelBinding::TlsExporter(b"exporter".to_vec());
+    let available = [unique.clone(), end_point.clone(), exporter.clone()];
+
+    let cases = [
+        (true, &available[..], None, &exporter),
+        (false, &available[..], None, &unique),
+        (true, &available[..], Some(&[TLS_UNIQUE, TLS_SERVER_END_POINT][..]), &end_point),
+        (false, &available[..], Some(&[TLS_EXPORTER][..]), &ChannelBinding::None),
+        (true, &available[..1], None, &ChannelBinding::None),
+    ];
+    for (tls13, available, peer_types, expected) in cases {
+        if select(tls13, available, peer_types) != *expected {
+            bail!("Expected {:?} for {:?}", expected, peer_types);
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn test_tls_server_end_point() -> Result<()> {
+    let certificate = |alg: &'static rcgen::SignatureAlgorithm| -> Result<Vec<u8>> {
+        let key = rcgen::KeyPair::generate_for(alg)?;
+        let params = rcgen::CertificateParams::new(vec!["localhost".to_string()])?;
+        Ok(params.self_signed(&key)?.der().to_vec())
+    };
+
+    let der = certificate(&rcgen::PKCS_ECDSA_P256_SHA256)?;
+    if ChannelBinding::tls_server_end_point(&der)? != ChannelBinding::TlsServerEndPoint(Sha256::digest(&der).to_vec()) {
+        bail!("Expected a SHA-256 hash");
+    }
+    let der = certificate(&rcgen::PKCS_ECDSA_P384_SHA384)?;
+    if ChannelBinding::tls_server_end_point(&der)? != ChannelBinding::TlsServerEndPoint(Sha384::digest(&der).to_vec()) {
+        bail!("Expected a SHA-384 hash");
+    }
+    match ChannelBinding::tls_server_end_point(&certificate(&rcgen::PKCS_ED25519)?) {
+        Err(err) if err.to_string() == ERR_UNSUPPORTED_SIGNATURE_ALGORITHM => {}
+        _ => bail!("Expected Ed25519 to be unsupported"),
+    }
+    match ChannelBinding::tls_server_end_point(&der[..der.len() / 2]) {
+        Err(err) if err.to_string() == ERR_MALFORMED_CERTIFICATE => {}
+        _ => bail!("Expected a malformed certificate"),
+    }
+    Ok(())
+}
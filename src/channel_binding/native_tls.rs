@@ -0,0 +1,49 @@
+//! Channel bindings of native-tls streams. native-tls only exposes the peer
+//! certificate, so only `tls-server-end-point` is supported, on the client
+//! side.
+
+use crate::channel_binding::{ChannelBinding, ERR_NO_PEER_CERTIFICATE};
+
+use ::native_tls::TlsStream;
+use anyhow::{bail, Result};
+use std::io::{Read, Write};
+
+/// Returns the `tls-server-end-point` channel binding of a client stream,
+/// from the certificate presented by the server. Servers must use
+/// `ChannelBinding::tls_server_end_point` with their own certificate.
+pub fn tls_server_end_point<S: Read + Write>(stream: &TlsStream<S>) -> Result<ChannelBinding> {
+    match stream.peer_certificate()? {
+        Some(certificate) => ChannelBinding::tls_server_end_point(&certificate.to_der()?),
+        None => bail!(ERR_NO_PEER_CERTIFICATE),
+    }
+}
+
+#[test]
+fn test_tls_server_end_point() -> Result<()> {
+    use ::native_tls::{Identity, TlsAcceptor, TlsConnector};
+    use std::net::{TcpListener, TcpStream};
+
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()])?;
+    let expected = ChannelBinding::tls_server_end_point(cert.cert.der())?;
+    let identity = Identity::from_pkcs8(cert.cert.pem().as_bytes(), cert.key_pair.serialize_pem().as_bytes())?;
+    let acceptor = TlsAcceptor::new(identity)?;
+
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let server = std::thread::spawn(move || -> Result<()> {
+        let (stream, _) = listener.accept()?;
+        let stream = acceptor.accept(stream)?;
+        if tls_server_end_point(&stream).is_ok() {
+            bail!("Expected no client certificate");
+        }
+        Ok(())
+    });
+
+    let connector = TlsConnector::builder().danger_accept_invalid_certs(true).build()?;
+    let stream = connector.connect("localhost", TcpStream::connect(addr)?)?;
+    if tls_server_end_point(&stream)? != expected {
+        bail!("Unexpected binding");
+    }
+    drop(stream);
+    server.join().map_err(|_| anyhow::anyhow!("server panicked"))?
+}
@@ -0,0 +1,110 @@
+//! Channel bindings of OpenSSL connections. They take the `SslRef` of a
+//! connection, e.g. from `SslStream::ssl`.
+
+use crate::channel_binding::{
+    ChannelBinding, ERR_HANDSHAKE_IN_PROGRESS, ERR_NO_PEER_CERTIFICATE, ERR_TLS_EXPORTER_UNDEFINED, ERR_TLS_UNIQUE_UNDEFINED,
+    TLS_EXPORTER_LABEL, TLS_EXPORTER_LENGTH,
+};
+
+use ::openssl::ssl::{SslRef, SslVersion};
+use anyhow::{bail, Result};
+
+/// Returns the `tls-unique` channel binding of a connection, as described
+/// in RFC 5929 section 3.1: the first Finished message of the latest
+/// handshake, sent by the client unless the session was resumed. Only
+/// defined up to TLS 1.2.
+pub fn tls_unique(ssl: &SslRef) -> Result<ChannelBinding> {
+    if !ssl.is_init_finished() {
+        bail!(ERR_HANDSHAKE_IN_PROGRESS);
+    }
+    if ssl.version2() == Some(SslVersion::TLS1_3) {
+        bail!(ERR_TLS_UNIQUE_UNDEFINED);
+    }
+    // Finished messages are 12 bytes long with every TLS 1.2 cipher suite.
+    let mut data = [0; 64];
+    let len = if ssl.is_server() == ssl.session_reused() {
+        ssl.finished(&mut data)
+    } else {
+        ssl.peer_finished(&mut data)
+    };
+    Ok(ChannelBinding::TlsUnique(data[..len.min(data.len())].to_vec()))
+}
+
+/// Returns the `tls-exporter` channel binding of a connection, as
+/// described in RFC 9266. Only TLS 1.3 connections are supported, see
+/// `channel_binding::rustls::tls_exporter`.
+pub fn tls_exporter(ssl: &SslRef) -> Result<ChannelBinding> {
+    if !ssl.is_init_finished() {
+        bail!(ERR_HANDSHAKE_IN_PROGRESS);
+    }
+    if ssl.version2() != Some(SslVersion::TLS1_3) {
+        bail!(ERR_TLS_EXPORTER_UNDEFINED);
+    }
+    let mut data = [0; TLS_EXPORTER_LENGTH];
+    ssl.export_keying_material(&mut data, TLS_EXPORTER_LABEL, None)?;
+    Ok(ChannelBinding::TlsExporter(data.to_vec()))
+}
+
+/// Returns the `tls-server-end-point` channel binding of a client
+/// connection, from the certificate presented by the server. Servers must
+/// use `ChannelBinding::tls_server_end_point` with their own certificate.
+pub fn tls_server_end_point(ssl: &SslRef) -> Result<ChannelBinding> {
+    match ssl.peer_certificate() {
+        Some(certificate) => ChannelBinding::tls_server_end_point(&certificate.to_der()?),
+        None => bail!(ERR_NO_PEER_CERTIFICATE),
+    }
+}
+
+#[test]
+fn test_openssl_channel_bindings() -> Result<()> {
+    use ::openssl::pkey::PKey;
+    use ::openssl::ssl::{SslAcceptor, SslConnector, SslMethod, SslVerifyMode};
+    use ::openssl::x509::X509;
+    use std::net::{TcpListener, TcpStream};
+
+    type Bindings = (ChannelBinding, Option<ChannelBinding>, Option<ChannelBinding>);
+
+    // Returns the tls-unique, tls-exporter and tls-server-end-point bindings.
+    fn bindings(ssl: &SslRef) -> Bindings {
+        (tls_unique(ssl).unwrap_or_default(), tls_exporter(ssl).ok(), tls_server_end_point(ssl).ok())
+    }
+
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()])?;
+    let end_point = ChannelBinding::tls_server_end_point(cert.cert.der())?;
+    let x509 = X509::from_der(cert.cert.der())?;
+    let key = PKey::private_key_from_der(&cert.key_pair.serialize_der())?;
+    for version in [SslVersion::TLS1_2, SslVersion::TLS1_3] {
+        let mut acceptor = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls())?;
+        acceptor.set_certificate(&x509)?;
+        acceptor.set_private_key(&key)?;
+        acceptor.set_max_proto_version(Some(version))?;
+        let acceptor = acceptor.build();
+
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        let server = std::thread::spawn(move || -> Result<Bindings> {
+            let (stream, _) = listener.accept()?;
+            let stream = acceptor.accept(stream)?;
+            Ok(bindings(stream.ssl()))
+        });
+
+        let mut connector = SslConnector::builder(SslMethod::tls())?;
+        connector.set_verify(SslVerifyMode::NONE);
+        let stream = connector.build().connect("localhost", TcpStream::connect(addr)?)?;
+        let (unique, exporter, peer_end_point) = bindings(stream.ssl());
+        let (server_unique, server_exporter, _) = server.join().map_err(|_| anyhow::anyhow!("server panicked"))??;
+
+        if peer_end_point.as_ref() != Some(&end_point) || unique != server_unique || exporter != server_exporter {
+            bail!("Expected both sides to agree on bindings with {:?}", version);
+        }
+        let expected = if version == SslVersion::TLS1_3 {
+            unique.is_none() && exporter.is_some()
+        } else {
+            unique.data().is_some_and(|data| data.len() == 12) && exporter.is_none()
+        };
+        if !expected {
+            bail!("Unexpected bindings with {:?}", version);
+        }
+    }
+    Ok(())
+}
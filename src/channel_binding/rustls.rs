@@ -0,0 +1,148 @@
+//! Channel bindings of rustls connections. `ClientConnection` and
+//! `ServerConnection` both dereference to `rustls::CommonState`.
+
+use crate::channel_binding::{
+    ChannelBinding, ERR_HANDSHAKE_IN_PROGRESS, ERR_NO_PEER_CERTIFICATE, ERR_TLS_EXPORTER_UNDEFINED, ERR_TLS_UNIQUE_UNDEFINED,
+    TLS_EXPORTER_LABEL, TLS_EXPORTER_LENGTH,
+};
+
+use ::rustls::{CommonState, ConnectionCommon, ProtocolVersion};
+use anyhow::{bail, Result};
+
+pub const ERR_TLS_UNIQUE_UNSUPPORTED: &str = "sasl: tls-unique is not available from rustls";
+
+/// Returns the `tls-unique` channel binding of a connection.
+///
+/// tls-unique is only defined up to TLS 1.2 (RFC 9266), and rustls doesn't
+/// expose the Finished messages it is made of, so this only reports why the
+/// binding is unavailable. Use `tls-server-end-point` or `tls-exporter`
+/// instead.
+pub fn tls_unique(conn: &CommonState) -> Result<ChannelBinding> {
+    if conn.is_handshaking() {
+        bail!(ERR_HANDSHAKE_IN_PROGRESS);
+    }
+    match conn.protocol_version() {
+        Some(ProtocolVersion::TLSv1_3) => bail!(ERR_TLS_UNIQUE_UNDEFINED),
+        _ => bail!(ERR_TLS_UNIQUE_UNSUPPORTED),
+    }
+}
+
+/// Returns the `tls-server-end-point` channel binding of a client
+/// connection, from the certificate presented by the server. Servers must
+/// use `ChannelBinding::tls_server_end_point` with their own certificate.
+pub fn tls_server_end_point(conn: &CommonState) -> Result<ChannelBinding> {
+    if conn.is_handshaking() {
+        bail!(ERR_HANDSHAKE_IN_PROGRESS);
+    }
+    match conn.peer_certificates().and_then(|certs| certs.first()) {
+        Some(certificate) => ChannelBinding::tls_server_end_point(certificate),
+        None => bail!(ERR_NO_PEER_CERTIFICATE),
+    }
+}
+
+/// Returns the `tls-exporter` channel binding of a connection, as
+/// described in RFC 9266. Only TLS 1.3 connections are supported: with TLS
+/// 1.2, the exported keying material is only unique to the session if the
+/// extended master secret extension was negotiated, which rustls doesn't
+/// report.
+pub fn tls_exporter<D>(conn: &ConnectionCommon<D>) -> Result<ChannelBinding> {
+    if conn.is_handshaking() {
+        bail!(ERR_HANDSHAKE_IN_PROGRESS);
+    }
+    if conn.protocol_version() != Some(ProtocolVersion::TLSv1_3) {
+        bail!(ERR_TLS_EXPORTER_UNDEFINED);
+    }
+    let data = conn.export_keying_material([0; TLS_EXPORTER_LENGTH], TLS_EXPORTER_LABEL.as_bytes(), None)?;
+    Ok(ChannelBinding::TlsExporter(data.to_vec()))
+}
+
+/// Completes a handshake between in-memory rustls endpoints, for tests.
+#[cfg(test)]
+pub(crate) fn handshake(
+    version: &'static ::rustls::SupportedProtocolVersion,
+) -> Result<(::rustls::ClientConnection, ::rustls::ServerConnection)> {
+    use ::rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+    use ::rustls::{ClientConfig, ClientConnection, RootCertStore, ServerConfig, ServerConnection};
+    use std::sync::Arc;
+
+    let provider = Arc::new(::rustls::crypto::ring::default_provider());
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()])?;
+    let der = CertificateDer::from(cert.cert.der().to_vec());
+    let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(cert.key_pair.serialize_der()));
+
+    let mut roots = RootCertStore::empty();
+    roots.add(der.clone())?;
+    let client_config = ClientConfig::builder_with_provider(provider.clone())
+        .with_protocol_versions(&[version])?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    let server_config = ServerConfig::builder_with_provider(provider)
+        .with_protocol_versions(&[version])?
+        .with_no_client_auth()
+        .with_single_cert(vec![der], key)?;
+
+    let mut client = ClientConnection::new(Arc::new(client_config), "localhost".try_into()?)?;
+    let mut server = ServerConnection::new(Arc::new(server_config))?;
+    while client.is_handshaking() || server.is_handshaking() {
+        let mut buf = Vec::new();
+        client.write_tls(&mut buf)?;
+        server.read_tls(&mut buf.as_slice())?;
+        server.process_new_packets()?;
+        buf.clear();
+        server.write_tls(&mut buf)?;
+        client.read_tls(&mut buf.as_slice())?;
+        client.process_new_packets()?;
+    }
+    Ok((client, server))
+}
+
+#[test]
+fn test_tls_unique() -> Result<()> {
+    for (version, expected) in [
+        (&::rustls::version::TLS12, ERR_TLS_UNIQUE_UNSUPPORTED),
+        (&::rustls::version::TLS13, ERR_TLS_UNIQUE_UNDEFINED),
+    ] {
+        let (client, _) = handshake(version)?;
+        match tls_unique(&client) {
+            Err(err) if err.to_string() == expected => {}
+            _ => bail!("Expected {:?}", expected),
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn test_tls_server_end_point() -> Result<()> {
+    use sha2::{Digest, Sha256};
+
+    let (client, server) = handshake(&::rustls::version::TLS13)?;
+    let certificate = client.peer_certificates().and_then(|certs| certs.first()).cloned();
+    let expected = certificate.map(|certificate| ChannelBinding::TlsServerEndPoint(Sha256::digest(certificate).to_vec()));
+    if Some(tls_server_end_point(&client)?) != expected {
+        bail!("Unexpected binding");
+    }
+    if tls_server_end_point(&server).is_ok() {
+        bail!("Expected no client certificate");
+    }
+    Ok(())
+}
+
+#[test]
+fn test_tls_exporter() -> Result<()> {
+    let (client, server) = handshake(&::rustls::version::TLS13)?;
+    let binding = tls_exporter(&client)?;
+    if binding != tls_exporter(&server)? || binding.data().map(|data| data.len()) != Some(TLS_EXPORTER_LENGTH) {
+        bail!("Expected both sides to agree on the binding");
+    }
+    let (other, _) = handshake(&::rustls::version::TLS13)?;
+    if tls_exporter(&other)? == binding {
+        bail!("Expected bindings to be unique to the session");
+    }
+
+    let (client, _) = handshake(&::rustls::version::TLS12)?;
+    match tls_exporter(&client) {
+        Err(err) if err.to_string() == ERR_TLS_EXPORTER_UNDEFINED => {}
+        _ => bail!("Expected tls-exporter to be undefined for TLS 1.2"),
+    }
+    Ok(())
+}
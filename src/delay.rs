@@ -0,0 +1,229 @@
+//! Increasing delays before returning authentication failures, to slow
+//! down online brute forcing on a connection: each consecutive failure
+//! doubles the delay, up to a maximum, and a success resets it.
+
+#[cfg(feature = "tokio")]
+use crate::async_sasl::AsyncServer;
+use crate::master::MasterUsers;
+use crate::sasl;
+use crate::security_layer::BoxSecurityLayer;
+
+use anyhow::Result;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// The delay of the first failure, doubled by each consecutive one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Backoff {
+    pub initial: Duration,
+    pub max: Duration,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            initial: Duration::from_secs(1),
+            max: Duration::from_secs(30),
+        }
+    }
+}
+
+impl Backoff {
+    /// Returns the delay after `failures` consecutive failures.
+    pub fn delay(&self, failures: u32) -> Duration {
+        let factor = 1u32.checked_shl(failures.saturating_sub(1)).unwrap_or(u32::MAX);
+        self.initial.saturating_mul(factor).min(self.max)
+    }
+}
+
+/// The backoff of each mechanism.
+#[derive(Clone, Debug, Default)]
+pub struct DelayPolicy {
+    default: Backoff,
+    mechanisms: HashMap<String, Backoff>,
+}
+
+impl DelayPolicy {
+    pub fn new(default: Backoff) -> Self {
+        Self {
+            default,
+            mechanisms: HashMap::new(),
+        }
+    }
+
+    /// Sets the backoff of a mechanism, e.g. a longer one for mechanisms
+    /// verifying passwords.
+    pub fn with_mechanism(mut self, mechanism: &str, backoff: Backoff) -> Self {
+        self.mechanisms.insert(mechanism.to_ascii_uppercase(), backoff);
+        self
+    }
+
+    pub fn backoff(&self, mechanism: &str) -> Backoff {
+        self.mechanisms.get(&mechanism.to_ascii_uppercase()).copied().unwrap_or(self.default)
+    }
+}
+
+/// Counts consecutive failures, shared by the synchronous and asynchronous
+/// servers.
+struct Failures {
+    policy: DelayPolicy,
+    count: u32,
+}
+
+impl Failures {
+    /// Records the outcome of a step, returning the delay to apply before
+    /// returning it.
+    fn record<T>(&mut self, mechanism: &str, step: &Result<T>) -> Option<Duration> {
+        match step {
+            Ok(_) => {
+                self.count = 0;
+                None
+            }
+            Err(_) => {
+                self.count = self.count.saturating_add(1);
+                Some(self.policy.backoff(mechanism).delay(self.count))
+            }
+        }
+    }
+}
+
+/// A server wrapper delaying failures. The count of failures survives
+/// `reset`, so that it grows with the attempts made on a connection.
+pub struct DelayServer<S> {
+    inner: S,
+    failures: Failures,
+}
+
+impl<S: sasl::Server> DelayServer<S> {
+    pub fn new(inner: S, policy: DelayPolicy) -> Self {
+        Self {
+            inner,
+            failures: Failures { policy, count: 0 },
+        }
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<S: sasl::Server> sasl::Server for DelayServer<S> {
+    fn mechanism_name(&self) -> &str {
+        self.inner.mechanism_name()
+    }
+
+    fn next(&mut self, response: Option<&[u8]>) -> Result<sasl::ServerStep> {
+        let step = self.inner.next(response);
+        if let Some(delay) = self.failures.record(self.inner.mechanism_name(), &step) {
+            std::thread::sleep(delay);
+        }
+        step
+    }
+
+    fn is_done(&self) -> bool {
+        self.inner.is_done()
+    }
+
+    fn reset(&mut self) -> Result<()> {
+        self.inner.reset()
+    }
+
+    fn security_layer(&mut self) -> Option<BoxSecurityLayer> {
+        self.inner.security_layer()
+    }
+
+    fn authentication_id(&self) -> Option<&str> {
+        self.inner.authentication_id()
+    }
+
+    fn authorization_id(&self) -> Option<&str> {
+        self.inner.authorization_id()
+    }
+
+    fn set_master_users(&mut self, masters: Arc<MasterUsers>) -> bool {
+        self.inner.set_master_users(masters)
+    }
+}
+
+/// An asynchronous server wrapper delaying failures without blocking the
+/// runtime. See `DelayServer`.
+#[cfg(feature = "tokio")]
+pub struct AsyncDelayServer<S> {
+    inner: S,
+    failures: Failures,
+}
+
+#[cfg(feature = "tokio")]
+impl<S: AsyncServer> AsyncDelayServer<S> {
+    pub fn new(inner: S, policy: DelayPolicy) -> Self {
+        Self {
+            inner,
+            failures: Failures { policy, count: 0 },
+        }
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl<S: AsyncServer> AsyncServer for AsyncDelayServer<S> {
+    fn mechanism_name(&self) -> &str {
+        self.inner.mechanism_name()
+    }
+
+    async fn next(&mut self, response: Option<&[u8]>) -> Result<sasl::ServerStep> {
+        let step = self.inner.next(response).await;
+        if let Some(delay) = self.failures.record(self.inner.mechanism_name(), &step) {
+            tokio::time::sleep(delay).await;
+        }
+        step
+    }
+
+    fn is_done(&self) -> bool {
+        self.inner.is_done()
+    }
+
+    fn reset(&mut self) -> Result<()> {
+        self.inner.reset()
+    }
+
+    fn security_layer(&mut self) -> Option<BoxSecurityLayer> {
+        self.inner.security_layer()
+    }
+}
+
+#[cfg(feature = "plain")]
+#[test]
+fn test_failure_delay() -> Result<()> {
+    use crate::plain::PlainServer;
+    use crate::sasl::Server;
+    use anyhow::bail;
+    use std::time::Instant;
+
+    let backoff = Backoff {
+        initial: Duration::from_millis(5),
+        max: Duration::from_millis(12),
+    };
+    if [1, 2, 3, 40].map(|failures| backoff.delay(failures)) != [5, 10, 12, 12].map(Duration::from_millis) {
+        bail!("Unexpected backoff");
+    }
+
+    let plain = PlainServer::new(Box::new(|_, _, password| match password {
+        "password" => Ok(()),
+        _ => bail!("Invalid credentials"),
+    }));
+    let policy = DelayPolicy::new(Backoff::default()).with_mechanism("plain", backoff);
+    let mut server = DelayServer::new(plain, policy);
+    for (response, min_delay) in [(&b"\x00username\x00wrong"[..], 5), (b"\x00username\x00wrong", 10), (b"\x00username\x00password", 0)] {
+        server.reset()?;
+        let start = Instant::now();
+        let _ = server.next(Some(response));
+        if start.elapsed() < Duration::from_millis(min_delay) {
+            bail!("Unexpected delay: {:?}", start.elapsed());
+        }
+    }
+    Ok(())
+}
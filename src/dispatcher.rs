@@ -0,0 +1,372 @@
+use crate::audit::{AuditServer, AuditSink};
+use crate::master::MasterUsers;
+use crate::metrics::{MetricsServer, MetricsSink};
+use crate::policy::{ConnectionContext, Preference, SecurityPolicy, ERR_MECHANISM_FORBIDDEN};
+use crate::registry::Registry;
+use crate::sasl;
+use crate::security_layer::BoxSecurityLayer;
+
+use anyhow::{anyhow, bail, Result};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+pub const ERR_UNKNOWN_MECHANISM: &str = "sasl: unknown mechanism";
+
+/// The line sent by clients to cancel an exchange in text protocols such as
+/// SMTP, IMAP and POP3, instead of an encoded response.
+pub const CANCEL: &str = "*";
+
+/// Reports whether a line received during an exchange is a cancellation.
+pub fn is_cancel(line: &str) -> bool {
+    line.trim_end_matches(['\r', '\n']) == CANCEL
+}
+
+/// Routes authentication requests from clients, e.g. an SMTP `AUTH` command,
+/// to a new server for the requested mechanism. Mechanisms forbidden by the
+/// security policy on a connection are neither advertised nor accepted.
+///
+/// A process serving many tenants can route connections to a dispatcher per
+/// tenant by SNI hostname, see `with_tenant`, and users to a credential
+/// backend per tenant by the domain of their username, see
+/// `store::tenant::TenantVerifier`.
+pub struct ServerDispatcher {
+    registry: Registry,
+    policy: SecurityPolicy,
+    preference: Preference,
+    masters: Option<Arc<MasterUsers>>,
+    inherit_masters: bool,
+    audit: Option<Arc<dyn AuditSink>>,
+    metrics: Option<Arc<dyn MetricsSink>>,
+    tenants: BTreeMap<String, ServerDispatcher>,
+}
+
+impl ServerDispatcher {
+    /// Creates a dispatcher for the server mechanisms of a registry, with
+    /// the default security policy.
+    pub fn new(registry: Registry) -> Self {
+        Self {
+            registry,
+            policy: SecurityPolicy::default(),
+            preference: Preference::default(),
+            masters: None,
+            inherit_masters: false,
+            audit: None,
+            metrics: None,
+            tenants: BTreeMap::new(),
+        }
+    }
+
+    pub fn with_policy(mut self, policy: SecurityPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Sets the order mechanisms are advertised in, clients usually
+    /// picking the first one they support.
+    pub fn with_preference(mut self, preference: Preference) -> Self {
+        self.preference = preference;
+        self
+    }
+
+    /// Lets master users log in as other users with PLAIN and LOGIN, see
+    /// `master::MasterUsers`.
+    pub fn with_master_users(mut self, masters: Arc<MasterUsers>) -> Self {
+        self.masters = Some(masters);
+        self
+    }
+
+    /// Lets the master users of the parent dispatcher log in on this one,
+    /// as a tenant without master users of its own. By default, they can't
+    /// impersonate the users of tenants.
+    pub fn with_inherited_masters(mut self) -> Self {
+        self.inherit_masters = true;
+        self
+    }
+
+    /// Records the outcome of every exchange to a sink, with the peer of
+    /// the connection. See `audit::AuditServer`.
+    pub fn with_audit_sink(mut self, sink: Arc<dyn AuditSink>) -> Self {
+        self.audit = Some(sink);
+        self
+    }
+
+    /// Reports the attempts, outcomes and durations of exchanges to a sink.
+    pub fn with_metrics_sink(mut self, sink: Arc<dyn MetricsSink>) -> Self {
+        self.metrics = Some(sink);
+        self
+    }
+
+    /// Hands connections whose SNI hostname is `domain` or one of its
+    /// subdomains over to the dispatcher of a tenant, with its own
+    /// mechanisms, backends and policies. The most specific domain wins.
+    /// Tenants without their own audit sink or metrics sink use those of
+    /// this dispatcher, and its master users only if they opted in with
+    /// `with_inherited_masters`.
+    pub fn with_tenant(mut self, domain: &str, dispatcher: ServerDispatcher) -> Self {
+        self.tenants.insert(domain.to_ascii_lowercase(), dispatcher);
+        self
+    }
+
+    /// Returns the dispatcher of the tenant of a connection, or this one.
+    fn tenant(&self, conn: &ConnectionContext) -> &ServerDispatcher {
+        let Some(server_name) = &conn.server_name else {
+            return self;
+        };
+        let mut domain = server_name.trim_end_matches('.').to_ascii_lowercase();
+        loop {
+            if let Some(tenant) = self.tenants.get(&domain) {
+                return tenant;
+            }
+            match domain.split_once('.') {
+                Some((_, parent)) => domain = parent.to_string(),
+                None => return self,
+            }
+        }
+    }
+
+    /// Lists the mechanisms to advertise to clients on a connection.
+    pub fn mechanisms(&self, conn: &ConnectionContext) -> Vec<&str> {
+        let dispatcher = self.tenant(conn);
+        let mut mechanisms = dispatcher.policy.server_mechanisms(&dispatcher.registry, conn);
+        dispatcher.preference.sort(&mut mechanisms);
+        mechanisms
+    }
+
+    /// Starts an exchange for a mechanism requested by a client. The
+    /// returned exchange must be used for the rest of the authentication,
+    /// and dropped with the connection.
+    pub fn start(
+        &self,
+        conn: &ConnectionContext,
+        mechanism: &str,
+        initial_response: Option<&[u8]>,
+    ) -> Result<(Exchange, sasl::ServerStep)> {
+        self.tenant(conn).start_server(conn, mechanism, initial_response, self)
+    }
+
+    fn start_server(
+        &self,
+        conn: &ConnectionContext,
+        mechanism: &str,
+        initial_response: Option<&[u8]>,
+        parent: &ServerDispatcher,
+    ) -> Result<(Exchange, sasl::ServerStep)> {
+        let properties = self.registry.properties(mechanism).ok_or_else(|| anyhow!(ERR_UNKNOWN_MECHANISM))?;
+        if !self.policy.allows(mechanism, &properties, conn) {
+            bail!(ERR_MECHANISM_FORBIDDEN);
+        }
+        let mut server = self
+            .registry
+            .new_server(mechanism)
+            .ok_or_else(|| anyhow!(ERR_UNKNOWN_MECHANISM))?;
+        let inherited = parent.masters.as_ref().filter(|_| self.inherit_masters);
+        if let Some(masters) = self.masters.as_ref().or(inherited) {
+            server.set_master_users(masters.clone());
+        }
+        if let Some(sink) = self.audit.as_ref().or(parent.audit.as_ref()) {
+            let mut audited = AuditServer::new(server, sink.clone());
+            if let Some(peer) = conn.peer {
+                audited = audited.with_peer(&peer.to_string());
+            }
+            server = Box::new(audited);
+        }
+        if let Some(sink) = self.metrics.as_ref().or(parent.metrics.as_ref()) {
+            server = Box::new(MetricsServer::new(server, sink.clone()));
+        }
+        let step = server.next(initial_response)?;
+        let exchange = Exchange {
+            mechanism: server.mechanism_name().to_string(),
+            server: Some(server),
+        };
+        Ok((exchange, step))
+    }
+}
+
+/// An exchange started by a `ServerDispatcher`. Canceling it drops the
+/// server, so that a half-finished exchange can't be resumed.
+pub struct Exchange {
+    mechanism: String,
+    server: Option<sasl::BoxServer>,
+}
+
+impl Exchange {
+    pub fn mechanism_name(&self) -> &str {
+        &self.mechanism
+    }
+
+    /// Continues the exchange with a decoded client response. Fails with
+    /// `sasl::Error::Canceled` once the exchange has been canceled.
+    pub fn next(&mut self, response: &[u8]) -> Result<sasl::ServerStep> {
+        match &mut self.server {
+            Some(server) => server.next(Some(response)),
+            None => bail!(sasl::Error::Canceled),
+        }
+    }
+
+    /// Cancels the exchange, e.g. when the client sent `CANCEL`. It returns
+    /// the error to report the outcome with.
+    pub fn cancel(&mut self) -> anyhow::Error {
+        self.server = None;
+        sasl::Error::Canceled.into()
+    }
+
+    pub fn is_canceled(&self) -> bool {
+        self.server.is_none()
+    }
+
+    /// Reports whether the exchange is over, either completed or canceled.
+    pub fn is_done(&self) -> bool {
+        self.server.as_ref().is_none_or(|server| server.is_done())
+    }
+
+    /// Returns the security layer negotiated by the mechanism, to apply to
+    /// the connection once the exchange succeeded. See
+    /// `sasl::Server::security_layer`.
+    pub fn security_layer(&mut self) -> Option<BoxSecurityLayer> {
+        self.server.as_mut()?.security_layer()
+    }
+}
+
+impl From<Registry> for ServerDispatcher {
+    fn from(registry: Registry) -> Self {
+        Self::new(registry)
+    }
+}
+
+#[cfg(all(feature = "login", feature = "plain"))]
+#[test]
+fn test_server_dispatcher() -> Result<()> {
+    use crate::login::{LoginServer, LOGIN};
+    use crate::plain::{PlainServer, PLAIN};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    let mut registry = Registry::new();
+    registry
+        .register_server(PLAIN, || Box::new(PlainServer::new(Box::new(|_, _, _| Ok(())))))
+        .register_server(LOGIN, || Box::new(LoginServer::new(Box::new(|_, _| Ok(())))));
+    let dispatcher = ServerDispatcher::from(registry);
+    let conn = ConnectionContext {
+        tls: true,
+        ..Default::default()
+    };
+
+    match dispatcher.start(&conn, "CRAM-MD5", None) {
+        Err(err) if err.to_string() == ERR_UNKNOWN_MECHANISM => {}
+        _ => bail!("Expected an unknown mechanism error"),
+    }
+
+    let (_, step) = dispatcher.start(&conn, "plain", Some(b"\x00username\x00password"))?;
+    if !step.is_done() {
+        bail!("Expected authentication to be done");
+    }
+
+    match dispatcher.start(&ConnectionContext::default(), PLAIN, Some(b"\x00username\x00password")) {
+        Err(err) if err.to_string() == ERR_MECHANISM_FORBIDDEN => {}
+        _ => bail!("Expected PLAIN to be forbidden on a cleartext connection"),
+    }
+
+    // Forbidden mechanisms aren't instantiated, and mechanisms without
+    // properties are forbidden like plaintext ones.
+    let instances = Arc::new(AtomicUsize::new(0));
+    let counter = instances.clone();
+    let mut registry = Registry::new();
+    registry.register_server("X-CUSTOM", move || {
+        counter.fetch_add(1, Ordering::SeqCst);
+        Box::new(PlainServer::new(Box::new(|_, _, _| Ok(()))))
+    });
+    let custom = ServerDispatcher::from(registry);
+    if custom.mechanisms(&ConnectionContext::default()).contains(&"X-CUSTOM") {
+        bail!("Expected X-CUSTOM not to be advertised on a cleartext connection");
+    }
+    match custom.start(&ConnectionContext::default(), "X-CUSTOM", None) {
+        Err(err) if err.to_string() == ERR_MECHANISM_FORBIDDEN && instances.load(Ordering::SeqCst) == 0 => {}
+        _ => bail!("Expected X-CUSTOM to be forbidden before being instantiated"),
+    }
+    custom.start(&conn, "X-CUSTOM", None)?;
+    if instances.load(Ordering::SeqCst) != 1 {
+        bail!("Expected X-CUSTOM to be instantiated once");
+    }
+
+    // Each exchange gets its own server.
+    let (mut first, _) = dispatcher.start(&conn, LOGIN, None)?;
+    let (mut second, _) = dispatcher.start(&conn, LOGIN, Some(b"username"))?;
+    if first.next(b"username")? != sasl::ServerStep::Challenge(b"Password:".to_vec()) {
+        bail!("Expected a password challenge");
+    }
+    if !second.next(b"password")?.is_done() || first.is_done() {
+        bail!("Expected exchanges to be independent");
+    }
+
+    if !is_cancel("*\r\n") {
+        bail!("Expected a cancellation");
+    }
+    let _ = first.cancel();
+    match first.next(b"password") {
+        Err(err) if matches!(err.downcast_ref(), Some(sasl::Error::Canceled)) => {}
+        _ => bail!("Expected the exchange to be canceled"),
+    }
+    if !first.is_canceled() || !first.is_done() {
+        bail!("Expected the exchange to be over");
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_exchange_security_layer() -> Result<()> {
+    use crate::security_layer::SecurityLayer;
+
+    struct Layer;
+
+    impl SecurityLayer for Layer {
+        fn wrap(&mut self, message: &[u8]) -> Result<Vec<u8>> {
+            Ok(message.iter().rev().copied().collect())
+        }
+
+        fn unwrap(&mut self, message: &[u8]) -> Result<Vec<u8>> {
+            Ok(message.iter().rev().copied().collect())
+        }
+
+        fn max_send_size(&self) -> usize {
+            1024
+        }
+    }
+
+    struct Server;
+
+    impl sasl::Server for Server {
+        fn mechanism_name(&self) -> &str {
+            "X-LAYER"
+        }
+
+        fn next(&mut self, _response: Option<&[u8]>) -> Result<sasl::ServerStep> {
+            Ok(sasl::ServerStep::Done { additional_data: None })
+        }
+
+        fn security_layer(&mut self) -> Option<BoxSecurityLayer> {
+            Some(Box::new(Layer))
+        }
+    }
+
+    let mut registry = Registry::new();
+    registry.register_server("X-LAYER", || Box::new(Server)).set_properties(
+        "X-LAYER",
+        sasl::Properties {
+            security_layer: true,
+            ssf: 56,
+            ..Default::default()
+        },
+    );
+    let (mut exchange, _) = ServerDispatcher::new(registry).start(&ConnectionContext::default(), "X-LAYER", None)?;
+    let Some(mut layer) = exchange.security_layer() else {
+        bail!("Expected the security layer of the mechanism");
+    };
+    if layer.wrap(b"abc")? != b"cba" {
+        bail!("Unexpected security layer");
+    }
+    let _ = exchange.cancel();
+    if exchange.security_layer().is_some() {
+        bail!("Expected no security layer once canceled");
+    }
+    Ok(())
+}
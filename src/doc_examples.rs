@@ -0,0 +1,183 @@
+//! A worked example of a custom mechanism, to be used as a template when
+//! implementing mechanisms outside of this crate.
+//!
+//! The X-EXAMPLE-TOKEN mechanism sends a username and a token in the initial
+//! response, separated by a NUL byte. The server verifies them with an
+//! authenticator callback and confirms success with the `+OK` additional
+//! data, which the client checks in `finish`.
+//!
+//! ```
+//! use rs_sasl::doc_examples::{ExampleTokenClient, ExampleTokenServer};
+//! use rs_sasl::sasl::{Client, Server, ServerStep};
+//!
+//! # fn main() -> anyhow::Result<()> {
+//! let mut client = ExampleTokenClient::new("username".to_string(), "s3cr3t".to_string());
+//! let mut server = ExampleTokenServer::new(Box::new(|username, token| {
+//!     if username != "username" || token != "s3cr3t" {
+//!         anyhow::bail!("invalid token");
+//!     }
+//!     Ok(())
+//! }));
+//!
+//! let (_, ir) = client.start()?;
+//! match server.next(ir.as_deref())? {
+//!     ServerStep::Done { additional_data } => client.finish(additional_data.as_deref())?,
+//!     ServerStep::Challenge(_) => unreachable!(),
+//! }
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Custom mechanisms are registered like built-in ones, so that protocol
+//! servers can instantiate them by name, along with their properties:
+//!
+//! ```
+//! use rs_sasl::doc_examples::{ExampleTokenServer, EXAMPLE_TOKEN, PROPERTIES};
+//! use rs_sasl::registry::Registry;
+//!
+//! let mut registry = Registry::new();
+//! registry
+//!     .register_server(EXAMPLE_TOKEN, || {
+//!         Box::new(ExampleTokenServer::new(Box::new(|_, _| anyhow::bail!("invalid token"))))
+//!     })
+//!     .set_properties(EXAMPLE_TOKEN, PROPERTIES);
+//!
+//! let server = registry.new_server("x-example-token").unwrap();
+//! assert_eq!(server.mechanism_name(), EXAMPLE_TOKEN);
+//! assert!(registry.properties(EXAMPLE_TOKEN).unwrap().mutual_auth);
+//! ```
+
+use crate::sasl;
+
+use anyhow::{anyhow, bail, Result};
+
+/// The X-EXAMPLE-TOKEN mechanism name.
+pub const EXAMPLE_TOKEN: &str = "X-EXAMPLE-TOKEN";
+
+/// The security properties of the X-EXAMPLE-TOKEN mechanism.
+pub const PROPERTIES: sasl::Properties = sasl::Properties {
+    plaintext: true,
+    anonymous: false,
+    channel_binding: false,
+    mutual_auth: true,
+    security_layer: false,
+    ssf: 0,
+};
+
+const SUCCESS_DATA: &[u8] = b"+OK";
+
+/// A client implementation of the X-EXAMPLE-TOKEN mechanism.
+pub struct ExampleTokenClient {
+    username: String,
+    token: String,
+}
+
+impl ExampleTokenClient {
+    pub fn new(username: String, token: String) -> Self {
+        Self { username, token }
+    }
+}
+
+impl sasl::Client for ExampleTokenClient {
+    fn mechanism_name(&self) -> &str {
+        EXAMPLE_TOKEN
+    }
+
+    fn start(&mut self) -> Result<(String, Option<Vec<u8>>)> {
+        // The mechanism is client-first: always send an initial response.
+        Ok((
+            EXAMPLE_TOKEN.to_string(),
+            Some(format!("{}\x00{}", self.username, self.token).into_bytes()),
+        ))
+    }
+
+    fn next(&mut self, _challenge: &[u8]) -> Result<Vec<u8>> {
+        // A server which didn't get the initial response sends an empty
+        // challenge, any other challenge is a protocol violation.
+        Err(anyhow!(sasl::ERR_UNEXPECTED_SERVER_CHALLENGE))
+    }
+
+    fn finish(&mut self, data: Option<&[u8]>) -> Result<()> {
+        // Mutual authentication: only trust servers confirming success.
+        if data != Some(SUCCESS_DATA) {
+            bail!("sasl: server did not confirm authentication");
+        }
+        Ok(())
+    }
+}
+
+/// Authenticates users with a username and a token.
+pub type ExampleTokenAuthenticator = Box<dyn Fn(&str, &str) -> Result<()> + Send + Sync>;
+
+/// A server implementation of the X-EXAMPLE-TOKEN mechanism.
+pub struct ExampleTokenServer {
+    done: bool,
+    authenticator: ExampleTokenAuthenticator,
+}
+
+impl ExampleTokenServer {
+    pub fn new(authenticator: ExampleTokenAuthenticator) -> Self {
+        Self {
+            done: false,
+            authenticator,
+        }
+    }
+}
+
+impl sasl::Server for ExampleTokenServer {
+    fn mechanism_name(&self) -> &str {
+        EXAMPLE_TOKEN
+    }
+
+    fn next(&mut self, response: Option<&[u8]>) -> Result<sasl::ServerStep> {
+        if self.done {
+            bail!(sasl::ERR_UNEXPECTED_CLIENT_RESPONSE);
+        }
+
+        // No initial response, ask for one with an empty challenge.
+        let response = match response {
+            Some(response) => response,
+            None => return Ok(sasl::ServerStep::Challenge(Vec::new())),
+        };
+        self.done = true;
+
+        let mut parts = response.splitn(2, |&b| b == b'\x00');
+        let username = parts.next().unwrap_or_default();
+        let token = parts.next().ok_or(sasl::FailureReason::MalformedResponse)?;
+
+        (self.authenticator)(std::str::from_utf8(username)?, std::str::from_utf8(token)?)?;
+
+        Ok(sasl::ServerStep::Done {
+            additional_data: Some(SUCCESS_DATA.to_vec()),
+        })
+    }
+
+    fn is_done(&self) -> bool {
+        self.done
+    }
+
+    fn reset(&mut self) -> Result<()> {
+        self.done = false;
+        Ok(())
+    }
+}
+
+#[test]
+fn test_example_token_rejected() -> Result<()> {
+    use crate::sasl::{Client, FailureReason, Server};
+
+    let mut server = ExampleTokenServer::new(Box::new(|_, _| bail!(FailureReason::InvalidCredentials)));
+    if server.next(None)? != sasl::ServerStep::Challenge(Vec::new()) {
+        bail!("Expected an empty challenge");
+    }
+    let (_, ir) = ExampleTokenClient::new("username".to_string(), "token".to_string()).start()?;
+    let err = server.next(ir.as_deref()).unwrap_err();
+    if FailureReason::of(&err) != FailureReason::InvalidCredentials {
+        bail!("Unexpected error: {}", err);
+    }
+    if server.next(Some(b"")).is_ok() {
+        bail!("Server accepted a response after the exchange ended");
+    }
+
+    Ok(())
+}
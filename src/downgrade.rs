@@ -0,0 +1,224 @@
+//! Detection of and policy enforcement against mechanism downgrade attacks.
+//!
+//! An active attacker can strip the strongest mechanisms from the list
+//! advertised by a server (e.g. the -PLUS channel binding variants, or SCRAM
+//! altogether) to make the client fall back to a weaker one. A
+//! `DowngradeGuard` pins the security-relevant mechanisms seen for each peer
+//! and reports their disappearance, along with signals observed elsewhere
+//! (e.g. channel binding mismatches on the server side). Once a peer
+//! accumulates enough signals, the configured action is enforced.
+
+use anyhow::{bail, Result};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+pub const ERR_DOWNGRADE_DETECTED: &str = "sasl: repeated mechanism downgrade attempts detected";
+pub const ERR_PEER_QUARANTINED: &str = "sasl: peer quarantined after repeated downgrade attempts";
+
+/// A sign of a downgrade attempt.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum DowngradeSignal {
+    /// The peer stopped advertising the -PLUS variant of a mechanism it
+    /// still advertises.
+    StrippedPlus,
+    /// The peer stopped advertising SCRAM mechanisms.
+    MissingScram,
+    /// The channel binding data sent by the peer doesn't match the
+    /// connection.
+    ChannelBindingMismatch,
+}
+
+/// What to do once a peer reached the signal threshold.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DowngradeAction {
+    /// Only report the event.
+    Report,
+    /// Fail the authentication attempt which triggered the event.
+    HardFail,
+    /// Fail authentication attempts with the peer for the given duration.
+    Quarantine(Duration),
+}
+
+/// Configures when and how a `DowngradeGuard` acts.
+#[derive(Clone, Copy, Debug)]
+pub struct DowngradePolicy {
+    /// The number of signals triggering the action.
+    pub threshold: usize,
+    /// The period during which signals are counted.
+    pub window: Duration,
+    pub action: DowngradeAction,
+}
+
+impl Default for DowngradePolicy {
+    fn default() -> Self {
+        Self {
+            threshold: 3,
+            window: Duration::from_secs(3600),
+            action: DowngradeAction::Report,
+        }
+    }
+}
+
+/// Emitted when a peer reached the signal threshold.
+#[derive(Clone, Debug)]
+pub struct DowngradeEvent {
+    pub peer: String,
+    pub signal: DowngradeSignal,
+    /// The number of signals in the current window.
+    pub count: usize,
+    pub action: DowngradeAction,
+}
+
+/// Receives downgrade events, e.g. to log them.
+pub type DowngradeListener = Box<dyn Fn(&DowngradeEvent) + Send + Sync>;
+
+#[derive(Default)]
+struct PeerState {
+    signals: VecDeque<Instant>,
+    pinned: HashSet<String>,
+    quarantined_until: Option<Instant>,
+}
+
+/// Tracks downgrade signals per peer and enforces a `DowngradePolicy`. Peers
+/// are identified by an application-defined key, e.g. a hostname.
+pub struct DowngradeGuard {
+    policy: DowngradePolicy,
+    listener: Option<DowngradeListener>,
+    peers: Mutex<HashMap<String, PeerState>>,
+}
+
+impl DowngradeGuard {
+    pub fn new(policy: DowngradePolicy) -> Self {
+        Self {
+            policy,
+            listener: None,
+            peers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn with_listener(mut self, listener: DowngradeListener) -> Self {
+        self.listener = Some(listener);
+        self
+    }
+
+    /// Fails if the peer is quarantined.
+    pub fn check(&self, peer: &str) -> Result<()> {
+        let peers = self.peers.lock().unwrap_or_else(|err| err.into_inner());
+        let quarantined_until = peers.get(peer).and_then(|state| state.quarantined_until);
+        if quarantined_until.is_some_and(|until| until > Instant::now()) {
+            bail!(ERR_PEER_QUARANTINED);
+        }
+        Ok(())
+    }
+
+    /// Records the mechanisms advertised by a server, and reports the
+    /// previously seen -PLUS and SCRAM mechanisms which are missing. Fails if
+    /// the policy requires it.
+    pub fn observe_advertisement(&self, peer: &str, advertised: &[&str]) -> Result<Vec<DowngradeSignal>> {
+        let advertised = advertised
+            .iter()
+            .map(|mech| mech.to_uppercase())
+            .collect::<HashSet<_>>();
+
+        let mut signals = Vec::new();
+        {
+            let mut peers = self.peers.lock().unwrap_or_else(|err| err.into_inner());
+            let state = peers.entry(peer.to_string()).or_default();
+            for mech in state.pinned.difference(&advertised) {
+                let signal = match mech.strip_suffix("-PLUS") {
+                    Some(base) if advertised.contains(base) => DowngradeSignal::StrippedPlus,
+                    _ if !advertised.iter().any(|mech| mech.starts_with("SCRAM-")) => DowngradeSignal::MissingScram,
+                    _ => continue,
+                };
+                if !signals.contains(&signal) {
+                    signals.push(signal);
+                }
+            }
+            state.pinned.extend(
+                advertised
+                    .iter()
+                    .filter(|mech| mech.ends_with("-PLUS") || mech.starts_with("SCRAM-"))
+                    .cloned(),
+            );
+        }
+
+        for signal in &signals {
+            self.record(peer, *signal)?;
+        }
+        Ok(signals)
+    }
+
+    /// Records a downgrade signal for a peer. Fails if the policy requires
+    /// it.
+    pub fn record(&self, peer: &str, signal: DowngradeSignal) -> Result<()> {
+        let now = Instant::now();
+        let event = {
+            let mut peers = self.peers.lock().unwrap_or_else(|err| err.into_inner());
+            let state = peers.entry(peer.to_string()).or_default();
+            while state
+                .signals
+                .front()
+                .is_some_and(|&t| now.duration_since(t) > self.policy.window)
+            {
+                state.signals.pop_front();
+            }
+            state.signals.push_back(now);
+            if state.signals.len() < self.policy.threshold {
+                return Ok(());
+            }
+            if let DowngradeAction::Quarantine(duration) = self.policy.action {
+                state.quarantined_until = Some(now + duration);
+            }
+            DowngradeEvent {
+                peer: peer.to_string(),
+                signal,
+                count: state.signals.len(),
+                action: self.policy.action,
+            }
+        };
+
+        if let Some(listener) = &self.listener {
+            listener(&event);
+        }
+        match self.policy.action {
+            DowngradeAction::Report => Ok(()),
+            DowngradeAction::HardFail => bail!(ERR_DOWNGRADE_DETECTED),
+            DowngradeAction::Quarantine(_) => bail!(ERR_PEER_QUARANTINED),
+        }
+    }
+}
+
+#[test]
+fn test_downgrade_quarantine() -> Result<()> {
+    use std::sync::Arc;
+
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let reported = events.clone();
+    let guard = DowngradeGuard::new(DowngradePolicy {
+        threshold: 2,
+        window: Duration::from_secs(60),
+        action: DowngradeAction::Quarantine(Duration::from_secs(60)),
+    })
+    .with_listener(Box::new(move |event| reported.lock().unwrap().push(event.signal)));
+
+    guard.observe_advertisement("mail.example.com", &["SCRAM-SHA-256-PLUS", "SCRAM-SHA-256", "PLAIN"])?;
+    let signals = guard.observe_advertisement("mail.example.com", &["SCRAM-SHA-256", "PLAIN"])?;
+    if signals != [DowngradeSignal::StrippedPlus] {
+        bail!("Expected a stripped -PLUS signal, got {:?}", signals);
+    }
+    guard.check("mail.example.com")?;
+
+    if guard.observe_advertisement("mail.example.com", &["PLAIN"]).is_ok() {
+        bail!("Expected the peer to be quarantined");
+    }
+    if guard.check("mail.example.com").is_ok() || guard.check("other.example.com").is_err() {
+        bail!("Invalid quarantine state");
+    }
+    if *events.lock().unwrap() != [DowngradeSignal::MissingScram] {
+        bail!("Invalid events: {:?}", events.lock().unwrap());
+    }
+
+    Ok(())
+}
@@ -0,0 +1,159 @@
+//! Blocking drivers running a whole exchange over a transport, given as a
+//! pair of closures sending and receiving messages. They take care of
+//! cancelling the exchange when a step fails, verifying additional data
+//! with success and reporting the outcome, so that integrators only need to
+//! encode messages for their protocol.
+
+use crate::sasl;
+use crate::security_layer::BoxSecurityLayer;
+
+use anyhow::{anyhow, bail, Result};
+
+pub const ERR_UNEXPECTED_MESSAGE: &str = "sasl: driver: unexpected message";
+pub const ERR_MECHANISM_MISMATCH: &str = "sasl: driver: client requested another mechanism";
+
+/// A message sent by the client.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ClientMessage {
+    /// Starts the exchange.
+    Start { mechanism: String, initial_response: Option<Vec<u8>> },
+    Response(Vec<u8>),
+    /// Aborts the exchange, in protocols with their own way to cancel.
+    Cancel,
+}
+
+/// A message sent by the server.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ServerMessage {
+    Challenge(Vec<u8>),
+    /// Authentication succeeded, with additional data if any.
+    Success(Option<Vec<u8>>),
+    Failure,
+}
+
+/// The outcome of an exchange which ran to completion. Transport errors and
+/// protocol violations by the peer are returned as errors instead.
+pub enum Outcome {
+    /// Authentication succeeded. The security layer, if the mechanism
+    /// negotiated one, must be applied to the connection.
+    Success { security_layer: Option<BoxSecurityLayer> },
+    /// Authentication failed: the server rejected the client, the client
+    /// couldn't answer a challenge, or the server couldn't be
+    /// authenticated.
+    Failure(anyhow::Error),
+}
+
+impl Outcome {
+    pub fn is_success(&self) -> bool {
+        matches!(self, Outcome::Success { .. })
+    }
+}
+
+/// Runs a client exchange.
+pub fn authenticate_client<C, S, R>(client: &mut C, mut send: S, mut recv: R) -> Result<Outcome>
+where
+    C: sasl::Client + ?Sized,
+    S: FnMut(ClientMessage) -> Result<()>,
+    R: FnMut() -> Result<ServerMessage>,
+{
+    let (mechanism, initial_response) = client.start()?;
+    send(ClientMessage::Start { mechanism, initial_response })?;
+    loop {
+        match recv()? {
+            ServerMessage::Challenge(challenge) => match client.next(&challenge) {
+                Ok(response) => send(ClientMessage::Response(response))?,
+                Err(err) => {
+                    send(client.cancel().map_or(ClientMessage::Cancel, ClientMessage::Response))?;
+                    return match recv()? {
+                        ServerMessage::Failure => Ok(Outcome::Failure(err)),
+                        _ => bail!(ERR_UNEXPECTED_MESSAGE),
+                    };
+                }
+            },
+            ServerMessage::Success(data) => {
+                return match client.finish(data.as_deref()) {
+                    Ok(()) => Ok(Outcome::Success {
+                        security_layer: client.security_layer(),
+                    }),
+                    Err(err) => Ok(Outcome::Failure(err)),
+                };
+            }
+            ServerMessage::Failure => {
+                let err = client.failure().unwrap_or_else(|| anyhow!(sasl::ERR_AUTHENTICATION_FAILED));
+                return Ok(Outcome::Failure(err));
+            }
+        }
+    }
+}
+
+/// Runs a server exchange, starting with the client's `Start` message.
+pub fn authenticate_server<V, R, S>(server: &mut V, mut recv: R, mut send: S) -> Result<Outcome>
+where
+    V: sasl::Server + ?Sized,
+    R: FnMut() -> Result<ClientMessage>,
+    S: FnMut(ServerMessage) -> Result<()>,
+{
+    let ClientMessage::Start { mechanism, initial_response } = recv()? else {
+        bail!(ERR_UNEXPECTED_MESSAGE);
+    };
+    if !mechanism.eq_ignore_ascii_case(server.mechanism_name()) {
+        send(ServerMessage::Failure)?;
+        return Ok(Outcome::Failure(anyhow!(ERR_MECHANISM_MISMATCH)));
+    }
+    let mut step = server.next(initial_response.as_deref());
+    loop {
+        match step {
+            Ok(sasl::ServerStep::Challenge(challenge)) => {
+                send(ServerMessage::Challenge(challenge))?;
+                step = match recv()? {
+                    ClientMessage::Response(response) => server.next(Some(&response)),
+                    ClientMessage::Cancel => Err(sasl::Error::Canceled.into()),
+                    ClientMessage::Start { .. } => bail!(ERR_UNEXPECTED_MESSAGE),
+                };
+            }
+            Ok(sasl::ServerStep::Done { additional_data }) => {
+                send(ServerMessage::Success(additional_data))?;
+                return Ok(Outcome::Success {
+                    security_layer: server.security_layer(),
+                });
+            }
+            Err(err) => {
+                send(ServerMessage::Failure)?;
+                return Ok(Outcome::Failure(err));
+            }
+        }
+    }
+}
+
+#[cfg(feature = "login")]
+#[test]
+fn test_drivers() -> Result<()> {
+    use crate::login::{LoginClient, LoginServer};
+    use std::sync::mpsc;
+
+    for (password, success) in [("password", true), ("wrong", false)] {
+        let (client_tx, client_rx) = mpsc::channel();
+        let (server_tx, server_rx) = mpsc::channel();
+        let server = std::thread::spawn(move || {
+            let mut server = LoginServer::new(Box::new(|_, p| match p {
+                "password" => Ok(()),
+                _ => bail!("invalid credentials"),
+            }));
+            authenticate_server(&mut server, || Ok(client_rx.recv()?), |message| Ok(server_tx.send(message)?))
+        });
+
+        let mut client = LoginClient::new("username".to_string(), password.to_string());
+        let outcome = authenticate_client(&mut client, |message| Ok(client_tx.send(message)?), || Ok(server_rx.recv()?))?;
+        let server_outcome = server.join().map_err(|_| anyhow!("server panicked"))??;
+        if outcome.is_success() != success || server_outcome.is_success() != success {
+            bail!("Unexpected outcome with password {}", password);
+        }
+        if let Outcome::Failure(err) = outcome {
+            if err.to_string() != sasl::ERR_AUTHENTICATION_FAILED {
+                bail!("Unexpected client error: {}", err);
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -1,32 +1,111 @@
+use crate::authorization::{authorize, AuthorizationPolicy};
+#[cfg(feature = "external-channel-binding")]
+use crate::channel_binding::ChannelBinding;
+#[cfg(feature = "external-channel-binding")]
+use crate::constant_time;
 use crate::sasl;
 
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, bail, Result};
+use std::sync::Arc;
 
 /// The EXTERNAL mechanism name.
 pub const EXTERNAL: &str = "EXTERNAL";
 
+/// The security properties of the EXTERNAL mechanism.
+pub const PROPERTIES: sasl::Properties = sasl::Properties {
+    plaintext: false,
+    anonymous: false,
+    channel_binding: cfg!(feature = "external-channel-binding"),
+    mutual_auth: false,
+    security_layer: false,
+    ssf: 0,
+};
+
+pub const ERR_IDENTITY_MISMATCH: &str = "sasl: authorization identity doesn't match the certificate";
+pub const ERR_NO_CERTIFICATE_IDENTITY: &str = "sasl: no identity found in the client certificate";
+pub const ERR_NO_CLIENT_CERTIFICATE: &str = "sasl: no client certificate";
+#[cfg(feature = "external-channel-binding")]
+pub const ERR_CHANNEL_BINDING_MISMATCH: &str = "sasl: channel binding mismatch";
+#[cfg(feature = "external-channel-binding")]
+pub const ERR_CHANNEL_BINDING_REQUIRED: &str = "sasl: channel binding required";
+#[cfg(feature = "external-channel-binding")]
+pub const ERR_UNVERIFIABLE_CHANNEL_BINDING: &str = "sasl: channel binding sent to a server without binding data";
+
 /// An implementation of the EXTERNAL authentication mechanism, as described in
 /// RFC 4422. Authorization identity may be left blank to indicate that the
 /// client is requesting to act as the identity associated with the
-//. authentication credentials.
+/// authentication credentials.
+///
+/// With the `external-channel-binding` feature, the client may bind the
+/// exchange to the TLS session the external credentials were presented on,
+/// so that a proxy cannot forward them to another session. The binding is
+/// appended to the identity after a NUL byte, as `<type>=<data>` (e.g.
+/// `tls-exporter=...`). This encoding is specific to this library, no
+/// specification defines it: other servers reject it, and there is no
+/// fallback, so both ends must be built with the feature.
 pub struct ExternalClient {
     identity: String,
+    #[cfg(feature = "external-channel-binding")]
+    channel_binding: ChannelBinding,
 }
 
 impl ExternalClient {
     pub fn new(identity: String) -> Self {
         Self {
             identity,
+            #[cfg(feature = "external-channel-binding")]
+            channel_binding: ChannelBinding::None,
         }
     }
+
+    /// Creates a client authenticating with a TLS client certificate. If
+    /// `authzid` is `None` or the identity of the certificate, the
+    /// authorization identity is left empty, so that the server derives it
+    /// from the certificate, as per RFC 4422 appendix A.1. Otherwise, the
+    /// client asks to act as `authzid`, which the server may refuse.
+    pub fn from_certificate(
+        resolver: &dyn TlsIdentityResolver,
+        certificate: &[u8],
+        authzid: Option<String>,
+    ) -> Result<Self> {
+        let identity = match authzid {
+            Some(authzid) if authzid != resolver.resolve(certificate)? => authzid,
+            _ => String::new(),
+        };
+        Ok(Self::new(identity))
+    }
+
+    /// Returns the requested authorization identity, empty to act as the
+    /// identity of the external credentials.
+    pub fn authzid(&self) -> &str {
+        &self.identity
+    }
+
+    /// Binds the exchange to a TLS session, which only servers of this
+    /// library can verify.
+    #[cfg(feature = "external-channel-binding")]
+    pub fn with_channel_binding(mut self, channel_binding: ChannelBinding) -> Self {
+        self.channel_binding = channel_binding;
+        self
+    }
 }
 
 impl sasl::Client for ExternalClient {
-    fn start(&mut self) -> Result<(String, Vec<u8>)> {
-        Ok((
-            EXTERNAL.to_string(),
-            self.identity.clone().into_bytes(),
-        ))
+    fn mechanism_name(&self) -> &str {
+        EXTERNAL
+    }
+
+    fn start(&mut self) -> Result<(String, Option<Vec<u8>>)> {
+        #[cfg_attr(not(feature = "external-channel-binding"), allow(unused_mut))]
+        let mut ir = self.identity.clone().into_bytes();
+        #[cfg(feature = "external-channel-binding")]
+        if let (Some(cb_type), Some(data)) = (self.channel_binding.name(), self.channel_binding.data()) {
+            ir.push(b'\x00');
+            ir.extend_from_slice(cb_type.as_bytes());
+            ir.push(b'=');
+            ir.extend_from_slice(data);
+        }
+        Ok((EXTERNAL.to_string(), Some(ir)))
     }
 
     fn next(&mut self, _challenge: &[u8]) -> Result<Vec<u8>> {
@@ -38,42 +117,370 @@ impl sasl::Client for ExternalClient {
 /// the identity is left blank, it indicates that it is the same as the one used
 /// in the external credentials. If identity is not empty and the server doesn't
 /// support it, an error must be returned.
-pub type ExternalAuthenticator = Box<dyn Fn(&str) -> Result<()> + Send>;
+pub type ExternalAuthenticator = Box<dyn Fn(&str) -> Result<()> + Send + Sync>;
 
-/// NewExternalServer creates a server implementation of the EXTERNAL
-/// authentication mechanism, as described in RFC 4422.
+/// Decides whether the identity of a client certificate may act as another
+/// authorization identity. Arguments are the certificate identity and the
+/// requested authorization identity.
+pub type ExternalAuthorizer = Box<dyn Fn(&str, &str) -> Result<()> + Send + Sync>;
+
+/// Maps a TLS client certificate to an identity.
+pub trait TlsIdentityResolver: Send + Sync {
+    /// Returns the identity of a certificate, in DER form, which the TLS
+    /// library already verified.
+    fn resolve(&self, certificate: &[u8]) -> Result<String>;
+}
+
+impl<F: Fn(&[u8]) -> Result<String> + Send + Sync> TlsIdentityResolver for F {
+    fn resolve(&self, certificate: &[u8]) -> Result<String> {
+        self(certificate)
+    }
+}
+
+/// A server implementation of the EXTERNAL authentication mechanism, as
+/// described in RFC 4422. See `ExternalClient` for the channel binding
+/// extension of the `external-channel-binding` feature.
 pub struct ExternalServer {
     done: bool,
     authenticator: ExternalAuthenticator,
+    #[cfg(feature = "external-channel-binding")]
+    channel_binding: ChannelBinding,
+    #[cfg(feature = "external-channel-binding")]
+    require_channel_binding: bool,
+    certificate_identity: Option<String>,
+    policy: Option<Arc<dyn AuthorizationPolicy>>,
+    authzid: Option<String>,
 }
 
 impl ExternalServer {
-    pub fn new<F>(authenticator: ExternalAuthenticator) -> Self {
+    pub fn new(authenticator: ExternalAuthenticator) -> Self {
         Self {
             done: false,
             authenticator,
+            #[cfg(feature = "external-channel-binding")]
+            channel_binding: ChannelBinding::None,
+            #[cfg(feature = "external-channel-binding")]
+            require_channel_binding: false,
+            certificate_identity: None,
+            policy: None,
+            authzid: None,
+        }
+    }
+
+    /// Creates a server authenticating clients with their TLS certificate,
+    /// which must have been verified by the TLS library. Clients requesting
+    /// an authorization identity other than the one of the certificate are
+    /// rejected, unless `authorizer` allows it.
+    pub fn from_certificate(
+        resolver: &dyn TlsIdentityResolver,
+        certificate: &[u8],
+        authorizer: Option<ExternalAuthorizer>,
+    ) -> Result<Self> {
+        let mut server = Self::new(Box::new(|_| Ok(())));
+        server.certificate_identity = Some(resolver.resolve(certificate)?);
+        if let Some(authorizer) = authorizer {
+            server.policy = Some(Arc::new(authorizer));
+        }
+        Ok(server)
+    }
+
+    /// Decides with a policy whether clients may act as an authorization
+    /// identity other than the one of their certificate. Only servers
+    /// created from a certificate know that identity: others leave
+    /// authorization to their authenticator.
+    pub fn with_authorization_policy(mut self, policy: Arc<dyn AuthorizationPolicy>) -> Self {
+        self.policy = Some(policy);
+        self
+    }
+
+    /// Creates a server for a rustls connection, authenticating clients
+    /// with the certificate verified by rustls. See `from_certificate`.
+    #[cfg(feature = "rustls")]
+    pub fn from_rustls(
+        conn: &rustls::CommonState,
+        resolver: &dyn TlsIdentityResolver,
+        authorizer: Option<ExternalAuthorizer>,
+    ) -> Result<Self> {
+        match conn.peer_certificates().and_then(|certs| certs.first()) {
+            Some(certificate) => Self::from_certificate(resolver, certificate, authorizer),
+            None => bail!(ERR_NO_CLIENT_CERTIFICATE),
+        }
+    }
+
+    /// Verifies channel bindings sent by clients against the binding data
+    /// of the TLS session. Clients which don't send any are still accepted,
+    /// unless `require_channel_binding` is set.
+    #[cfg(feature = "external-channel-binding")]
+    pub fn with_channel_binding(mut self, channel_binding: ChannelBinding) -> Self {
+        self.channel_binding = channel_binding;
+        self
+    }
+
+    /// Rejects clients which don't send a channel binding.
+    #[cfg(feature = "external-channel-binding")]
+    pub fn require_channel_binding(mut self) -> Self {
+        self.require_channel_binding = true;
+        self
+    }
+
+    #[cfg(feature = "external-channel-binding")]
+    fn verify_channel_binding(&self, binding: Option<&[u8]>) -> Result<()> {
+        let (binding, cb_type, expected) = match (binding, self.channel_binding.name(), self.channel_binding.data()) {
+            (Some(binding), Some(cb_type), Some(expected)) => (binding, cb_type, expected),
+            // Servers which don't know the TLS session can't verify the
+            // binding, and the response must then be the identity alone.
+            (Some(_), _, _) => bail!(ERR_UNVERIFIABLE_CHANNEL_BINDING),
+            (None, _, _) if self.require_channel_binding => bail!(ERR_CHANNEL_BINDING_REQUIRED),
+            (None, _, _) => return Ok(()),
+        };
+
+        let mut parts = binding.splitn(2, |&b| b == b'=');
+        let received_type = parts.next().unwrap_or_default();
+        let data = parts.next().ok_or(sasl::FailureReason::MalformedResponse)?;
+        if received_type != cb_type.as_bytes() || !constant_time::eq(data, expected) {
+            bail!(ERR_CHANNEL_BINDING_MISMATCH);
+        }
+        Ok(())
+    }
+}
+
+/// The parts of a certificate `CertificateIdentityResolver` can derive an
+/// identity from.
+#[cfg(feature = "x509")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CertificateIdentity {
+    /// The first email (rfc822Name) subject alternative name.
+    Email,
+    /// The first DNS subject alternative name.
+    DnsName,
+    /// The common name of the subject.
+    CommonName,
+    /// The SHA-256 fingerprint of the certificate, in lowercase hex.
+    Fingerprint,
+}
+
+/// Resolves identities from X.509 certificates, trying each source in
+/// order.
+#[cfg(feature = "x509")]
+pub struct CertificateIdentityResolver {
+    sources: Vec<CertificateIdentity>,
+}
+
+#[cfg(feature = "x509")]
+impl CertificateIdentityResolver {
+    pub fn new(sources: Vec<CertificateIdentity>) -> Self {
+        Self { sources }
+    }
+}
+
+#[cfg(feature = "x509")]
+impl TlsIdentityResolver for CertificateIdentityResolver {
+    fn resolve(&self, certificate: &[u8]) -> Result<String> {
+        use sha2::{Digest, Sha256};
+        use x509_parser::prelude::{FromDer, GeneralName, X509Certificate};
+
+        let (_, cert) = X509Certificate::from_der(certificate)?;
+        let alt_name = |f: for<'a> fn(&GeneralName<'a>) -> Option<&'a str>| -> Result<Option<String>> {
+            let san = cert.subject_alternative_name()?;
+            Ok(san.and_then(|san| san.value.general_names.iter().find_map(f)).map(str::to_string))
+        };
+        for source in &self.sources {
+            let identity = match source {
+                CertificateIdentity::Email => alt_name(|name| match name {
+                    GeneralName::RFC822Name(email) => Some(email),
+                    _ => None,
+                })?,
+                CertificateIdentity::DnsName => alt_name(|name| match name {
+                    GeneralName::DNSName(dns) => Some(dns),
+                    _ => None,
+                })?,
+                CertificateIdentity::CommonName => {
+                    let cn = cert.subject().iter_common_name().next();
+                    cn.and_then(|cn| cn.as_str().ok()).map(str::to_string)
+                }
+                CertificateIdentity::Fingerprint => {
+                    Some(Sha256::digest(certificate).iter().map(|b| format!("{:02x}", b)).collect())
+                }
+            };
+            if let Some(identity) = identity.filter(|identity| !identity.is_empty()) {
+                return Ok(identity);
+            }
         }
+        bail!(ERR_NO_CERTIFICATE_IDENTITY)
     }
 }
 
 impl sasl::Server for ExternalServer {
-    fn next(&mut self, response: Option<&[u8]>) -> Result<(Vec<u8>, bool)> {
+    fn mechanism_name(&self) -> &str {
+        EXTERNAL
+    }
+
+    fn next(&mut self, response: Option<&[u8]>) -> Result<sasl::ServerStep> {
         if self.done {
             return Err(anyhow!(sasl::ERR_UNEXPECTED_CLIENT_RESPONSE));
         }
 
         if response.is_none() {
-            return Ok((Vec::new(), false));
+            return Ok(sasl::ServerStep::Challenge(Vec::new()));
         }
         let response = response.unwrap();
 
         self.done = true;
 
-        if response.contains(&b'\x00') {
-            return Err(anyhow!("identity contains a NUL character"));
+        #[cfg(feature = "external-channel-binding")]
+        let identity = {
+            let mut parts = response.splitn(2, |&b| b == b'\x00');
+            let identity = parts.next().unwrap_or_default();
+            self.verify_channel_binding(parts.next())?;
+            identity
+        };
+        #[cfg(not(feature = "external-channel-binding"))]
+        let identity = match response.contains(&b'\x00') {
+            true => bail!(sasl::FailureReason::MalformedResponse),
+            false => response,
+        };
+
+        let authzid = std::str::from_utf8(identity)?;
+        self.authzid = Some(authzid.to_string()).filter(|authzid| !authzid.is_empty());
+        (self.authenticator)(authzid)?;
+        if let Some(authcid) = &self.certificate_identity {
+            match &self.policy {
+                None if !authzid.is_empty() && authzid != authcid => bail!(ERR_IDENTITY_MISMATCH),
+                policy => authorize(policy.as_deref(), authcid, authzid)?,
+            }
+        }
+        Ok(sasl::ServerStep::Done { additional_data: None })
+    }
+
+    fn is_done(&self) -> bool {
+        self.done
+    }
+
+    fn reset(&mut self) -> Result<()> {
+        self.done = false;
+        self.authzid = None;
+        Ok(())
+    }
+
+    fn authentication_id(&self) -> Option<&str> {
+        self.certificate_identity.as_deref()
+    }
+
+    fn authorization_id(&self) -> Option<&str> {
+        self.authzid.as_deref()
+    }
+}
+
+#[test]
+fn test_external_client_from_certificate() -> Result<()> {
+    let resolver = |certificate: &[u8]| -> Result<String> { Ok(String::from_utf8(certificate.to_vec())?) };
+
+    let cases = [(None, ""), (Some("user"), ""), (Some("admin"), "admin")];
+    for (authzid, expected) in cases {
+        let client = ExternalClient::from_certificate(&resolver, b"user", authzid.map(str::to_string))?;
+        if client.authzid() != expected {
+            bail!("Unexpected authorization identity for {:?}", authzid);
         }
+    }
+    Ok(())
+}
 
-        (self.authenticator)(std::str::from_utf8(response)?)?;
-        Ok((Vec::new(), true))
+#[cfg(not(feature = "external-channel-binding"))]
+#[test]
+fn test_external_without_channel_binding() -> Result<()> {
+    use crate::sasl::{FailureReason, Server};
+
+    let mut server = ExternalServer::new(Box::new(|_| Ok(())));
+    match server.next(Some(b"identity\x00tls-exporter=session")) {
+        Err(err) if FailureReason::of(&err) == FailureReason::MalformedResponse => {}
+        _ => bail!("Expected the channel binding extension to be rejected"),
+    }
+    if PROPERTIES.channel_binding {
+        bail!("Expected EXTERNAL not to be channel-bound");
     }
-}
\ No newline at end of file
+    Ok(())
+}
+
+#[cfg(feature = "external-channel-binding")]
+#[test]
+fn test_external_channel_binding() -> Result<()> {
+    use crate::sasl::{Client, Server};
+
+    let session = || ChannelBinding::TlsExporter(b"session".to_vec());
+    let new_server = || ExternalServer::new(Box::new(|_| Ok(()))).with_channel_binding(session());
+    let authenticate = |mut client: ExternalClient, mut server: ExternalServer| -> Result<()> {
+        let (_, ir) = client.start()?;
+        server.next(ir.as_deref()).map(|_| ())
+    };
+
+    let client = || ExternalClient::new("identity".to_string());
+    authenticate(client().with_channel_binding(session()), new_server())?;
+    authenticate(client(), new_server())?;
+    if authenticate(client().with_channel_binding(ChannelBinding::TlsExporter(b"other".to_vec())), new_server()).is_ok() {
+        bail!("Expected a channel binding mismatch");
+    }
+    if authenticate(client().with_channel_binding(ChannelBinding::TlsUnique(b"session".to_vec())), new_server()).is_ok() {
+        bail!("Expected a channel binding type mismatch");
+    }
+    if authenticate(client(), new_server().require_channel_binding()).is_ok() {
+        bail!("Expected a missing channel binding to be rejected");
+    }
+    let mut server = ExternalServer::new(Box::new(|_| Ok(())));
+    if server.next(Some(b"identity\x00anything")).is_ok() {
+        bail!("Expected a binding the server can't verify to be rejected");
+    }
+
+    Ok(())
+}
+
+#[cfg(feature = "x509")]
+#[test]
+fn test_external_certificate_identity() -> Result<()> {
+    use crate::sasl::Server;
+    use rcgen::{CertificateParams, DnType, KeyPair, SanType};
+
+    let mut params = CertificateParams::default();
+    params.distinguished_name.push(DnType::CommonName, "User");
+    params.subject_alt_names = vec![SanType::DnsName("client.example.com".try_into()?)];
+    let certificate = params.self_signed(&KeyPair::generate()?)?;
+    let der = certificate.der();
+
+    let resolver = CertificateIdentityResolver::new(vec![CertificateIdentity::Email, CertificateIdentity::CommonName]);
+    if resolver.resolve(der)? != "User" {
+        bail!("Expected the common name");
+    }
+    let fingerprint = CertificateIdentityResolver::new(vec![CertificateIdentity::Fingerprint]).resolve(der)?;
+    if fingerprint.len() != 64 || CertificateIdentityResolver::new(vec![CertificateIdentity::Email]).resolve(der).is_ok() {
+        bail!("Unexpected identities");
+    }
+
+    let resolver = CertificateIdentityResolver::new(vec![CertificateIdentity::DnsName]);
+    let authenticate = |authzid: &[u8], authorizer: Option<ExternalAuthorizer>| -> Result<()> {
+        let mut server = ExternalServer::from_certificate(&resolver, der, authorizer)?;
+        server.next(Some(authzid)).map(|_| ())
+    };
+    authenticate(b"", None)?;
+    authenticate(b"client.example.com", None)?;
+    if authenticate(b"admin", None).is_ok() {
+        bail!("Expected a different authorization identity to be rejected");
+    }
+    authenticate(
+        b"admin",
+        Some(Box::new(|identity, authzid| {
+            if identity != "client.example.com" || authzid != "admin" {
+                bail!("Unexpected identities");
+            }
+            Ok(())
+        })),
+    )?;
+
+    #[cfg(feature = "rustls")]
+    {
+        let (_, server) = crate::channel_binding::rustls::handshake(&rustls::version::TLS13)?;
+        match ExternalServer::from_rustls(&server, &resolver, None) {
+            Err(err) if err.to_string() == ERR_NO_CLIENT_CERTIFICATE => {}
+            _ => bail!("Expected no client certificate"),
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,143 @@
+use crate::master::MasterUsers;
+use crate::sasl;
+use crate::security_layer::BoxSecurityLayer;
+
+use anyhow::{anyhow, Result};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Controls which failure details are disclosed to clients.
+#[derive(Clone, Copy, Debug, Default)]
+pub enum FailurePolicy {
+    /// Errors are returned as produced by the mechanism and authenticator.
+    #[default]
+    Detailed,
+    /// All failures are returned as `sasl::ERR_AUTHENTICATION_FAILED`, and
+    /// are delayed until at least `min_duration` has elapsed since the step
+    /// started, so that clients can't tell an unknown user from a wrong
+    /// password or a disabled account.
+    Uniform { min_duration: Duration },
+}
+
+/// Receives the mechanism name and the detailed error of every failure, e.g.
+/// to log it. Use `sasl::FailureReason::of` to get the failure reason.
+pub type FailureReporter = Box<dyn Fn(&str, &anyhow::Error) + Send + Sync>;
+
+/// A server wrapper applying a failure policy to another server.
+pub struct FailurePolicyServer<S> {
+    inner: S,
+    policy: FailurePolicy,
+    reporter: Option<FailureReporter>,
+}
+
+impl<S: sasl::Server> FailurePolicyServer<S> {
+    pub fn new(inner: S, policy: FailurePolicy) -> Self {
+        Self {
+            inner,
+            policy,
+            reporter: None,
+        }
+    }
+
+    pub fn with_reporter(mut self, reporter: FailureReporter) -> Self {
+        self.reporter = Some(reporter);
+        self
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<S: sasl::Server> sasl::Server for FailurePolicyServer<S> {
+    fn mechanism_name(&self) -> &str {
+        self.inner.mechanism_name()
+    }
+
+    fn next(&mut self, response: Option<&[u8]>) -> Result<sasl::ServerStep> {
+        let start = Instant::now();
+        let err = match self.inner.next(response) {
+            Ok(step) => return Ok(step),
+            Err(err) => err,
+        };
+
+        if let Some(reporter) = &self.reporter {
+            reporter(self.inner.mechanism_name(), &err);
+        }
+
+        match self.policy {
+            FailurePolicy::Detailed => Err(err),
+            FailurePolicy::Uniform { min_duration } => {
+                if let Some(remaining) = min_duration.checked_sub(start.elapsed()) {
+                    std::thread::sleep(remaining);
+                }
+                Err(anyhow!(sasl::ERR_AUTHENTICATION_FAILED))
+            }
+        }
+    }
+
+    fn is_done(&self) -> bool {
+        self.inner.is_done()
+    }
+
+    fn reset(&mut self) -> Result<()> {
+        self.inner.reset()
+    }
+
+    fn security_layer(&mut self) -> Option<BoxSecurityLayer> {
+        self.inner.security_layer()
+    }
+
+    fn authentication_id(&self) -> Option<&str> {
+        self.inner.authentication_id()
+    }
+
+    fn authorization_id(&self) -> Option<&str> {
+        self.inner.authorization_id()
+    }
+
+    fn set_master_users(&mut self, masters: Arc<MasterUsers>) -> bool {
+        self.inner.set_master_users(masters)
+    }
+}
+
+#[cfg(feature = "plain")]
+#[test]
+fn test_uniform_failures() -> Result<()> {
+    use crate::plain::PlainServer;
+    use crate::sasl::{FailureReason, Server};
+    use anyhow::bail;
+    use std::sync::{Arc, Mutex};
+
+    let reasons = Arc::new(Mutex::new(Vec::new()));
+    let mut errors = Vec::new();
+    for response in [&b"\x00unknown\x00password"[..], b"\x00username\x00wrong"] {
+        let plain = PlainServer::new(Box::new(|_, username, password| {
+            if username != "username" {
+                bail!(FailureReason::UnknownUser);
+            }
+            if password != "password" {
+                bail!(FailureReason::InvalidCredentials);
+            }
+            Ok(())
+        }));
+        let reported = reasons.clone();
+        let mut s = FailurePolicyServer::new(plain, FailurePolicy::Uniform { min_duration: Duration::from_millis(10) })
+            .with_reporter(Box::new(move |_, err| reported.lock().unwrap().push(FailureReason::of(err))));
+
+        let start = Instant::now();
+        errors.push(s.next(Some(response)).unwrap_err().to_string());
+        if start.elapsed() < Duration::from_millis(10) {
+            bail!("Failure was not delayed");
+        }
+    }
+
+    if errors[0] != errors[1] || errors[0] != sasl::ERR_AUTHENTICATION_FAILED {
+        bail!("Failures are distinguishable: {:?}", errors);
+    }
+    if *reasons.lock().unwrap() != [FailureReason::UnknownUser, FailureReason::InvalidCredentials] {
+        bail!("Invalid reported reasons: {:?}", reasons.lock().unwrap());
+    }
+
+    Ok(())
+}
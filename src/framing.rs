@@ -0,0 +1,163 @@
+//! Encoding of SASL messages in line-based protocols such as SMTP, IMAP,
+//! POP3 and ManageSieve: challenges and responses are sent base64-encoded,
+//! challenges after a continuation prefix (e.g. `334 ` or `+ `), and an
+//! empty initial response sent along with the command is written as `=`.
+
+use crate::dispatcher;
+
+use anyhow::{anyhow, bail, Result};
+
+pub const ERR_INVALID_BASE64: &str = "sasl: invalid base64 data";
+pub const ERR_MISSING_CONTINUATION: &str = "sasl: expected a continuation request";
+
+/// An empty initial response, which can't be sent as an empty argument.
+pub const EMPTY_INITIAL_RESPONSE: &str = "=";
+
+/// The continuation prefix of SMTP challenges (RFC 4954).
+pub const SMTP_CONTINUATION: &str = "334 ";
+
+/// The continuation prefix of IMAP and POP3 challenges (RFC 3501, RFC 5034).
+pub const IMAP_CONTINUATION: &str = "+ ";
+
+const BASE64_ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+pub fn base64_encode(data: &[u8]) -> String {
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let b = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(BASE64_ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+pub fn base64_decode(data: &str) -> Result<Vec<u8>> {
+    if !data.len().is_multiple_of(4) {
+        bail!(ERR_INVALID_BASE64);
+    }
+    let padding = data.len() - data.trim_end_matches('=').len();
+    if padding > 2 {
+        bail!(ERR_INVALID_BASE64);
+    }
+    let mut out = Vec::with_capacity(data.len() / 4 * 3);
+    let mut acc = 0u32;
+    let mut bits = 0;
+    for c in data[..data.len() - padding].bytes() {
+        let v = BASE64_ALPHABET
+            .iter()
+            .position(|&a| a == c)
+            .ok_or_else(|| anyhow!(ERR_INVALID_BASE64))?;
+        acc = acc << 6 | v as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((acc >> bits) as u8);
+        }
+    }
+    Ok(out)
+}
+
+/// Encodes an initial response to send along with the command, or `None`
+/// if the client has none.
+pub fn encode_initial_response(ir: Option<&[u8]>) -> Option<String> {
+    match ir {
+        None => None,
+        Some([]) => Some(EMPTY_INITIAL_RESPONSE.to_string()),
+        Some(ir) => Some(base64_encode(ir)),
+    }
+}
+
+/// Decodes an initial response sent along with the command.
+pub fn decode_initial_response(ir: Option<&str>) -> Result<Option<Vec<u8>>> {
+    match ir {
+        None => Ok(None),
+        Some(EMPTY_INITIAL_RESPONSE) => Ok(Some(Vec::new())),
+        Some(ir) => Ok(Some(base64_decode(ir)?)),
+    }
+}
+
+/// Encodes a challenge line, without its CRLF terminator.
+pub fn encode_challenge(prefix: &str, challenge: &[u8]) -> String {
+    format!("{}{}", prefix, base64_encode(challenge))
+}
+
+/// Decodes a challenge line. Servers sending empty challenges sometimes
+/// omit the trailing space of the prefix, which is accepted.
+pub fn decode_challenge(prefix: &str, line: &str) -> Result<Vec<u8>> {
+    let line = line.trim_end_matches(['\r', '\n']);
+    match line.strip_prefix(prefix) {
+        Some(data) => base64_decode(data.trim_end()),
+        None if line == prefix.trim_end() => Ok(Vec::new()),
+        None => bail!(ERR_MISSING_CONTINUATION),
+    }
+}
+
+/// Encodes a response line, without its CRLF terminator.
+pub fn encode_response(response: &[u8]) -> String {
+    base64_encode(response)
+}
+
+/// Decodes a response line, returning `None` if the client canceled the
+/// exchange.
+pub fn decode_response(line: &str) -> Result<Option<Vec<u8>>> {
+    if dispatcher::is_cancel(line) {
+        return Ok(None);
+    }
+    Ok(Some(base64_decode(line.trim_end_matches(['\r', '\n']))?))
+}
+
+#[test]
+fn test_base64() -> Result<()> {
+    for (data, encoded) in [
+        (&b""[..], ""),
+        (b"f", "Zg=="),
+        (b"fo", "Zm8="),
+        (b"foo", "Zm9v"),
+        (b"\x00user\x00pass", "AHVzZXIAcGFzcw=="),
+    ] {
+        if base64_encode(data) != encoded {
+            bail!("Invalid encoding of {:?}: {}", data, base64_encode(data));
+        }
+        if base64_decode(encoded)? != data {
+            bail!("Invalid decoding of {}", encoded);
+        }
+    }
+    for invalid in ["Zg=", "Z===", "Zm9v!", "="] {
+        if base64_decode(invalid).is_ok() {
+            bail!("Expected {} to be rejected", invalid);
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn test_framing() -> Result<()> {
+    if encode_initial_response(Some(b"")).as_deref() != Some("=") || encode_initial_response(None).is_some() {
+        bail!("Invalid initial response encoding");
+    }
+    if decode_initial_response(Some("="))? != Some(Vec::new()) || decode_initial_response(Some("Zm9v"))? != Some(b"foo".to_vec()) {
+        bail!("Invalid initial response decoding");
+    }
+
+    if encode_challenge(SMTP_CONTINUATION, b"foo") != "334 Zm9v" {
+        bail!("Invalid challenge encoding");
+    }
+    if decode_challenge(IMAP_CONTINUATION, "+ Zm9v\r\n")? != b"foo" || !decode_challenge(IMAP_CONTINUATION, "+")?.is_empty() {
+        bail!("Invalid challenge decoding");
+    }
+    match decode_challenge(SMTP_CONTINUATION, "535 5.7.8 Authentication failed") {
+        Err(err) if err.to_string() == ERR_MISSING_CONTINUATION => {}
+        _ => bail!("Expected a missing continuation error"),
+    }
+
+    if decode_response("*\r\n")?.is_some() || decode_response("")? != Some(Vec::new()) {
+        bail!("Invalid response decoding");
+    }
+    Ok(())
+}
@@ -0,0 +1,151 @@
+//! The GS2 header starting the first client message of GS2-style
+//! mechanisms, such as SCRAM and OAUTHBEARER, as described in RFC 5801
+//! section 4. It tells whether the client uses channel binding, with which
+//! type, and carries the authorization identity.
+
+use crate::channel_binding::ChannelBinding;
+
+use anyhow::{anyhow, bail, Result};
+
+pub const ERR_MALFORMED_HEADER: &str = "sasl: malformed GS2 header";
+pub const ERR_UNSUPPORTED_CHANNEL_BINDING: &str = "sasl: unsupported channel binding type";
+pub const ERR_CHANNEL_BINDING_UNAVAILABLE: &str = "sasl: channel binding type not available on this connection";
+
+/// The channel binding flag of a GS2 header.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CbFlag {
+    /// `n`: the client doesn't support channel binding.
+    NotSupported,
+    /// `y`: the client supports channel binding, but thinks the server
+    /// doesn't.
+    NotUsed,
+    /// `p=<name>`: the client uses the named channel binding type.
+    Used(String),
+}
+
+/// A GS2 header.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Header {
+    pub cb_flag: CbFlag,
+    pub authzid: Option<String>,
+}
+
+impl Header {
+    /// Creates the header of a client using a channel binding if there is
+    /// one.
+    pub fn new(binding: &ChannelBinding, authzid: Option<String>) -> Self {
+        let cb_flag = match binding.name() {
+            Some(name) => CbFlag::Used(name.to_string()),
+            None => CbFlag::NotSupported,
+        };
+        Self { cb_flag, authzid }
+    }
+
+    /// Parses the header at the start of a client message, returning it and
+    /// the rest of the message.
+    pub fn parse(message: &[u8]) -> Result<(Self, &[u8])> {
+        let mut parts = message.splitn(3, |&b| b == b',');
+        let (flag, authzid, rest) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(flag), Some(authzid), Some(rest)) => (flag, authzid, rest),
+            _ => bail!(ERR_MALFORMED_HEADER),
+        };
+
+        let cb_flag = match flag {
+            b"n" => CbFlag::NotSupported,
+            b"y" => CbFlag::NotUsed,
+            [b'p', b'=', name @ ..]
+                if !name.is_empty() && name.iter().all(|&b| b.is_ascii_alphanumeric() || b == b'.' || b == b'-') =>
+            {
+                CbFlag::Used(String::from_utf8(name.to_vec())?)
+            }
+            _ => bail!(ERR_MALFORMED_HEADER),
+        };
+        let authzid = match authzid {
+            [] => None,
+            [b'a', b'=', name @ ..] => Some(decode_saslname(name)?),
+            _ => bail!(ERR_MALFORMED_HEADER),
+        };
+        Ok((Self { cb_flag, authzid }, rest))
+    }
+
+    /// Encodes the header, including the trailing comma.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut header = match &self.cb_flag {
+            CbFlag::NotSupported => "n".to_string(),
+            CbFlag::NotUsed => "y".to_string(),
+            CbFlag::Used(name) => format!("p={}", name),
+        };
+        header.push(',');
+        if let Some(authzid) = &self.authzid {
+            header.push_str("a=");
+            header.push_str(&authzid.replace('=', "=3D").replace(',', "=2C"));
+        }
+        header.push(',');
+        header.into_bytes()
+    }
+
+    /// Returns the channel binding input of the exchange, e.g. for the
+    /// `c=` attribute of SCRAM: the encoded header, followed by the binding
+    /// data of the connection if the client uses it. Fails if the type
+    /// requested by the client isn't the one of the connection.
+    pub fn cbind_input(&self, binding: &ChannelBinding) -> Result<Vec<u8>> {
+        let mut input = self.encode();
+        if let CbFlag::Used(name) = &self.cb_flag {
+            if ChannelBinding::from_name(name, Vec::new()).is_none() {
+                bail!(ERR_UNSUPPORTED_CHANNEL_BINDING);
+            }
+            match (binding.name(), binding.data()) {
+                (Some(available), Some(data)) if available == name => input.extend_from_slice(data),
+                _ => bail!(ERR_CHANNEL_BINDING_UNAVAILABLE),
+            }
+        }
+        Ok(input)
+    }
+}
+
+/// Decodes a saslname, where `,` and `=` are escaped as `=2C` and `=3D`.
+fn decode_saslname(name: &[u8]) -> Result<String> {
+    let name = std::str::from_utf8(name)?;
+    let mut decoded = String::with_capacity(name.len());
+    let mut parts = name.split('=');
+    decoded.push_str(parts.next().unwrap_or_default());
+    for part in parts {
+        let c = match part.get(..2) {
+            Some("2C") => ',',
+            Some("3D") => '=',
+            _ => return Err(anyhow!(ERR_MALFORMED_HEADER)),
+        };
+        decoded.push(c);
+        decoded.push_str(&part[2..]);
+    }
+    Ok(decoded)
+}
+
+#[test]
+fn test_gs2_header() -> Result<()> {
+    let (header, rest) = Header::parse(b"p=tls-exporter,a=us=2Cer=3D,n=user,r=nonce")?;
+    if header.cb_flag != CbFlag::Used("tls-exporter".to_string()) || header.authzid.as_deref() != Some("us,er=") {
+        bail!("Unexpected header: {:?}", header);
+    }
+    if rest != b"n=user,r=nonce" || header.encode() != b"p=tls-exporter,a=us=2Cer=3D," {
+        bail!("Expected the header to round-trip");
+    }
+
+    let binding = ChannelBinding::TlsExporter(b"session".to_vec());
+    if header.cbind_input(&binding)? != b"p=tls-exporter,a=us=2Cer=3D,session" {
+        bail!("Unexpected channel binding input");
+    }
+    if header.cbind_input(&ChannelBinding::TlsServerEndPoint(b"cert".to_vec())).is_ok() {
+        bail!("Expected a channel binding type mismatch");
+    }
+    if Header::new(&ChannelBinding::None, None).cbind_input(&binding)? != b"n,," {
+        bail!("Expected no channel binding data");
+    }
+
+    for message in [&b"x,,"[..], b"p=,,", b"n,u=user,", b"n,a=us=er,", b"n"] {
+        if Header::parse(message).is_ok() {
+            bail!("Expected {:?} to be rejected", String::from_utf8_lossy(message));
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,234 @@
+//! Resolution of user-facing identities, such as email addresses with
+//! subaddresses, to the canonical identities known to the user database.
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+/// A custom rewriting step.
+pub type IdentityRule = Box<dyn Fn(&str) -> Result<String> + Send + Sync>;
+
+/// A built-in rewriting step, which can be loaded from a configuration file.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(tag = "rule", rename_all = "snake_case")]
+#[non_exhaustive]
+pub enum AliasRule {
+    /// Lowercases the whole identity.
+    Lowercase,
+    /// Lowercases the domain of `local@domain` identities. Local parts are
+    /// case-sensitive as per RFC 5321.
+    LowercaseDomain,
+    /// Removes subaddresses, e.g. `user+tag@domain` becomes `user@domain`.
+    StripSubaddress { separator: char },
+    /// Removes dots from the local part for the given domains, whose
+    /// providers ignore them (e.g. `first.last@gmail.com`).
+    IgnoreDots { domains: Vec<String> },
+    /// Removes the domain of `local@domain` identities for the given
+    /// domains, or for any domain if none are given.
+    StripDomain {
+        #[serde(default)]
+        domains: Vec<String>,
+    },
+    /// Appends a domain to identities without one.
+    AppendDomain { domain: String },
+    /// Converts internationalized domains to their ASCII form, e.g.
+    /// `bücher.example` becomes `xn--bcher-kva.example`.
+    #[cfg(feature = "idna")]
+    AsciiDomain,
+}
+
+fn split(identity: &str) -> (&str, Option<&str>) {
+    match identity.rsplit_once('@') {
+        Some((local, domain)) => (local, Some(domain)),
+        None => (identity, None),
+    }
+}
+
+fn join(local: &str, domain: Option<&str>) -> String {
+    match domain {
+        Some(domain) => format!("{}@{}", local, domain),
+        None => local.to_string(),
+    }
+}
+
+impl AliasRule {
+    pub fn apply(&self, identity: &str) -> Result<String> {
+        let (local, domain) = split(identity);
+        let identity = match self {
+            AliasRule::Lowercase => identity.to_lowercase(),
+            AliasRule::LowercaseDomain => join(local, domain.map(|d| d.to_lowercase()).as_deref()),
+            AliasRule::StripSubaddress { separator } => {
+                let local = local.split_once(*separator).map_or(local, |(local, _)| local);
+                join(local, domain)
+            }
+            AliasRule::IgnoreDots { domains } => match domain {
+                Some(domain) if domains.iter().any(|d| d.eq_ignore_ascii_case(domain)) => {
+                    join(&local.replace('.', ""), Some(domain))
+                }
+                _ => identity.to_string(),
+            },
+            AliasRule::StripDomain { domains } => match domain {
+                Some(domain) if domains.is_empty() || domains.iter().any(|d| d.eq_ignore_ascii_case(domain)) => local.to_string(),
+                _ => identity.to_string(),
+            },
+            AliasRule::AppendDomain { domain: default } => join(local, Some(domain.unwrap_or(default))),
+            #[cfg(feature = "idna")]
+            AliasRule::AsciiDomain => match domain {
+                Some(domain) => {
+                    let domain = idna::domain_to_ascii(domain).map_err(|_| anyhow!("sasl: invalid domain: {}", domain))?;
+                    join(local, Some(&domain))
+                }
+                None => identity.to_string(),
+            },
+        };
+        Ok(identity)
+    }
+}
+
+enum Step {
+    Alias(AliasRule),
+    Custom(IdentityRule),
+}
+
+/// A pipeline of rules mapping an identity to its canonical form, applied
+/// in order before authorization. Servers accepting a canonicalizer (e.g.
+/// `PlainServer::with_canonicalizer`) apply it to usernames before their
+/// authenticator runs, like cyrus-sasl's `canon_user`.
+#[derive(Default)]
+pub struct IdentityMapper {
+    steps: Vec<Step>,
+}
+
+impl IdentityMapper {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_rule(mut self, rule: AliasRule) -> Self {
+        self.steps.push(Step::Alias(rule));
+        self
+    }
+
+    pub fn with_custom_rule(mut self, rule: IdentityRule) -> Self {
+        self.steps.push(Step::Custom(rule));
+        self
+    }
+
+    /// Returns the canonical form of an identity.
+    pub fn map(&self, identity: &str) -> Result<String> {
+        let mut identity = identity.to_string();
+        for step in &self.steps {
+            identity = match step {
+                Step::Alias(rule) => rule.apply(&identity)?,
+                Step::Custom(rule) => rule(&identity)?,
+            };
+        }
+        if identity.is_empty() {
+            return Err(anyhow!("sasl: identity mapped to an empty string"));
+        }
+        Ok(identity)
+    }
+}
+
+/// Returns the canonical form of an identity if a server has a
+/// canonicalizer, or the identity itself.
+#[cfg(any(feature = "plain", feature = "login", feature = "oauthbearer"))]
+pub(crate) fn canonicalize(canonicalizer: Option<&IdentityMapper>, identity: &str) -> Result<String> {
+    match canonicalizer {
+        Some(mapper) => mapper.map(identity),
+        None => Ok(identity.to_string()),
+    }
+}
+
+pub const ERR_RAW_CREDENTIALS: &str = "sasl: canonicalization, SASLprep and UTF-8 policies need decoded credentials";
+
+/// How servers decode credentials which aren't valid UTF-8.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Utf8Policy {
+    /// Rejects them.
+    #[default]
+    Require,
+    /// Decodes them as ISO-8859-1, as sent by legacy clients. Valid UTF-8
+    /// is still decoded as such.
+    Latin1,
+}
+
+/// Decodes a username or password according to the UTF-8 policy of a
+/// server.
+#[cfg(any(feature = "plain", feature = "login"))]
+pub(crate) fn decode(policy: Utf8Policy, bytes: &[u8]) -> Result<std::borrow::Cow<'_, str>> {
+    match (std::str::from_utf8(bytes), policy) {
+        (Ok(s), _) => Ok(std::borrow::Cow::Borrowed(s)),
+        (Err(_), Utf8Policy::Latin1) => Ok(std::borrow::Cow::Owned(bytes.iter().map(|&b| char::from(b)).collect())),
+        (Err(err), Utf8Policy::Require) => Err(err.into()),
+    }
+}
+
+/// Prepares a username or password with SASLprep if a client or server
+/// enables it, or returns it as is.
+#[cfg(any(feature = "plain", feature = "login"))]
+pub(crate) fn prepare(saslprep: bool, s: &str) -> Result<std::borrow::Cow<'_, str>> {
+    match saslprep {
+        #[cfg(feature = "saslprep")]
+        true => crate::saslprep::saslprep(s),
+        _ => Ok(std::borrow::Cow::Borrowed(s)),
+    }
+}
+
+impl FromIterator<AliasRule> for IdentityMapper {
+    fn from_iter<I: IntoIterator<Item = AliasRule>>(rules: I) -> Self {
+        Self {
+            steps: rules.into_iter().map(Step::Alias).collect(),
+        }
+    }
+}
+
+#[test]
+fn test_identity_mapper() -> Result<()> {
+    use anyhow::bail;
+
+    let rules: Vec<AliasRule> = serde_json::from_str(
+        r#"[
+            {"rule": "lowercase_domain"},
+            {"rule": "strip_subaddress", "separator": "+"},
+            {"rule": "ignore_dots", "domains": ["gmail.com"]}
+        ]"#,
+    )?;
+    let mapper = rules
+        .into_iter()
+        .collect::<IdentityMapper>()
+        .with_custom_rule(Box::new(|identity| Ok(identity.replace("@googlemail.com", "@gmail.com"))));
+
+    let cases = [
+        ("John.Doe+news@GMail.com", "JohnDoe@gmail.com"),
+        ("john.doe+news@gmail.com", "johndoe@gmail.com"),
+        ("john.doe@googlemail.com", "john.doe@gmail.com"),
+        ("john.doe+news@example.org", "john.doe@example.org"),
+        ("john.doe", "john.doe"),
+    ];
+    for (identity, expected) in cases {
+        let mapped = mapper.map(identity)?;
+        if mapped != expected {
+            bail!("Expected {} to map to {}, got {}", identity, expected, mapped);
+        }
+    }
+
+    let mapper = IdentityMapper::from_iter([
+        AliasRule::Lowercase,
+        AliasRule::StripDomain { domains: vec!["example.org".to_string()] },
+        AliasRule::AppendDomain { domain: "example.com".to_string() },
+    ]);
+    for (identity, expected) in [("John@Example.org", "john@example.com"), ("john@example.net", "john@example.net"), ("john", "john@example.com")] {
+        let mapped = mapper.map(identity)?;
+        if mapped != expected {
+            bail!("Expected {} to map to {}, got {}", identity, expected, mapped);
+        }
+    }
+
+    #[cfg(feature = "idna")]
+    if AliasRule::AsciiDomain.apply("user@Bücher.example")? != "user@xn--bcher-kva.example" {
+        bail!("Expected the domain to be converted to ASCII");
+    }
+
+    Ok(())
+}
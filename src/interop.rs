@@ -0,0 +1,15 @@
+//! Bridges between this crate and other SASL implementations: Rust crates,
+//! each behind a cargo feature named after the crate, and C libraries bound
+//! through FFI, behind a feature named after the library.
+//!
+//! lettre is not covered: its SMTP transport only supports a closed set of
+//! built-in mechanisms and can't drive external ones.
+
+#[cfg(feature = "async-imap")]
+pub mod async_imap;
+#[cfg(feature = "cyrus-sasl")]
+pub mod cyrus_sasl;
+#[cfg(feature = "gsasl")]
+pub mod gsasl;
+#[cfg(feature = "rsasl")]
+pub mod rsasl;
@@ -0,0 +1,108 @@
+use crate::replay::ReplayGuard;
+use crate::sasl;
+
+use std::sync::Arc;
+
+/// Drives a client as an `async_imap::Authenticator`, for use with
+/// `async_imap::Client::authenticate`:
+///
+/// ```ignore
+/// let mut authenticator = ImapAuthenticator::new(client);
+/// let mechanism = authenticator.mechanism_name().to_string();
+/// let session = imap_client.authenticate(mechanism, &mut authenticator).await;
+/// ```
+///
+/// async-imap doesn't support SASL-IR, so the initial response is sent in
+/// reply to the first continuation request. Authenticators can't abort the
+/// exchange either: client errors are answered with an empty response and
+/// can be retrieved with `take_error`.
+pub struct ImapAuthenticator<C> {
+    client: C,
+    started: bool,
+    error: Option<anyhow::Error>,
+    replay_guard: Option<Arc<ReplayGuard>>,
+}
+
+impl<C: sasl::Client> ImapAuthenticator<C> {
+    pub fn new(client: C) -> Self {
+        Self {
+            client,
+            started: false,
+            error: None,
+            replay_guard: None,
+        }
+    }
+
+    /// Refuses challenges replayed from previous exchanges sharing the same
+    /// guard, e.g. when authentication is retried.
+    pub fn with_replay_guard(mut self, guard: Arc<ReplayGuard>) -> Self {
+        self.replay_guard = Some(guard);
+        self
+    }
+
+    /// Returns the mechanism name to pass to `authenticate`.
+    pub fn mechanism_name(&self) -> &str {
+        self.client.mechanism_name()
+    }
+
+    /// Returns the first error reported by the client, if any.
+    pub fn take_error(&mut self) -> Option<anyhow::Error> {
+        self.error.take()
+    }
+
+    fn step(&mut self, challenge: &[u8]) -> anyhow::Result<Vec<u8>> {
+        if !self.started {
+            self.started = true;
+            if let (_, Some(ir)) = self.client.start()? {
+                return Ok(ir);
+            }
+        }
+        if let Some(guard) = &self.replay_guard {
+            guard.check(self.client.mechanism_name(), challenge)?;
+        }
+        self.client.next(challenge)
+    }
+}
+
+impl<C: sasl::Client> From<C> for ImapAuthenticator<C> {
+    fn from(client: C) -> Self {
+        Self::new(client)
+    }
+}
+
+impl<C: sasl::Client> ::async_imap::Authenticator for &mut ImapAuthenticator<C> {
+    type Response = Vec<u8>;
+
+    fn process(&mut self, challenge: &[u8]) -> Self::Response {
+        match self.step(challenge) {
+            Ok(response) => response,
+            Err(err) => {
+                self.error.get_or_insert(err);
+                Vec::new()
+            }
+        }
+    }
+}
+
+#[cfg(feature = "plain")]
+#[test]
+fn test_imap_authenticator() -> anyhow::Result<()> {
+    use crate::plain::PlainClient;
+    use ::async_imap::Authenticator;
+    use anyhow::bail;
+
+    let mut authenticator = ImapAuthenticator::new(PlainClient::new(
+        String::new(),
+        "username".to_string(),
+        "password".to_string(),
+    ));
+    if (&mut authenticator).process(b"") != b"\x00username\x00password" {
+        bail!("Expected the initial response");
+    }
+    (&mut authenticator).process(b"unexpected");
+    if authenticator.take_error().is_none() {
+        bail!("Expected an unexpected challenge error");
+    }
+
+    Ok(())
+}
@@ -0,0 +1,316 @@
+//! Client mechanisms of the Cyrus SASL library (libsasl2), through FFI, for
+//! mechanisms this crate doesn't implement (e.g. GSSAPI or NTLM).
+//! Mechanisms are loaded from the library's plugins, and credentials are
+//! supplied by answering its interaction prompts.
+//!
+//! Security layers aren't supported: clients are configured with a maximum
+//! SSF of 0, so that mechanisms which can negotiate one don't.
+
+use crate::sasl;
+
+use anyhow::{anyhow, bail, Result};
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int, c_uint, c_ulong, c_void};
+use std::ptr;
+use std::sync::OnceLock;
+
+pub const ERR_LIBSASL2: &str = "sasl: cyrus-sasl: libsasl2 error";
+pub const ERR_UNSUPPORTED_PROMPT: &str = "sasl: cyrus-sasl: unsupported interaction prompt";
+pub const ERR_INCOMPLETE: &str = "sasl: cyrus-sasl: exchange incomplete";
+
+const SASL_OK: c_int = 0;
+const SASL_CONTINUE: c_int = 1;
+const SASL_INTERACT: c_int = 2;
+
+const SASL_CB_LIST_END: c_ulong = 0;
+const SASL_CB_USER: c_ulong = 0x4001;
+const SASL_CB_AUTHNAME: c_ulong = 0x4002;
+const SASL_CB_PASS: c_ulong = 0x4004;
+const SASL_CB_GETREALM: c_ulong = 0x4008;
+
+const SASL_SEC_PROPS: c_int = 101;
+
+#[repr(C)]
+struct SaslConn {
+    _private: [u8; 0],
+}
+
+#[repr(C)]
+struct SaslCallback {
+    id: c_ulong,
+    proc_: Option<unsafe extern "C" fn() -> c_int>,
+    context: *mut c_void,
+}
+
+#[repr(C)]
+struct SaslInteract {
+    id: c_ulong,
+    challenge: *const c_char,
+    prompt: *const c_char,
+    defresult: *const c_char,
+    result: *const c_void,
+    len: c_uint,
+}
+
+#[repr(C)]
+struct SaslSecurityProperties {
+    min_ssf: c_uint,
+    max_ssf: c_uint,
+    maxbufsize: c_uint,
+    security_flags: c_uint,
+    property_names: *const *const c_char,
+    property_values: *const *const c_char,
+}
+
+#[link(name = "sasl2")]
+extern "C" {
+    fn sasl_client_init(callbacks: *const SaslCallback) -> c_int;
+    fn sasl_client_new(
+        service: *const c_char,
+        server_fqdn: *const c_char,
+        iplocalport: *const c_char,
+        ipremoteport: *const c_char,
+        prompt_supp: *const SaslCallback,
+        flags: c_uint,
+        pconn: *mut *mut SaslConn,
+    ) -> c_int;
+    fn sasl_client_start(
+        conn: *mut SaslConn,
+        mechlist: *const c_char,
+        prompt_need: *mut *mut SaslInteract,
+        clientout: *mut *const c_char,
+        clientoutlen: *mut c_uint,
+        mech: *mut *const c_char,
+    ) -> c_int;
+    fn sasl_client_step(
+        conn: *mut SaslConn,
+        serverin: *const c_char,
+        serverinlen: c_uint,
+        prompt_need: *mut *mut SaslInteract,
+        clientout: *mut *const c_char,
+        clientoutlen: *mut c_uint,
+    ) -> c_int;
+    fn sasl_setprop(conn: *mut SaslConn, propnum: c_int, value: *const c_void) -> c_int;
+    fn sasl_errdetail(conn: *mut SaslConn) -> *const c_char;
+    fn sasl_errstring(saslerr: c_int, langlist: *const c_char, outlang: *mut *const c_char) -> *const c_char;
+    fn sasl_dispose(pconn: *mut *mut SaslConn);
+}
+
+/// The prompts answered by `CyrusClient`, terminated as libsasl2 expects.
+/// Callbacks without a procedure tell the library to prompt instead.
+static PROMPTS: [SaslCallback; 5] = [
+    SaslCallback { id: SASL_CB_USER, proc_: None, context: ptr::null_mut() },
+    SaslCallback { id: SASL_CB_AUTHNAME, proc_: None, context: ptr::null_mut() },
+    SaslCallback { id: SASL_CB_PASS, proc_: None, context: ptr::null_mut() },
+    SaslCallback { id: SASL_CB_GETREALM, proc_: None, context: ptr::null_mut() },
+    SaslCallback { id: SASL_CB_LIST_END, proc_: None, context: ptr::null_mut() },
+];
+
+// SAFETY: the callbacks are immutable and their context pointers null.
+unsafe impl Sync for SaslCallback {}
+
+fn init() -> Result<()> {
+    static INIT: OnceLock<c_int> = OnceLock::new();
+    // SAFETY: called once per process, without global callbacks.
+    let rc = *INIT.get_or_init(|| unsafe { sasl_client_init(ptr::null()) });
+    match rc {
+        SASL_OK => Ok(()),
+        rc => Err(error(ptr::null_mut(), rc)),
+    }
+}
+
+fn error(conn: *mut SaslConn, rc: c_int) -> anyhow::Error {
+    // SAFETY: libsasl2 returns static or connection-owned NUL-terminated
+    // strings.
+    let detail = unsafe {
+        let detail = if conn.is_null() { sasl_errstring(rc, ptr::null(), ptr::null_mut()) } else { sasl_errdetail(conn) };
+        if detail.is_null() { String::new() } else { CStr::from_ptr(detail).to_string_lossy().into_owned() }
+    };
+    anyhow!("{}: {}", ERR_LIBSASL2, detail)
+}
+
+/// A libsasl2 client connection running one mechanism.
+pub struct CyrusClient {
+    conn: *mut SaslConn,
+    mechanism: String,
+    authzid: CString,
+    username: CString,
+    password: CString,
+    complete: bool,
+}
+
+// SAFETY: a libsasl2 connection can be used from any thread, as long as it
+// isn't used concurrently, which `&mut self` methods ensure.
+unsafe impl Send for CyrusClient {}
+
+impl CyrusClient {
+    /// Creates a client for the mechanism, authenticating to `service`
+    /// (e.g. `imap`) on `server_fqdn`, which mechanisms such as GSSAPI use
+    /// to name the server principal.
+    pub fn new(mechanism: &str, service: &str, server_fqdn: &str) -> Result<Self> {
+        init()?;
+        let service = CString::new(service)?;
+        let server_fqdn = CString::new(server_fqdn)?;
+        let mut conn = ptr::null_mut();
+        // SAFETY: the strings outlive the call, the prompts are static.
+        let rc = unsafe {
+            sasl_client_new(service.as_ptr(), server_fqdn.as_ptr(), ptr::null(), ptr::null(), PROMPTS.as_ptr(), 0, &mut conn)
+        };
+        if rc != SASL_OK {
+            return Err(error(conn, rc));
+        }
+        let props = SaslSecurityProperties {
+            min_ssf: 0,
+            max_ssf: 0,
+            maxbufsize: 0,
+            security_flags: 0,
+            property_names: ptr::null(),
+            property_values: ptr::null(),
+        };
+        let client = Self {
+            conn,
+            mechanism: mechanism.to_string(),
+            authzid: CString::default(),
+            username: CString::default(),
+            password: CString::default(),
+            complete: false,
+        };
+        // SAFETY: libsasl2 copies the properties.
+        let rc = unsafe { sasl_setprop(conn, SASL_SEC_PROPS, &props as *const _ as *const c_void) };
+        if rc != SASL_OK {
+            return Err(error(conn, rc));
+        }
+        Ok(client)
+    }
+
+    /// Sets the credentials answering the library's prompts. An empty
+    /// authorization identity means the same as the username.
+    pub fn with_credentials(mut self, authzid: &str, username: &str, password: &str) -> Result<Self> {
+        self.authzid = CString::new(authzid)?;
+        self.username = CString::new(username)?;
+        self.password = CString::new(password)?;
+        Ok(self)
+    }
+
+    /// Answers the prompts libsasl2 needs before it can proceed.
+    ///
+    /// SAFETY: `prompts` must be the array returned with `SASL_INTERACT`.
+    unsafe fn interact(&self, mut prompts: *mut SaslInteract) -> Result<()> {
+        while !prompts.is_null() && (*prompts).id != SASL_CB_LIST_END {
+            let prompt = &mut *prompts;
+            let result: &CStr = match prompt.id {
+                SASL_CB_USER => &self.authzid,
+                SASL_CB_AUTHNAME => &self.username,
+                SASL_CB_PASS => &self.password,
+                SASL_CB_GETREALM if !prompt.defresult.is_null() => CStr::from_ptr(prompt.defresult),
+                SASL_CB_GETREALM => c"",
+                _ => bail!(ERR_UNSUPPORTED_PROMPT),
+            };
+            prompt.result = result.as_ptr() as *const c_void;
+            prompt.len = result.to_bytes().len() as c_uint;
+            prompts = prompts.add(1);
+        }
+        Ok(())
+    }
+
+    fn output(&mut self, rc: c_int, out: *const c_char, len: c_uint) -> Result<Option<Vec<u8>>> {
+        match rc {
+            SASL_OK => self.complete = true,
+            SASL_CONTINUE => {}
+            rc => return Err(error(self.conn, rc)),
+        }
+        if out.is_null() {
+            return Ok(None);
+        }
+        // SAFETY: libsasl2 returns `len` bytes owned by the connection.
+        Ok(Some(unsafe { std::slice::from_raw_parts(out as *const u8, len as usize) }.to_vec()))
+    }
+
+    fn step(&mut self, challenge: &[u8]) -> Result<Option<Vec<u8>>> {
+        let (mut prompts, mut out, mut len) = (ptr::null_mut(), ptr::null(), 0);
+        loop {
+            // SAFETY: the challenge outlives the call, answered prompts
+            // point to strings owned by `self`.
+            let rc = unsafe {
+                sasl_client_step(self.conn, challenge.as_ptr() as *const c_char, challenge.len() as c_uint, &mut prompts, &mut out, &mut len)
+            };
+            if rc != SASL_INTERACT {
+                return self.output(rc, out, len);
+            }
+            // SAFETY: the prompts were returned with SASL_INTERACT.
+            unsafe { self.interact(prompts)? };
+        }
+    }
+}
+
+impl sasl::Client for CyrusClient {
+    fn mechanism_name(&self) -> &str {
+        &self.mechanism
+    }
+
+    fn start(&mut self) -> Result<(String, Option<Vec<u8>>)> {
+        let mechlist = CString::new(self.mechanism.as_str())?;
+        let (mut prompts, mut out, mut len, mut mech) = (ptr::null_mut(), ptr::null(), 0, ptr::null());
+        loop {
+            // SAFETY: the mechanism list outlives the call, answered
+            // prompts point to strings owned by `self`.
+            let rc = unsafe { sasl_client_start(self.conn, mechlist.as_ptr(), &mut prompts, &mut out, &mut len, &mut mech) };
+            if rc != SASL_INTERACT {
+                let ir = self.output(rc, out, len)?;
+                return Ok((self.mechanism.clone(), ir));
+            }
+            // SAFETY: the prompts were returned with SASL_INTERACT.
+            unsafe { self.interact(prompts)? };
+        }
+    }
+
+    fn next(&mut self, challenge: &[u8]) -> Result<Vec<u8>> {
+        if self.complete {
+            bail!(sasl::ERR_UNEXPECTED_SERVER_CHALLENGE);
+        }
+        Ok(self.step(challenge)?.unwrap_or_default())
+    }
+
+    fn finish(&mut self, data: Option<&[u8]>) -> Result<()> {
+        if let Some(data) = data.filter(|data| !data.is_empty()) {
+            if self.complete {
+                bail!(sasl::ERR_UNEXPECTED_SUCCESS_DATA);
+            }
+            if self.step(data)?.is_some_and(|out| !out.is_empty()) {
+                bail!(ERR_INCOMPLETE);
+            }
+        }
+        if !self.complete {
+            bail!(ERR_INCOMPLETE);
+        }
+        Ok(())
+    }
+}
+
+impl Drop for CyrusClient {
+    fn drop(&mut self) {
+        // SAFETY: the connection was created by sasl_client_new and isn't
+        // used afterwards.
+        unsafe { sasl_dispose(&mut self.conn) };
+    }
+}
+
+#[cfg(feature = "plain")]
+#[test]
+fn test_cyrus_plain_client() -> Result<()> {
+    use crate::plain::{PlainServer, PLAIN};
+    use crate::sasl::{Client, Server};
+
+    let mut client = CyrusClient::new(PLAIN, "imap", "localhost")?.with_credentials("", "username", "password")?;
+    let mut server = PlainServer::new(Box::new(|identity, username, password| {
+        if !identity.is_empty() || username != "username" || password != "password" {
+            bail!("Invalid credentials");
+        }
+        Ok(())
+    }));
+    let (_, ir) = client.start()?;
+    match server.next(ir.as_deref())? {
+        sasl::ServerStep::Done { additional_data } => client.finish(additional_data.as_deref()),
+        step => bail!("Unexpected step: {:?}", step),
+    }
+}
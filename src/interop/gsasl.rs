@@ -0,0 +1,348 @@
+//! Client and server mechanisms of GNU SASL (libgsasl), through FFI, for
+//! interoperability testing and for mechanisms this crate doesn't
+//! implement (e.g. GSSAPI or SCRAM).
+//!
+//! libgsasl doesn't tell client-first mechanisms apart: clients send the
+//! output of their first step as initial response unless it's empty and
+//! the mechanism needs a challenge first.
+
+use crate::sasl;
+
+use anyhow::{anyhow, bail, Result};
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int, c_void};
+use std::ptr;
+
+pub const ERR_LIBGSASL: &str = "sasl: gsasl: libgsasl error";
+
+const GSASL_OK: c_int = 0;
+const GSASL_NEEDS_MORE: c_int = 1;
+const GSASL_AUTHENTICATION_ERROR: c_int = 31;
+const GSASL_NO_CALLBACK: c_int = 51;
+
+const GSASL_AUTHID: c_int = 1;
+const GSASL_AUTHZID: c_int = 2;
+const GSASL_PASSWORD: c_int = 3;
+const GSASL_SERVICE: c_int = 5;
+const GSASL_HOSTNAME: c_int = 6;
+const GSASL_VALIDATE_SIMPLE: c_int = 500;
+
+#[repr(C)]
+struct Gsasl {
+    _private: [u8; 0],
+}
+
+#[repr(C)]
+struct GsaslSession {
+    _private: [u8; 0],
+}
+
+type GsaslCallback = unsafe extern "C" fn(ctx: *mut Gsasl, sctx: *mut GsaslSession, prop: c_int) -> c_int;
+
+#[link(name = "gsasl")]
+extern "C" {
+    fn gsasl_init(ctx: *mut *mut Gsasl) -> c_int;
+    fn gsasl_done(ctx: *mut Gsasl);
+    fn gsasl_callback_set(ctx: *mut Gsasl, cb: GsaslCallback);
+    fn gsasl_client_start(ctx: *mut Gsasl, mech: *const c_char, sctx: *mut *mut GsaslSession) -> c_int;
+    fn gsasl_server_start(ctx: *mut Gsasl, mech: *const c_char, sctx: *mut *mut GsaslSession) -> c_int;
+    fn gsasl_step(sctx: *mut GsaslSession, input: *const c_char, input_len: usize, output: *mut *mut c_char, output_len: *mut usize) -> c_int;
+    fn gsasl_finish(sctx: *mut GsaslSession);
+    fn gsasl_free(ptr: *mut c_void);
+    fn gsasl_property_set(sctx: *mut GsaslSession, prop: c_int, data: *const c_char) -> c_int;
+    fn gsasl_property_fast(sctx: *mut GsaslSession, prop: c_int) -> *const c_char;
+    fn gsasl_session_hook_set(sctx: *mut GsaslSession, hook: *mut c_void);
+    fn gsasl_session_hook_get(sctx: *mut GsaslSession) -> *mut c_void;
+    fn gsasl_strerror(err: c_int) -> *const c_char;
+}
+
+fn error(rc: c_int) -> anyhow::Error {
+    // SAFETY: gsasl_strerror returns static NUL-terminated strings.
+    let detail = unsafe { CStr::from_ptr(gsasl_strerror(rc)) };
+    anyhow!("{}: {}", ERR_LIBGSASL, detail.to_string_lossy())
+}
+
+/// A libgsasl context and session running one mechanism.
+struct Session {
+    ctx: *mut Gsasl,
+    sctx: *mut GsaslSession,
+}
+
+impl Session {
+    fn new(mechanism: &str, server: bool, callback: Option<GsaslCallback>) -> Result<Self> {
+        let mechanism = CString::new(mechanism)?;
+        let mut session = Self {
+            ctx: ptr::null_mut(),
+            sctx: ptr::null_mut(),
+        };
+        // SAFETY: the pointers are initialized by libgsasl, and released by
+        // `drop` even if starting the session fails.
+        unsafe {
+            let rc = gsasl_init(&mut session.ctx);
+            if rc != GSASL_OK {
+                return Err(error(rc));
+            }
+            if let Some(callback) = callback {
+                gsasl_callback_set(session.ctx, callback);
+            }
+            let rc = match server {
+                true => gsasl_server_start(session.ctx, mechanism.as_ptr(), &mut session.sctx),
+                false => gsasl_client_start(session.ctx, mechanism.as_ptr(), &mut session.sctx),
+            };
+            if rc != GSASL_OK {
+                return Err(error(rc));
+            }
+        }
+        Ok(session)
+    }
+
+    fn set_property(&mut self, prop: c_int, value: &str) -> Result<()> {
+        let value = CString::new(value)?;
+        // SAFETY: libgsasl copies the value.
+        match unsafe { gsasl_property_set(self.sctx, prop, value.as_ptr()) } {
+            GSASL_OK => Ok(()),
+            rc => Err(error(rc)),
+        }
+    }
+
+    /// Runs a step, returning whether the mechanism completed and its
+    /// output.
+    fn step(&mut self, input: &[u8]) -> Result<(bool, Vec<u8>)> {
+        let (mut output, mut output_len) = (ptr::null_mut(), 0);
+        // SAFETY: the input outlives the call, the output is allocated by
+        // libgsasl and freed once copied.
+        unsafe {
+            let rc = gsasl_step(self.sctx, input.as_ptr() as *const c_char, input.len(), &mut output, &mut output_len);
+            let data = match output.is_null() {
+                true => Vec::new(),
+                false => std::slice::from_raw_parts(output as *const u8, output_len).to_vec(),
+            };
+            gsasl_free(output as *mut c_void);
+            match rc {
+                GSASL_OK => Ok((true, data)),
+                GSASL_NEEDS_MORE => Ok((false, data)),
+                rc => Err(error(rc)),
+            }
+        }
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        // SAFETY: the session and context aren't used afterwards.
+        unsafe {
+            if !self.sctx.is_null() {
+                gsasl_finish(self.sctx);
+            }
+            if !self.ctx.is_null() {
+                gsasl_done(self.ctx);
+            }
+        }
+    }
+}
+
+// SAFETY: libgsasl contexts and sessions can be used from any thread, as
+// long as they aren't used concurrently, which `&mut self` methods ensure.
+unsafe impl Send for Session {}
+
+/// A libgsasl client.
+pub struct GsaslClient {
+    session: Session,
+    mechanism: String,
+    complete: bool,
+}
+
+impl GsaslClient {
+    /// Creates a client for the mechanism, authenticating to `service`
+    /// (e.g. `imap`) on `hostname`.
+    pub fn new(mechanism: &str, service: &str, hostname: &str) -> Result<Self> {
+        let mut session = Session::new(mechanism, false, None)?;
+        session.set_property(GSASL_SERVICE, service)?;
+        session.set_property(GSASL_HOSTNAME, hostname)?;
+        Ok(Self {
+            session,
+            mechanism: mechanism.to_string(),
+            complete: false,
+        })
+    }
+
+    /// Sets the credentials. An empty authorization identity is left
+    /// unset.
+    pub fn with_credentials(mut self, authzid: &str, username: &str, password: &str) -> Result<Self> {
+        if !authzid.is_empty() {
+            self.session.set_property(GSASL_AUTHZID, authzid)?;
+        }
+        self.session.set_property(GSASL_AUTHID, username)?;
+        self.session.set_property(GSASL_PASSWORD, password)?;
+        Ok(self)
+    }
+}
+
+impl sasl::Client for GsaslClient {
+    fn mechanism_name(&self) -> &str {
+        &self.mechanism
+    }
+
+    fn start(&mut self) -> Result<(String, Option<Vec<u8>>)> {
+        let (complete, output) = self.session.step(&[])?;
+        self.complete = complete;
+        let ir = (complete || !output.is_empty()).then_some(output);
+        Ok((self.mechanism.clone(), ir))
+    }
+
+    fn next(&mut self, challenge: &[u8]) -> Result<Vec<u8>> {
+        if self.complete {
+            bail!(sasl::ERR_UNEXPECTED_SERVER_CHALLENGE);
+        }
+        let (complete, output) = self.session.step(challenge)?;
+        self.complete = complete;
+        Ok(output)
+    }
+
+    fn finish(&mut self, data: Option<&[u8]>) -> Result<()> {
+        match data.filter(|data| !data.is_empty()) {
+            Some(_) if self.complete => bail!(sasl::ERR_UNEXPECTED_SUCCESS_DATA),
+            Some(data) => {
+                let (complete, output) = self.session.step(data)?;
+                if !complete || !output.is_empty() {
+                    bail!(sasl::ERR_AUTHENTICATION_FAILED);
+                }
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+/// Validates credentials received by a `GsaslServer`, given the
+/// authorization identity, username and password.
+pub type GsaslAuthenticator = Box<dyn Fn(&str, &str, &str) -> Result<()> + Send + Sync>;
+
+/// Looks up the password of a user, for mechanisms which need it to verify
+/// the client (e.g. CRAM-MD5 or SCRAM).
+pub type GsaslPasswordLookup = Box<dyn Fn(&str) -> Option<String> + Send + Sync>;
+
+struct Callbacks {
+    authenticator: GsaslAuthenticator,
+    password_lookup: Option<GsaslPasswordLookup>,
+}
+
+unsafe extern "C" fn server_callback(_ctx: *mut Gsasl, sctx: *mut GsaslSession, prop: c_int) -> c_int {
+    let property = |prop| {
+        // SAFETY: libgsasl returns NUL-terminated strings owned by the
+        // session, or null.
+        let value = unsafe { gsasl_property_fast(sctx, prop) };
+        match value.is_null() {
+            true => String::new(),
+            false => unsafe { CStr::from_ptr(value) }.to_string_lossy().into_owned(),
+        }
+    };
+    // SAFETY: the hook points to the callbacks boxed by the server owning
+    // the session.
+    let callbacks = unsafe { &*(gsasl_session_hook_get(sctx) as *const Callbacks) };
+    match prop {
+        GSASL_VALIDATE_SIMPLE => match (callbacks.authenticator)(&property(GSASL_AUTHZID), &property(GSASL_AUTHID), &property(GSASL_PASSWORD)) {
+            Ok(()) => GSASL_OK,
+            Err(_) => GSASL_AUTHENTICATION_ERROR,
+        },
+        GSASL_PASSWORD => {
+            let password = callbacks.password_lookup.as_ref().and_then(|lookup| lookup(&property(GSASL_AUTHID)));
+            match password.and_then(|password| CString::new(password).ok()) {
+                // SAFETY: libgsasl copies the value.
+                Some(password) => unsafe { gsasl_property_set(sctx, GSASL_PASSWORD, password.as_ptr()) },
+                None => GSASL_NO_CALLBACK,
+            }
+        }
+        _ => GSASL_NO_CALLBACK,
+    }
+}
+
+/// A libgsasl server.
+pub struct GsaslServer {
+    session: Session,
+    mechanism: String,
+    // Referenced by the session hook: boxed so that its address is stable,
+    // and dropped after the session.
+    _callbacks: Box<Callbacks>,
+    done: bool,
+}
+
+impl GsaslServer {
+    pub fn new(mechanism: &str, authenticator: GsaslAuthenticator) -> Result<Self> {
+        Self::with_callbacks(mechanism, Callbacks { authenticator, password_lookup: None })
+    }
+
+    /// Creates a server for mechanisms which need the password of the user.
+    pub fn with_password_lookup(mechanism: &str, authenticator: GsaslAuthenticator, password_lookup: GsaslPasswordLookup) -> Result<Self> {
+        Self::with_callbacks(
+            mechanism,
+            Callbacks {
+                authenticator,
+                password_lookup: Some(password_lookup),
+            },
+        )
+    }
+
+    fn with_callbacks(mechanism: &str, callbacks: Callbacks) -> Result<Self> {
+        let session = Session::new(mechanism, true, Some(server_callback))?;
+        let callbacks = Box::new(callbacks);
+        // SAFETY: the callbacks outlive the session, see `_callbacks`.
+        unsafe { gsasl_session_hook_set(session.sctx, &*callbacks as *const Callbacks as *mut c_void) };
+        Ok(Self {
+            session,
+            mechanism: mechanism.to_string(),
+            _callbacks: callbacks,
+            done: false,
+        })
+    }
+}
+
+impl sasl::Server for GsaslServer {
+    fn mechanism_name(&self) -> &str {
+        &self.mechanism
+    }
+
+    fn next(&mut self, response: Option<&[u8]>) -> Result<sasl::ServerStep> {
+        if self.done {
+            bail!(sasl::ERR_UNEXPECTED_CLIENT_RESPONSE);
+        }
+        match self.session.step(response.unwrap_or_default()) {
+            Ok((false, challenge)) => Ok(sasl::ServerStep::Challenge(challenge)),
+            Ok((true, data)) => {
+                self.done = true;
+                Ok(sasl::ServerStep::Done {
+                    additional_data: (!data.is_empty()).then_some(data),
+                })
+            }
+            Err(err) => {
+                self.done = true;
+                Err(err)
+            }
+        }
+    }
+
+    fn is_done(&self) -> bool {
+        self.done
+    }
+}
+
+#[test]
+fn test_gsasl_plain() -> Result<()> {
+    use crate::sasl::{Client, Server};
+
+    let mut client = GsaslClient::new("PLAIN", "imap", "localhost")?.with_credentials("", "username", "password")?;
+    let mut server = GsaslServer::new(
+        "PLAIN",
+        Box::new(|identity, username, password| {
+            if !identity.is_empty() || username != "username" || password != "password" {
+                bail!("Invalid credentials");
+            }
+            Ok(())
+        }),
+    )?;
+    let (_, ir) = client.start()?;
+    match server.next(ir.as_deref())? {
+        sasl::ServerStep::Done { additional_data } => client.finish(additional_data.as_deref()),
+        step => bail!("Unexpected step: {:?}", step),
+    }
+}
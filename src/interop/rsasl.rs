@@ -0,0 +1,51 @@
+use crate::login::LOGIN;
+use crate::plain::{PlainAuthenticator, PLAIN};
+
+use ::rsasl::callback::{Context, SessionCallback, SessionData};
+use ::rsasl::property::{AuthId, AuthzId, Password};
+use ::rsasl::validate::{Validate, Validation, ValidationError};
+
+/// The validation produced by `PlainCallback`: the result of the
+/// authenticator.
+pub struct PasswordValidation;
+
+impl Validation for PasswordValidation {
+    type Value = anyhow::Result<()>;
+}
+
+/// Validates rsasl PLAIN and LOGIN server sessions with a
+/// `PlainAuthenticator`, so that an authentication backend written for this
+/// crate can be reused by rsasl servers. The outcome is retrieved with the
+/// `PasswordValidation` type.
+pub struct PlainCallback {
+    authenticator: PlainAuthenticator,
+}
+
+impl PlainCallback {
+    pub fn new(authenticator: PlainAuthenticator) -> Self {
+        Self { authenticator }
+    }
+}
+
+impl SessionCallback for PlainCallback {
+    fn validate(
+        &self,
+        session_data: &SessionData,
+        context: &Context,
+        validate: &mut Validate<'_>,
+    ) -> Result<(), ValidationError> {
+        let mechanism = &**session_data.mechanism().mechanism;
+        if mechanism != PLAIN && mechanism != LOGIN {
+            return Ok(());
+        }
+
+        let username = context.get_ref::<AuthId>().ok_or(ValidationError::MissingRequiredProperty)?;
+        let password = context.get_ref::<Password>().ok_or(ValidationError::MissingRequiredProperty)?;
+        let identity = context.get_ref::<AuthzId>().unwrap_or_default();
+        validate.with::<PasswordValidation, _>(|| {
+            let password = std::str::from_utf8(password).map_err(|err| ValidationError::Boxed(Box::new(err)))?;
+            Ok((self.authenticator)(identity, username, password))
+        })?;
+        Ok(())
+    }
+}
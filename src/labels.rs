@@ -0,0 +1,119 @@
+//! Stable label values for metrics, tracing spans, audit events and
+//! statistics. Dashboards and alerting rules depend on them: values may be
+//! added, but never renamed or removed.
+//!
+//! Mechanism labels have a bounded set of values, unknown mechanisms being
+//! reported as `other`, so that peers can't create arbitrarily many time
+//! series.
+
+use crate::audit::AuthOutcome;
+use crate::sasl::FailureReason;
+
+/// The label of mechanisms which aren't in `MECHANISMS`.
+pub const OTHER: &str = "other";
+
+/// The mechanism labels.
+pub const MECHANISMS: &[&str] = &[
+    "ANONYMOUS",
+    "CRAM-MD5",
+    "DIGEST-MD5",
+    "EXTERNAL",
+    "GSSAPI",
+    "GS2-KRB5",
+    "LOGIN",
+    "NTLM",
+    "OAUTHBEARER",
+    "PLAIN",
+    "SCRAM-SHA-1",
+    "SCRAM-SHA-1-PLUS",
+    "SCRAM-SHA-256",
+    "SCRAM-SHA-256-PLUS",
+    "SCRAM-SHA-512",
+    "SCRAM-SHA-512-PLUS",
+    "XOAUTH2",
+];
+
+/// The step labels. Exchanges longer than 9 steps are reported as `10+`.
+pub const STEPS: &[&str] = &["1", "2", "3", "4", "5", "6", "7", "8", "9", "10+"];
+
+/// The outcome labels, which match the serialized form of `AuthOutcome`.
+pub const OUTCOMES: &[&str] = &[
+    "success",
+    "unknown_user",
+    "invalid_credentials",
+    "account_disabled",
+    "authorization_denied",
+    "malformed_response",
+    "other",
+];
+
+/// Returns the label of a mechanism name.
+pub fn mechanism(name: &str) -> &'static str {
+    MECHANISMS
+        .iter()
+        .find(|m| m.eq_ignore_ascii_case(name))
+        .unwrap_or(&OTHER)
+}
+
+/// Returns the label of a step, counted from 1.
+pub fn step(n: usize) -> &'static str {
+    STEPS[n.clamp(1, STEPS.len()) - 1]
+}
+
+/// Returns the label of a failure reason.
+pub fn failure_reason(reason: FailureReason) -> &'static str {
+    match reason {
+        FailureReason::UnknownUser => "unknown_user",
+        FailureReason::InvalidCredentials => "invalid_credentials",
+        FailureReason::AccountDisabled => "account_disabled",
+        FailureReason::AuthorizationDenied => "authorization_denied",
+        FailureReason::MalformedResponse => "malformed_response",
+        FailureReason::Other => "other",
+    }
+}
+
+/// Returns the label of an outcome.
+pub fn outcome(outcome: AuthOutcome) -> &'static str {
+    match outcome {
+        AuthOutcome::Success => "success",
+        AuthOutcome::Failure(reason) => failure_reason(reason),
+    }
+}
+
+// Changing the values below breaks the dashboards of operators.
+#[test]
+fn test_labels_are_stable() -> anyhow::Result<()> {
+    use anyhow::bail;
+
+    if mechanism("scram-sha-256-plus") != "SCRAM-SHA-256-PLUS" || mechanism("X-UNKNOWN") != OTHER {
+        bail!("Unexpected mechanism labels");
+    }
+    if step(0) != "1" || step(3) != "3" || step(10) != "10+" || step(42) != "10+" {
+        bail!("Unexpected step labels");
+    }
+
+    let reasons = [
+        FailureReason::UnknownUser,
+        FailureReason::InvalidCredentials,
+        FailureReason::AccountDisabled,
+        FailureReason::AuthorizationDenied,
+        FailureReason::MalformedResponse,
+        FailureReason::Other,
+    ];
+    let outcomes = std::iter::once(AuthOutcome::Success).chain(reasons.map(AuthOutcome::Failure));
+    for (outcome, expected) in outcomes.zip(OUTCOMES) {
+        if self::outcome(outcome) != *expected {
+            bail!("Unexpected label for {:?}", outcome);
+        }
+        // Audit events must use the same vocabulary.
+        let reason = match outcome {
+            AuthOutcome::Success => "success".to_string(),
+            AuthOutcome::Failure(reason) => serde_json::to_value(reason)?.as_str().unwrap_or_default().to_string(),
+        };
+        if reason != *expected {
+            bail!("Audit events and labels disagree on {:?}", outcome);
+        }
+    }
+
+    Ok(())
+}
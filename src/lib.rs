@@ -1,6 +1,54 @@
+#[cfg(feature = "anonymous")]
 pub mod anonymous;
+#[cfg(feature = "tokio")]
+pub mod async_driver;
+#[cfg(feature = "tokio")]
+pub mod async_sasl;
+pub mod audit;
+pub mod authorization;
+pub mod channel_binding;
+pub mod constant_time;
+pub mod delay;
+pub mod dispatcher;
+pub mod doc_examples;
+pub mod downgrade;
+pub mod driver;
+#[cfg(feature = "external")]
 pub mod external;
+pub mod failure;
+pub mod framing;
+pub mod gs2;
+pub mod identity;
+pub mod interop;
+pub mod labels;
+#[cfg(feature = "oauthbearer")]
 pub mod oauthbearer;
+#[cfg(feature = "login")]
 pub mod login;
+pub mod master;
+pub mod metrics;
+pub mod negotiator;
+pub mod nonce;
+#[cfg(feature = "password")]
+pub mod password;
+#[cfg(feature = "plain")]
 pub mod plain;
+pub mod policy;
+pub mod protocol;
+pub mod ratelimit;
+pub mod registry;
+pub mod replay;
 pub mod sasl;
+#[cfg(feature = "saslprep")]
+pub mod saslprep;
+pub mod security_layer;
+pub mod selftest;
+pub mod store;
+pub mod typestate;
+#[cfg(feature = "api-v2")]
+pub mod v2;
+
+#[cfg(all(test, feature = "plain"))]
+mod testserver;
+#[cfg(test)]
+mod timing;
@@ -1,10 +1,36 @@
+use crate::authorization::{authorize, AuthorizationPolicy};
+use crate::identity::{canonicalize, decode, prepare, IdentityMapper, Utf8Policy, ERR_RAW_CREDENTIALS};
+use crate::master::MasterUsers;
 use crate::sasl;
+use crate::store::CredentialVerifier;
+#[cfg(feature = "tokio")]
+use crate::store::AsyncCredentialVerifier;
+#[cfg(feature = "tokio")]
+use crate::async_sasl::{AsyncServer, BoxFuture};
 
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, bail, Result};
+use std::sync::Arc;
+use zeroize::{Zeroize, Zeroizing};
 
 /// The LOGIN mechanism name.
 pub const LOGIN: &str = "LOGIN";
 
+/// The security properties of the LOGIN mechanism.
+pub const PROPERTIES: sasl::Properties = sasl::Properties {
+    plaintext: true,
+    anonymous: false,
+    channel_binding: false,
+    mutual_auth: false,
+    security_layer: false,
+    ssf: 0,
+};
+
+/// The challenge requesting the username, unless configured otherwise.
+pub const DEFAULT_USERNAME_PROMPT: &[u8] = b"Username:";
+
+/// The challenge requesting the password, unless configured otherwise.
+pub const DEFAULT_PASSWORD_PROMPT: &[u8] = b"Password:";
+
 /// A client implementation of the LOGIN authentication mechanism for SMTP,
 /// as described in http://www.iana.org/go/draft-murchison-sasl-login
 ///
@@ -13,6 +39,8 @@ pub const LOGIN: &str = "LOGIN";
 pub struct LoginClient {
     username: String,
     password: String,
+    saslprep: bool,
+    password_prompt: Vec<u8>,
 }
 
 impl LoginClient {
@@ -20,21 +48,44 @@ impl LoginClient {
         Self {
             username,
             password,
+            saslprep: false,
+            password_prompt: DEFAULT_PASSWORD_PROMPT.to_vec(),
         }
     }
+
+    /// Sets the challenge the server requests the password with. The
+    /// username is sent as the initial response, or in reply to the first
+    /// challenge by adapters of protocols which can't carry one, so its
+    /// prompt isn't needed.
+    pub fn with_password_prompt(mut self, prompt: &[u8]) -> Self {
+        self.password_prompt = prompt.to_vec();
+        self
+    }
+
+    /// Prepares the username and password with SASLprep before sending
+    /// them.
+    #[cfg(feature = "saslprep")]
+    pub fn with_saslprep(mut self) -> Self {
+        self.saslprep = true;
+        self
+    }
 }
 
 impl sasl::Client for LoginClient {
-    fn start(&mut self) -> Result<(String, Vec<u8>)> {
+    fn mechanism_name(&self) -> &str {
+        LOGIN
+    }
+
+    fn start(&mut self) -> Result<(String, Option<Vec<u8>>)> {
         Ok((
             LOGIN.to_string(),
-            self.username.clone().into_bytes(),
+            Some(prepare(self.saslprep, &self.username)?.into_owned().into_bytes()),
         ))
     }
 
     fn next(&mut self, challenge: &[u8]) -> Result<Vec<u8>> {
-        if challenge == b"Password:" {
-            Ok(self.password.clone().into_bytes())
+        if challenge == self.password_prompt {
+            Ok(prepare(self.saslprep, &self.password)?.into_owned().into_bytes())
         } else {
             Err(anyhow!(sasl::ERR_UNEXPECTED_SERVER_CHALLENGE))
         }
@@ -42,12 +93,156 @@ impl sasl::Client for LoginClient {
 }
 
 /// Authenticates users with an username and a password.
-pub type LoginAuthenticator = Box<dyn Fn(&str, &str) -> Result<()> + Send>;
+pub type LoginAuthenticator = Box<dyn Fn(&str, &str) -> Result<()> + Send + Sync>;
+
+/// Authenticates users with the raw username and password sent by the
+/// client, whatever their encoding.
+pub type LoginBytesAuthenticator = Box<dyn Fn(&[u8], &[u8]) -> Result<()> + Send + Sync>;
+
+enum LoginVerifier {
+    Str(LoginAuthenticator),
+    Bytes(LoginBytesAuthenticator),
+}
 
 enum LoginState {
+    /// No response was received yet.
     LoginNotStarted,
+    /// The username was requested.
     LoginWaitingUsername,
+    /// The password was requested.
     LoginWaitingPassword,
+    /// The credentials were received, or the exchange failed.
+    Done,
+}
+
+/// The LOGIN exchange, shared by the synchronous and asynchronous servers.
+struct LoginExchange {
+    state: LoginState,
+    username: String,
+    raw_username: Vec<u8>,
+    password: Vec<u8>,
+    canonicalizer: Option<Arc<IdentityMapper>>,
+    saslprep: bool,
+    utf8_policy: Utf8Policy,
+    /// Whether credentials are verified as sent, without decoding them.
+    raw: bool,
+    username_prompt: Vec<u8>,
+    password_prompt: Vec<u8>,
+    masters: Option<Arc<MasterUsers>>,
+    /// The master users and the target user, once the username named a
+    /// master user, who is then the username.
+    master: Option<(Arc<MasterUsers>, String)>,
+}
+
+impl LoginExchange {
+    fn new() -> Self {
+        Self {
+            state: LoginState::LoginNotStarted,
+            username: String::new(),
+            raw_username: Vec::new(),
+            password: Vec::new(),
+            canonicalizer: None,
+            saslprep: false,
+            utf8_policy: Utf8Policy::Require,
+            raw: false,
+            username_prompt: DEFAULT_USERNAME_PROMPT.to_vec(),
+            password_prompt: DEFAULT_PASSWORD_PROMPT.to_vec(),
+            masters: None,
+            master: None,
+        }
+    }
+
+    /// Processes a response. Returns `None` once the username and password
+    /// are known and must be verified. The exchange ends on errors, until
+    /// it is reset.
+    fn next(&mut self, response: Option<&[u8]>) -> Result<Option<sasl::ServerStep>> {
+        match (std::mem::replace(&mut self.state, LoginState::Done), response) {
+            (LoginState::LoginNotStarted, None) => {
+                self.state = LoginState::LoginWaitingUsername;
+                Ok(Some(sasl::ServerStep::Challenge(self.username_prompt.clone())))
+            }
+            // Clients may send the username as initial response, as per
+            // RFC 4422 section 3.
+            (LoginState::LoginNotStarted | LoginState::LoginWaitingUsername, response) => {
+                self.parse_username(response)?;
+                self.state = LoginState::LoginWaitingPassword;
+                Ok(Some(sasl::ServerStep::Challenge(self.password_prompt.clone())))
+            }
+            (LoginState::LoginWaitingPassword, response) => {
+                self.password = response.unwrap_or(&[]).to_vec();
+                Ok(None)
+            }
+            (LoginState::Done, _) => Err(anyhow!(sasl::ERR_UNEXPECTED_CLIENT_RESPONSE)),
+        }
+    }
+
+    /// Parses a username, in its canonical form if there is a
+    /// canonicalizer. Master users are recognized once it is prepared, and
+    /// the canonical form is then the one of their target user.
+    fn parse_username(&mut self, response: Option<&[u8]>) -> Result<()> {
+        let username = response.unwrap_or(&[]);
+        self.raw_username = username.to_vec();
+        if self.raw && (self.canonicalizer.is_some() || self.saslprep || self.utf8_policy != Utf8Policy::Require) {
+            bail!(ERR_RAW_CREDENTIALS);
+        }
+        let username = match self.raw {
+            true => match std::str::from_utf8(username) {
+                Ok(username) => username.into(),
+                Err(_) => {
+                    self.username = String::from_utf8_lossy(username).into_owned();
+                    return Ok(());
+                }
+            },
+            false => prepare(self.saslprep, &decode(self.utf8_policy, username)?)?.into_owned(),
+        };
+        if let Some(masters) = &self.masters {
+            if let Some((target, master)) = masters.split(&username) {
+                self.master = Some((masters.clone(), canonicalize(self.canonicalizer.as_deref(), target)?));
+                self.username = master.to_string();
+                return Ok(());
+            }
+        }
+        self.username = match self.raw {
+            true => username,
+            false => canonicalize(self.canonicalizer.as_deref(), &username)?,
+        };
+        Ok(())
+    }
+
+    /// Returns the decoded password, wiped from memory when dropped along
+    /// with the copies made to decode and prepare it.
+    fn password(&self) -> Result<Zeroizing<String>> {
+        let password = Zeroizing::new(decode(self.utf8_policy, &self.password)?.into_owned());
+        Ok(Zeroizing::new(prepare(self.saslprep, &password)?.into_owned()))
+    }
+
+    /// Wipes the credentials once verified. The username is kept as the
+    /// authentication identity.
+    fn clear_credentials(&mut self) {
+        self.raw_username.zeroize();
+        self.password.zeroize();
+    }
+
+    fn is_done(&self) -> bool {
+        matches!(self.state, LoginState::Done)
+    }
+
+    /// Returns the username, once received.
+    fn username(&self) -> Option<&str> {
+        match self.state {
+            LoginState::LoginWaitingPassword | LoginState::Done if !self.username.is_empty() => Some(&self.username),
+            _ => None,
+        }
+    }
+
+    /// Starts a new exchange, e.g. for another attempt on the same
+    /// connection.
+    fn reset(&mut self) {
+        self.state = LoginState::LoginNotStarted;
+        self.username.clear();
+        self.master = None;
+        self.clear_credentials();
+    }
 }
 
 /// A server implementation of the LOGIN authentication mechanism, as described
@@ -56,47 +251,332 @@ enum LoginState {
 /// LOGIN is obsolete and should only be enabled for legacy clients that cannot
 /// be updated to use PLAIN.
 pub struct LoginServer {
-    state: LoginState,
-    username: String,
-    password: String,
-    authenticator: LoginAuthenticator,
+    exchange: LoginExchange,
+    verifier: LoginVerifier,
+    policy: Option<Arc<dyn AuthorizationPolicy>>,
 }
 
 impl LoginServer {
-    pub fn new<F>(authenticator: LoginAuthenticator) -> Self {
+    pub fn new(authenticator: LoginAuthenticator) -> Self {
         Self {
-            state: LoginState::LoginNotStarted,
-            username: String::new(),
-            password: String::new(),
-            authenticator,
+            exchange: LoginExchange::new(),
+            verifier: LoginVerifier::Str(authenticator),
+            policy: None,
         }
     }
+
+    /// Creates a server passing the raw username and password to the
+    /// authenticator, e.g. to verify ISO-8859-1 passwords of legacy
+    /// clients. `authentication_id` reports the username with invalid
+    /// UTF-8 replaced. Canonicalization, SASLprep and UTF-8 policies other
+    /// than `Utf8Policy::Require` need decoded credentials: usernames fail
+    /// with `ERR_RAW_CREDENTIALS` if they are set.
+    pub fn from_bytes(authenticator: LoginBytesAuthenticator) -> Self {
+        let mut server = Self {
+            exchange: LoginExchange::new(),
+            verifier: LoginVerifier::Bytes(authenticator),
+            policy: None,
+        };
+        server.exchange.raw = true;
+        server
+    }
+
+    /// Creates a server verifying credentials with a verifier, which may be
+    /// shared with other servers.
+    pub fn from_verifier(verifier: Arc<dyn CredentialVerifier>) -> Self {
+        Self::new(Box::new(move |username, password| verifier.verify("", username, password)))
+    }
+
+    /// Canonicalizes usernames before the authenticator runs, which then
+    /// gets the canonical form, as does `authentication_id`.
+    pub fn with_canonicalizer(mut self, canonicalizer: Arc<IdentityMapper>) -> Self {
+        self.exchange.canonicalizer = Some(canonicalizer);
+        self
+    }
+
+    /// Prepares usernames and passwords with SASLprep before
+    /// canonicalization and the authenticator, which should compare them
+    /// to credentials stored in the same form.
+    #[cfg(feature = "saslprep")]
+    pub fn with_saslprep(mut self) -> Self {
+        self.exchange.saslprep = true;
+        self
+    }
+
+    /// Sets how credentials which aren't valid UTF-8 are decoded, instead
+    /// of rejecting them.
+    pub fn with_utf8_policy(mut self, utf8_policy: Utf8Policy) -> Self {
+        self.exchange.utf8_policy = utf8_policy;
+        self
+    }
+
+    /// Restricts the users master users may act as, in addition to the
+    /// policy of `MasterUsers`. LOGIN has no authorization identity, so
+    /// other users always act as themselves.
+    pub fn with_authorization_policy(mut self, policy: Arc<dyn AuthorizationPolicy>) -> Self {
+        self.policy = Some(policy);
+        self
+    }
+
+    /// Sets the challenges requesting the username and the password, e.g.
+    /// `User Name\0` and `Password\0` for legacy clients, or localized
+    /// prompts.
+    pub fn with_prompts(mut self, username: &[u8], password: &[u8]) -> Self {
+        self.exchange.username_prompt = username.to_vec();
+        self.exchange.password_prompt = password.to_vec();
+        self
+    }
+
+    /// Returns the challenge requesting the username. Protocol adapters
+    /// and dispatchers relay the challenges of the server as they are, so
+    /// only applications which configure both ends need it.
+    pub fn username_prompt(&self) -> &[u8] {
+        &self.exchange.username_prompt
+    }
+
+    /// Returns the challenge requesting the password, e.g. for
+    /// `LoginClient::with_password_prompt`.
+    pub fn password_prompt(&self) -> &[u8] {
+        &self.exchange.password_prompt
+    }
 }
 
 impl sasl::Server for LoginServer {
-    fn next(&mut self, response: Option<&[u8]>) -> Result<(Vec<u8>, bool)> {
-        match self.state {
-            LoginState::LoginNotStarted => {
-                // Check for initial response field, as per RFC4422 section 3
-                if response.is_none() {
-                    return Ok((b"Username:".to_vec(), false));
-                }
-                self.state = LoginState::LoginWaitingUsername;
-                self.username = String::from_utf8(response.unwrap_or(&[]).to_vec())?;
-                self.state = LoginState::LoginWaitingPassword;
-                return Ok((b"Password:".to_vec(), false));
-            }
-            LoginState::LoginWaitingUsername => {
-                self.username = String::from_utf8(response.unwrap_or(&[]).to_vec())?;
-                self.state = LoginState::LoginWaitingPassword;
-                return Ok((b"Password:".to_vec(), false));
+    fn mechanism_name(&self) -> &str {
+        LOGIN
+    }
+
+    fn next(&mut self, response: Option<&[u8]>) -> Result<sasl::ServerStep> {
+        if let Some(step) = self.exchange.next(response)? {
+            return Ok(step);
+        }
+        let result = match (&self.exchange.master, &self.verifier) {
+            (Some((masters, target)), _) => {
+                let username = &self.exchange.username;
+                let policy = self.policy.as_deref();
+                self.exchange
+                    .password()
+                    .and_then(|password| masters.verify(target, username, &password))
+                    .and_then(|_| policy.map_or(Ok(()), |policy| authorize(Some(policy), username, target)))
             }
-            LoginState::LoginWaitingPassword => {
-                self.password = String::from_utf8(response.unwrap_or(&[]).to_vec())?;
-                (self.authenticator)(&self.username, &self.password)?;
-                self.state = LoginState::LoginNotStarted;
-                return Ok((Vec::new(), true));
+            (None, LoginVerifier::Str(authenticator)) => {
+                self.exchange.password().and_then(|password| authenticator(&self.exchange.username, &password))
             }
+            (None, LoginVerifier::Bytes(authenticator)) => authenticator(&self.exchange.raw_username, &self.exchange.password),
+        };
+        self.exchange.clear_credentials();
+        result?;
+        Ok(sasl::ServerStep::Done { additional_data: None })
+    }
+
+    fn is_done(&self) -> bool {
+        self.exchange.is_done()
+    }
+
+    fn reset(&mut self) -> Result<()> {
+        self.exchange.reset();
+        Ok(())
+    }
+
+    fn authentication_id(&self) -> Option<&str> {
+        self.exchange.username()
+    }
+
+    fn authorization_id(&self) -> Option<&str> {
+        self.exchange.username().and(self.exchange.master.as_ref()).map(|(_, target)| target.as_str())
+    }
+
+    fn set_master_users(&mut self, masters: Arc<MasterUsers>) -> bool {
+        self.exchange.masters = Some(masters);
+        true
+    }
+}
+
+/// Authenticates users with an username and a password asynchronously.
+/// The authenticator owns the password, and should wipe it once verified,
+/// e.g. with `zeroize`.
+#[cfg(feature = "tokio")]
+pub type AsyncLoginAuthenticator = Box<dyn Fn(String, String) -> BoxFuture<'static, Result<()>> + Send + Sync>;
+
+/// A server implementation of the LOGIN authentication mechanism with an
+/// asynchronous authenticator. See `LoginServer`.
+#[cfg(feature = "tokio")]
+pub struct AsyncLoginServer {
+    exchange: LoginExchange,
+    authenticator: AsyncLoginAuthenticator,
+}
+
+#[cfg(feature = "tokio")]
+impl AsyncLoginServer {
+    pub fn new(authenticator: AsyncLoginAuthenticator) -> Self {
+        Self {
+            exchange: LoginExchange::new(),
+            authenticator,
         }
     }
+
+    /// Creates a server verifying credentials with a verifier, which may be
+    /// shared with other servers.
+    pub fn from_verifier(verifier: Arc<dyn AsyncCredentialVerifier>) -> Self {
+        Self::new(Box::new(move |username, password| {
+            let verifier = verifier.clone();
+            Box::pin(async move { verifier.verify("", &username, &password).await })
+        }))
+    }
+
+    /// Canonicalizes usernames before the authenticator runs. See
+    /// `LoginServer::with_canonicalizer`.
+    pub fn with_canonicalizer(mut self, canonicalizer: Arc<IdentityMapper>) -> Self {
+        self.exchange.canonicalizer = Some(canonicalizer);
+        self
+    }
+
+    /// Prepares usernames and passwords with SASLprep. See
+    /// `LoginServer::with_saslprep`.
+    #[cfg(feature = "saslprep")]
+    pub fn with_saslprep(mut self) -> Self {
+        self.exchange.saslprep = true;
+        self
+    }
+
+    /// Sets how credentials which aren't valid UTF-8 are decoded. See
+    /// `LoginServer::with_utf8_policy`.
+    pub fn with_utf8_policy(mut self, utf8_policy: Utf8Policy) -> Self {
+        self.exchange.utf8_policy = utf8_policy;
+        self
+    }
+
+    /// Sets the challenges requesting the username and the password, e.g.
+    /// `User Name\0` and `Password\0` for legacy clients, or localized
+    /// prompts.
+    pub fn with_prompts(mut self, username: &[u8], password: &[u8]) -> Self {
+        self.exchange.username_prompt = username.to_vec();
+        self.exchange.password_prompt = password.to_vec();
+        self
+    }
+
+    /// Returns the challenge requesting the username. Protocol adapters
+    /// and dispatchers relay the challenges of the server as they are, so
+    /// only applications which configure both ends need it.
+    pub fn username_prompt(&self) -> &[u8] {
+        &self.exchange.username_prompt
+    }
+
+    /// Returns the challenge requesting the password, e.g. for
+    /// `LoginClient::with_password_prompt`.
+    pub fn password_prompt(&self) -> &[u8] {
+        &self.exchange.password_prompt
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl AsyncServer for AsyncLoginServer {
+    fn mechanism_name(&self) -> &str {
+        LOGIN
+    }
+
+    async fn next(&mut self, response: Option<&[u8]>) -> Result<sasl::ServerStep> {
+        if let Some(step) = self.exchange.next(response)? {
+            return Ok(step);
+        }
+        let password = self.exchange.password();
+        self.exchange.clear_credentials();
+        let mut password = password?;
+        (self.authenticator)(self.exchange.username.clone(), std::mem::take(&mut *password)).await?;
+        Ok(sasl::ServerStep::Done { additional_data: None })
+    }
+
+    fn is_done(&self) -> bool {
+        self.exchange.is_done()
+    }
+
+    fn reset(&mut self) -> Result<()> {
+        self.exchange.reset();
+        Ok(())
+    }
+}
+
+#[test]
+fn test_login_canonicalizer() -> Result<()> {
+    use crate::identity::AliasRule;
+    use crate::sasl::Server;
+    use anyhow::bail;
+
+    let mapper = IdentityMapper::from_iter([AliasRule::Lowercase, AliasRule::AppendDomain { domain: "example.org".to_string() }]);
+    let mut s = LoginServer::new(Box::new(|username, _| match username {
+        "john@example.org" => Ok(()),
+        _ => bail!("Unknown user: {}", username),
+    }))
+    .with_canonicalizer(Arc::new(mapper));
+
+    s.next(Some(b"John"))?;
+    if !s.next(Some(b"password"))?.is_done() || s.authentication_id() != Some("john@example.org") {
+        bail!("Expected the canonical username to be authenticated");
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_login_state_machine() -> Result<()> {
+    use crate::sasl::Server;
+    use anyhow::bail;
+
+    let mut s = LoginServer::new(Box::new(|username, password| match (username, password) {
+        ("username", "password") => Ok(()),
+        _ => bail!("Invalid credentials"),
+    }));
+    // The username can be sent as initial response.
+    if s.next(Some(b"username"))? != sasl::ServerStep::Challenge(b"Password:".to_vec()) {
+        bail!("Expected the password to be requested");
+    }
+    if s.next(Some(b"wrong")).is_ok() || !s.is_done() || !s.exchange.password.is_empty() {
+        bail!("Expected the exchange to fail and forget the password");
+    }
+    if s.next(Some(b"password")).is_ok() {
+        bail!("Expected a response after completion to be rejected");
+    }
+
+    s.reset()?;
+    if s.authentication_id().is_some() || s.next(None)? != sasl::ServerStep::Challenge(b"Username:".to_vec()) {
+        bail!("Expected a reset exchange to request the username");
+    }
+    s.next(Some(b"username"))?;
+    if !s.next(Some(b"password"))?.is_done() || s.authentication_id() != Some("username") {
+        bail!("Expected the second attempt to succeed");
+    }
+    if !s.exchange.password.is_empty() || !s.exchange.raw_username.is_empty() {
+        bail!("Expected the credentials to be cleared");
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_login_prompts() -> Result<()> {
+    use crate::sasl::{Client, Server};
+    use anyhow::bail;
+
+    let mut server = LoginServer::new(Box::new(|_, _| Ok(()))).with_prompts(b"User Name\0", b"Password\0");
+    if server.username_prompt() != b"User Name\0" || server.password_prompt() != b"Password\0" {
+        bail!("Expected the configured prompts");
+    }
+    if server.next(None)? != sasl::ServerStep::Challenge(b"User Name\0".to_vec()) {
+        bail!("Expected the configured username prompt");
+    }
+    let challenge = match server.next(Some(b"username"))? {
+        sasl::ServerStep::Challenge(challenge) => challenge,
+        step => bail!("Expected the password prompt, got {:?}", step),
+    };
+
+    let mut client = LoginClient::new("username".to_string(), "password".to_string());
+    if client.next(&challenge).is_ok() {
+        bail!("Expected an unknown prompt to be rejected");
+    }
+    let mut client = client.with_password_prompt(server.password_prompt());
+    if !server.next(Some(&client.next(&challenge)?))?.is_done() {
+        bail!("Expected the exchange to complete");
+    }
+
+    Ok(())
 }
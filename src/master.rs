@@ -0,0 +1,149 @@
+//! Dovecot-style master users, who log in as another user with their own
+//! password by sending `user*master` as their username, e.g. for migration
+//! tools and support access. Only PLAIN and LOGIN carry such usernames: their
+//! servers recognize master users once usernames were parsed and prepared,
+//! see `sasl::Server::set_master_users`.
+
+use crate::authorization::AuthorizationPolicy;
+use crate::sasl::FailureReason;
+use crate::store::{CredentialStore, CredentialVerifier};
+
+use anyhow::{bail, Result};
+use std::sync::Arc;
+
+/// The default separator between the target user and the master user.
+pub const DEFAULT_SEPARATOR: char = '*';
+
+/// The master users of a server, verified separately from regular users.
+pub struct MasterUsers {
+    verifier: Arc<dyn CredentialVerifier>,
+    users: Arc<dyn CredentialStore>,
+    policy: Option<Arc<dyn AuthorizationPolicy>>,
+    separator: char,
+}
+
+impl MasterUsers {
+    /// Creates master users verified by `verifier`, who may act as any
+    /// user of `users` unless a policy is set, here or on the server.
+    pub fn new(verifier: Arc<dyn CredentialVerifier>, users: Arc<dyn CredentialStore>) -> Self {
+        Self {
+            verifier,
+            users,
+            policy: None,
+            separator: DEFAULT_SEPARATOR,
+        }
+    }
+
+    pub fn with_separator(mut self, separator: char) -> Self {
+        self.separator = separator;
+        self
+    }
+
+    /// Restricts the users each master user may act as.
+    pub fn with_policy(mut self, policy: Arc<dyn AuthorizationPolicy>) -> Self {
+        self.policy = Some(policy);
+        self
+    }
+
+    /// Splits a username into the target user and the master user, if it
+    /// follows the `user*master` pattern.
+    pub fn split<'a>(&self, username: &'a str) -> Option<(&'a str, &'a str)> {
+        username
+            .rsplit_once(self.separator)
+            .filter(|(target, master)| !target.is_empty() && !master.is_empty())
+    }
+
+    /// Verifies the password of a master user, that it may act as the
+    /// target user, and that the target user exists.
+    pub fn verify(&self, target: &str, master: &str, password: &str) -> Result<()> {
+        self.verifier.verify("", master, password)?;
+        if let Some(policy) = &self.policy {
+            policy.authorize(master, target)?;
+        }
+        if self.users.get(target)?.is_none() {
+            bail!(FailureReason::UnknownUser);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(feature = "plain", feature = "login"))]
+#[test]
+fn test_master_users() -> Result<()> {
+    use crate::authorization::ProxyPolicy;
+    use crate::dispatcher::ServerDispatcher;
+    use crate::login::{LoginServer, LOGIN};
+    use crate::plain::{PlainServer, PLAIN};
+    use crate::policy::ConnectionContext;
+    use crate::registry::Registry;
+    use crate::sasl::Server;
+    use crate::store::memory::MemoryStore;
+    use crate::store::StoredCredential;
+
+    let users = Arc::new(MemoryStore::new());
+    users.add("username", StoredCredential::Plaintext("password".to_string()))?;
+    let masters = Arc::new(MemoryStore::new());
+    masters.add("support", StoredCredential::Plaintext("secret".to_string()))?;
+
+    let mut registry = Registry::new();
+    let verifier = users.clone();
+    registry.register_server(PLAIN, move || Box::new(PlainServer::from_verifier(verifier.clone()).strict()));
+    let verifier = users.clone();
+    registry.register_server(LOGIN, move || Box::new(LoginServer::from_verifier(verifier.clone())));
+    let masters = MasterUsers::new(masters, users.clone()).with_policy(Arc::new(ProxyPolicy::new().with_grant("support", "username").with_grant("support", "nobody")));
+    let masters = Arc::new(masters);
+    let dispatcher = ServerDispatcher::new(registry).with_master_users(masters.clone());
+    let conn = ConnectionContext {
+        tls: true,
+        ..Default::default()
+    };
+
+    dispatcher.start(&conn, PLAIN, Some(b"\x00username\x00password"))?;
+    let (exchange, _) = dispatcher.start(&conn, PLAIN, Some(b"\x00username*support\x00secret"))?;
+    if !exchange.is_done() {
+        bail!("Expected the master user to be authenticated");
+    }
+    let cases = [
+        (&b"\x00username*support\x00password"[..], FailureReason::InvalidCredentials),
+        (b"\x00admin*support\x00secret", FailureReason::AuthorizationDenied),
+        (b"\x00nobody*support\x00secret", FailureReason::UnknownUser),
+        // Master users are parsed as strictly as regular users.
+        (b"\x00username*support\x00secret\x00extra", FailureReason::MalformedResponse),
+    ];
+    for (response, reason) in cases {
+        match dispatcher.start(&conn, PLAIN, Some(response)) {
+            Err(err) if FailureReason::of(&err) == reason => {}
+            _ => bail!("Expected PLAIN to fail with {}", reason),
+        }
+    }
+
+    let (mut exchange, _) = dispatcher.start(&conn, LOGIN, Some(b"username*support"))?;
+    if !exchange.next(b"secret")?.is_done() {
+        bail!("Expected the master user to be authenticated with LOGIN");
+    }
+
+    let mut login = LoginServer::from_verifier(users.clone());
+    login.set_master_users(masters.clone());
+    login.next(Some(b"username*support"))?;
+    if !login.next(Some(b"secret"))?.is_done() || login.authentication_id() != Some("support") || login.authorization_id() != Some("username") {
+        bail!("Expected LOGIN to report the master and target users");
+    }
+
+    // The authorization policy of the server applies to master users too.
+    let policy = Arc::new(ProxyPolicy::new());
+    let mut plain = PlainServer::from_verifier(users.clone()).with_authorization_policy(policy.clone());
+    plain.set_master_users(masters.clone());
+    match plain.next(Some(b"\x00username*support\x00secret")) {
+        Err(err) if FailureReason::of(&err) == FailureReason::AuthorizationDenied => {}
+        _ => bail!("Expected the policy of the PLAIN server to deny the master user"),
+    }
+    let mut login = LoginServer::from_verifier(users).with_authorization_policy(policy);
+    login.set_master_users(masters);
+    login.next(Some(b"username*support"))?;
+    match login.next(Some(b"secret")) {
+        Err(err) if FailureReason::of(&err) == FailureReason::AuthorizationDenied => {}
+        _ => bail!("Expected the policy of the LOGIN server to deny the master user"),
+    }
+
+    Ok(())
+}
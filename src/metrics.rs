@@ -0,0 +1,179 @@
+//! Metrics of authentication exchanges, to be exported by applications,
+//! e.g. to Prometheus or StatsD.
+
+use crate::labels;
+use crate::master::MasterUsers;
+use crate::sasl::{self, FailureReason};
+use crate::security_layer::BoxSecurityLayer;
+
+use anyhow::Result;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Receives the metrics of exchanges, labelled by mechanism with
+/// `labels::mechanism`. Sinks are called synchronously during the exchange
+/// and should only update counters and histograms.
+pub trait MetricsSink: Send + Sync {
+    /// Counts an exchange which started.
+    fn attempt(&self, mechanism: &str);
+
+    /// Counts an exchange which succeeded.
+    fn success(&self, mechanism: &str);
+
+    /// Counts an exchange which failed.
+    fn failure(&self, mechanism: &str, reason: FailureReason);
+
+    /// Observes the duration of a finished exchange, from the first
+    /// response to success or failure.
+    fn duration(&self, mechanism: &str, duration: Duration);
+
+    /// Observes the number of steps of a finished exchange, as a
+    /// `labels::step` label.
+    fn steps(&self, _mechanism: &str, _steps: &'static str) {}
+}
+
+/// A server wrapper reporting its exchanges to a metrics sink. Resetting
+/// the server starts a new exchange.
+pub struct MetricsServer<S> {
+    inner: S,
+    sink: Arc<dyn MetricsSink>,
+    started: Option<Instant>,
+    steps: usize,
+}
+
+impl<S: sasl::Server> MetricsServer<S> {
+    pub fn new(inner: S, sink: Arc<dyn MetricsSink>) -> Self {
+        Self {
+            inner,
+            sink,
+            started: None,
+            steps: 0,
+        }
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<S: sasl::Server> sasl::Server for MetricsServer<S> {
+    fn mechanism_name(&self) -> &str {
+        self.inner.mechanism_name()
+    }
+
+    fn next(&mut self, response: Option<&[u8]>) -> Result<sasl::ServerStep> {
+        let mechanism = labels::mechanism(self.inner.mechanism_name());
+        let started = *self.started.get_or_insert_with(|| {
+            self.sink.attempt(mechanism);
+            Instant::now()
+        });
+        self.steps += 1;
+        let step = self.inner.next(response);
+        match &step {
+            Ok(sasl::ServerStep::Challenge(_)) => return step,
+            Ok(sasl::ServerStep::Done { .. }) => self.sink.success(mechanism),
+            Err(err) => self.sink.failure(mechanism, FailureReason::of(err)),
+        }
+        self.sink.duration(mechanism, started.elapsed());
+        self.sink.steps(mechanism, labels::step(self.steps));
+        step
+    }
+
+    fn is_done(&self) -> bool {
+        self.inner.is_done()
+    }
+
+    fn reset(&mut self) -> Result<()> {
+        self.started = None;
+        self.steps = 0;
+        self.inner.reset()
+    }
+
+    fn security_layer(&mut self) -> Option<BoxSecurityLayer> {
+        self.inner.security_layer()
+    }
+
+    fn authentication_id(&self) -> Option<&str> {
+        self.inner.authentication_id()
+    }
+
+    fn authorization_id(&self) -> Option<&str> {
+        self.inner.authorization_id()
+    }
+
+    fn set_master_users(&mut self, masters: Arc<MasterUsers>) -> bool {
+        self.inner.set_master_users(masters)
+    }
+}
+
+#[cfg(feature = "login")]
+#[test]
+fn test_metrics_sink() -> Result<()> {
+    use crate::dispatcher::ServerDispatcher;
+    use crate::login::{LoginServer, LOGIN};
+    use crate::policy::ConnectionContext;
+    use crate::registry::Registry;
+    use anyhow::bail;
+    use std::sync::Mutex;
+
+    #[derive(Default)]
+    struct Sink(Mutex<Vec<String>>);
+
+    impl MetricsSink for Sink {
+        fn attempt(&self, mechanism: &str) {
+            self.0.lock().unwrap().push(format!("attempt {}", mechanism));
+        }
+
+        fn success(&self, mechanism: &str) {
+            self.0.lock().unwrap().push(format!("success {}", mechanism));
+        }
+
+        fn failure(&self, mechanism: &str, reason: FailureReason) {
+            self.0.lock().unwrap().push(format!("failure {} {:?}", mechanism, reason));
+        }
+
+        fn duration(&self, mechanism: &str, _duration: Duration) {
+            self.0.lock().unwrap().push(format!("duration {}", mechanism));
+        }
+
+        fn steps(&self, mechanism: &str, steps: &'static str) {
+            self.0.lock().unwrap().push(format!("steps {} {}", mechanism, steps));
+        }
+    }
+
+    let mut registry = Registry::new();
+    registry.register_server(LOGIN, || {
+        Box::new(LoginServer::new(Box::new(|_, password| match password {
+            "password" => Ok(()),
+            _ => bail!(FailureReason::InvalidCredentials),
+        })))
+    });
+    let sink = Arc::new(Sink::default());
+    let dispatcher = ServerDispatcher::new(registry).with_metrics_sink(sink.clone());
+    let conn = ConnectionContext {
+        tls: true,
+        ..Default::default()
+    };
+
+    let (mut exchange, _) = dispatcher.start(&conn, LOGIN, None)?;
+    exchange.next(b"username")?;
+    exchange.next(b"password")?;
+    let (mut exchange, _) = dispatcher.start(&conn, LOGIN, Some(b"username"))?;
+    let _ = exchange.next(b"wrong");
+
+    let expected = [
+        "attempt LOGIN",
+        "success LOGIN",
+        "duration LOGIN",
+        "steps LOGIN 3",
+        "attempt LOGIN",
+        "failure LOGIN InvalidCredentials",
+        "duration LOGIN",
+        "steps LOGIN 2",
+    ];
+    if *sink.0.lock().unwrap() != expected {
+        bail!("Unexpected metrics: {:?}", sink.0.lock().unwrap());
+    }
+
+    Ok(())
+}
@@ -0,0 +1,293 @@
+#[cfg(feature = "anonymous")]
+use crate::anonymous::{AnonymousClient, ANONYMOUS};
+#[cfg(feature = "external")]
+use crate::external::{ExternalClient, EXTERNAL};
+#[cfg(feature = "login")]
+use crate::login::{LoginClient, LOGIN};
+#[cfg(feature = "oauthbearer")]
+use crate::oauthbearer::{OAuthBearerClient, OAuthBearerOptions, OAUTHBEARER};
+#[cfg(feature = "plain")]
+use crate::plain::{PlainClient, PLAIN};
+use crate::channel_binding::{self, ChannelBinding, ERR_NO_CHANNEL_BINDING};
+use crate::policy::{ConnectionContext, Preference, SecurityPolicy};
+use crate::registry::builtin_properties;
+use crate::sasl;
+
+use anyhow::{anyhow, bail, Result};
+
+pub const ERR_NO_COMMON_MECHANISM: &str = "sasl: no mechanism supported by both the client and the server";
+
+/// Picks the best client mechanism among the ones advertised by a server,
+/// given the credentials available.
+///
+/// Unless a preference is set with `with_preference`, mechanisms are
+/// preferred in the following order:
+///
+///  1. EXTERNAL, with a client certificate
+///  2. OAUTHBEARER, with a token
+///  3. PLAIN, with a password
+///  4. LOGIN, with a password
+///  5. ANONYMOUS, with a trace
+///
+/// Channel-bound (-PLUS) and SCRAM mechanisms will be ranked above
+/// OAUTHBEARER once they are implemented.
+///
+/// Mechanisms forbidden by the security policy, if any, or whose cargo
+/// feature is disabled, are never selected.
+///
+/// The channel binding used by channel-bound mechanisms is picked among the
+/// ones available on the connection, see `channel_binding::select`. It is
+/// only sent if the peer's supported types are known or the policy
+/// requires channel binding.
+#[derive(Clone, Default)]
+pub struct Negotiator {
+    password: Option<(String, String, String)>,
+    #[cfg(feature = "oauthbearer")]
+    token: Option<OAuthBearerOptions>,
+    certificate: Option<String>,
+    trace: Option<String>,
+    allow_login: bool,
+    policy: Option<(SecurityPolicy, ConnectionContext)>,
+    preference: Preference,
+    channel_bindings: Option<(bool, Vec<ChannelBinding>)>,
+    peer_channel_bindings: Option<Vec<String>>,
+}
+
+impl Negotiator {
+    pub fn new() -> Self {
+        Self {
+            allow_login: true,
+            ..Default::default()
+        }
+    }
+
+    /// Enables PLAIN and LOGIN. See `PlainClient`.
+    pub fn with_password(mut self, identity: String, username: String, password: String) -> Self {
+        self.password = Some((identity, username, password));
+        self
+    }
+
+    /// Enables OAUTHBEARER.
+    #[cfg(feature = "oauthbearer")]
+    pub fn with_token(mut self, options: OAuthBearerOptions) -> Self {
+        self.token = Some(options);
+        self
+    }
+
+    /// Enables EXTERNAL, for connections authenticated with a client
+    /// certificate. See `ExternalClient`.
+    pub fn with_client_certificate(mut self, identity: String) -> Self {
+        self.certificate = Some(identity);
+        self
+    }
+
+    /// Enables ANONYMOUS.
+    pub fn with_anonymous(mut self, trace: String) -> Self {
+        self.trace = Some(trace);
+        self
+    }
+
+    /// Disables the obsolete LOGIN mechanism, even if the server offers no
+    /// alternative.
+    pub fn without_login(mut self) -> Self {
+        self.allow_login = false;
+        self
+    }
+
+    /// Enforces a security policy on the connection the exchange happens on.
+    pub fn with_policy(mut self, policy: SecurityPolicy, conn: ConnectionContext) -> Self {
+        self.policy = Some((policy, conn));
+        self
+    }
+
+    /// Sets the channel bindings available on the connection, and whether it
+    /// uses TLS 1.3.
+    pub fn with_channel_bindings(mut self, tls13: bool, available: Vec<ChannelBinding>) -> Self {
+        self.channel_bindings = Some((tls13, available));
+        self
+    }
+
+    /// Sets the channel binding types supported by the server, if it
+    /// advertises them (e.g. XMPP's XEP-0440).
+    pub fn with_peer_channel_bindings(mut self, types: Vec<String>) -> Self {
+        self.peer_channel_bindings = Some(types);
+        self
+    }
+
+    /// Returns the channel binding to use on the connection, or
+    /// `ChannelBinding::None` if channel binding isn't used. Fails if none
+    /// is available while the security policy requires it.
+    pub fn channel_binding(&self) -> Result<ChannelBinding> {
+        let required = self.policy.as_ref().is_some_and(|(policy, _)| policy.require_channel_binding);
+        let binding = match &self.channel_bindings {
+            Some((tls13, available)) => {
+                let peer_types = self
+                    .peer_channel_bindings
+                    .as_ref()
+                    .map(|types| types.iter().map(String::as_str).collect::<Vec<_>>());
+                channel_binding::select(*tls13, available, peer_types.as_deref())
+            }
+            None => ChannelBinding::None,
+        };
+        if binding.is_none() && required {
+            bail!(ERR_NO_CHANNEL_BINDING);
+        }
+        if !required && self.peer_channel_bindings.is_none() {
+            return Ok(ChannelBinding::None);
+        }
+        Ok(binding)
+    }
+
+    /// Overrides the built-in preference order.
+    pub fn with_preference(mut self, preference: Preference) -> Self {
+        self.preference = preference;
+        self
+    }
+
+    /// Lists the mechanisms which can be used with the available credentials,
+    /// most preferred first.
+    pub fn mechanisms(&self) -> Vec<&'static str> {
+        let mut mechanisms: Vec<&'static str> = Vec::new();
+        #[cfg(feature = "external")]
+        if self.certificate.is_some() {
+            mechanisms.push(EXTERNAL);
+        }
+        #[cfg(feature = "oauthbearer")]
+        if self.token.is_some() {
+            mechanisms.push(OAUTHBEARER);
+        }
+        #[cfg(feature = "plain")]
+        if self.password.is_some() {
+            mechanisms.push(PLAIN);
+        }
+        #[cfg(feature = "login")]
+        if self.password.is_some() && self.allow_login {
+            mechanisms.push(LOGIN);
+        }
+        #[cfg(feature = "anonymous")]
+        if self.trace.is_some() {
+            mechanisms.push(ANONYMOUS);
+        }
+        if let Some((policy, conn)) = &self.policy {
+            let mut conn = conn.clone();
+            if self.channel_bindings.is_some() {
+                conn.channel_binding = self.channel_binding().is_ok_and(|binding| !binding.is_none());
+            }
+            mechanisms.retain(|m| policy.allows(m, &builtin_properties(m), &conn));
+        }
+        self.preference.sort(&mut mechanisms);
+        mechanisms
+    }
+
+    /// Returns a client for the most preferred mechanism advertised by the
+    /// server. Mechanism names are compared case-insensitively.
+    pub fn select(&self, advertised: &[&str]) -> Result<sasl::BoxClient> {
+        #[cfg_attr(not(feature = "external-channel-binding"), allow(unused_variables))]
+        let binding = self.channel_binding()?;
+        let mechanism = self
+            .mechanisms()
+            .into_iter()
+            .find(|m| advertised.iter().any(|a| a.eq_ignore_ascii_case(m)));
+
+        let client: Option<sasl::BoxClient> = match (mechanism, &self.password) {
+            #[cfg(feature = "external")]
+            (Some(EXTERNAL), _) => {
+                let client = ExternalClient::new(self.certificate.clone().unwrap_or_default());
+                #[cfg(feature = "external-channel-binding")]
+                let client = client.with_channel_binding(binding);
+                Some(Box::new(client))
+            }
+            #[cfg(feature = "oauthbearer")]
+            (Some(OAUTHBEARER), _) => Some(Box::new(OAuthBearerClient::new(self.token.clone().unwrap_or_default()))),
+            #[cfg(feature = "plain")]
+            (Some(PLAIN), Some((identity, username, password))) => {
+                Some(Box::new(PlainClient::new(identity.clone(), username.clone(), password.clone())))
+            }
+            #[cfg(feature = "login")]
+            (Some(LOGIN), Some((_, username, password))) => Some(Box::new(LoginClient::new(username.clone(), password.clone()))),
+            #[cfg(feature = "anonymous")]
+            (Some(ANONYMOUS), _) => Some(Box::new(AnonymousClient::new(self.trace.clone().unwrap_or_default()))),
+            _ => None,
+        };
+        client.ok_or_else(|| anyhow!(ERR_NO_COMMON_MECHANISM))
+    }
+}
+
+#[cfg(all(feature = "login", feature = "oauthbearer", feature = "plain"))]
+#[test]
+fn test_negotiator() -> Result<()> {
+    let negotiator = Negotiator::new()
+        .with_password(String::new(), "username".to_string(), "password".to_string())
+        .with_token(OAuthBearerOptions {
+            username: "username".to_string(),
+            token: "token".to_string(),
+            ..Default::default()
+        });
+
+    let cases: [(&[&str], Option<&str>); 4] = [
+        (&["LOGIN", "PLAIN", "OAUTHBEARER"], Some(OAUTHBEARER)),
+        (&["login", "plain"], Some(PLAIN)),
+        (&["LOGIN", "EXTERNAL"], Some(LOGIN)),
+        (&["EXTERNAL", "ANONYMOUS"], None),
+    ];
+    for (advertised, expected) in cases {
+        let selected = negotiator.select(advertised).ok();
+        if selected.as_ref().map(|c| c.mechanism_name()) != expected {
+            bail!("Unexpected mechanism for {:?}", advertised);
+        }
+    }
+
+    if negotiator.clone().without_login().select(&["LOGIN"]).is_ok() {
+        bail!("Expected LOGIN to be disabled");
+    }
+
+    let preference = Preference::List(vec![PLAIN.to_string()]);
+    let selected = negotiator.clone().with_preference(preference).select(&["OAUTHBEARER", "PLAIN"])?;
+    if selected.mechanism_name() != PLAIN {
+        bail!("Expected the preference to be respected");
+    }
+
+    let policy = SecurityPolicy {
+        min_ssf: 128,
+        ..Default::default()
+    };
+    let negotiator = negotiator.with_policy(policy, ConnectionContext::default());
+    if negotiator.select(&["PLAIN", "OAUTHBEARER"]).is_ok() {
+        bail!("Expected mechanisms to be rejected on a cleartext connection");
+    }
+
+    Ok(())
+}
+
+#[cfg(feature = "external-channel-binding")]
+#[test]
+fn test_negotiator_channel_binding() -> Result<()> {
+    let policy = SecurityPolicy {
+        require_channel_binding: true,
+        ..Default::default()
+    };
+    let conn = ConnectionContext {
+        tls: true,
+        ..Default::default()
+    };
+    let available = vec![
+        ChannelBinding::TlsUnique(b"unique".to_vec()),
+        ChannelBinding::TlsExporter(b"exporter".to_vec()),
+    ];
+    let negotiator = Negotiator::new()
+        .with_client_certificate(String::new())
+        .with_policy(policy, conn);
+
+    let tls13 = negotiator.clone().with_channel_bindings(true, available.clone());
+    let (_, ir) = tls13.select(&["EXTERNAL"])?.start()?;
+    if tls13.channel_binding()? != available[1] || ir.as_deref() != Some(b"\x00tls-exporter=exporter") {
+        bail!("Expected tls-exporter to be used with TLS 1.3");
+    }
+
+    let tls12 = negotiator.with_channel_bindings(false, available).with_peer_channel_bindings(vec![channel_binding::TLS_SERVER_END_POINT.to_string()]);
+    match tls12.select(&["EXTERNAL"]) {
+        Err(err) if err.to_string() == ERR_NO_CHANNEL_BINDING => {}
+        _ => bail!("Expected no channel binding to be available"),
+    }
+    Ok(())
+}
@@ -0,0 +1,157 @@
+//! Nonces for server-generated challenges, unique across all exchanges in
+//! the process even if the entropy source misbehaves.
+//!
+//! A nonce is made of a unique part and a random part. The unique part
+//! combines an instance ID, the time the generator was created and a
+//! counter, so that concurrent exchanges can never receive the same nonce.
+//! The random part makes nonces unpredictable.
+//!
+//! The instance ID is random by default. Deployments running several
+//! processes which must not share nonces (e.g. behind a load balancer) can
+//! assign each process a distinct ID with `set_instance_id`, or persist one
+//! with `load_instance_id`.
+
+use anyhow::{anyhow, bail, Context, Result};
+use std::fmt::Write;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The number of random bytes in a nonce.
+const RANDOM_LEN: usize = 12;
+
+/// Fills a buffer with random bytes.
+pub type EntropySource = Box<dyn Fn(&mut [u8]) -> Result<()> + Send + Sync>;
+
+/// Generates unique nonces. Most users want the process-wide generator used
+/// by `nonce`.
+pub struct NonceGenerator {
+    instance_id: u64,
+    epoch: u32,
+    counter: AtomicU64,
+    entropy: EntropySource,
+}
+
+impl NonceGenerator {
+    pub fn new(instance_id: u64) -> Self {
+        let epoch = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs() as u32)
+            .unwrap_or_default();
+        Self {
+            instance_id,
+            epoch,
+            counter: AtomicU64::new(0),
+            entropy: Box::new(|buf| getrandom::fill(buf).map_err(|err| anyhow!("sasl: {}", err))),
+        }
+    }
+
+    /// Replaces the entropy source, which defaults to the operating system's
+    /// random number generator.
+    pub fn with_entropy(mut self, entropy: EntropySource) -> Self {
+        self.entropy = entropy;
+        self
+    }
+
+    pub fn instance_id(&self) -> u64 {
+        self.instance_id
+    }
+
+    /// Generates a new nonce, made of lowercase hexadecimal characters.
+    pub fn generate(&self) -> Result<String> {
+        let count = self.counter.fetch_add(1, Ordering::Relaxed);
+        let mut random = [0u8; RANDOM_LEN];
+        (self.entropy)(&mut random)?;
+
+        let mut nonce = String::with_capacity(2 * (8 + 4 + 8 + RANDOM_LEN));
+        let unique = [
+            &self.instance_id.to_be_bytes()[..],
+            &self.epoch.to_be_bytes(),
+            &count.to_be_bytes(),
+            &random,
+        ];
+        for b in unique.concat() {
+            let _ = write!(nonce, "{:02x}", b);
+        }
+        Ok(nonce)
+    }
+}
+
+static GLOBAL: OnceLock<NonceGenerator> = OnceLock::new();
+
+fn global() -> Result<&'static NonceGenerator> {
+    if let Some(generator) = GLOBAL.get() {
+        return Ok(generator);
+    }
+    let mut id = [0u8; 8];
+    getrandom::fill(&mut id).map_err(|err| anyhow!("sasl: {}", err))?;
+    Ok(GLOBAL.get_or_init(|| NonceGenerator::new(u64::from_be_bytes(id))))
+}
+
+/// Generates a nonce with the process-wide generator.
+pub fn nonce() -> Result<String> {
+    global()?.generate()
+}
+
+/// Sets the instance ID of the process-wide generator. It must be called
+/// before the first nonce is generated.
+pub fn set_instance_id(instance_id: u64) -> Result<()> {
+    GLOBAL
+        .set(NonceGenerator::new(instance_id))
+        .map_err(|_| anyhow!("sasl: nonce instance ID already initialized"))?;
+    Ok(())
+}
+
+/// Loads the instance ID of the process-wide generator from a file
+/// containing a hexadecimal ID, creating it with a random ID if it doesn't
+/// exist. Each process must use its own file. It must be called before the
+/// first nonce is generated.
+pub fn load_instance_id(path: &Path) -> Result<u64> {
+    let instance_id = match std::fs::read_to_string(path) {
+        Ok(contents) => u64::from_str_radix(contents.trim(), 16)
+            .with_context(|| format!("sasl: invalid nonce instance ID in {}", path.display()))?,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+            let mut id = [0u8; 8];
+            getrandom::fill(&mut id).map_err(|err| anyhow!("sasl: {}", err))?;
+            let instance_id = u64::from_be_bytes(id);
+            std::fs::write(path, format!("{:016x}\n", instance_id))?;
+            instance_id
+        }
+        Err(err) => bail!(err),
+    };
+    set_instance_id(instance_id)?;
+    Ok(instance_id)
+}
+
+#[test]
+fn test_concurrent_nonces_are_unique() -> Result<()> {
+    use std::collections::HashSet;
+    use std::sync::Arc;
+
+    // A broken entropy source must not lead to collisions.
+    let generator = Arc::new(NonceGenerator::new(42).with_entropy(Box::new(|buf| {
+        buf.fill(0);
+        Ok(())
+    })));
+    let threads = (0..8)
+        .map(|_| {
+            let generator = generator.clone();
+            std::thread::spawn(move || (0..1000).map(|_| generator.generate()).collect::<Result<Vec<_>>>())
+        })
+        .collect::<Vec<_>>();
+
+    let mut nonces = HashSet::new();
+    for thread in threads {
+        for nonce in thread.join().map_err(|_| anyhow!("thread panicked"))?? {
+            if !nonces.insert(nonce) {
+                bail!("Duplicate nonce");
+            }
+        }
+    }
+    if nonces.len() != 8000 || nonce()? == nonce()? {
+        bail!("Duplicate nonce");
+    }
+
+    Ok(())
+}
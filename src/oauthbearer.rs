@@ -1,16 +1,114 @@
+#[cfg(feature = "jwt")]
+pub mod jwt;
+#[cfg(feature = "jwt")]
+pub mod oidc;
+
+use crate::authorization::{authorize, AuthorizationPolicy};
+use crate::identity::{canonicalize, IdentityMapper};
 use crate::sasl;
+#[cfg(feature = "tokio")]
+use crate::async_sasl::{AsyncServer, BoxFuture};
 
 use anyhow::{anyhow, bail, Result};
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 /// The OAUTHBEARER mechanism name.
 pub const OAUTHBEARER: &str = "OAUTHBEARER";
 
-#[derive(Deserialize, Serialize)]
+/// The security properties of the OAUTHBEARER mechanism.
+pub const PROPERTIES: sasl::Properties = sasl::Properties {
+    plaintext: true,
+    anonymous: false,
+    channel_binding: false,
+    mutual_auth: false,
+    security_layer: false,
+    ssf: 0,
+};
+
+/// The status of errors about malformed client responses.
+pub const STATUS_INVALID_REQUEST: &str = "invalid_request";
+/// The status of errors about tokens which are expired, revoked, or
+/// otherwise invalid, as well as tokens meant for another service.
+pub const STATUS_INVALID_TOKEN: &str = "invalid_token";
+/// The status of errors about valid tokens not granting enough access.
+pub const STATUS_INSUFFICIENT_SCOPE: &str = "insufficient_scope";
+
+pub const ERR_STRICT_GS2_HEADER: &str = "sasl: oauthbearer: malformed GS2 header";
+pub const ERR_STRICT_CHANNEL_BINDING: &str = "sasl: oauthbearer: channel binding flag must be 'n'";
+pub const ERR_STRICT_AUTHZID: &str = "sasl: oauthbearer: malformed authorization identity";
+pub const ERR_STRICT_TERMINATOR: &str = "sasl: oauthbearer: response must end with 0x01 0x01";
+pub const ERR_STRICT_KVPAIR: &str = "sasl: oauthbearer: malformed key-value pair";
+pub const ERR_STRICT_KEY: &str = "sasl: oauthbearer: malformed key";
+pub const ERR_STRICT_VALUE: &str = "sasl: oauthbearer: malformed value";
+pub const ERR_STRICT_DUPLICATE_KEY: &str = "sasl: oauthbearer: duplicate key";
+pub const ERR_STRICT_MISSING_AUTH: &str = "sasl: oauthbearer: missing auth";
+pub const ERR_STRICT_AUTH: &str = "sasl: oauthbearer: malformed bearer token";
+pub const ERR_HOST_MISMATCH: &str = "sasl: oauthbearer: token sent for another host";
+pub const ERR_PORT_MISMATCH: &str = "sasl: oauthbearer: token sent for another port";
+pub const ERR_INVALID_TOKEN: &str = "sasl: oauthbearer: token must be a non-empty b64token";
+pub const ERR_INVALID_HOST: &str = "sasl: oauthbearer: malformed host";
+pub const ERR_INVALID_PORT: &str = "sasl: oauthbearer: port must not be 0";
+pub const ERR_INVALID_EXTENSION: &str = "sasl: oauthbearer: malformed extension";
+
+/// The JSON error sent by the server in an OAUTHBEARER error challenge, as
+/// described in RFC 7628 section 3.2.2. Optional fields are omitted from the
+/// serialized form when unset.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[non_exhaustive]
 pub struct OAuthBearerError {
     pub status: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub schemes: String,
-    pub scope: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scope: Option<String>,
+    #[serde(
+        default,
+        rename = "openid-configuration",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub openid_configuration: Option<String>,
+}
+
+impl OAuthBearerError {
+    pub fn new(status: &str) -> Self {
+        Self {
+            status: status.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// Returns an `invalid_request` error, for malformed responses.
+    pub fn invalid_request() -> Self {
+        Self::new(STATUS_INVALID_REQUEST)
+    }
+
+    /// Returns an `invalid_token` error, for rejected tokens.
+    pub fn invalid_token() -> Self {
+        Self::new(STATUS_INVALID_TOKEN)
+    }
+
+    /// Returns an `insufficient_scope` error, for tokens lacking `scope`.
+    pub fn insufficient_scope(scope: &str) -> Self {
+        Self::new(STATUS_INSUFFICIENT_SCOPE).with_scope(scope)
+    }
+
+    pub fn with_schemes(mut self, schemes: &str) -> Self {
+        self.schemes = schemes.to_string();
+        self
+    }
+
+    pub fn with_scope(mut self, scope: &str) -> Self {
+        self.scope = Some(scope.to_string());
+        self
+    }
+
+    pub fn with_openid_configuration(mut self, url: &str) -> Self {
+        self.openid_configuration = Some(url.to_string());
+        self
+    }
 }
 
 impl std::fmt::Display for OAuthBearerError {
@@ -19,84 +117,285 @@ impl std::fmt::Display for OAuthBearerError {
     }
 }
 
-#[derive(Default)]
+impl std::error::Error for OAuthBearerError {}
+
+#[derive(Clone, Default)]
 pub struct OAuthBearerOptions {
     pub username: String,
     pub token: String,
     pub host: String,
     pub port: u16,
+    /// Additional key-value pairs, which RFC 7628 section 3.1 allows
+    /// clients to send and servers to ignore.
+    pub extensions: BTreeMap<String, String>,
+}
+
+impl OAuthBearerOptions {
+    /// Checks that the options can be sent as described in RFC 7628
+    /// section 3.1.
+    pub fn validate(&self) -> Result<()> {
+        self.validate_with(&self.token)
+    }
+
+    fn validate_with(&self, token: &str) -> Result<()> {
+        if parse_bearer(&format!("Bearer {}", token)) != Some(token) {
+            bail!(ERR_INVALID_TOKEN);
+        }
+        if !is_value(&self.host) {
+            bail!(ERR_INVALID_HOST);
+        }
+        for (key, value) in &self.extensions {
+            if !is_key(key) || ["host", "port", "auth"].contains(&key.as_str()) || !is_value(value) {
+                bail!("{}: {}", ERR_INVALID_EXTENSION, key);
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns a builder of options validated upfront, instead of by the
+    /// server.
+    pub fn builder(token: &str) -> OAuthBearerOptionsBuilder {
+        OAuthBearerOptionsBuilder {
+            options: OAuthBearerOptions {
+                token: token.to_string(),
+                ..Default::default()
+            },
+            port: None,
+        }
+    }
+}
+
+/// A builder of `OAuthBearerOptions`, see `OAuthBearerOptions::builder`.
+pub struct OAuthBearerOptionsBuilder {
+    options: OAuthBearerOptions,
+    port: Option<u16>,
+}
+
+impl OAuthBearerOptionsBuilder {
+    pub fn with_username(mut self, username: &str) -> Self {
+        self.options.username = username.to_string();
+        self
+    }
+
+    pub fn with_host(mut self, host: &str) -> Self {
+        self.options.host = host.to_string();
+        self
+    }
+
+    pub fn with_port(mut self, port: u16) -> Self {
+        self.port = Some(port);
+        self
+    }
+
+    /// Adds a key-value pair. Keys are alphabetic, and other than `host`,
+    /// `port` and `auth`.
+    pub fn with_extension(mut self, key: &str, value: &str) -> Self {
+        self.options.extensions.insert(key.to_string(), value.to_string());
+        self
+    }
+
+    /// Validates the options against the grammar of RFC 7628 section 3.1.
+    pub fn build(self) -> Result<OAuthBearerOptions> {
+        let mut options = self.options;
+        if let Some(port) = self.port {
+            if port == 0 {
+                bail!(ERR_INVALID_PORT);
+            }
+            options.port = port;
+        }
+        options.validate()?;
+        Ok(options)
+    }
 }
 
 /// An implementation of the OAUTHBEARER authentication mechanism, as
 /// described in RFC 7628.
+///
+/// Error challenges are acknowledged with the `0x01` response required by
+/// RFC 7628 section 3.2.3, and the error is reported as an
+/// `OAuthBearerError` by `sasl::Client::failure` once the server failed the
+/// exchange. Options are validated before they are sent.
 #[derive(Default)]
-pub struct OAuthBearerClinet {
+pub struct OAuthBearerClient {
     options: OAuthBearerOptions,
+    provider: Option<Box<dyn TokenProvider>>,
+    error: Option<OAuthBearerError>,
 }
 
-impl OAuthBearerClinet {
+impl OAuthBearerClient {
     pub fn new(options: OAuthBearerOptions) -> Self {
         Self {
             options,
+            provider: None,
+            error: None,
         }
     }
+
+    /// Creates a client from options validated by the builder.
+    pub fn from_builder(builder: OAuthBearerOptionsBuilder) -> Result<Self> {
+        Ok(Self::new(builder.build()?))
+    }
+
+    /// Returns the error challenge received during the exchange, if any.
+    pub fn error(&self) -> Option<&OAuthBearerError> {
+        self.error.as_ref()
+    }
+
+    /// Fetches the token from a provider each time the client starts,
+    /// instead of using the token of the options. Tokens rejected with an
+    /// `invalid_token` error are invalidated, so that the client can be
+    /// started again with a new one.
+    pub fn with_token_provider(mut self, provider: Box<dyn TokenProvider>) -> Self {
+        self.provider = Some(provider);
+        self
+    }
 }
 
-impl sasl::Client for OAuthBearerClinet {
-    fn start(&mut self) -> Result<(String, Vec<u8>)> {
-        let mut authzid = String::new();
-        if !self.options.username.is_empty() {
-            authzid = format!("a={}", self.options.username);
-        }
-        let mut str = format!("n,{},", authzid);
+impl sasl::Client for OAuthBearerClient {
+    fn mechanism_name(&self) -> &str {
+        OAUTHBEARER
+    }
 
-        if !self.options.host.is_empty() {
-            str = format!("{str}\x01host={}", self.options.host);
-        }
+    fn start(&mut self) -> Result<(String, Option<Vec<u8>>)> {
+        self.error = None;
+        let provided = match &mut self.provider {
+            Some(provider) => Some(provider.token()?.token),
+            None => None,
+        };
+        let options = &self.options;
+        let token = provided.as_deref().unwrap_or(&options.token);
+        options.validate_with(token)?;
 
-        if self.options.port != 0 {
-            str = format!("{str}\x01port={}", self.options.port);
+        let mut response = Vec::with_capacity(64 + options.username.len() + options.host.len() + token.len());
+        response.extend_from_slice(b"n,");
+        if !options.username.is_empty() {
+            response.extend_from_slice(b"a=");
+            encode_saslname(&options.username, &mut response);
+        }
+        response.push(b',');
+        let mut kvpair = |key: &str, value: &[&str]| {
+            response.push(0x01);
+            response.extend_from_slice(key.as_bytes());
+            response.push(b'=');
+            value.iter().for_each(|value| response.extend_from_slice(value.as_bytes()));
+        };
+        if !options.host.is_empty() {
+            kvpair("host", &[&options.host]);
+        }
+        if options.port != 0 {
+            kvpair("port", &[&options.port.to_string()]);
         }
-        str = format!("{str}\x01auth=Bearer {}\x01\x01", self.options.token);
-        Ok((OAUTHBEARER.to_string(), str.into_bytes()))
+        for (key, value) in &options.extensions {
+            kvpair(key, &[value]);
+        }
+        kvpair("auth", &["Bearer ", token]);
+        response.extend_from_slice(b"\x01\x01");
+        Ok((OAUTHBEARER.to_string(), Some(response)))
     }
 
     fn next(&mut self, challenge: &[u8]) -> Result<Vec<u8>> {
+        if self.error.is_some() {
+            bail!(sasl::ERR_UNEXPECTED_SERVER_CHALLENGE);
+        }
         let auth_bearer_error: OAuthBearerError = serde_json::from_slice(challenge)?;
-        Err(anyhow!(auth_bearer_error.to_string()))
+        if auth_bearer_error.status == STATUS_INVALID_TOKEN {
+            if let Some(provider) = &mut self.provider {
+                provider.invalidate();
+            }
+        }
+        self.error = Some(auth_bearer_error);
+        Ok(vec![0x01])
+    }
+
+    fn cancel(&mut self) -> Option<Vec<u8>> {
+        Some(vec![0x01])
+    }
+
+    fn failure(&mut self) -> Option<anyhow::Error> {
+        self.error.clone().map(anyhow::Error::from)
     }
 }
 
-pub type OAuthBearerAuthenticator = Box<dyn Fn(OAuthBearerOptions) -> Result<(), OAuthBearerError> + Send>;
+/// The former, misspelled name of `OAuthBearerClient`.
+#[deprecated(note = "renamed to OAuthBearerClient")]
+pub type OAuthBearerClinet = OAuthBearerClient;
 
-pub struct OAuthBearerServer {
+/// Checks the options of a client. Its errors are sent to the client as
+/// they are, with an `invalid_token` status if they have none.
+pub type OAuthBearerAuthenticator = Box<dyn Fn(OAuthBearerOptions) -> Result<(), OAuthBearerError> + Send + Sync>;
+
+/// Validates the token of a client and returns the identity it was issued
+/// to, e.g. the `sub` claim of a JWT. Unlike an `OAuthBearerAuthenticator`,
+/// it leaves the authorization identity to the server's policy.
+pub type OAuthBearerValidator = Box<dyn Fn(&OAuthBearerOptions) -> Result<String, OAuthBearerError> + Send + Sync>;
+
+/// Checks client options, returning the identity of the token if known.
+type OAuthBearerVerifier = Box<dyn Fn(&OAuthBearerOptions) -> Result<Option<String>, OAuthBearerError> + Send + Sync>;
+
+/// The outcome of a client response parsed by `OAuthBearerExchange`.
+enum Parsed {
+    /// The step is complete without involving the authenticator.
+    Step(sasl::ServerStep),
+    /// The options sent by the client must be checked by the authenticator.
+    Authenticate(OAuthBearerOptions),
+}
+
+/// The OAUTHBEARER exchange, shared by the synchronous and asynchronous
+/// servers.
+struct OAuthBearerExchange {
     done: bool,
     fail_error: Option<anyhow::Error>,
-    authenticator: OAuthBearerAuthenticator,
+    authzid: Option<String>,
+    canonicalizer: Option<Arc<IdentityMapper>>,
+    strict: bool,
+    error: Option<OAuthBearerError>,
+    host: Option<String>,
+    port: Option<u16>,
 }
 
-impl OAuthBearerServer {
-    pub fn new(authenticator: OAuthBearerAuthenticator) -> Self {
+impl OAuthBearerExchange {
+    fn new() -> Self {
         Self {
             done: false,
             fail_error: None,
-            authenticator,
+            authzid: None,
+            canonicalizer: None,
+            strict: false,
+            error: None,
+            host: None,
+            port: None,
         }
     }
 
-    fn fail(&mut self, descr: &str) -> Result<(Vec<u8>, bool)> {
-        let oauth_bearer_error = OAuthBearerError{
-            status: "invalid_request".to_string(),
-            schemes: "bearer".to_string(),
-            scope: "".to_string(),
+    /// Returns the error sent for malformed responses.
+    fn request_error(&self) -> OAuthBearerError {
+        match &self.error {
+            Some(error) => error.clone(),
+            None => OAuthBearerError::invalid_request().with_schemes("bearer"),
+        }
+    }
+
+    fn fail(&mut self, descr: &str) -> Result<Parsed> {
+        let oauth_bearer_error = self.request_error();
+        self.fail_with(oauth_bearer_error, descr)
+    }
+
+    /// Fails with an `invalid_token` error, for well-formed responses whose
+    /// token can't be used here.
+    fn reject(&mut self, descr: &str) -> Result<Parsed> {
+        let oauth_bearer_error = OAuthBearerError {
+            status: STATUS_INVALID_TOKEN.to_string(),
+            ..self.request_error()
         };
+        self.fail_with(oauth_bearer_error, descr)
+    }
+
+    fn fail_with(&mut self, oauth_bearer_error: OAuthBearerError, descr: &str) -> Result<Parsed> {
         self.fail_error = Some(anyhow!(descr.to_string()));
-        Ok((serde_json::to_vec(&oauth_bearer_error)?, false))
+        Ok(Parsed::Step(sasl::ServerStep::Challenge(serde_json::to_vec(&oauth_bearer_error)?)))
     }
-}
 
-impl sasl::Server for OAuthBearerServer {
-    fn next(&mut self, response: Option<&[u8]>) -> Result<(Vec<u8>, bool)> {
+    fn parse(&mut self, response: Option<&[u8]>) -> Result<Parsed> {
         // Per RFC, we cannot just send an error, we need to return JSON-structured
         // value as a challenge and then after getting dummy response from the
         // client stop the exchange.
@@ -104,9 +403,9 @@ impl sasl::Server for OAuthBearerServer {
             // Server libraries (rs-smtp, rs-imap) will not call next on
             // protocol-specific SASL cancel response ('*'). However, GS2 (and
             // indirectly OAUTHBEARER) defines a protocol-independent way to do so
-            // using 0x01.
+            // using 0x01. Strict servers accept nothing else.
             let response = response.unwrap_or(&[]);
-            if response.len() != 1 && response.get(0) != Some(&0x01) {
+            if response != [0x01] && (self.strict || (response.len() != 1 && response.first() != Some(&0x01))) {
                 bail!("unexpected response");
             }
             return Err(self.fail_error.take().unwrap());
@@ -118,12 +417,19 @@ impl sasl::Server for OAuthBearerServer {
 
         // Generate empty challenge.
         if response.is_none() {
-            return Ok((Vec::new(), false));
+            return Ok(Parsed::Step(sasl::ServerStep::Challenge(Vec::new())));
         }
         let response = response.unwrap();
 
         self.done = true;
 
+        if self.strict {
+            return match parse_strict(response) {
+                Ok(opts) => self.authenticate(opts),
+                Err(descr) => self.fail(&descr),
+            };
+        }
+
         // Cut n,a=username,\x01host=...\x01auth=...
         // into
         //   n
@@ -139,11 +445,15 @@ impl sasl::Server for OAuthBearerServer {
             return self.fail("Invalid response, missing 'n' in gs2-cb-flag");
         }
         let mut opts = OAuthBearerOptions::default();
-        if authzid.len() > 0 {
+        if !authzid.is_empty() {
             if !authzid.starts_with(b"a=") {
                 return self.fail("Invalid response, missing 'a=' in gs2-authzid");
             }
-            opts.username = String::from_utf8(authzid[2..].to_vec())?;
+            let Ok(username) = String::from_utf8(authzid[2..].to_vec()) else {
+                return self.fail("Invalid response, malformed 'a=' in gs2-authzid");
+            };
+            // Clients which don't escape their authzid are tolerated.
+            opts.username = decode_saslname(&username).unwrap_or(username);
         }
 
         // Cut \x01host=...\x01auth=...\x01\x01
@@ -169,37 +479,978 @@ impl sasl::Server for OAuthBearerServer {
 
             match p_parts[0] {
                 b"host" => {
-                    opts.host = String::from_utf8(p_parts[1].to_vec())?;
+                    let Ok(host) = String::from_utf8(p_parts[1].to_vec()) else {
+                        return self.fail("Invalid response, malformed 'host' value");
+                    };
+                    opts.host = host;
                 }
                 b"port" => {
-                    let port = String::from_utf8(p_parts[1].to_vec());
-                    if let Ok(port) = port {
-                        opts.port = port.parse()?;
-                    } else {
-                        return self.fail("Invalid response, malformed 'port' value");
+                    match std::str::from_utf8(p_parts[1]).ok().and_then(|port| port.parse().ok()) {
+                        Some(port) => opts.port = port,
+                        None => return self.fail("Invalid response, malformed 'port' value"),
                     }
                 }
                 b"auth" => {
                     const PREFIX: &str = "bearer ";
-                    let auth = String::from_utf8(p_parts[1].to_vec())?.to_lowercase();
-                    if !auth.starts_with(PREFIX) {
-                        return self.fail("Unsupported token type");
+                    let Ok(auth) = String::from_utf8(p_parts[1].to_vec()) else {
+                        return self.fail("Invalid response, malformed 'auth' value");
+                    };
+                    // The scheme is case-insensitive, the token is not.
+                    match auth.get(..PREFIX.len()) {
+                        Some(scheme) if scheme.eq_ignore_ascii_case(PREFIX) => {}
+                        _ => return self.fail("Unsupported token type"),
                     }
-                    
+
                     opts.token = auth[PREFIX.len()..].to_string();
                 }
-                _ => {
-                    return self.fail(&format!("Invalid response, unknown parameter: {}", String::from_utf8(p_parts[0].to_vec())?));
+                key => match (std::str::from_utf8(key), std::str::from_utf8(p_parts[1])) {
+                    (Ok(key), Ok(value)) if is_key(key) && is_value(value) => {
+                        opts.extensions.insert(key.to_string(), value.to_string());
+                    }
+                    _ => {
+                        return self.fail(&format!("Invalid response, malformed parameter: {}", String::from_utf8_lossy(key)));
+                    }
+                },
+            }
+        }
+
+        self.authenticate(opts)
+    }
+
+    /// Hands parsed options over to the authenticator, with the canonical
+    /// authorization identity, unless the client meant them for another
+    /// service.
+    fn authenticate(&mut self, mut opts: OAuthBearerOptions) -> Result<Parsed> {
+        if self.host.as_ref().is_some_and(|host| !host.eq_ignore_ascii_case(&opts.host)) {
+            return self.reject(&format!("{}: {:?}", ERR_HOST_MISMATCH, opts.host));
+        }
+        if self.port.is_some_and(|port| port != opts.port) {
+            return self.reject(&format!("{}: {}", ERR_PORT_MISMATCH, opts.port));
+        }
+        if !opts.username.is_empty() {
+            opts.username = canonicalize(self.canonicalizer.as_deref(), &opts.username)?;
+            self.authzid = Some(opts.username.clone());
+        }
+        Ok(Parsed::Authenticate(opts))
+    }
+
+    /// Rejects the authorization identity requested by a client whose
+    /// token is valid, reporting `err` once the client acknowledged it.
+    fn deny(&mut self, err: anyhow::Error) -> Result<sasl::ServerStep> {
+        let oauth_bearer_error = OAuthBearerError {
+            status: STATUS_INSUFFICIENT_SCOPE.to_string(),
+            ..self.request_error()
+        };
+        self.fail_error = Some(err);
+        Ok(sasl::ServerStep::Challenge(serde_json::to_vec(&oauth_bearer_error)?))
+    }
+
+    /// Completes the exchange with the outcome of the authenticator.
+    fn complete(&mut self, result: Result<(), OAuthBearerError>) -> Result<sasl::ServerStep> {
+        if let Err(mut err) = result {
+            if err.status.is_empty() {
+                err.status = STATUS_INVALID_TOKEN.to_string();
+            }
+            if let Some(error) = &self.error {
+                if err.schemes.is_empty() {
+                    err.schemes.clone_from(&error.schemes);
                 }
+                err.scope = err.scope.or_else(|| error.scope.clone());
+                err.openid_configuration = err.openid_configuration.or_else(|| error.openid_configuration.clone());
+            }
+            let challenge = serde_json::to_vec(&err)?;
+            self.fail_error = Some(err.into());
+            return Ok(sasl::ServerStep::Challenge(challenge));
+        }
+
+        Ok(sasl::ServerStep::Done { additional_data: None })
+    }
+
+    fn is_done(&self) -> bool {
+        self.done && self.fail_error.is_none()
+    }
+
+    fn reset(&mut self) {
+        self.done = false;
+        self.fail_error = None;
+        self.authzid = None;
+    }
+}
+
+/// Parses a client response following the grammar of RFC 7628 section 3.1
+/// exactly, returning a description of the first violation. Keys other
+/// than `host`, `port` and `auth` are collected as extensions.
+fn parse_strict(response: &[u8]) -> Result<OAuthBearerOptions, String> {
+    let response = std::str::from_utf8(response).map_err(|_| ERR_STRICT_KVPAIR.to_string())?;
+    let mut header = response.splitn(3, ',');
+    let (flag, authzid, pairs) = match (header.next(), header.next(), header.next()) {
+        (Some(flag), Some(authzid), Some(pairs)) => (flag, authzid, pairs),
+        _ => return Err(ERR_STRICT_GS2_HEADER.to_string()),
+    };
+    if flag != "n" {
+        return Err(format!("{}: {:?}", ERR_STRICT_CHANNEL_BINDING, flag));
+    }
+
+    let mut opts = OAuthBearerOptions::default();
+    if !authzid.is_empty() {
+        let saslname = authzid.strip_prefix("a=").filter(|name| !name.is_empty()).ok_or(ERR_STRICT_AUTHZID)?;
+        opts.username = decode_saslname(saslname).ok_or(ERR_STRICT_AUTHZID)?;
+    }
+
+    // The pairs are each terminated by 0x01, and so is the list.
+    let pairs = pairs
+        .strip_prefix('\x01')
+        .and_then(|pairs| pairs.strip_suffix('\x01'))
+        .filter(|pairs| pairs.is_empty() || pairs.ends_with('\x01'))
+        .ok_or(ERR_STRICT_TERMINATOR)?;
+    let mut seen = Vec::new();
+    for pair in pairs.split_terminator('\x01') {
+        let (key, value) = pair.split_once('=').ok_or(ERR_STRICT_KVPAIR)?;
+        if !is_key(key) {
+            return Err(format!("{}: {:?}", ERR_STRICT_KEY, key));
+        }
+        if !is_value(value) {
+            return Err(format!("{}: {}", ERR_STRICT_VALUE, key));
+        }
+        if seen.contains(&key) {
+            return Err(format!("{}: {}", ERR_STRICT_DUPLICATE_KEY, key));
+        }
+        seen.push(key);
+
+        match key {
+            "host" => opts.host = value.to_string(),
+            "port" => opts.port = value.parse().map_err(|_| format!("{}: port", ERR_STRICT_VALUE))?,
+            "auth" => opts.token = parse_bearer(value).ok_or(ERR_STRICT_AUTH)?.to_string(),
+            _ => {
+                opts.extensions.insert(key.to_string(), value.to_string());
             }
         }
+    }
+    if !seen.contains(&"auth") {
+        return Err(ERR_STRICT_MISSING_AUTH.to_string());
+    }
+    Ok(opts)
+}
+
+/// Returns whether a kvpair key is valid, `1*(ALPHA)`.
+fn is_key(key: &str) -> bool {
+    !key.is_empty() && key.bytes().all(|b| b.is_ascii_alphabetic())
+}
+
+/// Returns whether a kvpair value is valid, `*(VCHAR / SP / HTAB / CR / LF)`.
+fn is_value(value: &str) -> bool {
+    value.bytes().all(|b| matches!(b, 0x21..=0x7e | b' ' | b'\t' | b'\r' | b'\n'))
+}
+
+/// Encodes a GS2 saslname, escaping `,` and `=` as `=2C` and `=3D`.
+fn encode_saslname(name: &str, out: &mut Vec<u8>) {
+    for &b in name.as_bytes() {
+        match b {
+            b',' => out.extend_from_slice(b"=2C"),
+            b'=' => out.extend_from_slice(b"=3D"),
+            _ => out.push(b),
+        }
+    }
+}
+
+/// Decodes a GS2 saslname, in which `,` and `=` are escaped as `=2C` and
+/// `=3D`.
+fn decode_saslname(saslname: &str) -> Option<String> {
+    let mut decoded = String::with_capacity(saslname.len());
+    let mut parts = saslname.split('=');
+    decoded.push_str(parts.next()?);
+    for part in parts {
+        match part.get(..2) {
+            Some("2C") => decoded.push(','),
+            Some("3D") => decoded.push('='),
+            _ => return None,
+        }
+        decoded.push_str(&part[2..]);
+    }
+    Some(decoded)
+}
+
+/// Returns the token of an `auth` value, `"Bearer" 1*SP b64token` as per
+/// RFC 6750 section 2.1.
+fn parse_bearer(value: &str) -> Option<&str> {
+    let (scheme, token) = value.split_once(' ')?;
+    let token = token.trim_start_matches(' ');
+    let b64 = token.trim_end_matches('=');
+    let valid = !b64.is_empty() && b64.bytes().all(|b| b.is_ascii_alphanumeric() || b"-._~+/".contains(&b));
+    (scheme.eq_ignore_ascii_case("bearer") && valid).then_some(token)
+}
+
+/// A server implementation of the OAUTHBEARER authentication mechanism, as
+/// described in RFC 7628.
+pub struct OAuthBearerServer {
+    exchange: OAuthBearerExchange,
+    verifier: OAuthBearerVerifier,
+    policy: Option<Arc<dyn AuthorizationPolicy>>,
+    subject: Option<String>,
+}
+
+impl OAuthBearerServer {
+    pub fn new(authenticator: OAuthBearerAuthenticator) -> Self {
+        Self {
+            exchange: OAuthBearerExchange::new(),
+            verifier: Box::new(move |opts| authenticator(opts.clone()).map(|()| None)),
+            policy: None,
+            subject: None,
+        }
+    }
+
+    /// Creates a server validating tokens with a validator. Clients may
+    /// only act as the identity of their token, unless a policy allows
+    /// otherwise, see `with_authorization_policy`.
+    pub fn from_validator(validator: OAuthBearerValidator) -> Self {
+        Self {
+            verifier: Box::new(move |opts| validator(opts).map(Some)),
+            ..Self::new(Box::new(|_| Ok(())))
+        }
+    }
+
+    /// Decides with a policy whether clients may act as an authorization
+    /// identity other than the one of their token. Only servers created
+    /// with `from_validator` know that identity.
+    pub fn with_authorization_policy(mut self, policy: Arc<dyn AuthorizationPolicy>) -> Self {
+        self.policy = Some(policy);
+        self
+    }
+
+    /// Canonicalizes the authorization identities of clients before the
+    /// authenticator runs, which then gets the canonical form, as does
+    /// `authorization_id`.
+    pub fn with_canonicalizer(mut self, canonicalizer: Arc<IdentityMapper>) -> Self {
+        self.exchange.canonicalizer = Some(canonicalizer);
+        self
+    }
 
-        if let Err(err) = (self.authenticator)(opts) {
-            self.fail_error = Some(anyhow!(err.to_string()));
-            return Ok((serde_json::to_vec(&err)?, false));
+    /// Validates client responses strictly against RFC 7628, instead of
+    /// the default lenient parsing, and reports which field is malformed.
+    pub fn strict(mut self) -> Self {
+        self.exchange.strict = true;
+        self
+    }
+
+    /// Sets the error sent for malformed responses, instead of an
+    /// `invalid_request` status with the `bearer` scheme. Its schemes,
+    /// scope and OpenID configuration are also filled into the errors of
+    /// the authenticator which lack them, e.g. so that clients can discover
+    /// the authorization server.
+    pub fn with_error(mut self, error: OAuthBearerError) -> Self {
+        self.exchange.error = Some(error);
+        self
+    }
+
+    /// Rejects clients whose `host` kvpair isn't the host name of the
+    /// service, compared case-insensitively, e.g. clients misdirected to
+    /// this server, before their token reaches the authenticator. Clients
+    /// which send no host are rejected too.
+    pub fn with_expected_host(mut self, host: &str) -> Self {
+        self.exchange.host = Some(host.to_string());
+        self
+    }
+
+    /// Rejects clients whose `port` kvpair isn't the port of the service.
+    /// See `with_expected_host`.
+    pub fn with_expected_port(mut self, port: u16) -> Self {
+        self.exchange.port = Some(port);
+        self
+    }
+}
+
+impl sasl::Server for OAuthBearerServer {
+    fn mechanism_name(&self) -> &str {
+        OAUTHBEARER
+    }
+
+    fn next(&mut self, response: Option<&[u8]>) -> Result<sasl::ServerStep> {
+        match self.exchange.parse(response)? {
+            Parsed::Step(step) => Ok(step),
+            Parsed::Authenticate(opts) => {
+                self.subject = match (self.verifier)(&opts) {
+                    Ok(subject) => subject,
+                    Err(err) => return self.exchange.complete(Err(err)),
+                };
+                if let Some(subject) = &self.subject {
+                    if let Err(err) = authorize(self.policy.as_deref(), subject, &opts.username) {
+                        return self.exchange.deny(err);
+                    }
+                }
+                self.exchange.complete(Ok(()))
+            }
         }
+    }
+
+    fn is_done(&self) -> bool {
+        self.exchange.is_done()
+    }
 
-        Ok((Vec::new(), true))
+    fn reset(&mut self) -> Result<()> {
+        self.exchange.reset();
+        self.subject = None;
+        Ok(())
+    }
+
+    fn authentication_id(&self) -> Option<&str> {
+        self.subject.as_deref()
+    }
+
+    fn authorization_id(&self) -> Option<&str> {
+        self.exchange.authzid.as_deref()
     }
 }
 
+/// Checks OAUTHBEARER options asynchronously. See
+/// `OAuthBearerAuthenticator`.
+#[cfg(feature = "tokio")]
+pub type AsyncOAuthBearerAuthenticator =
+    Box<dyn Fn(OAuthBearerOptions) -> BoxFuture<'static, Result<(), OAuthBearerError>> + Send + Sync>;
+
+/// Validates the token of a client asynchronously. See
+/// `OAuthBearerValidator`.
+#[cfg(feature = "tokio")]
+pub type AsyncOAuthBearerValidator = Box<dyn Fn(OAuthBearerOptions) -> BoxFuture<'static, Result<String, OAuthBearerError>> + Send + Sync>;
+
+/// Checks client options asynchronously, returning the identity of the
+/// token if known.
+#[cfg(feature = "tokio")]
+type AsyncOAuthBearerVerifier = Box<dyn Fn(OAuthBearerOptions) -> BoxFuture<'static, Result<Option<String>, OAuthBearerError>> + Send + Sync>;
+
+/// A server implementation of the OAUTHBEARER authentication mechanism with
+/// an asynchronous authenticator, e.g. performing token introspection. See
+/// `OAuthBearerServer`.
+#[cfg(feature = "tokio")]
+pub struct AsyncOAuthBearerServer {
+    exchange: OAuthBearerExchange,
+    verifier: AsyncOAuthBearerVerifier,
+    policy: Option<Arc<dyn AuthorizationPolicy>>,
+}
+
+#[cfg(feature = "tokio")]
+impl AsyncOAuthBearerServer {
+    pub fn new(authenticator: AsyncOAuthBearerAuthenticator) -> Self {
+        Self {
+            exchange: OAuthBearerExchange::new(),
+            verifier: Box::new(move |opts| {
+                let result = authenticator(opts);
+                Box::pin(async move { result.await.map(|()| None) })
+            }),
+            policy: None,
+        }
+    }
+
+    /// Creates a server validating tokens with a validator. See
+    /// `OAuthBearerServer::from_validator`.
+    pub fn from_validator(validator: AsyncOAuthBearerValidator) -> Self {
+        Self {
+            verifier: Box::new(move |opts| {
+                let result = validator(opts);
+                Box::pin(async move { result.await.map(Some) })
+            }),
+            ..Self::new(Box::new(|_| Box::pin(async { Ok(()) })))
+        }
+    }
+
+    /// Decides with a policy whether clients may act as another identity
+    /// than the one of their token. See
+    /// `OAuthBearerServer::with_authorization_policy`.
+    pub fn with_authorization_policy(mut self, policy: Arc<dyn AuthorizationPolicy>) -> Self {
+        self.policy = Some(policy);
+        self
+    }
+
+    /// Canonicalizes the authorization identities of clients before the
+    /// authenticator runs. See `OAuthBearerServer::with_canonicalizer`.
+    pub fn with_canonicalizer(mut self, canonicalizer: Arc<IdentityMapper>) -> Self {
+        self.exchange.canonicalizer = Some(canonicalizer);
+        self
+    }
+
+    /// Validates client responses strictly. See `OAuthBearerServer::strict`.
+    pub fn strict(mut self) -> Self {
+        self.exchange.strict = true;
+        self
+    }
+
+    /// Sets the error sent for malformed responses. See
+    /// `OAuthBearerServer::with_error`.
+    pub fn with_error(mut self, error: OAuthBearerError) -> Self {
+        self.exchange.error = Some(error);
+        self
+    }
+
+    /// Rejects clients sending another host. See
+    /// `OAuthBearerServer::with_expected_host`.
+    pub fn with_expected_host(mut self, host: &str) -> Self {
+        self.exchange.host = Some(host.to_string());
+        self
+    }
+
+    /// Rejects clients sending another port. See
+    /// `OAuthBearerServer::with_expected_port`.
+    pub fn with_expected_port(mut self, port: u16) -> Self {
+        self.exchange.port = Some(port);
+        self
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl AsyncServer for AsyncOAuthBearerServer {
+    fn mechanism_name(&self) -> &str {
+        OAUTHBEARER
+    }
+
+    async fn next(&mut self, response: Option<&[u8]>) -> Result<sasl::ServerStep> {
+        match self.exchange.parse(response)? {
+            Parsed::Step(step) => Ok(step),
+            Parsed::Authenticate(opts) => {
+                let authzid = opts.username.clone();
+                let subject = match (self.verifier)(opts).await {
+                    Ok(subject) => subject,
+                    Err(err) => return self.exchange.complete(Err(err)),
+                };
+                if let Some(subject) = &subject {
+                    if let Err(err) = authorize(self.policy.as_deref(), subject, &authzid) {
+                        return self.exchange.deny(err);
+                    }
+                }
+                self.exchange.complete(Ok(()))
+            }
+        }
+    }
+
+    fn is_done(&self) -> bool {
+        self.exchange.is_done()
+    }
+
+    fn reset(&mut self) -> Result<()> {
+        self.exchange.reset();
+        Ok(())
+    }
+}
+
+/// The default margin before expiry within which `CachingTokenProvider`
+/// and `OAuthTokenProvider` renew tokens.
+pub const DEFAULT_REFRESH_AHEAD: Duration = Duration::from_secs(60);
+
+/// An OAuth access token, as returned by a token endpoint.
+#[derive(Clone)]
+pub struct OAuthToken {
+    pub token: String,
+    pub expires_at: Option<Instant>,
+}
+
+impl OAuthToken {
+    /// Creates a token which never expires.
+    pub fn new(token: String) -> Self {
+        Self { token, expires_at: None }
+    }
+
+    /// Sets the lifetime of the token, e.g. from the `expires_in` field of
+    /// a token response.
+    pub fn with_expires_in(mut self, expires_in: Duration) -> Self {
+        self.expires_at = Some(Instant::now() + expires_in);
+        self
+    }
+
+    /// Reports whether the token expires within a margin, or has expired
+    /// already with a zero margin.
+    pub fn expires_within(&self, margin: Duration) -> bool {
+        self.expires_at.is_some_and(|expires_at| Instant::now() + margin >= expires_at)
+    }
+}
+
+/// Supplies the access tokens of an `OAuthBearerClient`, fetched lazily
+/// when it starts so that long-lived clients can renew them.
+pub trait TokenProvider: Send + Sync {
+    /// Returns a valid token.
+    fn token(&mut self) -> Result<OAuthToken>;
+
+    /// Discards the current token, after the server rejected it.
+    fn invalidate(&mut self) {}
+}
+
+impl<F: FnMut() -> Result<OAuthToken> + Send + Sync> TokenProvider for F {
+    fn token(&mut self) -> Result<OAuthToken> {
+        self()
+    }
+}
+
+/// A `TokenProvider` caching a token until shortly before it expires. See
+/// `OAuthTokenProvider` to share tokens between async clients.
+pub struct CachingTokenProvider {
+    refresher: Box<dyn FnMut() -> Result<OAuthToken> + Send + Sync>,
+    refresh_ahead: Duration,
+    cached: Option<OAuthToken>,
+}
+
+impl CachingTokenProvider {
+    pub fn new(refresher: Box<dyn FnMut() -> Result<OAuthToken> + Send + Sync>) -> Self {
+        Self {
+            refresher,
+            refresh_ahead: DEFAULT_REFRESH_AHEAD,
+            cached: None,
+        }
+    }
+
+    /// Sets how long before expiry tokens are renewed.
+    pub fn with_refresh_ahead(mut self, refresh_ahead: Duration) -> Self {
+        self.refresh_ahead = refresh_ahead;
+        self
+    }
+}
+
+impl TokenProvider for CachingTokenProvider {
+    fn token(&mut self) -> Result<OAuthToken> {
+        match self.cached.as_ref().filter(|token| !token.expires_within(self.refresh_ahead)) {
+            Some(token) => Ok(token.clone()),
+            None => Ok(self.cached.insert((self.refresher)()?).clone()),
+        }
+    }
+
+    fn invalidate(&mut self) {
+        self.cached = None;
+    }
+}
+
+/// Fetches a new access token, e.g. with a refresh token grant.
+#[cfg(feature = "tokio")]
+pub type OAuthTokenRefresher = Box<dyn Fn() -> BoxFuture<'static, Result<OAuthToken>> + Send + Sync>;
+
+/// Caches an access token shared by many connections, renewing it ahead of
+/// its expiry. Concurrent requests for a token while it is being renewed
+/// wait for a single refresh instead of each calling the refresher. If
+/// renewing fails while the cached token hasn't expired yet, the cached
+/// token is used and renewal is attempted again on the next request.
+#[cfg(feature = "tokio")]
+pub struct OAuthTokenProvider {
+    refresher: OAuthTokenRefresher,
+    refresh_ahead: Duration,
+    cached: tokio::sync::Mutex<Option<OAuthToken>>,
+}
+
+#[cfg(feature = "tokio")]
+impl OAuthTokenProvider {
+    pub fn new(refresher: OAuthTokenRefresher) -> Self {
+        Self {
+            refresher,
+            refresh_ahead: DEFAULT_REFRESH_AHEAD,
+            cached: tokio::sync::Mutex::new(None),
+        }
+    }
+
+    /// Sets how long before expiry tokens are renewed.
+    pub fn with_refresh_ahead(mut self, refresh_ahead: Duration) -> Self {
+        self.refresh_ahead = refresh_ahead;
+        self
+    }
+
+    /// Returns a valid token, renewing it if needed.
+    pub async fn token(&self) -> Result<OAuthToken> {
+        let mut cached = self.cached.lock().await;
+        if let Some(token) = cached.as_ref().filter(|token| !token.expires_within(self.refresh_ahead)) {
+            return Ok(token.clone());
+        }
+        match (self.refresher)().await {
+            Ok(token) => Ok(cached.insert(token).clone()),
+            Err(err) => match cached.as_ref().filter(|token| !token.expires_within(Duration::ZERO)) {
+                Some(token) => Ok(token.clone()),
+                None => Err(err),
+            },
+        }
+    }
+
+    /// Discards the cached token, e.g. after the server rejected it with
+    /// an `invalid_token` error.
+    pub async fn invalidate(&self) {
+        *self.cached.lock().await = None;
+    }
+
+    /// Fills in the token of client options.
+    pub async fn options(&self, options: OAuthBearerOptions) -> Result<OAuthBearerOptions> {
+        let token = self.token().await?;
+        Ok(OAuthBearerOptions {
+            token: token.token,
+            ..options
+        })
+    }
+}
+
+#[test]
+fn test_strict_parsing() -> Result<()> {
+    use crate::sasl::Server;
+    use std::sync::Mutex;
+
+    let received = Arc::new(Mutex::new(OAuthBearerOptions::default()));
+    let options = received.clone();
+    let mut s = OAuthBearerServer::new(Box::new(move |opts| {
+        *options.lock().unwrap() = opts;
+        Ok(())
+    }))
+    .strict();
+
+    if !s.next(Some(b"n,a=user=2Cname,\x01host=server.example.com\x01port=143\x01auth=Bearer vF9dft4qmT==\x01\x01"))?.is_done() {
+        bail!("Expected a valid response to be accepted");
+    }
+    let opts = received.lock().unwrap().clone();
+    if opts.username != "user,name" || opts.host != "server.example.com" || opts.port != 143 || opts.token != "vF9dft4qmT==" {
+        bail!("Unexpected options: {} {} {} {}", opts.username, opts.host, opts.port, opts.token);
+    }
+
+    let cases = [
+        (&b"y,,\x01auth=Bearer token\x01\x01"[..], ERR_STRICT_CHANNEL_BINDING),
+        (b"p=tls-unique,,\x01auth=Bearer token\x01\x01", ERR_STRICT_CHANNEL_BINDING),
+        (b"n,a=user=2name,\x01auth=Bearer token\x01\x01", ERR_STRICT_AUTHZID),
+        (b"n,,\x01auth=Bearer token\x01", ERR_STRICT_TERMINATOR),
+        (b"n,,\x01auth=Bearer token", ERR_STRICT_TERMINATOR),
+        (b"n,,\x01auth-1=x\x01auth=Bearer token\x01\x01", ERR_STRICT_KEY),
+        (b"n,,\x01auth=Bearer token\x01auth=Bearer other\x01\x01", ERR_STRICT_DUPLICATE_KEY),
+        (b"n,,\x01host=a\x00b\x01auth=Bearer token\x01\x01", ERR_STRICT_VALUE),
+        (b"n,,\x01auth=Basic dXNlcg==\x01\x01", ERR_STRICT_AUTH),
+        (b"n,,\x01host=server\x01\x01", ERR_STRICT_MISSING_AUTH),
+    ];
+    for (response, expected) in cases {
+        s.reset()?;
+        if !matches!(s.next(Some(response))?, sasl::ServerStep::Challenge(_)) {
+            bail!("Expected an error challenge for {:?}", response);
+        }
+        match s.next(Some(b"\x01")) {
+            Err(err) if err.to_string().starts_with(expected) => {}
+            result => bail!("Expected {:?} for {:?}, got {:?}", expected, response, result.err()),
+        }
+    }
+
+    // Error challenges must be acknowledged with exactly 0x01.
+    for acknowledgement in [&b"x"[..], b"\x01junk", b""] {
+        s.reset()?;
+        s.next(Some(b"n,,\x01auth=Bearer token\x01"))?;
+        match s.next(Some(acknowledgement)) {
+            Err(err) if err.to_string() == "unexpected response" => {}
+            result => bail!("Expected {:?} to be rejected, got {:?}", acknowledgement, result.err()),
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_expected_service() -> Result<()> {
+    use crate::sasl::Server;
+
+    let mut s = OAuthBearerServer::new(Box::new(|_| Ok(())))
+        .with_expected_host("imap.example.com")
+        .with_expected_port(993);
+    if !s.next(Some(b"n,,\x01host=IMAP.example.com\x01port=993\x01auth=Bearer token\x01\x01"))?.is_done() {
+        bail!("Expected the token to be accepted");
+    }
+
+    let cases = [
+        (&b"n,,\x01host=smtp.example.com\x01port=993\x01auth=Bearer token\x01\x01"[..], ERR_HOST_MISMATCH),
+        (b"n,,\x01port=993\x01auth=Bearer token\x01\x01", ERR_HOST_MISMATCH),
+        (b"n,,\x01host=imap.example.com\x01port=143\x01auth=Bearer token\x01\x01", ERR_PORT_MISMATCH),
+    ];
+    for (response, expected) in cases {
+        s.reset()?;
+        if s.next(Some(response))?.is_done() {
+            bail!("Expected an error challenge for {:?}", response);
+        }
+        match s.next(Some(b"\x01")) {
+            Err(err) if err.to_string().starts_with(expected) => {}
+            result => bail!("Expected {:?} for {:?}, got {:?}", expected, response, result.err()),
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_failure_statuses() -> Result<()> {
+    use crate::sasl::Server;
+
+    let mut s = OAuthBearerServer::new(Box::new(|opts| match opts.token.as_str() {
+        "scoped" => Err(OAuthBearerError::insufficient_scope("mail")),
+        _ => Err(OAuthBearerError::default()),
+    }))
+    .with_expected_host("imap.example.com");
+    let cases = [
+        (&b"x,,"[..], r#"{"status":"invalid_request","schemes":"bearer"}"#),
+        (b"n,,\x01host=imap.example.com\x01port=\xff\x01auth=Bearer token\x01\x01", r#"{"status":"invalid_request","schemes":"bearer"}"#),
+        (b"n,,\x01host=imap.example.com\x01auth=Bearer token\x01\x01", r#"{"status":"invalid_token"}"#),
+        (b"n,,\x01host=imap.example.com\x01auth=Bearer scoped\x01\x01", r#"{"status":"insufficient_scope","scope":"mail"}"#),
+        (b"n,,\x01host=smtp.example.com\x01auth=Bearer token\x01\x01", r#"{"status":"invalid_token","schemes":"bearer"}"#),
+    ];
+    for (response, json) in cases {
+        s.reset()?;
+        if s.next(Some(response))? != sasl::ServerStep::Challenge(json.as_bytes().to_vec()) {
+            bail!("Expected the error challenge {} for {:?}", json, response);
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_options_builder() -> Result<()> {
+    use crate::sasl::{Client, Server};
+    use std::sync::Mutex;
+
+    let invalid = [
+        (OAuthBearerOptions::builder(""), ERR_INVALID_TOKEN),
+        (OAuthBearerOptions::builder("a b"), ERR_INVALID_TOKEN),
+        (OAuthBearerOptions::builder("token").with_host("a\x01b"), ERR_INVALID_HOST),
+        (OAuthBearerOptions::builder("token").with_port(0), ERR_INVALID_PORT),
+        (OAuthBearerOptions::builder("token").with_extension("auth", "Bearer other"), ERR_INVALID_EXTENSION),
+        (OAuthBearerOptions::builder("token").with_extension("trace-id", "1"), ERR_INVALID_EXTENSION),
+    ];
+    for (builder, expected) in invalid {
+        match builder.build() {
+            Err(err) if err.to_string().starts_with(expected) => {}
+            result => bail!("Expected {}, got {:?}", expected, result.err()),
+        }
+    }
+
+    let options = OAuthBearerOptions::builder("vF9dft4qmT==")
+        .with_host("imap.example.com")
+        .with_port(993)
+        .with_extension("traceid", "4bf92f35")
+        .build()?;
+    for strict in [false, true] {
+        let received = Arc::new(Mutex::new(OAuthBearerOptions::default()));
+        let sink = received.clone();
+        let mut s = OAuthBearerServer::new(Box::new(move |opts| {
+            *sink.lock().unwrap() = opts;
+            Ok(())
+        }));
+        if strict {
+            s = s.strict();
+        }
+        let (_, response) = OAuthBearerClient::new(options.clone()).start()?;
+        if !s.next(response.as_deref())?.is_done() {
+            bail!("Expected the options to be accepted");
+        }
+        let extensions = received.lock().unwrap().extensions.clone();
+        if extensions.get("traceid").map(String::as_str) != Some("4bf92f35") {
+            bail!("Unexpected extensions: {:?}", extensions);
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_client_validation() -> Result<()> {
+    use crate::sasl::{Client, Server};
+    use std::sync::Mutex;
+
+    let mut c = OAuthBearerClient::from_builder(
+        OAuthBearerOptions::builder("vF9dft4qmT").with_username("user,name").with_host("server.example.com").with_port(143),
+    )?;
+    let (_, response) = c.start()?;
+    if response.as_deref() != Some(&b"n,a=user=2Cname,\x01host=server.example.com\x01port=143\x01auth=Bearer vF9dft4qmT\x01\x01"[..]) {
+        bail!("Unexpected response: {:?}", response);
+    }
+    for strict in [false, true] {
+        let received = Arc::new(Mutex::new(String::new()));
+        let sink = received.clone();
+        let mut s = OAuthBearerServer::new(Box::new(move |opts| {
+            *sink.lock().unwrap() = opts.username;
+            Ok(())
+        }));
+        if strict {
+            s = s.strict();
+        }
+        s.next(response.as_deref())?;
+        if *received.lock().unwrap() != "user,name" {
+            bail!("Unexpected username: {}", received.lock().unwrap());
+        }
+    }
+
+    #[allow(deprecated)]
+    let mut c = OAuthBearerClinet::new(OAuthBearerOptions {
+        token: "two words".to_string(),
+        ..Default::default()
+    });
+    match c.start() {
+        Err(err) if err.to_string() == ERR_INVALID_TOKEN => {}
+        result => bail!("Expected the token to be rejected, got {:?}", result),
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_token_provider() -> Result<()> {
+    use crate::sasl::Client;
+
+    let mut refreshes = 0;
+    let provider = CachingTokenProvider::new(Box::new(move || {
+        refreshes += 1;
+        Ok(OAuthToken::new(format!("token{}", refreshes)))
+    }));
+    let mut c = OAuthBearerClient::default().with_token_provider(Box::new(provider));
+
+    for expected in [&b"n,,\x01auth=Bearer token1\x01\x01"[..], b"n,,\x01auth=Bearer token1\x01\x01"] {
+        if c.start()?.1.as_deref() != Some(expected) {
+            bail!("Expected the cached token");
+        }
+    }
+    if c.next(br#"{"status":"invalid_token"}"#)? != [0x01] {
+        bail!("Expected the error challenge to be acknowledged");
+    }
+    match c.failure() {
+        Some(err) if err.downcast_ref::<OAuthBearerError>().is_some_and(|err| err.status == "invalid_token") => {}
+        _ => bail!("Expected the error challenge to be reported"),
+    }
+    if c.start()?.1.as_deref() != Some(&b"n,,\x01auth=Bearer token2\x01\x01"[..]) {
+        bail!("Expected a new token after invalid_token");
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_oauth_bearer_error_json() -> Result<()> {
+    use crate::sasl::Server;
+
+    let err = OAuthBearerError::new("invalid_token").with_schemes("bearer");
+    let json = serde_json::to_string(&err)?;
+    if json != r#"{"status":"invalid_token","schemes":"bearer"}"# {
+        bail!("Invalid error JSON: {}", json);
+    }
+
+    let err: OAuthBearerError = serde_json::from_str(
+        r#"{"status":"invalid_token","scope":"mail","openid-configuration":"https://example.com/.well-known/openid-configuration"}"#,
+    )?;
+    if err.scope.as_deref() != Some("mail") || err.openid_configuration.is_none() || !err.schemes.is_empty() {
+        bail!("Invalid parsed error: {:?}", err);
+    }
+
+    let discovery = "https://example.com/.well-known/openid-configuration";
+    let mut s = OAuthBearerServer::new(Box::new(|_| Err(OAuthBearerError::new("invalid_token"))))
+        .with_error(OAuthBearerError::new("invalid_request").with_scope("mail").with_openid_configuration(discovery));
+    let expected = [
+        (&b"n,,\x01auth=Bearer token\x01\x01"[..], r#"{"status":"invalid_token","scope":"mail","openid-configuration":"https://example.com/.well-known/openid-configuration"}"#),
+        (b"x,,", r#"{"status":"invalid_request","scope":"mail","openid-configuration":"https://example.com/.well-known/openid-configuration"}"#),
+    ];
+    for (response, json) in expected {
+        s.reset()?;
+        if s.next(Some(response))? != sasl::ServerStep::Challenge(json.as_bytes().to_vec()) {
+            bail!("Expected the error challenge {}", json);
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(feature = "tokio")]
+#[test]
+fn test_async_oauth_bearer_server() -> Result<()> {
+    let mut s = AsyncOAuthBearerServer::new(Box::new(|opts| {
+        Box::pin(async move {
+            tokio::task::yield_now().await;
+            if opts.token != "valid" {
+                return Err(OAuthBearerError::new("invalid_token"));
+            }
+            Ok(())
+        })
+    }));
+
+    let runtime = tokio::runtime::Builder::new_current_thread().build()?;
+    runtime.block_on(async {
+        let step = s.next(Some(b"n,,\x01auth=Bearer expired\x01\x01")).await?;
+        if step != sasl::ServerStep::Challenge(br#"{"status":"invalid_token"}"#.to_vec()) {
+            bail!("Expected an error challenge, got {:?}", step);
+        }
+        if s.next(Some(b"\x01")).await.is_ok() {
+            bail!("Expected authentication to fail");
+        }
+
+        s.reset()?;
+        if !s.next(Some(b"n,,\x01auth=Bearer valid\x01\x01")).await?.is_done() {
+            bail!("Expected authentication to succeed");
+        }
+
+        let policy = crate::authorization::ProxyPolicy::new().with_grant("support", "username");
+        let mut s = AsyncOAuthBearerServer::from_validator(Box::new(|opts| {
+            Box::pin(async move {
+                match opts.token.as_str() {
+                    "valid" => Ok("support".to_string()),
+                    _ => Err(OAuthBearerError::new("invalid_token")),
+                }
+            })
+        }))
+        .with_authorization_policy(Arc::new(policy));
+        if !s.next(Some(b"n,a=username,\x01auth=Bearer valid\x01\x01")).await?.is_done() {
+            bail!("Expected support to act as username");
+        }
+        s.reset()?;
+        if s.next(Some(b"n,a=admin,\x01auth=Bearer valid\x01\x01")).await?.is_done() {
+            bail!("Expected an error challenge");
+        }
+        match s.next(Some(b"\x01")).await {
+            Err(err) if sasl::FailureReason::of(&err) == sasl::FailureReason::AuthorizationDenied => {}
+            _ => bail!("Expected support not to act as admin"),
+        }
+        Ok(())
+    })
+}
+
+#[cfg(feature = "tokio")]
+#[test]
+fn test_oauth_token_provider() -> Result<()> {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    // Tokens are valid for 30s, and the third refresh fails.
+    fn refresher(refreshes: Arc<AtomicUsize>) -> OAuthTokenRefresher {
+        Box::new(move || {
+            let n = refreshes.fetch_add(1, Ordering::SeqCst) + 1;
+            Box::pin(async move {
+                tokio::task::yield_now().await;
+                if n == 3 {
+                    bail!("token endpoint unavailable");
+                }
+                Ok(OAuthToken::new(format!("token{}", n)).with_expires_in(Duration::from_secs(30)))
+            })
+        })
+    }
+
+    let runtime = tokio::runtime::Builder::new_current_thread().build()?;
+    runtime.block_on(async {
+        let refreshes = Arc::new(AtomicUsize::new(0));
+        let provider = Arc::new(OAuthTokenProvider::new(refresher(refreshes.clone())).with_refresh_ahead(Duration::from_secs(10)));
+        let tasks: Vec<_> = (0..8)
+            .map(|_| {
+                let provider = provider.clone();
+                tokio::spawn(async move { provider.token().await })
+            })
+            .collect();
+        for task in tasks {
+            if task.await??.token != "token1" {
+                bail!("Expected the first token");
+            }
+        }
+        if refreshes.load(Ordering::SeqCst) != 1 {
+            bail!("Expected a single refresh");
+        }
+
+        // With a margin longer than the token lifetime, every request
+        // renews the token, falling back to the cached one on errors.
+        let provider = OAuthTokenProvider::new(refresher(Arc::new(AtomicUsize::new(0)))).with_refresh_ahead(Duration::from_secs(40));
+        let opts = OAuthBearerOptions {
+            username: "username".to_string(),
+            ..Default::default()
+        };
+        let mut tokens = Vec::new();
+        for _ in 0..3 {
+            tokens.push(provider.options(opts.clone()).await?.token);
+        }
+        provider.invalidate().await;
+        tokens.push(provider.token().await?.token);
+        if tokens != ["token1", "token2", "token2", "token4"] {
+            bail!("Unexpected tokens: {:?}", tokens);
+        }
+        Ok(())
+    })
+}
@@ -0,0 +1,314 @@
+//! Validation of OAUTHBEARER tokens as JWTs signed by an authorization
+//! server, whose keys are published as a JWK set.
+
+use super::{
+    OAuthBearerAuthenticator, OAuthBearerError, OAuthBearerOptions, OAuthBearerValidator, STATUS_INSUFFICIENT_SCOPE,
+    STATUS_INVALID_TOKEN,
+};
+
+use anyhow::Result;
+use jsonwebtoken::jwk::JwkSet;
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+/// The default leeway for clock skew when checking `exp` and `nbf`.
+pub const DEFAULT_LEEWAY: Duration = Duration::from_secs(60);
+
+/// The minimum interval between refreshes of the keys caused by tokens
+/// signed with an unknown key, so that such tokens can't flood the
+/// authorization server.
+pub const MIN_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Fetches the current keys of the authorization server.
+pub type JwksFetcher = Box<dyn Fn() -> Result<JwkSet> + Send + Sync>;
+
+/// The claims of a validated token.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[non_exhaustive]
+pub struct Claims {
+    pub sub: String,
+    pub iss: String,
+    pub exp: u64,
+    /// Space-separated scopes, as in RFC 8693.
+    #[serde(default)]
+    pub scope: Option<String>,
+    /// Scopes as a list, as issued by e.g. Azure AD and Okta.
+    #[serde(default)]
+    pub scp: Option<Vec<String>>,
+}
+
+impl Claims {
+    /// Returns the scopes granted by the token.
+    pub fn scopes(&self) -> Vec<&str> {
+        let mut scopes: Vec<&str> = self.scope.iter().flat_map(|scope| scope.split_whitespace()).collect();
+        scopes.extend(self.scp.iter().flatten().map(String::as_str));
+        scopes
+    }
+}
+
+struct Keys {
+    set: JwkSet,
+    fetched: Instant,
+    /// The last attempt to refresh the keys, successful or not.
+    attempted: Option<Instant>,
+}
+
+/// Validates bearer tokens as JWTs: their signature against a JWK set, and
+/// their `exp`, `nbf`, `iss` and `aud` claims, as well as the scopes the
+/// server requires. The keys can be replaced while the server runs, e.g.
+/// when the authorization server rotates them, or refreshed by the
+/// validator itself.
+pub struct JwtValidator {
+    keys: RwLock<Keys>,
+    refresher: Option<(JwksFetcher, Duration)>,
+    min_refresh_interval: Duration,
+    issuer: String,
+    audience: String,
+    scopes: Vec<String>,
+    leeway: Duration,
+    openid_configuration: Option<String>,
+}
+
+impl JwtValidator {
+    pub fn new(keys: JwkSet, issuer: &str, audience: &str) -> Self {
+        Self {
+            keys: RwLock::new(Keys {
+                set: keys,
+                fetched: Instant::now(),
+                attempted: None,
+            }),
+            refresher: None,
+            min_refresh_interval: MIN_REFRESH_INTERVAL,
+            issuer: issuer.to_string(),
+            audience: audience.to_string(),
+            scopes: Vec::new(),
+            leeway: DEFAULT_LEEWAY,
+            openid_configuration: None,
+        }
+    }
+
+    /// Creates a validator from the JSON document served at the `jwks_uri`
+    /// of the authorization server.
+    pub fn from_jwks_json(jwks: &str, issuer: &str, audience: &str) -> Result<Self> {
+        Ok(Self::new(serde_json::from_str(jwks)?, issuer, audience))
+    }
+
+    /// Requires tokens to grant a scope. Tokens missing it are rejected
+    /// with an `insufficient_scope` error naming the required scopes.
+    pub fn with_scope(mut self, scope: &str) -> Self {
+        self.scopes.push(scope.to_string());
+        self
+    }
+
+    pub fn with_leeway(mut self, leeway: Duration) -> Self {
+        self.leeway = leeway;
+        self
+    }
+
+    /// Refreshes the keys with `fetcher` once they are older than
+    /// `max_age`, and when a token is signed with an unknown key, e.g. after
+    /// the authorization server rotated its keys. The current keys are kept
+    /// if fetching fails.
+    pub fn with_refresher(mut self, fetcher: JwksFetcher, max_age: Duration) -> Self {
+        self.refresher = Some((fetcher, max_age));
+        self
+    }
+
+    /// Sets the minimum interval between refreshes caused by tokens signed
+    /// with an unknown key, `MIN_REFRESH_INTERVAL` by default.
+    pub fn with_min_refresh_interval(mut self, interval: Duration) -> Self {
+        self.min_refresh_interval = interval;
+        self
+    }
+
+    /// Sets the `openid-configuration` field of the errors sent to
+    /// clients, pointing them to the discovery document of the issuer.
+    pub fn with_openid_configuration(mut self, url: &str) -> Self {
+        self.openid_configuration = Some(url.to_string());
+        self
+    }
+
+    /// Replaces the keys tokens are validated against.
+    pub fn update_keys(&self, keys: JwkSet) {
+        let mut current = self.keys.write().unwrap();
+        current.set = keys;
+        current.fetched = Instant::now();
+    }
+
+    /// Fetches the keys if they expired, or if a token was signed with an
+    /// unknown key, at most once per minimum refresh interval. The fetcher
+    /// runs synchronously, in the caller of `validate`.
+    fn refresh(&self, unknown_key: bool) {
+        let Some((fetcher, max_age)) = &self.refresher else {
+            return;
+        };
+        {
+            let keys = self.keys.read().unwrap();
+            if keys.attempted.is_some_and(|attempted| attempted.elapsed() < self.min_refresh_interval)
+                || (!unknown_key && keys.fetched.elapsed() < *max_age)
+            {
+                return;
+            }
+        }
+        let fetched = fetcher();
+        self.keys.write().unwrap().attempted = Some(Instant::now());
+        if let Ok(keys) = fetched {
+            self.update_keys(keys);
+        }
+    }
+
+    /// Validates a token and returns its claims. Tokens which fail
+    /// validation are rejected with an `invalid_token` error.
+    pub fn validate(&self, token: &str) -> Result<Claims, OAuthBearerError> {
+        let claims = self.decode(token).ok_or_else(|| self.error(STATUS_INVALID_TOKEN))?;
+        let scopes = claims.scopes();
+        if !self.scopes.iter().all(|scope| scopes.contains(&scope.as_str())) {
+            return Err(self.error(STATUS_INSUFFICIENT_SCOPE).with_scope(&self.scopes.join(" ")));
+        }
+        Ok(claims)
+    }
+
+    fn decode(&self, token: &str) -> Option<Claims> {
+        let header = decode_header(token).ok()?;
+        self.refresh(false);
+        if let Some(kid) = &header.kid {
+            if self.keys.read().unwrap().set.find(kid).is_none() {
+                self.refresh(true);
+            }
+        }
+        let keys = self.keys.read().unwrap();
+        let jwk = match &header.kid {
+            Some(kid) => keys.set.find(kid)?,
+            None if keys.set.keys.len() == 1 => &keys.set.keys[0],
+            None => return None,
+        };
+        // Only the algorithm of the key, if it has one, is allowed. Otherwise
+        // the one claimed by the token is, which jsonwebtoken checks against
+        // the family of the key: an HS256 token can't be verified with an
+        // RSA public key as its secret. Either way, jsonwebtoken rejects
+        // tokens whose `alg` isn't in `validation.algorithms`.
+        let algorithm = match jwk.common.key_algorithm {
+            Some(algorithm) => Algorithm::from_str(&algorithm.to_string()).ok()?,
+            None => header.alg,
+        };
+        let key = DecodingKey::from_jwk(jwk).ok()?;
+
+        let mut validation = Validation::new(algorithm);
+        validation.set_issuer(&[&self.issuer]);
+        validation.set_audience(&[&self.audience]);
+        validation.set_required_spec_claims(&["exp", "iss", "aud", "sub"]);
+        validation.validate_nbf = true;
+        validation.leeway = self.leeway.as_secs();
+        decode::<Claims>(token, &key, &validation).ok().map(|data| data.claims)
+    }
+
+    fn error(&self, status: &str) -> OAuthBearerError {
+        let error = OAuthBearerError::new(status).with_schemes("bearer");
+        match &self.openid_configuration {
+            Some(url) => error.with_openid_configuration(url),
+            None => error,
+        }
+    }
+
+    /// Returns an authenticator accepting valid tokens, for clients which
+    /// request no authorization identity or the subject of their token.
+    pub fn into_authenticator(self: Arc<Self>) -> OAuthBearerAuthenticator {
+        Box::new(move |options: OAuthBearerOptions| {
+            let claims = self.validate(&options.token)?;
+            if !options.username.is_empty() && options.username != claims.sub {
+                return Err(self.error(STATUS_INVALID_TOKEN));
+            }
+            Ok(())
+        })
+    }
+
+    /// Returns a validator reporting the subject of valid tokens, for
+    /// `OAuthBearerServer::from_validator`.
+    pub fn into_validator(self: Arc<Self>) -> OAuthBearerValidator {
+        Box::new(move |options: &OAuthBearerOptions| Ok(self.validate(&options.token)?.sub))
+    }
+}
+
+#[test]
+fn test_jwt_validator() -> Result<()> {
+    use super::OAuthBearerServer;
+    use crate::sasl::Server;
+    use anyhow::bail;
+    use jsonwebtoken::{encode, get_current_timestamp, EncodingKey, Header};
+
+    let jwks = r#"{"keys": [{"kty": "oct", "kid": "k1", "alg": "HS256", "k": "c2VjcmV0"}]}"#;
+    let validator = JwtValidator::from_jwks_json(jwks, "https://issuer.example", "imap").map(|v| v.with_scope("mail"))?;
+    let validator = Arc::new(validator);
+
+    let token = |secret: &[u8], claims: serde_json::Value| -> Result<String> {
+        let header = Header {
+            kid: Some("k1".to_string()),
+            ..Header::new(Algorithm::HS256)
+        };
+        Ok(encode(&header, &claims, &EncodingKey::from_secret(secret))?)
+    };
+    let now = get_current_timestamp();
+    let claims = |aud: &str, exp: u64, scope: &str| {
+        serde_json::json!({"sub": "username", "iss": "https://issuer.example", "aud": aud, "exp": exp, "scope": scope})
+    };
+
+    let valid = token(b"secret", claims("imap", now + 300, "openid mail"))?;
+    if validator.validate(&valid)?.sub != "username" {
+        bail!("Expected the subject of the token");
+    }
+    let cases = [
+        (token(b"wrong", claims("imap", now + 300, "mail"))?, "invalid_token"),
+        (token(b"secret", claims("smtp", now + 300, "mail"))?, "invalid_token"),
+        (token(b"secret", claims("imap", now - 300, "mail"))?, "invalid_token"),
+        (token(b"secret", claims("imap", now + 300, "openid"))?, "insufficient_scope"),
+    ];
+    for (token, status) in cases {
+        match validator.validate(&token) {
+            Err(err) if err.status == status => {}
+            result => bail!("Expected {}, got {:?}", status, result),
+        }
+    }
+
+    let mut server = OAuthBearerServer::new(validator.clone().into_authenticator());
+    server.next(Some(format!("n,a=username,\x01auth=Bearer {}\x01\x01", valid).as_bytes()))?;
+    if !server.is_done() {
+        bail!("Expected the token to be accepted");
+    }
+    let mut server = OAuthBearerServer::new(validator.into_authenticator());
+    if server.next(Some(format!("n,a=admin,\x01auth=Bearer {}\x01\x01", valid).as_bytes()))?.is_done() {
+        bail!("Expected the token to be rejected for another identity");
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_jwt_algorithm_confusion() -> Result<()> {
+    use anyhow::bail;
+    use jsonwebtoken::{encode, get_current_timestamp, EncodingKey, Header};
+
+    let modulus = [0xc5; 256];
+    let n = crate::framing::base64_encode(&modulus).trim_end_matches('=').replace('+', "-").replace('/', "_");
+    let claims = serde_json::json!({"sub": "username", "iss": "https://issuer.example", "aud": "imap", "exp": get_current_timestamp() + 300});
+    for alg in ["", r#""alg": "RS256","#] {
+        let jwks = format!(r#"{{"keys": [{{"kty": "RSA", "kid": "r1", {} "n": "{}", "e": "AQAB"}}]}}"#, alg, n);
+        let validator = JwtValidator::from_jwks_json(&jwks, "https://issuer.example", "imap")?;
+        if DecodingKey::from_jwk(&validator.keys.read().unwrap().set.keys[0]).is_err() {
+            bail!("Expected the RSA key to be usable");
+        }
+        for secret in [&modulus[..], n.as_bytes()] {
+            let header = Header {
+                kid: Some("r1".to_string()),
+                ..Header::new(Algorithm::HS256)
+            };
+            let token = encode(&header, &claims, &EncodingKey::from_secret(secret))?;
+            if validator.validate(&token).is_ok() {
+                bail!("Expected an HS256 token to be rejected for an RSA key");
+            }
+        }
+    }
+    Ok(())
+}
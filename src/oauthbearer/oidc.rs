@@ -0,0 +1,144 @@
+//! OpenID Connect discovery of the keys of an issuer, for `JwtValidator`.
+//! As in `store::ldap`, the HTTP client is left to the application:
+//! documents are fetched through an `HttpGet` function.
+//!
+//! Validators fetch the keys again synchronously, inside
+//! `JwtValidator::validate`, which blocks the thread of an asynchronous
+//! server for the duration of the request: run validation with
+//! `tokio::task::spawn_blocking` there, e.g. through
+//! `async_sasl::SpawnBlocking`.
+
+use super::jwt::{JwtValidator, MIN_REFRESH_INTERVAL};
+
+use anyhow::{bail, Result};
+use serde::Deserialize;
+use std::sync::Arc;
+use std::time::Duration;
+
+pub const ERR_ISSUER_MISMATCH: &str = "sasl: oidc: discovery document of another issuer";
+
+/// The path of the discovery document, relative to the issuer.
+pub const DISCOVERY_PATH: &str = "/.well-known/openid-configuration";
+
+/// The default age after which the keys of an issuer are fetched again.
+pub const DEFAULT_MAX_AGE: Duration = Duration::from_secs(3600);
+
+/// Fetches a document over HTTPS, returning its body. It is called
+/// synchronously, including while validating tokens.
+pub type HttpGet = Arc<dyn Fn(&str) -> Result<String> + Send + Sync>;
+
+/// The fields of a discovery document used to validate tokens.
+#[derive(Clone, Debug, Deserialize)]
+#[non_exhaustive]
+pub struct ProviderMetadata {
+    pub issuer: String,
+    pub jwks_uri: String,
+}
+
+/// Discovers the keys of an issuer from its discovery document, as
+/// described in OpenID Connect Discovery 1.0 section 4.
+pub struct OidcDiscovery {
+    issuer: String,
+    get: HttpGet,
+    max_age: Duration,
+    min_refresh_interval: Duration,
+}
+
+impl OidcDiscovery {
+    pub fn new(issuer: &str, get: HttpGet) -> Self {
+        Self {
+            issuer: issuer.to_string(),
+            get,
+            max_age: DEFAULT_MAX_AGE,
+            min_refresh_interval: MIN_REFRESH_INTERVAL,
+        }
+    }
+
+    /// Sets the age after which validators fetch the keys again.
+    pub fn with_max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = max_age;
+        self
+    }
+
+    /// Sets the minimum interval between refreshes caused by tokens signed
+    /// with an unknown key. See `JwtValidator::with_min_refresh_interval`.
+    pub fn with_min_refresh_interval(mut self, interval: Duration) -> Self {
+        self.min_refresh_interval = interval;
+        self
+    }
+
+    /// Returns the URL of the discovery document.
+    pub fn configuration_url(&self) -> String {
+        format!("{}{}", self.issuer.trim_end_matches('/'), DISCOVERY_PATH)
+    }
+
+    /// Fetches the discovery document, which must be the issuer's own.
+    pub fn metadata(&self) -> Result<ProviderMetadata> {
+        let metadata: ProviderMetadata = serde_json::from_str(&(self.get)(&self.configuration_url())?)?;
+        if metadata.issuer != self.issuer {
+            bail!("{}: {}", ERR_ISSUER_MISMATCH, metadata.issuer);
+        }
+        Ok(metadata)
+    }
+
+    /// Creates a validator of the tokens the issuer grants for `audience`.
+    /// Its keys are fetched again once they are older than the maximum age
+    /// or when a token is signed with an unknown key, and its errors point
+    /// clients to the discovery document.
+    pub fn validator(&self, audience: &str) -> Result<JwtValidator> {
+        let metadata = self.metadata()?;
+        let get = self.get.clone();
+        let jwks_uri = metadata.jwks_uri;
+        let fetch = move || -> Result<_> { Ok(serde_json::from_str(&get(&jwks_uri)?)?) };
+        Ok(JwtValidator::new(fetch()?, &self.issuer, audience)
+            .with_refresher(Box::new(fetch), self.max_age)
+            .with_min_refresh_interval(self.min_refresh_interval)
+            .with_openid_configuration(&self.configuration_url()))
+    }
+}
+
+#[test]
+fn test_oidc_discovery() -> Result<()> {
+    use jsonwebtoken::{encode, get_current_timestamp, Algorithm, EncodingKey, Header};
+    use std::sync::Mutex;
+
+    const ISSUER: &str = "https://issuer.example";
+    let jwks = Arc::new(Mutex::new(r#"{"keys": [{"kty": "oct", "kid": "k1", "k": "c2VjcmV0"}]}"#));
+    let served = jwks.clone();
+    let get: HttpGet = Arc::new(move |url| match url {
+        "https://issuer.example/.well-known/openid-configuration" => {
+            Ok(format!(r#"{{"issuer": "{}", "jwks_uri": "{}/jwks"}}"#, ISSUER, ISSUER))
+        }
+        "https://issuer.example/jwks" => Ok(served.lock().unwrap().to_string()),
+        _ => bail!("Unexpected URL {}", url),
+    });
+    if OidcDiscovery::new("https://other.example", get.clone()).validator("imap").is_ok() {
+        bail!("Expected the document of another issuer to be rejected");
+    }
+    let validator = OidcDiscovery::new(ISSUER, get.clone()).validator("imap")?;
+
+    let claims = serde_json::json!({"sub": "username", "iss": ISSUER, "aud": "imap", "exp": get_current_timestamp() + 300});
+    let header = Header {
+        kid: Some("k2".to_string()),
+        ..Header::new(Algorithm::HS256)
+    };
+    let token = encode(&header, &claims, &EncodingKey::from_secret(b"rotated"))?;
+    match validator.validate(&token) {
+        Err(err) if err.openid_configuration.as_deref() == Some("https://issuer.example/.well-known/openid-configuration") => {}
+        result => bail!("Expected the token to be rejected with the discovery document, got {:?}", result),
+    }
+
+    // Refreshes of unknown keys are rate limited, so the rotated key is only
+    // picked up without a minimum refresh interval.
+    let unlimited = OidcDiscovery::new(ISSUER, get).with_min_refresh_interval(Duration::ZERO).validator("imap")?;
+    if unlimited.validate(&token).is_ok() {
+        bail!("Expected the key not to be rotated yet");
+    }
+    *jwks.lock().unwrap() = r#"{"keys": [{"kty": "oct", "kid": "k2", "k": "cm90YXRlZA"}]}"#;
+    if validator.validate(&token).is_ok() {
+        bail!("Expected the keys not to be refreshed again");
+    }
+    unlimited.validate(&token)?;
+
+    Ok(())
+}
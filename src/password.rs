@@ -0,0 +1,183 @@
+//! Verification of password hashes in the common formats: Argon2, scrypt
+//! and PBKDF2 in PHC string format, and bcrypt in modular crypt format.
+//! Hashes are compared in constant time by the underlying crates.
+//!
+//! Only verification is provided: hashes are expected to be created by the
+//! application managing accounts, with parameters which can be audited with
+//! `cost`.
+
+use crate::sasl::FailureReason;
+
+use anyhow::{anyhow, bail, Result};
+use argon2::Argon2;
+use password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use pbkdf2::Pbkdf2;
+use scrypt::Scrypt;
+
+pub const ERR_UNSUPPORTED_SCHEME: &str = "sasl: password: unsupported hash scheme";
+pub const ERR_MALFORMED_HASH: &str = "sasl: password: malformed hash";
+
+/// A password hash scheme.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Scheme {
+    Argon2,
+    Bcrypt,
+    Scrypt,
+    /// PBKDF2 with HMAC-SHA-256 or HMAC-SHA-512.
+    Pbkdf2,
+}
+
+/// The cost parameters of a hash.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Cost {
+    Argon2 { m_cost: u32, t_cost: u32, p_cost: u32 },
+    /// The base-2 logarithm of the number of rounds.
+    Bcrypt { cost: u32 },
+    Scrypt { log_n: u8, r: u32, p: u32 },
+    Pbkdf2 { rounds: u32 },
+}
+
+/// Identifies the scheme of a hash from its prefix, e.g. `$argon2id$`.
+pub fn identify(hash: &str) -> Option<Scheme> {
+    let id = hash.strip_prefix('$')?.split('$').next()?;
+    match id {
+        "argon2id" | "argon2i" | "argon2d" => Some(Scheme::Argon2),
+        "2a" | "2b" | "2x" | "2y" => Some(Scheme::Bcrypt),
+        "scrypt" => Some(Scheme::Scrypt),
+        "pbkdf2-sha256" | "pbkdf2-sha512" => Some(Scheme::Pbkdf2),
+        _ => None,
+    }
+}
+
+fn parse_phc(hash: &str) -> Result<PasswordHash<'_>> {
+    PasswordHash::new(hash).map_err(|_| anyhow!(ERR_MALFORMED_HASH))
+}
+
+/// Verifies a password against a hash, failing with
+/// `FailureReason::InvalidCredentials` if it doesn't match.
+pub fn verify(password: &str, hash: &str) -> Result<()> {
+    let scheme = identify(hash).ok_or_else(|| anyhow!(ERR_UNSUPPORTED_SCHEME))?;
+    let matches = match scheme {
+        Scheme::Bcrypt => bcrypt::verify(password, hash).map_err(|_| anyhow!(ERR_MALFORMED_HASH))?,
+        Scheme::Argon2 | Scheme::Scrypt | Scheme::Pbkdf2 => {
+            let parsed = parse_phc(hash)?;
+            let result = match scheme {
+                Scheme::Argon2 => Argon2::default().verify_password(password.as_bytes(), &parsed),
+                Scheme::Scrypt => Scrypt.verify_password(password.as_bytes(), &parsed),
+                _ => Pbkdf2.verify_password(password.as_bytes(), &parsed),
+            };
+            match result {
+                Ok(()) => true,
+                Err(password_hash::Error::Password) => false,
+                Err(_) => bail!(ERR_MALFORMED_HASH),
+            }
+        }
+    };
+    if !matches {
+        bail!(FailureReason::InvalidCredentials);
+    }
+    Ok(())
+}
+
+/// Hashes a password with the scheme and cost parameters of another hash,
+/// and a random salt, e.g. to create decoys which take as long to verify.
+pub fn hash_like(password: &str, hash: &str) -> Result<String> {
+    let scheme = identify(hash).ok_or_else(|| anyhow!(ERR_UNSUPPORTED_SCHEME))?;
+    let mut salt = [0; 16];
+    getrandom::fill(&mut salt).map_err(|err| anyhow!("sasl: {}", err))?;
+    if let Cost::Bcrypt { cost } = cost(hash)? {
+        return Ok(bcrypt::hash_with_salt(password, cost, salt).map_err(|_| anyhow!(ERR_MALFORMED_HASH))?.to_string());
+    }
+    let parsed = parse_phc(hash)?;
+    let salt = SaltString::encode_b64(&salt).map_err(|_| anyhow!(ERR_MALFORMED_HASH))?;
+    let hashed = match scheme {
+        Scheme::Argon2 => {
+            let params = argon2::Params::try_from(&parsed).map_err(|_| anyhow!(ERR_MALFORMED_HASH))?;
+            Argon2::default().hash_password_customized(password.as_bytes(), Some(parsed.algorithm), parsed.version, params, &salt)
+        }
+        Scheme::Scrypt => {
+            let params = scrypt::Params::try_from(&parsed).map_err(|_| anyhow!(ERR_MALFORMED_HASH))?;
+            Scrypt.hash_password_customized(password.as_bytes(), None, None, params, &salt)
+        }
+        _ => {
+            let params = pbkdf2::Params::try_from(&parsed).map_err(|_| anyhow!(ERR_MALFORMED_HASH))?;
+            Pbkdf2.hash_password_customized(password.as_bytes(), Some(parsed.algorithm), None, params, &salt)
+        }
+    };
+    Ok(hashed.map_err(|_| anyhow!(ERR_MALFORMED_HASH))?.to_string())
+}
+
+/// Returns the cost parameters of a hash, e.g. to find hashes which must be
+/// upgraded.
+pub fn cost(hash: &str) -> Result<Cost> {
+    match identify(hash).ok_or_else(|| anyhow!(ERR_UNSUPPORTED_SCHEME))? {
+        Scheme::Argon2 => {
+            let params = argon2::Params::try_from(&parse_phc(hash)?).map_err(|_| anyhow!(ERR_MALFORMED_HASH))?;
+            Ok(Cost::Argon2 {
+                m_cost: params.m_cost(),
+                t_cost: params.t_cost(),
+                p_cost: params.p_cost(),
+            })
+        }
+        Scheme::Bcrypt => {
+            let parts: bcrypt::HashParts = hash.parse().map_err(|_| anyhow!(ERR_MALFORMED_HASH))?;
+            Ok(Cost::Bcrypt { cost: parts.get_cost() })
+        }
+        Scheme::Scrypt => {
+            let params = scrypt::Params::try_from(&parse_phc(hash)?).map_err(|_| anyhow!(ERR_MALFORMED_HASH))?;
+            Ok(Cost::Scrypt {
+                log_n: params.log_n(),
+                r: params.r(),
+                p: params.p(),
+            })
+        }
+        Scheme::Pbkdf2 => {
+            let rounds = parse_phc(hash)?.params.get_decimal("i").ok_or_else(|| anyhow!(ERR_MALFORMED_HASH))?;
+            Ok(Cost::Pbkdf2 { rounds })
+        }
+    }
+}
+
+#[test]
+fn test_password_hashes() -> Result<()> {
+    let salt = SaltString::from_b64("c2FsdHNhbHRzYWx0").map_err(|e| anyhow!("{}", e))?;
+    let argon2 = Argon2::new(argon2::Algorithm::Argon2id, argon2::Version::V0x13, argon2::Params::new(64, 1, 1, None).map_err(|e| anyhow!("{}", e))?);
+    let hashes = [
+        (argon2.hash_password(b"password", &salt).map_err(|e| anyhow!("{}", e))?.to_string(), Cost::Argon2 { m_cost: 64, t_cost: 1, p_cost: 1 }),
+        (bcrypt::hash_with_salt("password", 4, [0; 16])?.to_string(), Cost::Bcrypt { cost: 4 }),
+        (
+            Scrypt
+                .hash_password_customized(b"password", None, None, scrypt::Params::new(4, 8, 1, 32).map_err(|e| anyhow!("{}", e))?, &salt)
+                .map_err(|e| anyhow!("{}", e))?
+                .to_string(),
+            Cost::Scrypt { log_n: 4, r: 8, p: 1 },
+        ),
+        (
+            Pbkdf2
+                .hash_password_customized(b"password", Some(pbkdf2::Algorithm::Pbkdf2Sha256.ident()), None, pbkdf2::Params { rounds: 1000, output_length: 32 }, &salt)
+                .map_err(|e| anyhow!("{}", e))?
+                .to_string(),
+            Cost::Pbkdf2 { rounds: 1000 },
+        ),
+    ];
+    for (hash, expected) in hashes {
+        verify("password", &hash)?;
+        match verify("wrong", &hash) {
+            Err(err) if FailureReason::of(&err) == FailureReason::InvalidCredentials => {}
+            _ => bail!("Expected a wrong password to fail with {}", hash),
+        }
+        if cost(&hash)? != expected {
+            bail!("Unexpected cost of {}", hash);
+        }
+        let decoy = hash_like("decoy", &hash)?;
+        if decoy == hash || cost(&decoy)? != expected || verify("decoy", &decoy).is_err() {
+            bail!("Expected a decoy of {} with the same cost", hash);
+        }
+    }
+    if identify("$1$salt$hash").is_some() || verify("password", "$argon2id$garbage").is_ok() {
+        bail!("Expected unsupported and malformed hashes to be rejected");
+    }
+    Ok(())
+}
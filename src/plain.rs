@@ -1,10 +1,71 @@
-use crate::sasl;
+use crate::authorization::{authorize, AuthorizationPolicy};
+use crate::identity::{canonicalize, decode, prepare, IdentityMapper, Utf8Policy, ERR_RAW_CREDENTIALS};
+use crate::master::MasterUsers;
+use crate::sasl::{self, FailureReason};
+use crate::store::CredentialVerifier;
+#[cfg(feature = "tokio")]
+use crate::store::AsyncCredentialVerifier;
+#[cfg(feature = "tokio")]
+use crate::async_sasl::{AsyncServer, BoxFuture};
 
 use anyhow::{anyhow, bail, Result};
+use std::borrow::Cow;
+use std::sync::Arc;
 
 /// The PLAIN mechanism name.
 pub const PLAIN: &str = "PLAIN";
 
+/// The security properties of the PLAIN mechanism.
+pub const PROPERTIES: sasl::Properties = sasl::Properties {
+    plaintext: true,
+    anonymous: false,
+    channel_binding: false,
+    mutual_auth: false,
+    security_layer: false,
+    ssf: 0,
+};
+
+/// The default maximum length of each field accepted by strict servers.
+/// RFC 4616 requires servers to accept at least 255 octets.
+pub const DEFAULT_MAX_FIELD_LENGTH: usize = 1024;
+
+/// The violations of RFC 4616 rejected by strict servers, see
+/// `PlainServer::strict`. They are returned with the context
+/// `FailureReason::MalformedResponse`, and can be retrieved with
+/// `downcast_ref`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum PlainError {
+    /// The response has fewer than three NUL-separated fields.
+    MissingField,
+    /// The response has more than three NUL-separated fields.
+    ExtraField,
+    /// A field isn't valid UTF-8.
+    InvalidUtf8,
+    /// The authentication identity is empty.
+    EmptyUsername,
+    /// The password is empty.
+    EmptyPassword,
+    /// A field is longer than the maximum length of the server.
+    FieldTooLong,
+}
+
+impl std::fmt::Display for PlainError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let descr = match self {
+            PlainError::MissingField => "missing field",
+            PlainError::ExtraField => "extra field",
+            PlainError::InvalidUtf8 => "invalid UTF-8",
+            PlainError::EmptyUsername => "empty username",
+            PlainError::EmptyPassword => "empty password",
+            PlainError::FieldTooLong => "field too long",
+        };
+        write!(f, "sasl: plain: {}", descr)
+    }
+}
+
+impl std::error::Error for PlainError {}
+
 /// A client implementation of the PLAIN authentication mechanism, as described
 /// in RFC 4616. Authorization identity may be left blank to indicate that it is
 /// the same as the username.
@@ -12,6 +73,7 @@ pub struct PlainClient {
     identity: String,
     username: String,
     password: String,
+    saslprep: bool,
 }
 
 impl PlainClient {
@@ -20,15 +82,36 @@ impl PlainClient {
             identity,
             username,
             password,
+            saslprep: false,
         }
     }
+
+    /// Prepares the username and password with SASLprep before sending
+    /// them, as RFC 4616 recommends.
+    #[cfg(feature = "saslprep")]
+    pub fn with_saslprep(mut self) -> Self {
+        self.saslprep = true;
+        self
+    }
 }
 
 impl sasl::Client for PlainClient {
-    fn start(&mut self) -> Result<(String, Vec<u8>)> {
+    fn mechanism_name(&self) -> &str {
+        PLAIN
+    }
+
+    fn start(&mut self) -> Result<(String, Option<Vec<u8>>)> {
         Ok((
             PLAIN.to_string(),
-            format!("{}\x00{}\x00{}", self.identity, self.username, self.password).into_bytes()
+            Some(
+                format!(
+                    "{}\x00{}\x00{}",
+                    self.identity,
+                    prepare(self.saslprep, &self.username)?,
+                    prepare(self.saslprep, &self.password)?
+                )
+                .into_bytes(),
+            ),
         ))
     }
 
@@ -41,50 +124,415 @@ impl sasl::Client for PlainClient {
 /// identity is left blank, it indicates that it is the same as the username.
 /// If identity is not empty and the server doesn't support it, an error must be
 /// returned.
-pub type PlainAuthenticator = Box<dyn Fn(&str, &str, &str) -> Result<()> + Send>;
+pub type PlainAuthenticator = Box<dyn Fn(&str, &str, &str) -> Result<()> + Send + Sync>;
+
+/// Authenticates users with the raw identity, username and password sent by
+/// the client, whatever their encoding. See `PlainAuthenticator`.
+pub type PlainBytesAuthenticator = Box<dyn Fn(&[u8], &[u8], &[u8]) -> Result<()> + Send + Sync>;
+
+enum PlainVerifier {
+    Str(PlainAuthenticator),
+    Bytes(PlainBytesAuthenticator),
+}
 
 /// A server implementation of the PLAIN authentication mechanism, as described
 /// in RFC 4616.
 pub struct PlainServer {
     done: bool,
-    authenticator: PlainAuthenticator,
+    identity: Option<String>,
+    username: Option<String>,
+    canonicalizer: Option<Arc<IdentityMapper>>,
+    policy: Option<Arc<dyn AuthorizationPolicy>>,
+    max_field_length: Option<usize>,
+    saslprep: bool,
+    utf8_policy: Utf8Policy,
+    masters: Option<Arc<MasterUsers>>,
+    verifier: PlainVerifier,
 }
 
 impl PlainServer {
     pub fn new(authenticator: PlainAuthenticator) -> Self {
         Self {
             done: false,
-            authenticator,
+            identity: None,
+            username: None,
+            canonicalizer: None,
+            policy: None,
+            max_field_length: None,
+            saslprep: false,
+            utf8_policy: Utf8Policy::Require,
+            masters: None,
+            verifier: PlainVerifier::Str(authenticator),
+        }
+    }
+
+    /// Creates a server passing the raw fields of responses to the
+    /// authenticator, e.g. to verify ISO-8859-1 passwords of legacy
+    /// clients. `authentication_id` reports the username with invalid UTF-8
+    /// replaced.
+    ///
+    /// Canonicalization, SASLprep and UTF-8 policies other than
+    /// `Utf8Policy::Require` need decoded credentials: responses fail with
+    /// `ERR_RAW_CREDENTIALS` if they are set. An
+    /// authorization policy runs after the authenticator, which then gets
+    /// an empty identity, and requires the username and identity to be
+    /// UTF-8, as do strict servers.
+    pub fn from_bytes(authenticator: PlainBytesAuthenticator) -> Self {
+        Self {
+            verifier: PlainVerifier::Bytes(authenticator),
+            ..Self::new(Box::new(|_, _, _| Ok(())))
         }
     }
+
+    /// Canonicalizes usernames before the authenticator runs, which then
+    /// gets the canonical form, as does `authentication_id`.
+    pub fn with_canonicalizer(mut self, canonicalizer: Arc<IdentityMapper>) -> Self {
+        self.canonicalizer = Some(canonicalizer);
+        self
+    }
+
+    /// Leaves authorization identities to a policy: the authenticator then
+    /// gets an empty identity and only verifies credentials.
+    pub fn with_authorization_policy(mut self, policy: Arc<dyn AuthorizationPolicy>) -> Self {
+        self.policy = Some(policy);
+        self
+    }
+
+    /// Rejects responses violating RFC 4616 with a `PlainError`: extra
+    /// fields, which are otherwise ignored, an empty username or password,
+    /// and fields longer than `DEFAULT_MAX_FIELD_LENGTH`.
+    pub fn strict(self) -> Self {
+        self.with_max_field_length(DEFAULT_MAX_FIELD_LENGTH)
+    }
+
+    /// Parses responses strictly, with a maximum field length other than
+    /// `DEFAULT_MAX_FIELD_LENGTH`. See `strict`.
+    pub fn with_max_field_length(mut self, max_field_length: usize) -> Self {
+        self.max_field_length = Some(max_field_length);
+        self
+    }
+
+    /// Prepares usernames and passwords with SASLprep before
+    /// canonicalization and the authenticator, which should compare them
+    /// to credentials stored in the same form.
+    #[cfg(feature = "saslprep")]
+    pub fn with_saslprep(mut self) -> Self {
+        self.saslprep = true;
+        self
+    }
+
+    /// Sets how credentials which aren't valid UTF-8 are decoded, instead
+    /// of rejecting them.
+    pub fn with_utf8_policy(mut self, utf8_policy: Utf8Policy) -> Self {
+        self.utf8_policy = utf8_policy;
+        self
+    }
+
+    /// Creates a server verifying credentials with a verifier, which may be
+    /// shared with other servers.
+    pub fn from_verifier(verifier: Arc<dyn CredentialVerifier>) -> Self {
+        Self::new(Box::new(move |identity, username, password| verifier.verify(identity, username, password)))
+    }
+}
+
+/// Splits a PLAIN response into its fields, ignoring extra ones.
+fn split_response(response: &[u8]) -> Result<[&[u8]; 3]> {
+    let mut parts = response.split(|&b| b == b'\x00');
+    let identity = parts.next().ok_or_else(|| anyhow!("sasl: missing identity"))?;
+    let username = parts.next().ok_or_else(|| anyhow!("sasl: missing username"))?;
+    let password = parts.next().ok_or_else(|| anyhow!("sasl: missing password"))?;
+    Ok([identity, username, password])
+}
+
+fn malformed(err: PlainError) -> anyhow::Error {
+    anyhow::Error::new(err).context(FailureReason::MalformedResponse)
+}
+
+/// Splits a PLAIN response into its fields, following the grammar of
+/// RFC 4616 section 2 exactly.
+fn split_strict(response: &[u8], max_field_length: usize) -> Result<[&[u8]; 3]> {
+    let fields = response.split(|&b| b == b'\x00').collect::<Vec<_>>();
+    let [identity, username, password] = fields[..] else {
+        let err = if fields.len() < 3 { PlainError::MissingField } else { PlainError::ExtraField };
+        return Err(malformed(err));
+    };
+    if fields.iter().any(|field| field.len() > max_field_length) {
+        return Err(malformed(PlainError::FieldTooLong));
+    }
+    if username.is_empty() {
+        return Err(malformed(PlainError::EmptyUsername));
+    }
+    if password.is_empty() {
+        return Err(malformed(PlainError::EmptyPassword));
+    }
+    Ok([identity, username, password])
+}
+
+/// Splits a response strictly if a maximum field length is set.
+fn split(response: &[u8], max_field_length: Option<usize>) -> Result<[&[u8]; 3]> {
+    match max_field_length {
+        Some(max_field_length) => split_strict(response, max_field_length),
+        None => split_response(response),
+    }
+}
+
+/// Decodes the fields of a response according to a UTF-8 policy. Strict
+/// servers report invalid UTF-8 as a `PlainError`.
+fn decode_fields(fields: [&[u8]; 3], utf8_policy: Utf8Policy, strict: bool) -> Result<[Cow<'_, str>; 3]> {
+    let decode = |field| {
+        decode(utf8_policy, field).map_err(|err| match strict {
+            true => malformed(PlainError::InvalidUtf8),
+            false => err,
+        })
+    };
+    let [identity, username, password] = fields;
+    Ok([decode(identity)?, decode(username)?, decode(password)?])
 }
 
 impl sasl::Server for PlainServer {
-    fn next(&mut self, response: Option<&[u8]>) -> Result<(Vec<u8>, bool)> {
+    fn mechanism_name(&self) -> &str {
+        PLAIN
+    }
+
+    fn next(&mut self, response: Option<&[u8]>) -> Result<sasl::ServerStep> {
         if self.done {
             bail!(sasl::ERR_UNEXPECTED_CLIENT_RESPONSE);
         }
 
         // No initial response, send an empty challenge
         if response.is_none() {
-            return Ok((Vec::new(), false));
+            return Ok(sasl::ServerStep::Challenge(Vec::new()));
         }
         let response = response.unwrap();
 
-        let mut parts = response.split(|&b| b == b'\x00');
-        let identity = parts.next().ok_or_else(|| anyhow!("sasl: missing identity"))?;
-        let username = parts.next().ok_or_else(|| anyhow!("sasl: missing username"))?;
-        let password = parts.next().ok_or_else(|| anyhow!("sasl: missing password"))?;
+        self.done = true;
+
+        let fields = split(response, self.max_field_length)?;
+        let authenticator = match &self.verifier {
+            PlainVerifier::Str(authenticator) => authenticator,
+            PlainVerifier::Bytes(authenticator) => {
+                if self.canonicalizer.is_some() || self.saslprep || self.utf8_policy != Utf8Policy::Require {
+                    bail!(ERR_RAW_CREDENTIALS);
+                }
+                if self.max_field_length.is_some() {
+                    decode_fields(fields, Utf8Policy::Require, true)?;
+                }
+                if let [Ok(identity), Ok(username), Ok(password)] = fields.map(std::str::from_utf8) {
+                    if let Some((masters, target, master)) = self.master(identity, username)? {
+                        return self.authenticate_master(&masters, target, master, password);
+                    }
+                }
+                let [identity, username, password] = fields;
+                self.identity = Some(String::from_utf8_lossy(identity).into_owned()).filter(|identity| !identity.is_empty());
+                self.username = Some(String::from_utf8_lossy(username).into_owned());
+                match &self.policy {
+                    Some(policy) => {
+                        let (authcid, authzid) = (decode(Utf8Policy::Require, username)?, decode(Utf8Policy::Require, identity)?);
+                        authenticator(b"", username, password)?;
+                        authorize(Some(policy.as_ref()), &authcid, &authzid)?;
+                    }
+                    None => authenticator(identity, username, password)?,
+                }
+                return Ok(sasl::ServerStep::Done { additional_data: None });
+            }
+        };
+        let [identity, username, password] = decode_fields(fields, self.utf8_policy, self.max_field_length.is_some())?;
+        let password = prepare(self.saslprep, &password)?;
+        let username = prepare(self.saslprep, &username)?;
+        if let Some((masters, target, master)) = self.master(&identity, &username)? {
+            return self.authenticate_master(&masters, target, master, &password);
+        }
+        self.identity = Some(identity.to_string()).filter(|identity| !identity.is_empty());
+        let username = self.username.insert(canonicalize(self.canonicalizer.as_deref(), &username)?);
+        match &self.policy {
+            Some(policy) => {
+                authenticator("", username, &password)?;
+                authorize(Some(policy.as_ref()), username, &identity)?;
+            }
+            None => authenticator(&identity, username, &password)?,
+        }
+
+        Ok(sasl::ServerStep::Done { additional_data: None })
+    }
+
+    fn is_done(&self) -> bool {
+        self.done
+    }
+
+    fn reset(&mut self) -> Result<()> {
+        self.done = false;
+        self.identity = None;
+        self.username = None;
+        Ok(())
+    }
+
+    fn authentication_id(&self) -> Option<&str> {
+        self.username.as_deref()
+    }
+
+    fn authorization_id(&self) -> Option<&str> {
+        self.identity.as_deref()
+    }
+
+    fn set_master_users(&mut self, masters: Arc<MasterUsers>) -> bool {
+        self.masters = Some(masters);
+        true
+    }
+}
+
+impl PlainServer {
+    /// Returns the master users with the target user, in its canonical
+    /// form, and the master user, if the username names a master user
+    /// acting as the requested identity.
+    fn master<'a>(&self, identity: &str, username: &'a str) -> Result<Option<(Arc<MasterUsers>, String, &'a str)>> {
+        let Some(masters) = &self.masters else {
+            return Ok(None);
+        };
+        match masters.split(username).filter(|(target, _)| identity.is_empty() || identity == *target) {
+            Some((target, master)) => Ok(Some((masters.clone(), canonicalize(self.canonicalizer.as_deref(), target)?, master))),
+            None => Ok(None),
+        }
+    }
+
+    /// Authenticates a master user, reported as the authentication identity
+    /// with the target user as the authorization identity. The master user
+    /// must also be allowed to act as the target user by the authorization
+    /// policy of the server, if any.
+    fn authenticate_master(&mut self, masters: &MasterUsers, target: String, master: &str, password: &str) -> Result<sasl::ServerStep> {
+        let target = self.identity.insert(target);
+        self.username = Some(master.to_string());
+        masters.verify(target, master, password)?;
+        if let Some(policy) = &self.policy {
+            authorize(Some(policy.as_ref()), master, target)?;
+        }
+        Ok(sasl::ServerStep::Done { additional_data: None })
+    }
+}
+
+/// Authenticates users with an identity, a username and a password
+/// asynchronously. See `PlainAuthenticator`.
+#[cfg(feature = "tokio")]
+pub type AsyncPlainAuthenticator = Box<dyn Fn(String, String, String) -> BoxFuture<'static, Result<()>> + Send + Sync>;
+
+/// A server implementation of the PLAIN authentication mechanism with an
+/// asynchronous authenticator. See `PlainServer`.
+#[cfg(feature = "tokio")]
+pub struct AsyncPlainServer {
+    done: bool,
+    canonicalizer: Option<Arc<IdentityMapper>>,
+    policy: Option<Arc<dyn AuthorizationPolicy>>,
+    max_field_length: Option<usize>,
+    saslprep: bool,
+    utf8_policy: Utf8Policy,
+    authenticator: AsyncPlainAuthenticator,
+}
+
+#[cfg(feature = "tokio")]
+impl AsyncPlainServer {
+    pub fn new(authenticator: AsyncPlainAuthenticator) -> Self {
+        Self {
+            done: false,
+            canonicalizer: None,
+            policy: None,
+            max_field_length: None,
+            saslprep: false,
+            utf8_policy: Utf8Policy::Require,
+            authenticator,
+        }
+    }
+
+    /// Canonicalizes usernames before the authenticator runs. See
+    /// `PlainServer::with_canonicalizer`.
+    pub fn with_canonicalizer(mut self, canonicalizer: Arc<IdentityMapper>) -> Self {
+        self.canonicalizer = Some(canonicalizer);
+        self
+    }
+
+    /// Leaves authorization identities to a policy. See
+    /// `PlainServer::with_authorization_policy`.
+    pub fn with_authorization_policy(mut self, policy: Arc<dyn AuthorizationPolicy>) -> Self {
+        self.policy = Some(policy);
+        self
+    }
+
+    /// Parses responses strictly. See `PlainServer::strict`.
+    pub fn strict(self) -> Self {
+        self.with_max_field_length(DEFAULT_MAX_FIELD_LENGTH)
+    }
+
+    /// Parses responses strictly, with another maximum field length. See
+    /// `PlainServer::with_max_field_length`.
+    pub fn with_max_field_length(mut self, max_field_length: usize) -> Self {
+        self.max_field_length = Some(max_field_length);
+        self
+    }
 
-        (self.authenticator)(
-            std::str::from_utf8(identity)?,
-            std::str::from_utf8(username)?,
-            std::str::from_utf8(password)?,
-        )?;
+    /// Prepares usernames and passwords with SASLprep. See
+    /// `PlainServer::with_saslprep`.
+    #[cfg(feature = "saslprep")]
+    pub fn with_saslprep(mut self) -> Self {
+        self.saslprep = true;
+        self
+    }
+
+    /// Sets how credentials which aren't valid UTF-8 are decoded. See
+    /// `PlainServer::with_utf8_policy`.
+    pub fn with_utf8_policy(mut self, utf8_policy: Utf8Policy) -> Self {
+        self.utf8_policy = utf8_policy;
+        self
+    }
+
+    /// Creates a server verifying credentials with a verifier, which may be
+    /// shared with other servers.
+    pub fn from_verifier(verifier: Arc<dyn AsyncCredentialVerifier>) -> Self {
+        Self::new(Box::new(move |identity, username, password| {
+            let verifier = verifier.clone();
+            Box::pin(async move { verifier.verify(&identity, &username, &password).await })
+        }))
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl AsyncServer for AsyncPlainServer {
+    fn mechanism_name(&self) -> &str {
+        PLAIN
+    }
+
+    async fn next(&mut self, response: Option<&[u8]>) -> Result<sasl::ServerStep> {
+        if self.done {
+            bail!(sasl::ERR_UNEXPECTED_CLIENT_RESPONSE);
+        }
+
+        // No initial response, send an empty challenge
+        let response = match response {
+            Some(response) => response,
+            None => return Ok(sasl::ServerStep::Challenge(Vec::new())),
+        };
 
         self.done = true;
 
-        Ok((Vec::new(), true))
+        let fields = split(response, self.max_field_length)?;
+        let [identity, username, password] = decode_fields(fields, self.utf8_policy, self.max_field_length.is_some())?;
+        let password = prepare(self.saslprep, &password)?;
+        let username = canonicalize(self.canonicalizer.as_deref(), &prepare(self.saslprep, &username)?)?;
+        match &self.policy {
+            Some(policy) => {
+                (self.authenticator)(String::new(), username.clone(), password.to_string()).await?;
+                authorize(Some(policy.as_ref()), &username, &identity)?;
+            }
+            None => (self.authenticator)(identity.to_string(), username, password.to_string()).await?,
+        }
+
+        Ok(sasl::ServerStep::Done { additional_data: None })
+    }
+
+    fn is_done(&self) -> bool {
+        self.done
+    }
+
+    fn reset(&mut self) -> Result<()> {
+        self.done = false;
+        Ok(())
     }
 }
 
@@ -100,9 +548,142 @@ fn test_new_plain_client() -> Result<()> {
     }
 
     let expected = vec!(105, 100, 101, 110, 116, 105, 116, 121, 0, 117, 115, 101, 114, 110, 97, 109, 101, 0, 112, 97, 115, 115, 119, 111, 114, 100);
-    if ir != expected {
+    if ir != Some(expected) {
         bail!("Invalid initial response: {:?}", ir);
     }
 
     Ok(())
-}
\ No newline at end of file
+}
+#[test]
+fn test_plain_server() -> Result<()> {
+    use crate::sasl::{Server, ServerStep};
+
+    let mut s = PlainServer::new(Box::new(|identity, username, password| {
+        if !identity.is_empty() || username != "username" || password != "password" {
+            bail!("Invalid credentials");
+        }
+        Ok(())
+    }));
+
+    if s.mechanism_name() != PLAIN {
+        bail!("Invalid mechanism name: {}", s.mechanism_name());
+    }
+    if s.next(None)? != ServerStep::Challenge(Vec::new()) {
+        bail!("Expected an empty challenge");
+    }
+    if s.next(Some(b"\x00username\x00password"))? != (ServerStep::Done { additional_data: None }) {
+        bail!("Expected authentication to be done");
+    }
+    if !s.is_done() || s.next(Some(b"\x00username\x00password")).is_ok() {
+        bail!("Expected the exchange to be over");
+    }
+
+    s.reset()?;
+    if s.is_done() || !s.next(Some(b"\x00username\x00password"))?.is_done() {
+        bail!("Expected the server to be reusable after reset");
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_strict_plain_server() -> Result<()> {
+    use crate::sasl::Server;
+
+    let new_server = || PlainServer::new(Box::new(|_, _, _| Ok(()))).with_max_field_length(8);
+    new_server().next(Some(b"\x00username\x00password"))?;
+
+    let cases = [
+        (&b"\x00username"[..], PlainError::MissingField),
+        (b"admin\x00username\x00password\x00extra", PlainError::ExtraField),
+        (b"\x00\x00password", PlainError::EmptyUsername),
+        (b"\x00username\x00", PlainError::EmptyPassword),
+        (b"\x00username\x00password1", PlainError::FieldTooLong),
+        (b"\x00user\xff\x00password", PlainError::InvalidUtf8),
+    ];
+    for (response, expected) in cases {
+        match new_server().next(Some(response)) {
+            Err(err) if err.downcast_ref::<PlainError>() == Some(&expected) && FailureReason::of(&err) == FailureReason::MalformedResponse => {}
+            result => bail!("Expected {:?} for {:?}, got {:?}", expected, response, result),
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_non_utf8_credentials() -> Result<()> {
+    use crate::sasl::Server;
+
+    let response = b"\x00user\x00caf\xe9";
+    let authenticator = || -> PlainAuthenticator {
+        Box::new(|_, _, password| match password {
+            "café" => Ok(()),
+            _ => bail!(FailureReason::InvalidCredentials),
+        })
+    };
+    if PlainServer::new(authenticator()).next(Some(response)).is_ok() {
+        bail!("Expected ISO-8859-1 to be rejected by default");
+    }
+    PlainServer::new(authenticator()).with_utf8_policy(Utf8Policy::Latin1).next(Some(response))?;
+    PlainServer::new(authenticator()).with_utf8_policy(Utf8Policy::Latin1).next(Some("\x00user\x00café".as_bytes()))?;
+
+    let mut s = PlainServer::from_bytes(Box::new(|_, _, password| match password {
+        b"caf\xe9" => Ok(()),
+        _ => bail!(FailureReason::InvalidCredentials),
+    }));
+    s.next(Some(response))?;
+    if s.authentication_id() != Some("user") {
+        bail!("Unexpected authentication identity: {:?}", s.authentication_id());
+    }
+
+    // Options needing decoded credentials aren't ignored by bytes servers.
+    let bytes_server = || PlainServer::from_bytes(Box::new(|identity, _, _| match identity {
+        b"" => Ok(()),
+        _ => bail!("Expected the policy to decide on the identity"),
+    }));
+    let policy = Arc::new(crate::authorization::ProxyPolicy::new().with_grant("user", "admin"));
+    bytes_server().with_authorization_policy(policy.clone()).next(Some(b"admin\x00user\x00caf\xe9"))?;
+    if bytes_server().with_authorization_policy(policy).next(Some(b"other\x00user\x00caf\xe9")).is_ok() {
+        bail!("Expected the authorization policy to apply to bytes servers");
+    }
+    if bytes_server().with_canonicalizer(Arc::new(IdentityMapper::new())).next(Some(response)).is_ok() {
+        bail!("Expected canonicalization to be refused by bytes servers");
+    }
+    match bytes_server().with_utf8_policy(Utf8Policy::Latin1).next(Some(response)) {
+        Err(err) if err.to_string() == ERR_RAW_CREDENTIALS => {}
+        result => bail!("Expected the UTF-8 policy to be refused by bytes servers, got {:?}", result),
+    }
+    if bytes_server().strict().next(Some(response)).is_ok() {
+        bail!("Expected strict bytes servers to require UTF-8");
+    }
+
+    #[cfg(feature = "login")]
+    {
+        use crate::login::LoginServer;
+
+        let mut s = LoginServer::new(Box::new(|_, password| match password {
+            "café" => Ok(()),
+            _ => bail!(FailureReason::InvalidCredentials),
+        }))
+        .with_utf8_policy(Utf8Policy::Latin1);
+        s.next(Some(b"user"))?;
+        s.next(Some(b"caf\xe9"))?;
+
+        let mut s = LoginServer::from_bytes(Box::new(|username, password| match (username, password) {
+            (b"us\xe9r", b"caf\xe9") => Ok(()),
+            _ => bail!(FailureReason::InvalidCredentials),
+        }));
+        s.next(Some(b"us\xe9r"))?;
+        s.next(Some(b"caf\xe9"))?;
+
+        let mut s = LoginServer::from_bytes(Box::new(|_, _| Ok(()))).with_utf8_policy(Utf8Policy::Latin1);
+        s.next(None)?;
+        match s.next(Some(b"us\xe9r")) {
+            Err(err) if err.to_string() == ERR_RAW_CREDENTIALS => {}
+            result => bail!("Expected the UTF-8 policy to be refused by LOGIN bytes servers, got {:?}", result),
+        }
+    }
+
+    Ok(())
+}
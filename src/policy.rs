@@ -0,0 +1,194 @@
+use crate::registry::Registry;
+use crate::sasl;
+
+use std::net::IpAddr;
+use std::sync::Arc;
+
+pub const ERR_MECHANISM_FORBIDDEN: &str = "sasl: mechanism forbidden by security policy";
+
+/// The security state of a connection.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ConnectionContext {
+    /// The connection is encrypted, e.g. with TLS.
+    pub tls: bool,
+    /// Channel binding data is available for the connection.
+    pub channel_binding: bool,
+    /// The security strength factor of the encryption layer, e.g. 128 for a
+    /// TLS connection using AES-128.
+    pub ssf: u32,
+    /// The address of the peer, if known, e.g. for audit events.
+    pub peer: Option<IpAddr>,
+    /// The hostname the client requested with TLS SNI, if any.
+    pub server_name: Option<String>,
+}
+
+/// Decides which mechanisms may be used on a connection, based on their
+/// properties.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SecurityPolicy {
+    /// Forbids mechanisms sending plaintext credentials, such as PLAIN and
+    /// LOGIN, on unencrypted connections.
+    pub require_tls_for_plaintext: bool,
+    pub forbid_anonymous: bool,
+    /// Only allows mechanisms supporting channel binding, on connections
+    /// where it is available.
+    pub require_channel_binding: bool,
+    /// The minimum security strength factor: the strongest of the
+    /// connection's and the mechanism's security layers must be at least as
+    /// strong, as in Cyrus SASL.
+    pub min_ssf: u32,
+    /// Mechanisms allowed regardless of `min_ssf`, e.g. `["ANONYMOUS"]`.
+    pub allow_below_min_ssf: Vec<String>,
+}
+
+impl Default for SecurityPolicy {
+    fn default() -> Self {
+        Self {
+            require_tls_for_plaintext: true,
+            forbid_anonymous: false,
+            require_channel_binding: false,
+            min_ssf: 0,
+            allow_below_min_ssf: Vec::new(),
+        }
+    }
+}
+
+impl SecurityPolicy {
+    /// Reports whether a mechanism may be used on a connection.
+    pub fn allows(&self, mechanism: &str, properties: &sasl::Properties, conn: &ConnectionContext) -> bool {
+        if properties.ssf.max(conn.ssf) < self.min_ssf
+            && !self.allow_below_min_ssf.iter().any(|m| m.eq_ignore_ascii_case(mechanism))
+        {
+            return false;
+        }
+        if self.require_tls_for_plaintext && properties.plaintext && !conn.tls {
+            return false;
+        }
+        if self.forbid_anonymous && properties.anonymous {
+            return false;
+        }
+        if self.require_channel_binding && !(properties.channel_binding && conn.channel_binding) {
+            return false;
+        }
+        true
+    }
+
+    /// Lists the server mechanisms of a registry which may be used on a
+    /// connection.
+    pub fn server_mechanisms<'a>(&self, registry: &'a Registry, conn: &ConnectionContext) -> Vec<&'a str> {
+        registry
+            .server_mechanisms()
+            .filter(|name| registry.properties(name).is_some_and(|p| self.allows(name, &p, conn)))
+            .collect()
+    }
+}
+
+/// Scores a mechanism name, higher scores being preferred.
+pub type PreferenceScorer = Arc<dyn Fn(&str) -> i32 + Send + Sync>;
+
+/// The order in which mechanisms are preferred, e.g. when a client picks one
+/// of the mechanisms advertised by a server.
+#[derive(Clone, Default)]
+pub enum Preference {
+    /// The built-in order of the negotiator, or the registration order of
+    /// the registry.
+    #[default]
+    Default,
+    /// The listed mechanisms, most preferred first, followed by the others.
+    List(Vec<String>),
+    /// Mechanisms sorted by descending score, e.g.
+    /// `Arc::new(|m| if m.ends_with("-PLUS") { 1 } else { 0 })`.
+    Score(PreferenceScorer),
+}
+
+impl Preference {
+    /// Sorts mechanisms, most preferred first. Mechanisms which are
+    /// preferred equally keep their order.
+    pub fn sort(&self, mechanisms: &mut [&str]) {
+        match self {
+            Preference::Default => {}
+            Preference::List(list) => mechanisms.sort_by_key(|m| {
+                list.iter()
+                    .position(|l| l.eq_ignore_ascii_case(m))
+                    .unwrap_or(list.len())
+            }),
+            Preference::Score(score) => mechanisms.sort_by_key(|m| std::cmp::Reverse(score(m))),
+        }
+    }
+}
+
+#[cfg(all(feature = "anonymous", feature = "external", feature = "plain"))]
+#[test]
+fn test_security_policy() -> anyhow::Result<()> {
+    use crate::anonymous::AnonymousServer;
+    use crate::external::ExternalServer;
+    use crate::plain::PlainServer;
+    use anyhow::bail;
+
+    let mut registry = Registry::new();
+    registry
+        .register_server("PLAIN", || Box::new(PlainServer::new(Box::new(|_, _, _| Ok(())))))
+        .register_server("EXTERNAL", || Box::new(ExternalServer::new(Box::new(|_| Ok(())))))
+        .register_server("ANONYMOUS", || Box::new(AnonymousServer::new(Box::new(|_| Ok(())))));
+
+    let cleartext = ConnectionContext::default();
+    let tls = ConnectionContext {
+        tls: true,
+        channel_binding: true,
+        ssf: 256,
+        ..Default::default()
+    };
+    let cases = [
+        (SecurityPolicy::default(), &cleartext, vec!["EXTERNAL", "ANONYMOUS"]),
+        (SecurityPolicy::default(), &tls, vec!["PLAIN", "EXTERNAL", "ANONYMOUS"]),
+        (
+            SecurityPolicy {
+                forbid_anonymous: true,
+                require_channel_binding: true,
+                ..Default::default()
+            },
+            &tls,
+            // Only EXTERNAL with the channel binding extension is bound.
+            if cfg!(feature = "external-channel-binding") { vec!["EXTERNAL"] } else { vec![] },
+        ),
+        (
+            SecurityPolicy {
+                min_ssf: 1,
+                allow_below_min_ssf: vec!["anonymous".to_string()],
+                ..Default::default()
+            },
+            &cleartext,
+            vec!["ANONYMOUS"],
+        ),
+    ];
+    for (policy, conn, expected) in cases {
+        if policy.server_mechanisms(&registry, conn) != expected {
+            bail!("Unexpected mechanisms for {:?} on {:?}", policy, conn);
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_preference() -> anyhow::Result<()> {
+    use anyhow::bail;
+
+    let mut mechanisms = ["PLAIN", "SCRAM-SHA-256", "SCRAM-SHA-256-PLUS", "NTLM"];
+    Preference::List(vec!["scram-sha-256".to_string(), "PLAIN".to_string()]).sort(&mut mechanisms);
+    if mechanisms != ["SCRAM-SHA-256", "PLAIN", "SCRAM-SHA-256-PLUS", "NTLM"] {
+        bail!("Unexpected order: {:?}", mechanisms);
+    }
+
+    let score: PreferenceScorer = Arc::new(|m| match m {
+        m if m.ends_with("-PLUS") => 1,
+        "NTLM" => -1,
+        _ => 0,
+    });
+    Preference::Score(score).sort(&mut mechanisms);
+    if mechanisms != ["SCRAM-SHA-256-PLUS", "SCRAM-SHA-256", "PLAIN", "NTLM"] {
+        bail!("Unexpected order: {:?}", mechanisms);
+    }
+
+    Ok(())
+}
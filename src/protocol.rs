@@ -0,0 +1,285 @@
+//! Adapters framing SASL exchanges in application protocols, for client and
+//! server implementations of those protocols. They don't perform any I/O:
+//! callers pass the messages they receive and send the messages returned.
+
+pub mod amqp;
+pub mod cassandra;
+pub mod http;
+pub mod imap;
+pub mod kafka;
+pub mod ldap;
+pub mod memcached;
+pub mod mongodb;
+pub mod mqtt;
+pub mod nntp;
+pub mod pop3;
+pub mod postgres;
+pub mod sasl2;
+pub mod smtp;
+
+use crate::dispatcher::{self, Exchange, ServerDispatcher};
+use crate::framing;
+use crate::policy::ConnectionContext;
+use crate::replay::ReplayGuard;
+use crate::sasl;
+
+use anyhow::{anyhow, Result};
+use std::sync::Arc;
+
+/// The next step of a client exchange.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ClientStep {
+    /// Send a line to the server, without its line terminator, and pass
+    /// its reply.
+    Send(String),
+    /// Nothing to send, pass the next line received, e.g. the rest of a
+    /// multi-line reply.
+    Wait,
+    /// Authentication succeeded.
+    Done,
+}
+
+/// The reply of a server to a client command or response.
+#[derive(Debug)]
+pub enum ServerReply {
+    /// Send a challenge and pass the client's response.
+    Challenge(String),
+    /// Authentication succeeded, send the reply.
+    Success(String),
+    /// Authentication failed or was canceled, send the reply. The error
+    /// describes the failure, e.g. for auditing, and must not be disclosed
+    /// to the client.
+    Failure(String, anyhow::Error),
+}
+
+impl ServerReply {
+    /// Returns the line to send, without its line terminator.
+    pub fn line(&self) -> &str {
+        match self {
+            ServerReply::Challenge(line) | ServerReply::Success(line) | ServerReply::Failure(line, _) => line,
+        }
+    }
+
+    /// Reports whether the exchange is over.
+    pub fn is_done(&self) -> bool {
+        !matches!(self, ServerReply::Challenge(_))
+    }
+}
+
+/// Returns the error to report when the server rejected a client: the one
+/// its mechanism received, e.g. the error challenge of OAUTHBEARER, or
+/// `sasl::ERR_AUTHENTICATION_FAILED`.
+pub(crate) fn authentication_failed(client: &mut impl sasl::Client) -> anyhow::Error {
+    client.failure().unwrap_or_else(|| anyhow!(sasl::ERR_AUTHENTICATION_FAILED))
+}
+
+/// The client side of an exchange in a line-based protocol, shared by the
+/// adapters.
+pub(crate) struct LineClient<C> {
+    client: C,
+    deferred_ir: Option<Vec<u8>>,
+    replay_guard: Option<Arc<ReplayGuard>>,
+    started: bool,
+    done: bool,
+    canceled: bool,
+}
+
+impl<C: sasl::Client> LineClient<C> {
+    pub(crate) fn new(client: C) -> Self {
+        Self {
+            client,
+            deferred_ir: None,
+            replay_guard: None,
+            started: false,
+            done: false,
+            canceled: false,
+        }
+    }
+
+    /// Checks the challenges with a guard shared with previous exchanges.
+    pub(crate) fn set_replay_guard(&mut self, guard: Arc<ReplayGuard>) {
+        self.replay_guard = Some(guard);
+    }
+
+    /// Starts the client, returning the mechanism and initial response. See
+    /// `sasl::Client::start`.
+    pub(crate) fn start(&mut self) -> Result<(String, Option<Vec<u8>>)> {
+        let started = self.client.start()?;
+        self.started = true;
+        Ok(started)
+    }
+
+    /// Keeps an initial response which couldn't be sent with the command,
+    /// to answer the first challenge with. It should be empty, but servers
+    /// for mechanisms such as LOGIN send a prompt instead.
+    pub(crate) fn defer(&mut self, ir: Vec<u8>) {
+        self.deferred_ir = Some(ir);
+    }
+
+    /// Answers a challenge line starting with `prefix`.
+    pub(crate) fn challenge(&mut self, prefix: &str, line: &str) -> Result<ClientStep> {
+        let challenge = framing::decode_challenge(prefix, line)?;
+        let response = match self.deferred_ir.take() {
+            Some(ir) => ir,
+            None => {
+                if let Some(guard) = &self.replay_guard {
+                    guard.check(self.client.mechanism_name(), &challenge)?;
+                }
+                self.client.next(&challenge)?
+            }
+        };
+        Ok(ClientStep::Send(framing::encode_response(&response)))
+    }
+
+    /// Completes the exchange once the server reported success.
+    pub(crate) fn success(&mut self, data: Option<&[u8]>) -> Result<ClientStep> {
+        self.done = true;
+        self.client.finish(data)?;
+        Ok(ClientStep::Done)
+    }
+
+    /// Ends the exchange with an error, unless the mechanism received a
+    /// more specific one from the server.
+    pub(crate) fn fail(&mut self, err: anyhow::Error) -> Result<ClientStep> {
+        self.done = true;
+        Err(self.client.failure().unwrap_or(err))
+    }
+
+    /// Aborts the exchange, returning the line to send instead of a
+    /// response: the mechanism's own cancel response if it has one, `*`
+    /// otherwise.
+    pub(crate) fn cancel(&mut self) -> String {
+        self.canceled = true;
+        match self.client.cancel() {
+            Some(response) => framing::encode_response(&response),
+            None => dispatcher::CANCEL.to_string(),
+        }
+    }
+
+    pub(crate) fn is_canceled(&self) -> bool {
+        self.canceled
+    }
+
+    pub(crate) fn is_in_progress(&self) -> bool {
+        self.started && !self.done
+    }
+
+    pub(crate) fn into_inner(self) -> C {
+        self.client
+    }
+}
+
+/// The outcome of a step of a server exchange in a line-based protocol,
+/// turned into a reply by the adapters.
+pub(crate) enum LineOutcome {
+    Challenge(String),
+    /// Authentication succeeded, with additional data if the protocol can
+    /// carry it.
+    Success(Option<Vec<u8>>),
+    /// Authentication failed, or was canceled with `sasl::Error::Canceled`.
+    Failure(anyhow::Error),
+    /// The client sent a line which couldn't be decoded.
+    Malformed(anyhow::Error),
+}
+
+/// The server side of an exchange in a line-based protocol, shared by the
+/// adapters.
+pub(crate) struct LineServer {
+    continuation: &'static str,
+    success_data: bool,
+    exchange: Option<Exchange>,
+    success_pending: bool,
+}
+
+impl LineServer {
+    pub(crate) fn new(continuation: &'static str) -> Self {
+        Self {
+            continuation,
+            success_data: false,
+            exchange: None,
+            success_pending: false,
+        }
+    }
+
+    /// Returns additional data with success in the outcome, for protocols
+    /// which can carry it, instead of sending it as a final challenge.
+    pub(crate) fn with_success_data(mut self) -> Self {
+        self.success_data = true;
+        self
+    }
+
+    /// Starts an exchange with a dispatcher. Dispatcher errors are returned
+    /// as failures.
+    pub(crate) fn start(
+        &mut self,
+        dispatcher: &ServerDispatcher,
+        conn: &ConnectionContext,
+        mechanism: &str,
+        ir: Option<&[u8]>,
+    ) -> LineOutcome {
+        match dispatcher.start(conn, mechanism, ir) {
+            Ok((exchange, step)) => {
+                self.exchange = Some(exchange);
+                self.step(Ok(step))
+            }
+            Err(err) => LineOutcome::Failure(err),
+        }
+    }
+
+    /// Handles a client response line.
+    pub(crate) fn response(&mut self, line: &str) -> LineOutcome {
+        let exchange = match &mut self.exchange {
+            Some(exchange) => exchange,
+            None => return LineOutcome::Malformed(anyhow!(sasl::ERR_UNEXPECTED_CLIENT_RESPONSE)),
+        };
+        let outcome = match framing::decode_response(line) {
+            Ok(None) => LineOutcome::Failure(exchange.cancel()),
+            Ok(Some(response)) if self.success_pending => {
+                if !response.is_empty() {
+                    LineOutcome::Failure(anyhow!(sasl::ERR_UNEXPECTED_CLIENT_RESPONSE))
+                } else {
+                    LineOutcome::Success(None)
+                }
+            }
+            Ok(Some(response)) => {
+                let step = exchange.next(&response);
+                return self.step(step);
+            }
+            Err(err) => LineOutcome::Malformed(err),
+        };
+        self.abort();
+        outcome
+    }
+
+    fn step(&mut self, step: Result<sasl::ServerStep>) -> LineOutcome {
+        let outcome = match step {
+            Ok(sasl::ServerStep::Challenge(challenge)) => {
+                return LineOutcome::Challenge(framing::encode_challenge(self.continuation, &challenge));
+            }
+            Ok(sasl::ServerStep::Done { additional_data: None }) => LineOutcome::Success(None),
+            Ok(sasl::ServerStep::Done { additional_data: Some(data) }) if self.success_data => LineOutcome::Success(Some(data)),
+            // Otherwise additional data with success is sent as a final
+            // challenge, expecting an empty response.
+            Ok(sasl::ServerStep::Done { additional_data: Some(data) }) => {
+                self.success_pending = true;
+                return LineOutcome::Challenge(framing::encode_challenge(self.continuation, &data));
+            }
+            Err(err) => LineOutcome::Failure(err),
+        };
+        self.abort();
+        outcome
+    }
+
+    /// Ends the exchange, e.g. once a reply reporting the outcome was sent.
+    pub(crate) fn abort(&mut self) {
+        self.exchange = None;
+    }
+
+    pub(crate) fn mechanism_name(&self) -> Option<&str> {
+        self.exchange.as_ref().map(|exchange| exchange.mechanism_name())
+    }
+
+    pub(crate) fn is_done(&self) -> bool {
+        self.exchange.is_none()
+    }
+}
@@ -0,0 +1,224 @@
+//! AMQP SASL authentication, for both protocol families:
+//!
+//! - AMQP 0-9-1, where the server lists its mechanisms in
+//!   `connection.start`, the client answers with `connection.start-ok` and
+//!   challenges are carried by `connection.secure` and
+//!   `connection.secure-ok`. Success is signaled by `connection.tune`,
+//!   without additional data, failure by `connection.close`.
+//! - AMQP 1.0 (section 5.3 of the specification), where the server sends a
+//!   `sasl-mechanisms` frame, the client a `sasl-init` frame, challenges are
+//!   carried by `sasl-challenge` and `sasl-response` frames and the
+//!   exchange ends with a `sasl-outcome` frame.
+//!
+//! Encoding methods and frames is left to the AMQP implementation.
+
+use super::authentication_failed;
+use crate::sasl;
+
+use anyhow::{anyhow, bail, Result};
+
+pub const ERR_MECHANISM_UNSUPPORTED: &str = "sasl: amqp: mechanism not supported by the server";
+pub const ERR_UNEXPECTED_FRAME: &str = "sasl: amqp: unexpected frame";
+pub const ERR_CONNECTION_CLOSED: &str = "sasl: amqp: connection closed by the server";
+pub const ERR_SYSTEM_ERROR: &str = "sasl: amqp: system error";
+
+/// The `connection.close` reply code of authentication failures (AMQP
+/// 0-9-1).
+pub const ACCESS_REFUSED: u16 = 403;
+
+/// The codes of `sasl-outcome` frames (AMQP 1.0).
+pub const OK: u8 = 0;
+pub const AUTH: u8 = 1;
+pub const SYS: u8 = 2;
+pub const SYS_PERM: u8 = 3;
+pub const SYS_TEMP: u8 = 4;
+
+/// The SASL fields of a `connection.start-ok` method (AMQP 0-9-1).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StartOk {
+    pub mechanism: String,
+    pub response: Vec<u8>,
+}
+
+/// Drives a client through AMQP 0-9-1 authentication.
+pub struct Amqp091Client<C> {
+    client: C,
+    started: bool,
+    done: bool,
+}
+
+impl<C: sasl::Client> Amqp091Client<C> {
+    pub fn new(client: C) -> Self {
+        Self {
+            client,
+            started: false,
+            done: false,
+        }
+    }
+
+    /// Handles the space-separated `mechanisms` of `connection.start` and
+    /// returns the fields of `connection.start-ok`.
+    pub fn start(&mut self, mechanisms: &[u8]) -> Result<StartOk> {
+        if self.started {
+            bail!(ERR_UNEXPECTED_FRAME);
+        }
+        let name = self.client.mechanism_name().as_bytes();
+        if !mechanisms.split(|&b| b == b' ').any(|m| m == name) {
+            bail!(ERR_MECHANISM_UNSUPPORTED);
+        }
+        let (mechanism, ir) = self.client.start()?;
+        self.started = true;
+        Ok(StartOk {
+            mechanism,
+            response: ir.unwrap_or_default(),
+        })
+    }
+
+    /// Handles the challenge of `connection.secure` and returns the
+    /// response of `connection.secure-ok`.
+    pub fn secure(&mut self, challenge: &[u8]) -> Result<Vec<u8>> {
+        if !self.started || self.done {
+            bail!(ERR_UNEXPECTED_FRAME);
+        }
+        self.client.next(challenge)
+    }
+
+    /// Handles `connection.tune`, which ends the exchange successfully.
+    pub fn tune(&mut self) -> Result<()> {
+        if !self.started || self.done {
+            bail!(ERR_UNEXPECTED_FRAME);
+        }
+        self.done = true;
+        self.client.finish(None)
+    }
+
+    /// Handles `connection.close` received during the exchange, returning
+    /// the error to report.
+    pub fn close(&mut self, reply_code: u16) -> anyhow::Error {
+        self.done = true;
+        match reply_code {
+            ACCESS_REFUSED => authentication_failed(&mut self.client),
+            _ => anyhow!(ERR_CONNECTION_CLOSED),
+        }
+    }
+
+    pub fn into_inner(self) -> C {
+        self.client
+    }
+}
+
+/// A `sasl-init` frame (AMQP 1.0).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SaslInit {
+    pub mechanism: String,
+    pub initial_response: Option<Vec<u8>>,
+    pub hostname: Option<String>,
+}
+
+/// A `sasl-outcome` frame (AMQP 1.0).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SaslOutcome {
+    pub code: u8,
+    pub additional_data: Option<Vec<u8>>,
+}
+
+/// Drives a client through AMQP 1.0 SASL negotiation.
+pub struct Amqp10Client<C> {
+    client: C,
+    hostname: Option<String>,
+    started: bool,
+    done: bool,
+}
+
+impl<C: sasl::Client> Amqp10Client<C> {
+    pub fn new(client: C) -> Self {
+        Self {
+            client,
+            hostname: None,
+            started: false,
+            done: false,
+        }
+    }
+
+    /// Sets the `hostname` of `sasl-init`, for virtual hosting.
+    pub fn with_hostname(mut self, hostname: &str) -> Self {
+        self.hostname = Some(hostname.to_string());
+        self
+    }
+
+    /// Handles the `sasl-server-mechanisms` of `sasl-mechanisms` and
+    /// returns the `sasl-init` frame.
+    pub fn mechanisms(&mut self, mechanisms: &[&str]) -> Result<SaslInit> {
+        if self.started {
+            bail!(ERR_UNEXPECTED_FRAME);
+        }
+        if !mechanisms.contains(&self.client.mechanism_name()) {
+            bail!(ERR_MECHANISM_UNSUPPORTED);
+        }
+        let (mechanism, initial_response) = self.client.start()?;
+        self.started = true;
+        Ok(SaslInit {
+            mechanism,
+            initial_response,
+            hostname: self.hostname.clone(),
+        })
+    }
+
+    /// Handles a `sasl-challenge` frame and returns the `response` of the
+    /// `sasl-response` frame.
+    pub fn challenge(&mut self, challenge: &[u8]) -> Result<Vec<u8>> {
+        if !self.started || self.done {
+            bail!(ERR_UNEXPECTED_FRAME);
+        }
+        self.client.next(challenge)
+    }
+
+    /// Handles the `sasl-outcome` frame.
+    pub fn outcome(&mut self, outcome: &SaslOutcome) -> Result<()> {
+        if !self.started || self.done {
+            bail!(ERR_UNEXPECTED_FRAME);
+        }
+        self.done = true;
+        match outcome.code {
+            OK => self.client.finish(outcome.additional_data.as_deref()),
+            AUTH => Err(authentication_failed(&mut self.client)),
+            _ => bail!(ERR_SYSTEM_ERROR),
+        }
+    }
+
+    pub fn into_inner(self) -> C {
+        self.client
+    }
+}
+
+#[cfg(feature = "login")]
+#[test]
+fn test_amqp_clients() -> Result<()> {
+    use crate::login::LoginClient;
+
+    let new_client = || LoginClient::new("username".to_string(), "password".to_string());
+
+    let mut client = Amqp091Client::new(new_client());
+    let start_ok = client.start(b"AMQPLAIN LOGIN PLAIN")?;
+    if start_ok.mechanism != "LOGIN" || start_ok.response != b"username" || client.secure(b"Password:")? != b"password" {
+        bail!("Unexpected exchange: {:?}", start_ok);
+    }
+    client.tune()?;
+    match Amqp091Client::new(new_client()).start(b"PLAIN LOGINS") {
+        Err(err) if err.to_string() == ERR_MECHANISM_UNSUPPORTED => {}
+        _ => bail!("Expected LOGIN to be unsupported"),
+    }
+
+    let mut client = Amqp10Client::new(new_client()).with_hostname("example.com");
+    let init = client.mechanisms(&["PLAIN", "LOGIN"])?;
+    if init.initial_response.as_deref() != Some(b"username") || init.hostname.as_deref() != Some("example.com") {
+        bail!("Unexpected sasl-init: {:?}", init);
+    }
+    client.challenge(b"Password:")?;
+    match client.outcome(&SaslOutcome { code: AUTH, additional_data: None }) {
+        Err(err) if err.to_string() == sasl::ERR_AUTHENTICATION_FAILED => {}
+        _ => bail!("Expected authentication to fail"),
+    }
+
+    Ok(())
+}
@@ -0,0 +1,229 @@
+//! Cassandra native protocol authentication (section 4.2.3 of the protocol
+//! specification). The server answers STARTUP with AUTHENTICATE, naming its
+//! authenticator class rather than a mechanism; the client then sends
+//! AUTH_RESPONSE tokens, answered by AUTH_CHALLENGE until the server
+//! replies AUTH_SUCCESS, or ERROR on failure.
+//!
+//! Authenticator classes are mapped to mechanisms: the stock
+//! `PasswordAuthenticator` expects PLAIN tokens. DataStax's
+//! `DseAuthenticator` supports several mechanisms, which clients select by
+//! sending the mechanism name as the first token; the server then sends a
+//! `<mechanism>-START` challenge.
+//!
+//! Encoding frames is left to the driver.
+
+use super::authentication_failed;
+use crate::dispatcher::{self, Exchange, ServerDispatcher};
+use crate::policy::{ConnectionContext, ERR_MECHANISM_FORBIDDEN};
+use crate::sasl;
+
+use anyhow::{anyhow, bail, Result};
+
+pub const ERR_UNSUPPORTED_AUTHENTICATOR: &str = "sasl: cassandra: authenticator class not supported by the client";
+pub const ERR_UNEXPECTED_MESSAGE: &str = "sasl: cassandra: unexpected message";
+
+/// The codes of ERROR messages.
+pub const SERVER_ERROR: i32 = 0x0000;
+pub const PROTOCOL_ERROR: i32 = 0x000A;
+pub const BAD_CREDENTIALS: i32 = 0x0100;
+
+pub const PASSWORD_AUTHENTICATOR: &str = "org.apache.cassandra.auth.PasswordAuthenticator";
+pub const DSE_AUTHENTICATOR: &str = "com.datastax.bdp.cassandra.auth.DseAuthenticator";
+
+const PLAIN: &str = "PLAIN";
+
+/// Drives a client through authentication.
+pub struct CassandraClient<C> {
+    client: C,
+    authenticators: Vec<(String, String)>,
+    /// Whether the mechanism was sent and the `-START` challenge is
+    /// awaited.
+    selecting: bool,
+    started: bool,
+    done: bool,
+}
+
+impl<C: sasl::Client> CassandraClient<C> {
+    pub fn new(client: C) -> Self {
+        Self {
+            client,
+            authenticators: vec![(PASSWORD_AUTHENTICATOR.to_string(), PLAIN.to_string())],
+            selecting: false,
+            started: false,
+            done: false,
+        }
+    }
+
+    /// Maps a custom authenticator class to the mechanism whose tokens it
+    /// expects.
+    pub fn with_authenticator(mut self, class_name: &str, mechanism: &str) -> Self {
+        self.authenticators.push((class_name.to_string(), mechanism.to_string()));
+        self
+    }
+
+    /// Handles the authenticator class of AUTHENTICATE and returns the
+    /// token of the first AUTH_RESPONSE.
+    pub fn authenticate(&mut self, class_name: &str) -> Result<Vec<u8>> {
+        if self.started || self.selecting {
+            bail!(ERR_UNEXPECTED_MESSAGE);
+        }
+        let mechanism = self.client.mechanism_name();
+        if class_name == DSE_AUTHENTICATOR {
+            self.selecting = true;
+            return Ok(mechanism.as_bytes().to_vec());
+        }
+        if !self.authenticators.iter().any(|(class, m)| class == class_name && m == mechanism) {
+            bail!(ERR_UNSUPPORTED_AUTHENTICATOR);
+        }
+        self.start()
+    }
+
+    fn start(&mut self) -> Result<Vec<u8>> {
+        let (_, ir) = self.client.start()?;
+        self.started = true;
+        Ok(ir.unwrap_or_default())
+    }
+
+    /// Handles the token of AUTH_CHALLENGE and returns the token of the
+    /// next AUTH_RESPONSE.
+    pub fn challenge(&mut self, token: &[u8]) -> Result<Vec<u8>> {
+        if self.selecting {
+            self.selecting = false;
+            if token != format!("{}-START", self.client.mechanism_name()).as_bytes() {
+                bail!(ERR_UNEXPECTED_MESSAGE);
+            }
+            return self.start();
+        }
+        if !self.started || self.done {
+            bail!(ERR_UNEXPECTED_MESSAGE);
+        }
+        self.client.next(token)
+    }
+
+    /// Handles the token of AUTH_SUCCESS, which may be null.
+    pub fn success(&mut self, token: Option<&[u8]>) -> Result<()> {
+        if !self.started || self.done {
+            bail!(ERR_UNEXPECTED_MESSAGE);
+        }
+        self.done = true;
+        self.client.finish(token)
+    }
+
+    /// Handles an ERROR message received during authentication, returning
+    /// the error to report.
+    pub fn error(&mut self, code: i32) -> anyhow::Error {
+        self.done = true;
+        match code {
+            BAD_CREDENTIALS => authentication_failed(&mut self.client),
+            _ => anyhow!(ERR_UNEXPECTED_MESSAGE),
+        }
+    }
+
+    pub fn into_inner(self) -> C {
+        self.client
+    }
+}
+
+/// The reply of a server to AUTH_RESPONSE.
+#[derive(Debug)]
+pub enum CassandraReply {
+    /// Send AUTH_CHALLENGE with the token.
+    Challenge(Vec<u8>),
+    /// Send AUTH_SUCCESS with the token, null if there is none.
+    Success(Option<Vec<u8>>),
+    /// Send ERROR with the code. The error describes the failure, e.g. for
+    /// auditing, and must not be disclosed to the client.
+    Failure(i32, anyhow::Error),
+}
+
+/// Handles authentication on the server side, e.g. in a proxy, creating
+/// servers with a `ServerDispatcher`. One must be kept per connection.
+pub struct CassandraServer {
+    class_name: String,
+    mechanism: String,
+    exchange: Option<Exchange>,
+}
+
+impl CassandraServer {
+    /// Creates a server advertising `class_name` and running `mechanism`.
+    pub fn new(class_name: &str, mechanism: &str) -> Self {
+        Self {
+            class_name: class_name.to_string(),
+            mechanism: mechanism.to_string(),
+            exchange: None,
+        }
+    }
+
+    /// Returns the authenticator class of AUTHENTICATE.
+    pub fn authenticator(&self) -> &str {
+        &self.class_name
+    }
+
+    /// Handles the token of AUTH_RESPONSE.
+    pub fn auth_response(&mut self, dispatcher: &ServerDispatcher, conn: &ConnectionContext, token: &[u8]) -> CassandraReply {
+        let step = match &mut self.exchange {
+            Some(exchange) => exchange.next(token),
+            None => match dispatcher.start(conn, &self.mechanism, (!token.is_empty()).then_some(token)) {
+                Ok((exchange, step)) => {
+                    self.exchange = Some(exchange);
+                    Ok(step)
+                }
+                Err(err) => Err(err),
+            },
+        };
+        let reply = match step {
+            Ok(sasl::ServerStep::Challenge(challenge)) => return CassandraReply::Challenge(challenge),
+            Ok(sasl::ServerStep::Done { additional_data }) => CassandraReply::Success(additional_data),
+            Err(err) if err.to_string() == dispatcher::ERR_UNKNOWN_MECHANISM || err.to_string() == ERR_MECHANISM_FORBIDDEN => {
+                CassandraReply::Failure(SERVER_ERROR, err)
+            }
+            Err(err) => CassandraReply::Failure(BAD_CREDENTIALS, err),
+        };
+        self.exchange = None;
+        reply
+    }
+}
+
+#[cfg(feature = "plain")]
+#[test]
+fn test_cassandra_exchange() -> Result<()> {
+    use crate::plain::{PlainClient, PlainServer};
+    use crate::registry::Registry;
+
+    let mut registry = Registry::new();
+    registry.register_server(PLAIN, || {
+        Box::new(PlainServer::new(Box::new(|_, _, p| match p {
+            "password" => Ok(()),
+            _ => bail!("invalid password"),
+        })))
+    });
+    let dispatcher = ServerDispatcher::new(registry);
+    let conn = ConnectionContext {
+        tls: true,
+        ..Default::default()
+    };
+    let new_client = |password: &str| CassandraClient::new(PlainClient::new(String::new(), "username".to_string(), password.to_string()));
+
+    let mut server = CassandraServer::new(PASSWORD_AUTHENTICATOR, PLAIN);
+    let mut client = new_client("password");
+    match server.auth_response(&dispatcher, &conn, &client.authenticate(server.authenticator())?) {
+        CassandraReply::Success(token) => client.success(token.as_deref())?,
+        reply => bail!("Unexpected reply: {:?}", reply),
+    }
+    let mut client = new_client("wrong");
+    match server.auth_response(&dispatcher, &conn, &client.authenticate(server.authenticator())?) {
+        CassandraReply::Failure(code, _) if client.error(code).to_string() == sasl::ERR_AUTHENTICATION_FAILED => {}
+        reply => bail!("Unexpected reply: {:?}", reply),
+    }
+
+    let mut client = new_client("password");
+    if client.authenticate(DSE_AUTHENTICATOR)? != b"PLAIN" || client.challenge(b"PLAIN-START")? != b"\x00username\x00password" {
+        bail!("Unexpected DseAuthenticator exchange");
+    }
+    match new_client("password").authenticate("com.example.CustomAuthenticator") {
+        Err(err) if err.to_string() == ERR_UNSUPPORTED_AUTHENTICATOR => {}
+        _ => bail!("Expected an unsupported authenticator"),
+    }
+
+    Ok(())
+}
@@ -0,0 +1,169 @@
+//! HTTP authentication helpers: the `Negotiate` scheme (RFC 4559), carrying
+//! GSSAPI or SPNEGO tokens in `Authorization` and `WWW-Authenticate`
+//! headers, and the `Bearer` scheme (RFC 6750), sharing the OAUTHBEARER
+//! token and error types.
+//!
+//! The crate doesn't implement SPNEGO: `NegotiateClient` drives any
+//! `sasl::Client` producing the tokens, e.g. one backed by the platform's
+//! GSSAPI library.
+
+use super::authentication_failed;
+use crate::framing;
+#[cfg(feature = "oauthbearer")]
+use crate::oauthbearer::{OAuthBearerError, OAuthToken};
+use crate::sasl;
+
+use anyhow::{anyhow, bail, Result};
+
+pub const ERR_NO_NEGOTIATE_CHALLENGE: &str = "sasl: http: no Negotiate challenge";
+pub const ERR_UNEXPECTED_CHALLENGE: &str = "sasl: http: unexpected challenge";
+
+pub const NEGOTIATE: &str = "Negotiate";
+pub const BEARER: &str = "Bearer";
+
+/// Returns the parameters of the `scheme` challenge in a `WWW-Authenticate`
+/// header value, or `None` if there is no such challenge. Challenges are
+/// separated by commas, like their parameters: parameters of a challenge
+/// follow it until the next element which isn't a `name=value` pair.
+/// Quoted values containing commas aren't supported.
+fn challenge<'a>(header: &'a str, scheme: &str) -> Option<Vec<&'a str>> {
+    let mut elements = header.split(',').map(str::trim).skip_while(|element| {
+        let name = element.split(' ').next().unwrap_or_default();
+        !name.eq_ignore_ascii_case(scheme)
+    });
+    let first = elements.next()?;
+    let mut params: Vec<&str> = first.split_once(' ').map(|(_, rest)| rest.trim()).into_iter().collect();
+    params.extend(elements.take_while(|element| element.split_once('=').is_some_and(|(name, _)| !name.trim().contains(' '))));
+    Some(params.into_iter().filter(|param| !param.is_empty()).collect())
+}
+
+/// Parses the `Negotiate` challenge of a `WWW-Authenticate` header value,
+/// returning its token if it carries one.
+pub fn parse_negotiate(header: &str) -> Result<Option<Vec<u8>>> {
+    let params = challenge(header, NEGOTIATE).ok_or_else(|| anyhow!(ERR_NO_NEGOTIATE_CHALLENGE))?;
+    match params.first() {
+        Some(token) => Ok(Some(framing::base64_decode(token)?)),
+        None => Ok(None),
+    }
+}
+
+/// Encodes an `Authorization` header value for the `Negotiate` scheme.
+pub fn encode_negotiate(token: &[u8]) -> String {
+    format!("{} {}", NEGOTIATE, framing::base64_encode(token))
+}
+
+/// Drives a client through `Negotiate` authentication.
+pub struct NegotiateClient<C> {
+    client: C,
+    started: bool,
+    done: bool,
+}
+
+impl<C: sasl::Client> NegotiateClient<C> {
+    pub fn new(client: C) -> Self {
+        Self {
+            client,
+            started: false,
+            done: false,
+        }
+    }
+
+    /// Starts the client and returns the `Authorization` header value of
+    /// the first request.
+    pub fn authorization(&mut self) -> Result<String> {
+        let (_, ir) = self.client.start()?;
+        self.started = true;
+        Ok(encode_negotiate(&ir.unwrap_or_default()))
+    }
+
+    /// Handles the `WWW-Authenticate` header values of a 401 response and
+    /// returns the `Authorization` header value of the next request. A
+    /// `Negotiate` challenge without a token means the server rejected
+    /// the credentials.
+    pub fn unauthorized(&mut self, www_authenticate: &[&str]) -> Result<String> {
+        if !self.started || self.done {
+            bail!(ERR_UNEXPECTED_CHALLENGE);
+        }
+        let token = www_authenticate.iter().find_map(|header| parse_negotiate(header).ok().flatten());
+        match token {
+            Some(token) => Ok(encode_negotiate(&self.client.next(&token)?)),
+            None => {
+                self.done = true;
+                Err(authentication_failed(&mut self.client))
+            }
+        }
+    }
+
+    /// Handles the `WWW-Authenticate` header value of a successful
+    /// response, carrying the server's final token for mutual
+    /// authentication, if any.
+    pub fn success(&mut self, www_authenticate: Option<&str>) -> Result<()> {
+        if !self.started || self.done {
+            bail!(ERR_UNEXPECTED_CHALLENGE);
+        }
+        self.done = true;
+        let token = match www_authenticate {
+            Some(header) => parse_negotiate(header)?,
+            None => None,
+        };
+        self.client.finish(token.as_deref())
+    }
+
+    pub fn into_inner(self) -> C {
+        self.client
+    }
+}
+
+/// Encodes an `Authorization` header value for the `Bearer` scheme.
+#[cfg(feature = "oauthbearer")]
+pub fn encode_bearer(token: &OAuthToken) -> String {
+    format!("{} {}", BEARER, token.token)
+}
+
+/// Parses the `Bearer` challenge of a `WWW-Authenticate` header value into
+/// the error it reports, e.g. to invalidate the token on `invalid_token`.
+/// The status is empty if the challenge carries no `error` parameter.
+#[cfg(feature = "oauthbearer")]
+pub fn parse_bearer(header: &str) -> Option<OAuthBearerError> {
+    let mut err = OAuthBearerError::new("").with_schemes("bearer");
+    for param in challenge(header, BEARER)? {
+        let Some((name, value)) = param.split_once('=') else {
+            continue;
+        };
+        let value = value.trim().trim_matches('"');
+        match name.trim() {
+            "error" => err.status = value.to_string(),
+            "scope" => err = err.with_scope(value),
+            _ => {}
+        }
+    }
+    Some(err)
+}
+
+#[cfg(all(feature = "plain", feature = "oauthbearer"))]
+#[test]
+fn test_http_authentication() -> Result<()> {
+    use crate::plain::PlainClient;
+
+    let mut client = NegotiateClient::new(PlainClient::new(String::new(), "username".to_string(), "password".to_string()));
+    if client.authorization()? != "Negotiate AHVzZXJuYW1lAHBhc3N3b3Jk" {
+        bail!("Unexpected Authorization header");
+    }
+    match client.unauthorized(&["Basic realm=\"example\"", "Negotiate"]) {
+        Err(err) if err.to_string() == sasl::ERR_AUTHENTICATION_FAILED => {}
+        _ => bail!("Expected a Negotiate challenge without token to fail"),
+    }
+    if parse_negotiate("Basic realm=\"a, b\", Negotiate dG9rZW4=")? != Some(b"token".to_vec()) {
+        bail!("Expected the Negotiate token to be parsed");
+    }
+
+    let err = parse_bearer("Bearer realm=\"example\", error=\"invalid_token\", scope=\"mail\", Negotiate");
+    if !err.is_some_and(|err| err.status == "invalid_token" && err.scope.as_deref() == Some("mail")) {
+        bail!("Expected the Bearer error to be parsed");
+    }
+    if encode_bearer(&OAuthToken::new("token".to_string())) != "Bearer token" || parse_bearer("Negotiate").is_some() {
+        bail!("Unexpected Bearer helpers");
+    }
+
+    Ok(())
+}
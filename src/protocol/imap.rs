@@ -0,0 +1,281 @@
+//! IMAP `AUTHENTICATE` exchanges (RFC 3501 section 6.2.2), with initial
+//! responses as per SASL-IR (RFC 4959).
+
+use super::{ClientStep, LineClient, LineOutcome, LineServer, ServerReply};
+use crate::dispatcher::{self, ServerDispatcher};
+use crate::framing::{self, IMAP_CONTINUATION};
+use crate::policy::{ConnectionContext, ERR_MECHANISM_FORBIDDEN};
+use crate::replay::ReplayGuard;
+use crate::sasl;
+
+use anyhow::{anyhow, bail, Result};
+use std::sync::Arc;
+
+pub const ERR_UNEXPECTED_RESPONSE: &str = "sasl: imap: unexpected response";
+pub const ERR_INVALID_COMMAND: &str = "sasl: imap: invalid AUTHENTICATE command";
+
+/// The capability advertised by servers accepting initial responses.
+pub const SASL_IR: &str = "SASL-IR";
+
+/// Drives a client through an `AUTHENTICATE` command. Without SASL-IR, the
+/// initial response is sent in reply to the first continuation request.
+pub struct ImapClient<C> {
+    inner: LineClient<C>,
+    tag: String,
+    sasl_ir: bool,
+}
+
+impl<C: sasl::Client> ImapClient<C> {
+    /// Creates an adapter for a command tagged with `tag`.
+    pub fn new(client: C, tag: &str) -> Self {
+        Self {
+            inner: LineClient::new(client),
+            tag: tag.to_string(),
+            sasl_ir: false,
+        }
+    }
+
+    /// Refuses challenges replayed from previous exchanges sharing the same
+    /// guard with `sasl::Error::ReplayDetected`, e.g. when authentication
+    /// is retried.
+    pub fn with_replay_guard(mut self, guard: Arc<ReplayGuard>) -> Self {
+        self.inner.set_replay_guard(guard);
+        self
+    }
+
+    /// Sends the initial response with the command, if the server
+    /// advertised the `SASL-IR` capability.
+    pub fn with_sasl_ir(mut self, sasl_ir: bool) -> Self {
+        self.sasl_ir = sasl_ir;
+        self
+    }
+
+    /// Starts the client and returns the `AUTHENTICATE` command.
+    pub fn command(&mut self) -> Result<String> {
+        let (mechanism, ir) = self.inner.start()?;
+        let mut command = format!("{} AUTHENTICATE {}", self.tag, mechanism);
+        match ir {
+            Some(ir) if self.sasl_ir => {
+                command.push(' ');
+                command.push_str(&framing::encode_initial_response(Some(&ir)).unwrap_or_default());
+            }
+            Some(ir) => self.inner.defer(ir),
+            None => {}
+        }
+        Ok(command)
+    }
+
+    /// Handles a server response line. Untagged responses are skipped. On
+    /// error while the exchange is in progress, the exchange must be
+    /// aborted with `cancel`.
+    pub fn reply(&mut self, line: &str) -> Result<ClientStep> {
+        let line = line.trim_end_matches(['\r', '\n']);
+        if line.starts_with('+') && self.is_in_progress() {
+            return self.inner.challenge(IMAP_CONTINUATION, line);
+        }
+        if line.starts_with("* ") {
+            return Ok(ClientStep::Wait);
+        }
+        let status = line
+            .strip_prefix(self.tag.as_str())
+            .and_then(|rest| rest.strip_prefix(' '))
+            .and_then(|rest| rest.split(' ').next())
+            .map(|status| status.to_ascii_uppercase());
+        match status.as_deref() {
+            Some("OK") if self.is_in_progress() => self.inner.success(None),
+            Some("NO") => self.inner.fail(anyhow!(sasl::ERR_AUTHENTICATION_FAILED)),
+            Some("BAD") if self.inner.is_canceled() => self.inner.fail(sasl::Error::Canceled.into()),
+            _ => self.inner.fail(anyhow!(ERR_UNEXPECTED_RESPONSE)),
+        }
+    }
+
+    /// Aborts the exchange, returning the line to send instead of a
+    /// response: the mechanism's own cancel response if it has one, `*`
+    /// otherwise.
+    pub fn cancel(&mut self) -> String {
+        self.inner.cancel()
+    }
+
+    /// Reports whether the command was sent and the server hasn't sent the
+    /// tagged response yet.
+    pub fn is_in_progress(&self) -> bool {
+        self.inner.is_in_progress()
+    }
+
+    pub fn into_inner(self) -> C {
+        self.inner.into_inner()
+    }
+}
+
+/// Handles an `AUTHENTICATE` command on the server side, creating servers
+/// with a `ServerDispatcher`. Initial responses are accepted, servers
+/// should advertise `SASL_IR`.
+pub struct ImapServer {
+    inner: LineServer,
+    tag: String,
+}
+
+impl ImapServer {
+    /// Handles an `AUTHENTICATE` command line. The exchange continues with
+    /// `response` as long as the reply is a challenge.
+    pub fn start(dispatcher: &ServerDispatcher, conn: &ConnectionContext, command: &str) -> (Self, ServerReply) {
+        let mut server = Self {
+            inner: LineServer::new(IMAP_CONTINUATION),
+            tag: command.split(' ').next().filter(|tag| !tag.is_empty()).unwrap_or("*").to_string(),
+        };
+        let reply = match parse_command(command) {
+            Ok((_, mechanism, ir)) => {
+                let outcome = server.inner.start(dispatcher, conn, mechanism, ir.as_deref());
+                server.reply(outcome)
+            }
+            Err(err) => ServerReply::Failure(format!("{} BAD Invalid AUTHENTICATE command", server.tag), err),
+        };
+        (server, reply)
+    }
+
+    /// Handles a client response line.
+    pub fn response(&mut self, line: &str) -> ServerReply {
+        let outcome = self.inner.response(line);
+        self.reply(outcome)
+    }
+
+    fn reply(&self, outcome: LineOutcome) -> ServerReply {
+        let (text, err) = match outcome {
+            LineOutcome::Challenge(challenge) => return ServerReply::Challenge(challenge),
+            LineOutcome::Success(_) => return ServerReply::Success(format!("{} OK AUTHENTICATE completed", self.tag)),
+            LineOutcome::Malformed(err) => ("BAD Invalid response", err),
+            LineOutcome::Failure(err) if err.to_string() == dispatcher::ERR_UNKNOWN_MECHANISM => ("NO [CANNOT] Unsupported authentication mechanism", err),
+            LineOutcome::Failure(err) if err.to_string() == ERR_MECHANISM_FORBIDDEN => ("NO [CANNOT] Authentication mechanism not allowed", err),
+            LineOutcome::Failure(err) if matches!(err.downcast_ref(), Some(sasl::Error::Canceled)) => ("BAD AUTHENTICATE canceled", err),
+            LineOutcome::Failure(err) if matches!(err.downcast_ref(), Some(sasl::Error::RateLimited)) => ("NO [UNAVAILABLE] Too many failed attempts, try again later", err),
+            LineOutcome::Failure(err) => ("NO [AUTHENTICATIONFAILED] Authentication failed", err),
+        };
+        ServerReply::Failure(format!("{} {}", self.tag, text), err)
+    }
+
+    /// Returns the exchange's mechanism, if it started.
+    pub fn mechanism_name(&self) -> Option<&str> {
+        self.inner.mechanism_name()
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.inner.is_done()
+    }
+}
+
+/// Parses a `<tag> AUTHENTICATE <mechanism> [initial-response]` command.
+pub fn parse_command(command: &str) -> Result<(&str, &str, Option<Vec<u8>>)> {
+    let mut args = command.trim_end_matches(['\r', '\n']).split(' ');
+    let tag = args.next().filter(|tag| !tag.is_empty()).ok_or_else(|| anyhow!(ERR_INVALID_COMMAND))?;
+    if !args.next().is_some_and(|command| command.eq_ignore_ascii_case("AUTHENTICATE")) {
+        bail!(ERR_INVALID_COMMAND);
+    }
+    let mechanism = args.next().filter(|m| !m.is_empty()).ok_or_else(|| anyhow!(ERR_INVALID_COMMAND))?;
+    let ir = framing::decode_initial_response(args.next())?;
+    if args.next().is_some() {
+        bail!(ERR_INVALID_COMMAND);
+    }
+    Ok((tag, mechanism, ir))
+}
+
+#[cfg(all(feature = "login", feature = "plain"))]
+#[test]
+fn test_imap_exchange() -> Result<()> {
+    use crate::login::{LoginClient, LoginServer, LOGIN};
+    use crate::plain::{PlainClient, PlainServer, PLAIN};
+    use crate::registry::Registry;
+
+    let mut registry = Registry::new();
+    registry
+        .register_server(PLAIN, || Box::new(PlainServer::new(Box::new(|_, _, _| Ok(())))))
+        .register_server(LOGIN, || Box::new(LoginServer::new(Box::new(|_, _| Ok(())))));
+    let dispatcher = ServerDispatcher::new(registry);
+    let conn = ConnectionContext {
+        tls: true,
+        ..Default::default()
+    };
+
+    for sasl_ir in [true, false] {
+        let mut client = ImapClient::new(PlainClient::new(String::new(), "username".to_string(), "password".to_string()), "a1")
+            .with_sasl_ir(sasl_ir);
+        let mut lines = vec![client.command()?];
+        let (mut server, mut reply) = ImapServer::start(&dispatcher, &conn, &lines[0]);
+        while let ClientStep::Send(line) = client.reply(reply.line())? {
+            reply = server.response(&line);
+            lines.push(line);
+        }
+        if !matches!(reply, ServerReply::Success(_)) || lines.len() != if sasl_ir { 1 } else { 2 } {
+            bail!("Unexpected exchange: {:?}", lines);
+        }
+    }
+
+    let mut client = ImapClient::new(LoginClient::new("username".to_string(), "password".to_string()), "a2");
+    let (mut server, reply) = ImapServer::start(&dispatcher, &conn, &client.command()?);
+    if reply.line() != "+ VXNlcm5hbWU6" || client.reply(reply.line())? != ClientStep::Send("dXNlcm5hbWU=".to_string()) {
+        bail!("Expected the initial response after the first continuation request");
+    }
+    if client.reply("* OK still here")? != ClientStep::Wait {
+        bail!("Expected untagged responses to be skipped");
+    }
+    match server.response(&client.cancel()) {
+        ServerReply::Failure(line, _) if line == "a2 BAD AUTHENTICATE canceled" => match client.reply(&line) {
+            Err(err) if matches!(err.downcast_ref(), Some(sasl::Error::Canceled)) => {}
+            _ => bail!("Expected the client to report the cancellation"),
+        },
+        reply => bail!("Unexpected reply: {:?}", reply),
+    }
+
+    if ImapServer::start(&dispatcher, &conn, "a3 AUTHENTICATE CRAM-MD5").1.line() != "a3 NO [CANNOT] Unsupported authentication mechanism" {
+        bail!("Expected an unsupported mechanism");
+    }
+    if ImapServer::start(&dispatcher, &conn, "a4 LOGIN").1.line() != "a4 BAD Invalid AUTHENTICATE command" {
+        bail!("Expected an invalid command");
+    }
+
+    Ok(())
+}
+
+#[cfg(feature = "oauthbearer")]
+#[test]
+fn test_imap_oauthbearer_failure() -> Result<()> {
+    use crate::oauthbearer::{OAuthBearerClient, OAuthBearerError, OAuthBearerOptions, OAuthBearerServer, OAUTHBEARER};
+    use crate::registry::Registry;
+
+    let mut registry = Registry::new();
+    registry.register_server(OAUTHBEARER, || Box::new(OAuthBearerServer::new(Box::new(|_| Err(OAuthBearerError::invalid_token())))));
+    let dispatcher = ServerDispatcher::new(registry);
+    let conn = ConnectionContext {
+        tls: true,
+        ..Default::default()
+    };
+
+    let mut client = ImapClient::new(OAuthBearerClient::from_builder(OAuthBearerOptions::builder("token"))?, "a1").with_sasl_ir(true);
+    let (mut server, mut reply) = ImapServer::start(&dispatcher, &conn, &client.command()?);
+    let result = loop {
+        match client.reply(reply.line()) {
+            Ok(ClientStep::Send(line)) => reply = server.response(&line),
+            result => break result,
+        }
+    };
+    match result {
+        Err(err) if err.downcast_ref::<OAuthBearerError>().is_some_and(|err| err.status == "invalid_token") => Ok(()),
+        result => bail!("Expected the error challenge to be reported, got {:?}", result),
+    }
+}
+
+#[test]
+fn test_imap_replay_guard() -> Result<()> {
+    use crate::replay::NonceClient;
+
+    let guard = Arc::new(ReplayGuard::default());
+    for (tag, replayed) in [("a1", false), ("a2", true)] {
+        let mut client = ImapClient::new(NonceClient, tag).with_replay_guard(guard.clone());
+        client.command()?;
+        match client.reply("+ PDFAZXhhbXBsZS5vcmc+") {
+            Ok(ClientStep::Send(_)) if !replayed => {}
+            Err(err) if replayed && matches!(err.downcast_ref(), Some(sasl::Error::ReplayDetected)) => {}
+            _ => bail!("Unexpected reply, expected a replay: {}", replayed),
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,235 @@
+//! Kafka SASL authentication (KIP-43, KIP-152). Clients first send a
+//! `SaslHandshake` request naming the mechanism. With version 1 of the
+//! handshake, tokens are then carried by `SaslAuthenticate` requests and
+//! responses; with version 0, they are exchanged as raw frames prefixed
+//! with their length as a 4-byte big-endian integer. Brokers predating the
+//! handshake only support GSSAPI, with raw frames.
+//!
+//! Kafka has no way to carry additional data with success: the last token
+//! sent by the broker is passed to the client as a challenge, and as
+//! additional data if the client doesn't expect another challenge.
+
+use super::authentication_failed;
+use crate::sasl;
+
+use anyhow::{bail, Result};
+
+pub const ERR_MECHANISM_UNSUPPORTED: &str = "sasl: kafka: mechanism not supported by the broker";
+pub const ERR_ILLEGAL_STATE: &str = "sasl: kafka: illegal SASL state";
+pub const ERR_UNEXPECTED_RESPONSE: &str = "sasl: kafka: unexpected response";
+pub const ERR_LEGACY_MECHANISM: &str = "sasl: kafka: only GSSAPI can be used without a handshake";
+pub const ERR_FRAME_TOO_LONG: &str = "sasl: kafka: frame too long";
+
+/// The API keys of the requests.
+pub const SASL_HANDSHAKE: i16 = 17;
+pub const SASL_AUTHENTICATE: i16 = 36;
+
+/// The error codes of responses.
+pub const NONE: i16 = 0;
+pub const UNSUPPORTED_SASL_MECHANISM: i16 = 33;
+pub const ILLEGAL_SASL_STATE: i16 = 34;
+pub const SASL_AUTHENTICATION_FAILED: i16 = 58;
+
+const GSSAPI: &str = "GSSAPI";
+
+/// How tokens are carried once the mechanism is selected.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Framing {
+    /// In `SaslAuthenticate` requests and responses.
+    Authenticate,
+    /// As raw, length-prefixed frames.
+    Raw,
+}
+
+/// The next step of a client exchange.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum KafkaStep {
+    /// Send the token: the `auth_bytes` of a `SaslAuthenticate` request,
+    /// or a raw frame, already length-prefixed.
+    Send(Vec<u8>),
+    /// Authentication succeeded.
+    Done,
+}
+
+/// Drives a client through Kafka SASL authentication.
+pub struct KafkaClient<C> {
+    client: C,
+    framing: Option<Framing>,
+    done: bool,
+}
+
+impl<C: sasl::Client> KafkaClient<C> {
+    pub fn new(client: C) -> Self {
+        Self {
+            client,
+            framing: None,
+            done: false,
+        }
+    }
+
+    /// Returns the mechanism to request in the `SaslHandshake` request.
+    pub fn mechanism_name(&self) -> &str {
+        self.client.mechanism_name()
+    }
+
+    /// Handles the response to a `SaslHandshake` request of `version`, and
+    /// returns the first token.
+    pub fn handshake_response(&mut self, version: i16, error_code: i16, mechanisms: &[&str]) -> Result<KafkaStep> {
+        match error_code {
+            NONE => {}
+            UNSUPPORTED_SASL_MECHANISM => bail!(ERR_MECHANISM_UNSUPPORTED),
+            ILLEGAL_SASL_STATE => bail!(ERR_ILLEGAL_STATE),
+            _ => bail!(ERR_UNEXPECTED_RESPONSE),
+        }
+        if !mechanisms.contains(&self.client.mechanism_name()) {
+            bail!(ERR_MECHANISM_UNSUPPORTED);
+        }
+        self.start(if version == 0 { Framing::Raw } else { Framing::Authenticate })
+    }
+
+    /// Starts authenticating with a broker predating `SaslHandshake`, and
+    /// returns the first frame. Only GSSAPI is supported by such brokers.
+    pub fn start_legacy(&mut self) -> Result<KafkaStep> {
+        if self.client.mechanism_name() != GSSAPI {
+            bail!(ERR_LEGACY_MECHANISM);
+        }
+        self.start(Framing::Raw)
+    }
+
+    fn start(&mut self, framing: Framing) -> Result<KafkaStep> {
+        let (_, ir) = self.client.start()?;
+        self.framing = Some(framing);
+        Ok(self.send(ir.unwrap_or_default()))
+    }
+
+    /// Handles a `SaslAuthenticate` response.
+    pub fn authenticate_response(&mut self, error_code: i16, auth_bytes: &[u8]) -> Result<KafkaStep> {
+        if self.framing != Some(Framing::Authenticate) || self.done {
+            bail!(ERR_UNEXPECTED_RESPONSE);
+        }
+        match error_code {
+            NONE => self.token(auth_bytes),
+            SASL_AUTHENTICATION_FAILED => {
+                self.done = true;
+                Err(authentication_failed(&mut self.client))
+            }
+            ILLEGAL_SASL_STATE => {
+                self.done = true;
+                bail!(ERR_ILLEGAL_STATE)
+            }
+            _ => {
+                self.done = true;
+                bail!(ERR_UNEXPECTED_RESPONSE)
+            }
+        }
+    }
+
+    /// Handles a raw frame received from the broker, without its length
+    /// prefix. Brokers close the connection when authentication fails.
+    pub fn frame(&mut self, frame: &[u8]) -> Result<KafkaStep> {
+        if self.framing != Some(Framing::Raw) || self.done {
+            bail!(ERR_UNEXPECTED_RESPONSE);
+        }
+        self.token(frame)
+    }
+
+    fn token(&mut self, token: &[u8]) -> Result<KafkaStep> {
+        if token.is_empty() {
+            self.done = true;
+            self.client.finish(None)?;
+            return Ok(KafkaStep::Done);
+        }
+        match self.client.next(token) {
+            Ok(response) => Ok(self.send(response)),
+            Err(err) if err.to_string() == sasl::ERR_UNEXPECTED_SERVER_CHALLENGE => {
+                self.done = true;
+                self.client.finish(Some(token))?;
+                Ok(KafkaStep::Done)
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    fn send(&self, token: Vec<u8>) -> KafkaStep {
+        match self.framing {
+            Some(Framing::Raw) => KafkaStep::Send(encode_frame(&token)),
+            _ => KafkaStep::Send(token),
+        }
+    }
+
+    /// Returns how tokens are carried, once the exchange started.
+    pub fn framing(&self) -> Option<Framing> {
+        self.framing
+    }
+
+    pub fn into_inner(self) -> C {
+        self.client
+    }
+}
+
+/// Prefixes a token with its length.
+pub fn encode_frame(token: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(4 + token.len());
+    frame.extend_from_slice(&(token.len() as u32).to_be_bytes());
+    frame.extend_from_slice(token);
+    frame
+}
+
+/// Decodes the frame at the start of `input`, returning its token and the
+/// number of bytes consumed, or `None` if the frame is incomplete. Fails if
+/// the length of the frame doesn't fit in memory.
+pub fn decode_frame(input: &[u8]) -> Result<Option<(&[u8], usize)>> {
+    let Some(header) = input.get(..4) else {
+        return Ok(None);
+    };
+    let len = u32::from_be_bytes(header.try_into()?);
+    let Some(end) = usize::try_from(len).ok().and_then(|len| len.checked_add(4)) else {
+        bail!(ERR_FRAME_TOO_LONG);
+    };
+    Ok(input.get(4..end).map(|token| (token, end)))
+}
+
+#[cfg(feature = "plain")]
+#[test]
+fn test_kafka_client() -> Result<()> {
+    use crate::plain::PlainClient;
+
+    let new_client = || KafkaClient::new(PlainClient::new(String::new(), "username".to_string(), "password".to_string()));
+
+    let mut client = new_client();
+    match client.handshake_response(1, NONE, &["SCRAM-SHA-256", "PLAIN"])? {
+        KafkaStep::Send(token) if token == b"\x00username\x00password" => {}
+        step => bail!("Unexpected step: {:?}", step),
+    }
+    if client.authenticate_response(NONE, b"")? != KafkaStep::Done {
+        bail!("Expected authentication to succeed");
+    }
+
+    let mut client = new_client();
+    let KafkaStep::Send(frame) = client.handshake_response(0, NONE, &["PLAIN"])? else {
+        bail!("Expected a frame");
+    };
+    if decode_frame(&frame)? != Some((&b"\x00username\x00password"[..], frame.len())) || decode_frame(&frame[..6])?.is_some() {
+        bail!("Unexpected frame: {:?}", frame);
+    }
+    match decode_frame(b"\xff\xff\xff\xff\x00") {
+        Ok(None) if usize::BITS > 32 => {}
+        Err(err) if err.to_string() == ERR_FRAME_TOO_LONG => {}
+        result => bail!("Unexpected result for the longest frame: {:?}", result),
+    }
+    match client.frame(b"unexpected") {
+        Err(err) if err.to_string() == sasl::ERR_UNEXPECTED_SUCCESS_DATA => {}
+        _ => bail!("Expected unexpected data to be rejected"),
+    }
+
+    match new_client().handshake_response(1, UNSUPPORTED_SASL_MECHANISM, &["SCRAM-SHA-512"]) {
+        Err(err) if err.to_string() == ERR_MECHANISM_UNSUPPORTED => {}
+        _ => bail!("Expected PLAIN to be unsupported"),
+    }
+    match new_client().start_legacy() {
+        Err(err) if err.to_string() == ERR_LEGACY_MECHANISM => {}
+        _ => bail!("Expected PLAIN to require a handshake"),
+    }
+
+    Ok(())
+}
@@ -0,0 +1,269 @@
+//! LDAP SASL binds (RFC 4513 section 5.2.1). Each step of the exchange is
+//! a `BindRequest` carrying `SaslCredentials`, answered by a `BindResponse`
+//! with a result code and optional `serverSaslCreds`. Encoding them is left
+//! to the LDAP implementation.
+
+use super::authentication_failed;
+use crate::dispatcher::{self, Exchange, ServerDispatcher};
+use crate::policy::{ConnectionContext, ERR_MECHANISM_FORBIDDEN};
+use crate::sasl;
+
+use anyhow::{anyhow, Result};
+
+pub const ERR_MECHANISM_UNSUPPORTED: &str = "sasl: ldap: mechanism not supported by the server";
+pub const ERR_UNEXPECTED_RESULT: &str = "sasl: ldap: unexpected bind result";
+
+/// Bind result codes (RFC 4511 section 4.1.9).
+pub const SUCCESS: u32 = 0;
+pub const AUTH_METHOD_NOT_SUPPORTED: u32 = 7;
+pub const CONFIDENTIALITY_REQUIRED: u32 = 13;
+pub const SASL_BIND_IN_PROGRESS: u32 = 14;
+pub const INAPPROPRIATE_AUTHENTICATION: u32 = 48;
+pub const INVALID_CREDENTIALS: u32 = 49;
+
+/// The `SaslCredentials` of a `BindRequest`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SaslCredentials {
+    pub mechanism: String,
+    pub credentials: Option<Vec<u8>>,
+}
+
+/// The next step of a client bind.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LdapStep {
+    /// Send a `BindRequest` with these credentials and pass the response.
+    Bind(SaslCredentials),
+    /// The bind succeeded.
+    Done,
+}
+
+/// Drives a client through a SASL bind.
+pub struct LdapClient<C> {
+    client: C,
+    mechanism: String,
+    done: bool,
+    canceled: bool,
+}
+
+impl<C: sasl::Client> LdapClient<C> {
+    pub fn new(client: C) -> Self {
+        Self {
+            client,
+            mechanism: String::new(),
+            done: false,
+            canceled: false,
+        }
+    }
+
+    /// Starts the client and returns the credentials of the first
+    /// `BindRequest`.
+    pub fn bind_request(&mut self) -> Result<SaslCredentials> {
+        let (mechanism, ir) = self.client.start()?;
+        self.mechanism = mechanism.clone();
+        Ok(SaslCredentials {
+            mechanism,
+            credentials: ir,
+        })
+    }
+
+    /// Handles a `BindResponse`. On error while the bind is in progress,
+    /// it must be aborted with `cancel`.
+    pub fn bind_response(&mut self, result_code: u32, server_sasl_creds: Option<&[u8]>) -> Result<LdapStep> {
+        if self.done || self.mechanism.is_empty() {
+            return Err(anyhow!(ERR_UNEXPECTED_RESULT));
+        }
+        if self.canceled {
+            self.done = true;
+            return Err(sasl::Error::Canceled.into());
+        }
+        match result_code {
+            SASL_BIND_IN_PROGRESS => {
+                let response = self.client.next(server_sasl_creds.unwrap_or_default())?;
+                Ok(LdapStep::Bind(SaslCredentials {
+                    mechanism: self.mechanism.clone(),
+                    credentials: Some(response),
+                }))
+            }
+            SUCCESS => {
+                self.done = true;
+                self.client.finish(server_sasl_creds)?;
+                Ok(LdapStep::Done)
+            }
+            code => {
+                self.done = true;
+                match code {
+                    AUTH_METHOD_NOT_SUPPORTED => Err(anyhow!(ERR_MECHANISM_UNSUPPORTED)),
+                    INVALID_CREDENTIALS | INAPPROPRIATE_AUTHENTICATION => Err(authentication_failed(&mut self.client)),
+                    _ => Err(anyhow!(ERR_UNEXPECTED_RESULT)),
+                }
+            }
+        }
+    }
+
+    /// Aborts the bind, returning the credentials of the `BindRequest` to
+    /// send instead of a response: the mechanism's own cancel response if
+    /// it has one, an empty mechanism otherwise, which servers treat as a
+    /// new, invalid, bind.
+    pub fn cancel(&mut self) -> SaslCredentials {
+        self.canceled = true;
+        match self.client.cancel() {
+            Some(response) => SaslCredentials {
+                mechanism: self.mechanism.clone(),
+                credentials: Some(response),
+            },
+            None => SaslCredentials {
+                mechanism: String::new(),
+                credentials: None,
+            },
+        }
+    }
+
+    /// Reports whether the bind started and the server hasn't reported the
+    /// outcome yet.
+    pub fn is_in_progress(&self) -> bool {
+        !self.mechanism.is_empty() && !self.done
+    }
+
+    pub fn into_inner(self) -> C {
+        self.client
+    }
+}
+
+/// The outcome of a `BindRequest` handled by an `LdapServer`.
+#[derive(Debug)]
+pub enum BindOutcome {
+    /// Send a `saslBindInProgress` response with the challenge.
+    InProgress(Vec<u8>),
+    /// Send a `success` response, with the additional data if any.
+    Success(Option<Vec<u8>>),
+    /// Send a response with the result code. The error describes the
+    /// failure, e.g. for auditing, and must not be disclosed to the client.
+    Failure(u32, anyhow::Error),
+}
+
+impl BindOutcome {
+    pub fn result_code(&self) -> u32 {
+        match self {
+            BindOutcome::InProgress(_) => SASL_BIND_IN_PROGRESS,
+            BindOutcome::Success(_) => SUCCESS,
+            BindOutcome::Failure(code, _) => *code,
+        }
+    }
+
+    /// Returns the `serverSaslCreds` of the response.
+    pub fn server_sasl_creds(&self) -> Option<&[u8]> {
+        match self {
+            BindOutcome::InProgress(challenge) => Some(challenge),
+            BindOutcome::Success(data) => data.as_deref(),
+            BindOutcome::Failure(..) => None,
+        }
+    }
+}
+
+/// Handles SASL `BindRequest`s on the server side, creating servers with a
+/// `ServerDispatcher`. One must be kept per connection, as a bind spans
+/// several requests.
+#[derive(Default)]
+pub struct LdapServer {
+    exchange: Option<Exchange>,
+}
+
+impl LdapServer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Handles a SASL `BindRequest`. A request for another mechanism than
+    /// the bind in progress aborts it and starts a new bind.
+    pub fn bind(&mut self, dispatcher: &ServerDispatcher, conn: &ConnectionContext, credentials: &SaslCredentials) -> BindOutcome {
+        let step = match &mut self.exchange {
+            Some(exchange) if exchange.mechanism_name().eq_ignore_ascii_case(&credentials.mechanism) => {
+                exchange.next(credentials.credentials.as_deref().unwrap_or_default())
+            }
+            _ => match dispatcher.start(conn, &credentials.mechanism, credentials.credentials.as_deref()) {
+                Ok((exchange, step)) => {
+                    self.exchange = Some(exchange);
+                    Ok(step)
+                }
+                Err(err) => Err(err),
+            },
+        };
+        let outcome = match step {
+            Ok(sasl::ServerStep::Challenge(challenge)) => return BindOutcome::InProgress(challenge),
+            Ok(sasl::ServerStep::Done { additional_data }) => BindOutcome::Success(additional_data),
+            Err(err) if err.to_string() == dispatcher::ERR_UNKNOWN_MECHANISM => BindOutcome::Failure(AUTH_METHOD_NOT_SUPPORTED, err),
+            Err(err) if err.to_string() == ERR_MECHANISM_FORBIDDEN && !conn.tls => BindOutcome::Failure(CONFIDENTIALITY_REQUIRED, err),
+            Err(err) if err.to_string() == ERR_MECHANISM_FORBIDDEN => BindOutcome::Failure(INAPPROPRIATE_AUTHENTICATION, err),
+            Err(err) => BindOutcome::Failure(INVALID_CREDENTIALS, err),
+        };
+        self.exchange = None;
+        outcome
+    }
+
+    /// Returns the mechanism of the bind in progress, if any.
+    pub fn mechanism_name(&self) -> Option<&str> {
+        self.exchange.as_ref().map(|exchange| exchange.mechanism_name())
+    }
+}
+
+#[cfg(feature = "login")]
+#[test]
+fn test_ldap_bind() -> Result<()> {
+    use crate::login::{LoginClient, LoginServer, LOGIN};
+    use crate::registry::Registry;
+    use anyhow::bail;
+
+    let mut registry = Registry::new();
+    registry.register_server(LOGIN, || Box::new(LoginServer::new(Box::new(|_, p| match p {
+        "password" => Ok(()),
+        _ => bail!("invalid credentials"),
+    }))));
+    let dispatcher = ServerDispatcher::new(registry);
+    let conn = ConnectionContext {
+        tls: true,
+        ..Default::default()
+    };
+
+    let mut client = LdapClient::new(LoginClient::new("username".to_string(), "password".to_string()));
+    let mut server = LdapServer::new();
+    let mut request = client.bind_request()?;
+    let mut binds = 1;
+    loop {
+        let outcome = server.bind(&dispatcher, &conn, &request);
+        match client.bind_response(outcome.result_code(), outcome.server_sasl_creds())? {
+            LdapStep::Bind(next) => request = next,
+            LdapStep::Done => break,
+        }
+        binds += 1;
+    }
+    if binds != 2 || server.mechanism_name().is_some() {
+        bail!("Unexpected bind: {} requests", binds);
+    }
+
+    let mut client = LdapClient::new(LoginClient::new("username".to_string(), "wrong".to_string()));
+    let outcome = server.bind(&dispatcher, &conn, &client.bind_request()?);
+    if outcome.result_code() != SASL_BIND_IN_PROGRESS || server.mechanism_name() != Some(LOGIN) {
+        bail!("Expected the bind to be in progress");
+    }
+    let outcome = server.bind(&dispatcher, &conn, &client.cancel());
+    if outcome.result_code() != AUTH_METHOD_NOT_SUPPORTED || server.mechanism_name().is_some() {
+        bail!("Expected the bind to be aborted");
+    }
+    match client.bind_response(outcome.result_code(), None) {
+        Err(err) if matches!(err.downcast_ref(), Some(sasl::Error::Canceled)) => {}
+        _ => bail!("Expected the client to report the cancellation"),
+    }
+
+    let mut client = LdapClient::new(LoginClient::new("username".to_string(), "wrong".to_string()));
+    let outcome = server.bind(&dispatcher, &conn, &client.bind_request()?);
+    let LdapStep::Bind(request) = client.bind_response(outcome.result_code(), outcome.server_sasl_creds())? else {
+        bail!("Expected a password request");
+    };
+    let outcome = server.bind(&dispatcher, &conn, &request);
+    match client.bind_response(outcome.result_code(), outcome.server_sasl_creds()) {
+        Err(err) if outcome.result_code() == INVALID_CREDENTIALS && err.to_string() == sasl::ERR_AUTHENTICATION_FAILED => {}
+        _ => bail!("Expected invalid credentials"),
+    }
+
+    Ok(())
+}
@@ -0,0 +1,211 @@
+//! Memcached binary protocol SASL authentication. Clients list the
+//! mechanisms with `SASL List Mechs`, start the exchange with `SASL Auth`,
+//! whose key is the mechanism and value the initial response, and answer
+//! challenges with `SASL Step`. The server replies with the `Authentication
+//! continue` status and a challenge until the exchange completes.
+//!
+//! Encoding packet headers is left to the memcached implementation.
+
+use super::authentication_failed;
+use crate::dispatcher::{Exchange, ServerDispatcher};
+use crate::policy::ConnectionContext;
+use crate::sasl;
+
+use anyhow::{anyhow, bail, Result};
+
+pub const ERR_MECHANISM_UNSUPPORTED: &str = "sasl: memcached: mechanism not supported by the server";
+pub const ERR_UNEXPECTED_RESPONSE: &str = "sasl: memcached: unexpected response";
+pub const ERR_UNEXPECTED_REQUEST: &str = "sasl: memcached: unexpected request";
+
+/// Opcodes of SASL commands.
+pub const SASL_LIST_MECHS: u8 = 0x20;
+pub const SASL_AUTH: u8 = 0x21;
+pub const SASL_STEP: u8 = 0x22;
+
+/// Response statuses.
+pub const NO_ERROR: u16 = 0x0000;
+pub const AUTHENTICATION_ERROR: u16 = 0x0020;
+pub const AUTHENTICATION_CONTINUE: u16 = 0x0021;
+
+/// The key and value of a `SASL Auth` or `SASL Step` request.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Request {
+    pub opcode: u8,
+    /// The mechanism.
+    pub key: String,
+    pub value: Vec<u8>,
+}
+
+/// The next step of a client exchange.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MemcachedStep {
+    /// Send the request and pass the response.
+    Send(Request),
+    /// Authentication succeeded.
+    Done,
+}
+
+/// Drives a client through SASL authentication.
+pub struct MemcachedClient<C> {
+    client: C,
+    mechanism: String,
+    done: bool,
+}
+
+impl<C: sasl::Client> MemcachedClient<C> {
+    pub fn new(client: C) -> Self {
+        Self {
+            client,
+            mechanism: String::new(),
+            done: false,
+        }
+    }
+
+    /// Handles the space-separated mechanisms of the `SASL List Mechs`
+    /// response, and returns the `SASL Auth` request.
+    pub fn auth(&mut self, mechanisms: &[u8]) -> Result<Request> {
+        if !self.mechanism.is_empty() {
+            bail!(ERR_UNEXPECTED_RESPONSE);
+        }
+        let name = self.client.mechanism_name().as_bytes();
+        if !mechanisms.split(|&b| b == b' ').any(|m| m == name) {
+            bail!(ERR_MECHANISM_UNSUPPORTED);
+        }
+        let (mechanism, ir) = self.client.start()?;
+        self.mechanism = mechanism.clone();
+        Ok(Request {
+            opcode: SASL_AUTH,
+            key: mechanism,
+            value: ir.unwrap_or_default(),
+        })
+    }
+
+    /// Handles the response to `SASL Auth` or `SASL Step`.
+    pub fn response(&mut self, status: u16, value: &[u8]) -> Result<MemcachedStep> {
+        if self.mechanism.is_empty() || self.done {
+            bail!(ERR_UNEXPECTED_RESPONSE);
+        }
+        match status {
+            AUTHENTICATION_CONTINUE => Ok(MemcachedStep::Send(Request {
+                opcode: SASL_STEP,
+                key: self.mechanism.clone(),
+                value: self.client.next(value)?,
+            })),
+            NO_ERROR => {
+                self.done = true;
+                self.client.finish((!value.is_empty()).then_some(value))?;
+                Ok(MemcachedStep::Done)
+            }
+            AUTHENTICATION_ERROR => {
+                self.done = true;
+                Err(authentication_failed(&mut self.client))
+            }
+            _ => {
+                self.done = true;
+                bail!(ERR_UNEXPECTED_RESPONSE)
+            }
+        }
+    }
+
+    pub fn into_inner(self) -> C {
+        self.client
+    }
+}
+
+/// The response of a server to a SASL command.
+#[derive(Debug)]
+pub enum MemcachedReply {
+    /// Respond with the status and value.
+    Respond(u16, Vec<u8>),
+    /// Respond with the `Authentication error` status. The error describes
+    /// the failure, e.g. for auditing, and must not be disclosed to the
+    /// client.
+    Failure(anyhow::Error),
+}
+
+/// Handles SASL commands on the server side, creating servers with a
+/// `ServerDispatcher`. One must be kept per connection.
+#[derive(Default)]
+pub struct MemcachedServer {
+    exchange: Option<Exchange>,
+}
+
+impl MemcachedServer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Handles a `SASL List Mechs`, `SASL Auth` or `SASL Step` request. A
+    /// `SASL Auth` request aborts the exchange in progress.
+    pub fn request(&mut self, dispatcher: &ServerDispatcher, conn: &ConnectionContext, request: &Request) -> MemcachedReply {
+        let step = match (request.opcode, &mut self.exchange) {
+            (SASL_LIST_MECHS, _) => return MemcachedReply::Respond(NO_ERROR, dispatcher.mechanisms(conn).join(" ").into_bytes()),
+            (SASL_AUTH, _) => {
+                let ir = (!request.value.is_empty()).then_some(&request.value[..]);
+                match dispatcher.start(conn, &request.key, ir) {
+                    Ok((exchange, step)) => {
+                        self.exchange = Some(exchange);
+                        Ok(step)
+                    }
+                    Err(err) => Err(err),
+                }
+            }
+            (SASL_STEP, Some(exchange)) if exchange.mechanism_name().eq_ignore_ascii_case(&request.key) => exchange.next(&request.value),
+            _ => Err(anyhow!(ERR_UNEXPECTED_REQUEST)),
+        };
+        let reply = match step {
+            Ok(sasl::ServerStep::Challenge(challenge)) => return MemcachedReply::Respond(AUTHENTICATION_CONTINUE, challenge),
+            Ok(sasl::ServerStep::Done { additional_data }) => MemcachedReply::Respond(NO_ERROR, additional_data.unwrap_or_default()),
+            Err(err) => MemcachedReply::Failure(err),
+        };
+        self.exchange = None;
+        reply
+    }
+}
+
+#[cfg(feature = "login")]
+#[test]
+fn test_memcached_exchange() -> Result<()> {
+    use crate::login::{LoginClient, LoginServer, LOGIN};
+    use crate::registry::Registry;
+
+    let mut registry = Registry::new();
+    registry.register_server(LOGIN, || Box::new(LoginServer::new(Box::new(|_, p| match p {
+        "password" => Ok(()),
+        _ => bail!("invalid credentials"),
+    }))));
+    let dispatcher = ServerDispatcher::new(registry);
+    let conn = ConnectionContext {
+        tls: true,
+        ..Default::default()
+    };
+
+    let mut server = MemcachedServer::new();
+    let list = Request {
+        opcode: SASL_LIST_MECHS,
+        key: String::new(),
+        value: Vec::new(),
+    };
+    let MemcachedReply::Respond(NO_ERROR, mechanisms) = server.request(&dispatcher, &conn, &list) else {
+        bail!("Expected the mechanisms to be listed");
+    };
+
+    for (password, success) in [("password", true), ("wrong", false)] {
+        let mut client = MemcachedClient::new(LoginClient::new("username".to_string(), password.to_string()));
+        let mut request = client.auth(&mechanisms)?;
+        loop {
+            let (status, value) = match server.request(&dispatcher, &conn, &request) {
+                MemcachedReply::Respond(status, value) => (status, value),
+                MemcachedReply::Failure(_) => (AUTHENTICATION_ERROR, Vec::new()),
+            };
+            match client.response(status, &value) {
+                Ok(MemcachedStep::Send(next)) => request = next,
+                Ok(MemcachedStep::Done) if success => break,
+                Err(err) if !success && err.to_string() == sasl::ERR_AUTHENTICATION_FAILED => break,
+                step => bail!("Unexpected step: {:?}", step),
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,297 @@
+//! MongoDB SASL conversations. Clients send a `saslStart` command naming
+//! the mechanism, then `saslContinue` commands referring to the
+//! `conversationId` returned by the server, until a reply sets `done`.
+//! Commands and replies are modeled after their fields, their BSON
+//! encoding being left to the driver: payloads are BSON binary values.
+//!
+//! Unless clients set the `skipEmptyExchange` option, servers send the
+//! mechanism's additional data with `done` unset and wait for an empty
+//! `saslContinue` before completing the conversation.
+
+use super::authentication_failed;
+use crate::dispatcher::{self, Exchange, ServerDispatcher};
+use crate::policy::{ConnectionContext, ERR_MECHANISM_FORBIDDEN};
+use crate::sasl;
+
+use anyhow::{anyhow, bail, Result};
+
+pub const ERR_UNEXPECTED_REPLY: &str = "sasl: mongodb: unexpected reply";
+pub const ERR_MECHANISM_UNAVAILABLE: &str = "sasl: mongodb: mechanism not available on the server";
+pub const ERR_UNKNOWN_CONVERSATION: &str = "sasl: mongodb: unknown conversation";
+
+/// The codes of command errors.
+pub const PROTOCOL_ERROR: i32 = 17;
+pub const AUTHENTICATION_FAILED: i32 = 18;
+pub const MECHANISM_UNAVAILABLE: i32 = 334;
+
+/// A `saslStart` command.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SaslStart {
+    pub mechanism: String,
+    pub payload: Vec<u8>,
+    /// The `skipEmptyExchange` option.
+    pub skip_empty_exchange: bool,
+}
+
+/// A `saslContinue` command.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SaslContinue {
+    pub conversation_id: i32,
+    pub payload: Vec<u8>,
+}
+
+/// A successful (`ok: 1`) reply to `saslStart` or `saslContinue`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SaslReply {
+    pub conversation_id: i32,
+    pub done: bool,
+    pub payload: Vec<u8>,
+}
+
+/// The next step of a client conversation.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MongoStep {
+    /// Send a `saslContinue` command and pass the reply.
+    Continue(SaslContinue),
+    /// Authentication succeeded.
+    Done,
+}
+
+/// Drives a client through a SASL conversation.
+pub struct MongoClient<C> {
+    client: C,
+    skip_empty_exchange: bool,
+    started: bool,
+    finished: bool,
+    done: bool,
+}
+
+impl<C: sasl::Client> MongoClient<C> {
+    pub fn new(client: C) -> Self {
+        Self {
+            client,
+            skip_empty_exchange: true,
+            started: false,
+            finished: false,
+            done: false,
+        }
+    }
+
+    /// Sets the `skipEmptyExchange` option, on by default. Servers older
+    /// than MongoDB 4.4 ignore it.
+    pub fn with_skip_empty_exchange(mut self, skip_empty_exchange: bool) -> Self {
+        self.skip_empty_exchange = skip_empty_exchange;
+        self
+    }
+
+    /// Starts the client and returns the `saslStart` command.
+    pub fn sasl_start(&mut self) -> Result<SaslStart> {
+        let (mechanism, ir) = self.client.start()?;
+        self.started = true;
+        Ok(SaslStart {
+            mechanism,
+            payload: ir.unwrap_or_default(),
+            skip_empty_exchange: self.skip_empty_exchange,
+        })
+    }
+
+    /// Handles a successful reply. A payload received once the mechanism
+    /// doesn't expect challenges anymore is its additional data.
+    pub fn reply(&mut self, reply: &SaslReply) -> Result<MongoStep> {
+        if !self.started || self.done {
+            bail!(ERR_UNEXPECTED_REPLY);
+        }
+        if reply.done {
+            self.done = true;
+            if !self.finished {
+                self.finished = true;
+                self.client.finish((!reply.payload.is_empty()).then_some(&reply.payload[..]))?;
+            } else if !reply.payload.is_empty() {
+                bail!(ERR_UNEXPECTED_REPLY);
+            }
+            return Ok(MongoStep::Done);
+        }
+        if self.finished {
+            bail!(ERR_UNEXPECTED_REPLY);
+        }
+        let payload = match self.client.next(&reply.payload) {
+            Ok(response) => response,
+            Err(err) if err.to_string() == sasl::ERR_UNEXPECTED_SERVER_CHALLENGE => {
+                self.finished = true;
+                self.client.finish(Some(&reply.payload))?;
+                Vec::new()
+            }
+            Err(err) => return Err(err),
+        };
+        Ok(MongoStep::Continue(SaslContinue {
+            conversation_id: reply.conversation_id,
+            payload,
+        }))
+    }
+
+    /// Handles a command error (`ok: 0`) reply, returning the error to
+    /// report.
+    pub fn command_error(&mut self, code: i32) -> anyhow::Error {
+        self.done = true;
+        match code {
+            AUTHENTICATION_FAILED => authentication_failed(&mut self.client),
+            MECHANISM_UNAVAILABLE => anyhow!(ERR_MECHANISM_UNAVAILABLE),
+            _ => anyhow!(ERR_UNEXPECTED_REPLY),
+        }
+    }
+
+    pub fn into_inner(self) -> C {
+        self.client
+    }
+}
+
+/// The reply of a server to `saslStart` or `saslContinue`.
+#[derive(Debug)]
+pub enum MongoReply {
+    /// Reply with `ok: 1`.
+    Reply(SaslReply),
+    /// Reply with `ok: 0` and the error code. The error describes the
+    /// failure, e.g. for auditing, and must not be disclosed to the client.
+    Failure(i32, anyhow::Error),
+}
+
+/// Handles SASL conversations on the server side, e.g. in a proxy,
+/// creating servers with a `ServerDispatcher`. One must be kept per
+/// connection.
+#[derive(Default)]
+pub struct MongoServer {
+    exchange: Option<Exchange>,
+    conversation_id: i32,
+    skip_empty_exchange: bool,
+    /// Whether the mechanism completed and the empty `saslContinue` is
+    /// awaited.
+    success_pending: bool,
+}
+
+impl MongoServer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Handles a `saslStart` command, aborting any conversation in
+    /// progress.
+    pub fn sasl_start(&mut self, dispatcher: &ServerDispatcher, conn: &ConnectionContext, command: &SaslStart) -> MongoReply {
+        self.exchange = None;
+        self.success_pending = false;
+        self.conversation_id += 1;
+        self.skip_empty_exchange = command.skip_empty_exchange;
+        let ir = (!command.payload.is_empty()).then_some(&command.payload[..]);
+        let step = match dispatcher.start(conn, &command.mechanism, ir) {
+            Ok((exchange, step)) => {
+                self.exchange = Some(exchange);
+                Ok(step)
+            }
+            Err(err) => Err(err),
+        };
+        self.step(step)
+    }
+
+    /// Handles a `saslContinue` command.
+    pub fn sasl_continue(&mut self, command: &SaslContinue) -> MongoReply {
+        if command.conversation_id != self.conversation_id || !self.is_in_progress() {
+            return MongoReply::Failure(PROTOCOL_ERROR, anyhow!(ERR_UNKNOWN_CONVERSATION));
+        }
+        if self.success_pending {
+            self.success_pending = false;
+            if !command.payload.is_empty() {
+                return MongoReply::Failure(AUTHENTICATION_FAILED, anyhow!(sasl::ERR_UNEXPECTED_SUCCESS_DATA));
+            }
+            return self.reply(true, Vec::new());
+        }
+        let step = match &mut self.exchange {
+            Some(exchange) => exchange.next(&command.payload),
+            None => return MongoReply::Failure(PROTOCOL_ERROR, anyhow!(ERR_UNKNOWN_CONVERSATION)),
+        };
+        self.step(step)
+    }
+
+    fn step(&mut self, step: Result<sasl::ServerStep>) -> MongoReply {
+        let reply = match step {
+            Ok(sasl::ServerStep::Challenge(challenge)) => return self.reply(false, challenge),
+            Ok(sasl::ServerStep::Done { additional_data: Some(data) }) if !self.skip_empty_exchange => {
+                self.success_pending = true;
+                self.reply(false, data)
+            }
+            Ok(sasl::ServerStep::Done { additional_data }) => self.reply(true, additional_data.unwrap_or_default()),
+            Err(err) if err.to_string() == dispatcher::ERR_UNKNOWN_MECHANISM || err.to_string() == ERR_MECHANISM_FORBIDDEN => {
+                MongoReply::Failure(MECHANISM_UNAVAILABLE, err)
+            }
+            Err(err) => MongoReply::Failure(AUTHENTICATION_FAILED, err),
+        };
+        self.exchange = None;
+        reply
+    }
+
+    fn reply(&self, done: bool, payload: Vec<u8>) -> MongoReply {
+        MongoReply::Reply(SaslReply {
+            conversation_id: self.conversation_id,
+            done,
+            payload,
+        })
+    }
+
+    /// Reports whether a conversation is in progress.
+    pub fn is_in_progress(&self) -> bool {
+        self.exchange.is_some() || self.success_pending
+    }
+}
+
+#[cfg(feature = "login")]
+#[test]
+fn test_mongodb_conversation() -> Result<()> {
+    use crate::login::{LoginClient, LoginServer, LOGIN};
+    use crate::registry::Registry;
+
+    let mut registry = Registry::new();
+    registry.register_server(LOGIN, || Box::new(LoginServer::new(Box::new(|_, p| match p {
+        "password" => Ok(()),
+        _ => bail!("invalid credentials"),
+    }))));
+    let dispatcher = ServerDispatcher::new(registry);
+    let conn = ConnectionContext {
+        tls: true,
+        ..Default::default()
+    };
+
+    let mut client = MongoClient::new(LoginClient::new("username".to_string(), "password".to_string()));
+    let mut server = MongoServer::new();
+    let MongoReply::Reply(mut reply) = server.sasl_start(&dispatcher, &conn, &client.sasl_start()?) else {
+        bail!("Expected the conversation to start");
+    };
+    let mut commands = 1;
+    while let MongoStep::Continue(command) = client.reply(&reply)? {
+        match server.sasl_continue(&command) {
+            MongoReply::Reply(next) => reply = next,
+            MongoReply::Failure(code, err) => bail!("Unexpected failure: {} {}", code, err),
+        }
+        commands += 1;
+    }
+    if commands != 2 || server.is_in_progress() {
+        bail!("Unexpected conversation: {} commands", commands);
+    }
+
+    let mut client = MongoClient::new(LoginClient::new("username".to_string(), "wrong".to_string()));
+    let MongoReply::Reply(reply) = server.sasl_start(&dispatcher, &conn, &client.sasl_start()?) else {
+        bail!("Expected the conversation to start");
+    };
+    let MongoStep::Continue(command) = client.reply(&reply)? else {
+        bail!("Expected a password request");
+    };
+    if !matches!(server.sasl_continue(&SaslContinue { conversation_id: 0, ..command.clone() }), MongoReply::Failure(PROTOCOL_ERROR, _)) {
+        bail!("Expected an unknown conversation");
+    }
+    match server.sasl_continue(&command) {
+        MongoReply::Failure(code, _) => match client.command_error(code) {
+            err if code == AUTHENTICATION_FAILED && err.to_string() == sasl::ERR_AUTHENTICATION_FAILED => {}
+            err => bail!("Unexpected error: {}", err),
+        },
+        reply => bail!("Unexpected reply: {:?}", reply),
+    }
+
+    Ok(())
+}
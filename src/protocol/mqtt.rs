@@ -0,0 +1,270 @@
+//! MQTT 5 enhanced authentication (section 4.12 of the specification). The
+//! client names the mechanism in the Authentication Method property of
+//! CONNECT, challenges and responses are carried by the Authentication
+//! Data property of AUTH packets with the Continue Authentication reason
+//! code, and the server ends the exchange with CONNACK. Once connected,
+//! clients may re-authenticate with an AUTH packet, the server then ending
+//! the exchange with AUTH, or DISCONNECT on failure.
+//!
+//! Encoding packets is left to the MQTT implementation.
+
+use super::authentication_failed;
+use crate::dispatcher::{self, Exchange, ServerDispatcher};
+use crate::policy::ConnectionContext;
+use crate::sasl;
+
+use anyhow::{anyhow, bail, Result};
+
+pub const ERR_UNEXPECTED_PACKET: &str = "sasl: mqtt: unexpected packet";
+pub const ERR_METHOD_MISMATCH: &str = "sasl: mqtt: authentication method changed during the exchange";
+pub const ERR_BAD_AUTHENTICATION_METHOD: &str = "sasl: mqtt: authentication method not supported by the server";
+pub const ERR_REFUSED: &str = "sasl: mqtt: connection refused";
+
+/// Reason codes of CONNACK, AUTH and DISCONNECT packets.
+pub const SUCCESS: u8 = 0x00;
+pub const CONTINUE_AUTHENTICATION: u8 = 0x18;
+pub const RE_AUTHENTICATE: u8 = 0x19;
+pub const PROTOCOL_ERROR: u8 = 0x82;
+pub const BAD_USER_NAME_OR_PASSWORD: u8 = 0x86;
+pub const NOT_AUTHORIZED: u8 = 0x87;
+pub const BAD_AUTHENTICATION_METHOD: u8 = 0x8C;
+
+/// The Authentication Method and Authentication Data properties of a
+/// CONNECT packet.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AuthProperties {
+    pub method: String,
+    pub data: Option<Vec<u8>>,
+}
+
+/// An AUTH packet.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Auth {
+    pub reason_code: u8,
+    pub method: String,
+    pub data: Option<Vec<u8>>,
+}
+
+/// The next step of a client exchange.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MqttStep {
+    /// Send the AUTH packet.
+    Send(Auth),
+    /// Re-authentication succeeded.
+    Done,
+}
+
+/// Drives a client through enhanced authentication.
+pub struct MqttClient<C> {
+    client: C,
+    method: String,
+    done: bool,
+}
+
+impl<C: sasl::Client> MqttClient<C> {
+    pub fn new(client: C) -> Self {
+        Self {
+            client,
+            method: String::new(),
+            done: false,
+        }
+    }
+
+    /// Starts the client and returns the authentication properties of
+    /// CONNECT.
+    pub fn connect(&mut self) -> Result<AuthProperties> {
+        let (method, data) = self.client.start()?;
+        self.method = method.clone();
+        Ok(AuthProperties { method, data })
+    }
+
+    /// Starts the client to re-authenticate and returns the AUTH packet to
+    /// send. The method must be the one used to connect.
+    pub fn reauthenticate(&mut self) -> Result<Auth> {
+        let AuthProperties { method, data } = self.connect()?;
+        Ok(Auth {
+            reason_code: RE_AUTHENTICATE,
+            method,
+            data,
+        })
+    }
+
+    /// Handles an AUTH packet received from the server.
+    pub fn auth(&mut self, auth: &Auth) -> Result<MqttStep> {
+        if self.method.is_empty() || self.done {
+            bail!(ERR_UNEXPECTED_PACKET);
+        }
+        if auth.method != self.method {
+            bail!(ERR_METHOD_MISMATCH);
+        }
+        match auth.reason_code {
+            CONTINUE_AUTHENTICATION => Ok(MqttStep::Send(Auth {
+                reason_code: CONTINUE_AUTHENTICATION,
+                method: self.method.clone(),
+                data: Some(self.client.next(auth.data.as_deref().unwrap_or_default())?),
+            })),
+            SUCCESS => {
+                self.done = true;
+                self.client.finish(auth.data.as_deref())?;
+                Ok(MqttStep::Done)
+            }
+            _ => bail!(ERR_UNEXPECTED_PACKET),
+        }
+    }
+
+    /// Handles a CONNACK packet, with its Authentication Data property.
+    pub fn connack(&mut self, reason_code: u8, data: Option<&[u8]>) -> Result<()> {
+        if self.method.is_empty() || self.done {
+            bail!(ERR_UNEXPECTED_PACKET);
+        }
+        self.done = true;
+        match reason_code {
+            SUCCESS => self.client.finish(data),
+            BAD_USER_NAME_OR_PASSWORD | NOT_AUTHORIZED => Err(authentication_failed(&mut self.client)),
+            BAD_AUTHENTICATION_METHOD => bail!(ERR_BAD_AUTHENTICATION_METHOD),
+            _ => bail!(ERR_REFUSED),
+        }
+    }
+
+    pub fn into_inner(self) -> C {
+        self.client
+    }
+}
+
+/// The reply of a server to CONNECT or AUTH.
+#[derive(Debug)]
+pub enum MqttReply {
+    /// Send the AUTH packet.
+    Auth(Auth),
+    /// Authentication succeeded: send CONNACK, or AUTH when
+    /// re-authenticating, with the success reason code and the additional
+    /// data if any.
+    Success(Option<Vec<u8>>),
+    /// Send CONNACK, or DISCONNECT when re-authenticating, with the reason
+    /// code. The error describes the failure, e.g. for auditing, and must
+    /// not be disclosed to the client.
+    Failure(u8, anyhow::Error),
+}
+
+/// Handles enhanced authentication on the broker side, creating servers
+/// with a `ServerDispatcher`. One must be kept per connection.
+#[derive(Default)]
+pub struct MqttServer {
+    exchange: Option<Exchange>,
+    method: String,
+}
+
+impl MqttServer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Handles the authentication properties of CONNECT.
+    pub fn connect(&mut self, dispatcher: &ServerDispatcher, conn: &ConnectionContext, properties: &AuthProperties) -> MqttReply {
+        self.start(dispatcher, conn, &properties.method, properties.data.as_deref())
+    }
+
+    /// Handles an AUTH packet, continuing the exchange in progress or
+    /// re-authenticating.
+    pub fn auth(&mut self, dispatcher: &ServerDispatcher, conn: &ConnectionContext, auth: &Auth) -> MqttReply {
+        if auth.method != self.method {
+            self.exchange = None;
+            return MqttReply::Failure(PROTOCOL_ERROR, anyhow!(ERR_METHOD_MISMATCH));
+        }
+        match (auth.reason_code, &mut self.exchange) {
+            (CONTINUE_AUTHENTICATION, Some(exchange)) => {
+                let step = exchange.next(auth.data.as_deref().unwrap_or_default());
+                self.step(step)
+            }
+            (RE_AUTHENTICATE, None) => self.start(dispatcher, conn, &auth.method, auth.data.as_deref()),
+            _ => {
+                self.exchange = None;
+                MqttReply::Failure(PROTOCOL_ERROR, anyhow!(ERR_UNEXPECTED_PACKET))
+            }
+        }
+    }
+
+    fn start(&mut self, dispatcher: &ServerDispatcher, conn: &ConnectionContext, method: &str, data: Option<&[u8]>) -> MqttReply {
+        self.method = method.to_string();
+        let step = match dispatcher.start(conn, method, data) {
+            Ok((exchange, step)) => {
+                self.exchange = Some(exchange);
+                Ok(step)
+            }
+            Err(err) => Err(err),
+        };
+        self.step(step)
+    }
+
+    fn step(&mut self, step: Result<sasl::ServerStep>) -> MqttReply {
+        let reply = match step {
+            Ok(sasl::ServerStep::Challenge(challenge)) => {
+                return MqttReply::Auth(Auth {
+                    reason_code: CONTINUE_AUTHENTICATION,
+                    method: self.method.clone(),
+                    data: Some(challenge),
+                });
+            }
+            Ok(sasl::ServerStep::Done { additional_data }) => MqttReply::Success(additional_data),
+            Err(err) if err.to_string() == dispatcher::ERR_UNKNOWN_MECHANISM => MqttReply::Failure(BAD_AUTHENTICATION_METHOD, err),
+            Err(err) => MqttReply::Failure(NOT_AUTHORIZED, err),
+        };
+        self.exchange = None;
+        reply
+    }
+
+    /// Returns the authentication method of the connection, once CONNECT
+    /// was handled.
+    pub fn method(&self) -> Option<&str> {
+        Some(self.method.as_str()).filter(|method| !method.is_empty())
+    }
+}
+
+#[cfg(feature = "login")]
+#[test]
+fn test_mqtt_exchange() -> Result<()> {
+    use crate::login::{LoginClient, LoginServer, LOGIN};
+    use crate::registry::Registry;
+
+    let mut registry = Registry::new();
+    registry.register_server(LOGIN, || Box::new(LoginServer::new(Box::new(|_, p| match p {
+        "password" => Ok(()),
+        _ => bail!("invalid credentials"),
+    }))));
+    let dispatcher = ServerDispatcher::new(registry);
+    let conn = ConnectionContext {
+        tls: true,
+        ..Default::default()
+    };
+
+    let mut client = MqttClient::new(LoginClient::new("username".to_string(), "password".to_string()));
+    let mut server = MqttServer::new();
+    let MqttReply::Auth(auth) = server.connect(&dispatcher, &conn, &client.connect()?) else {
+        bail!("Expected a password request");
+    };
+    let MqttStep::Send(auth) = client.auth(&auth)? else {
+        bail!("Expected a password");
+    };
+    match server.auth(&dispatcher, &conn, &auth) {
+        MqttReply::Success(data) => client.connack(SUCCESS, data.as_deref())?,
+        reply => bail!("Unexpected reply: {:?}", reply),
+    }
+
+    let mut client = MqttClient::new(LoginClient::new("username".to_string(), "wrong".to_string()));
+    let MqttReply::Auth(auth) = server.auth(&dispatcher, &conn, &client.reauthenticate()?) else {
+        bail!("Expected a password request");
+    };
+    let MqttStep::Send(auth) = client.auth(&auth)? else {
+        bail!("Expected a password");
+    };
+    if !matches!(server.auth(&dispatcher, &conn, &auth), MqttReply::Failure(NOT_AUTHORIZED, _)) || server.method() != Some(LOGIN) {
+        bail!("Expected re-authentication to fail");
+    }
+
+    let mut client = MqttClient::new(LoginClient::new("username".to_string(), "password".to_string()));
+    if !matches!(server.auth(&dispatcher, &conn, &Auth { method: "PLAIN".to_string(), ..client.reauthenticate()? }), MqttReply::Failure(PROTOCOL_ERROR, _)) {
+        bail!("Expected the method change to be rejected");
+    }
+
+    Ok(())
+}
@@ -0,0 +1,205 @@
+//! NNTP `AUTHINFO SASL` exchanges (RFC 4643 section 2.4).
+
+use super::{ClientStep, LineClient, LineOutcome, LineServer, ServerReply};
+use crate::dispatcher::{self, ServerDispatcher};
+use crate::framing;
+use crate::policy::{ConnectionContext, ERR_MECHANISM_FORBIDDEN};
+use crate::replay::ReplayGuard;
+use crate::sasl;
+
+use anyhow::{anyhow, bail, Result};
+use std::sync::Arc;
+
+pub const ERR_MECHANISM_UNSUPPORTED: &str = "sasl: nntp: mechanism not supported by the server";
+pub const ERR_UNEXPECTED_RESPONSE: &str = "sasl: nntp: unexpected response";
+pub const ERR_INVALID_COMMAND: &str = "sasl: nntp: invalid AUTHINFO SASL command";
+
+/// The prefix of challenges.
+pub const CONTINUATION: &str = "383 ";
+
+/// The prefix of success responses carrying additional data.
+pub const SUCCESS_WITH_DATA: &str = "283 ";
+
+/// The maximum length of a command line, including its CRLF terminator
+/// (RFC 3977 section 3.1). Initial responses which don't fit are sent in
+/// reply to the first challenge instead.
+pub const MAX_COMMAND_LENGTH: usize = 512;
+
+/// Drives a client through an `AUTHINFO SASL` command.
+pub struct NntpClient<C> {
+    inner: LineClient<C>,
+}
+
+impl<C: sasl::Client> NntpClient<C> {
+    pub fn new(client: C) -> Self {
+        Self {
+            inner: LineClient::new(client),
+        }
+    }
+
+    /// Refuses challenges replayed from previous exchanges sharing the same
+    /// guard with `sasl::Error::ReplayDetected`, e.g. when authentication
+    /// is retried.
+    pub fn with_replay_guard(mut self, guard: Arc<ReplayGuard>) -> Self {
+        self.inner.set_replay_guard(guard);
+        self
+    }
+
+    /// Starts the client and returns the `AUTHINFO SASL` command, with the
+    /// initial response if the mechanism has one and it fits on the line.
+    pub fn command(&mut self) -> Result<String> {
+        let (mechanism, ir) = self.inner.start()?;
+        let mut command = format!("AUTHINFO SASL {}", mechanism);
+        if let Some(ir) = ir {
+            let encoded = framing::encode_initial_response(Some(&ir)).unwrap_or_default();
+            if command.len() + 1 + encoded.len() + 2 <= MAX_COMMAND_LENGTH {
+                command.push(' ');
+                command.push_str(&encoded);
+            } else {
+                self.inner.defer(ir);
+            }
+        }
+        Ok(command)
+    }
+
+    /// Handles a server response line. On error while the exchange is in
+    /// progress, the exchange must be aborted with `cancel`.
+    pub fn reply(&mut self, line: &str) -> Result<ClientStep> {
+        let line = line.trim_end_matches(['\r', '\n']);
+        match line.get(..3) {
+            Some("383") if self.is_in_progress() => self.inner.challenge(CONTINUATION, line),
+            Some("281") if self.is_in_progress() => self.inner.success(None),
+            Some("283") if self.is_in_progress() => {
+                let data = framing::decode_challenge(SUCCESS_WITH_DATA, line)?;
+                self.inner.success(Some(&data))
+            }
+            Some("481") if self.inner.is_canceled() => self.inner.fail(sasl::Error::Canceled.into()),
+            Some("481") => self.inner.fail(anyhow!(sasl::ERR_AUTHENTICATION_FAILED)),
+            Some("503") => self.inner.fail(anyhow!(ERR_MECHANISM_UNSUPPORTED)),
+            _ => self.inner.fail(anyhow!(ERR_UNEXPECTED_RESPONSE)),
+        }
+    }
+
+    /// Aborts the exchange, returning the line to send instead of a
+    /// response: the mechanism's own cancel response if it has one, `*`
+    /// otherwise.
+    pub fn cancel(&mut self) -> String {
+        self.inner.cancel()
+    }
+
+    /// Reports whether the command was sent and the server hasn't replied
+    /// with the outcome yet.
+    pub fn is_in_progress(&self) -> bool {
+        self.inner.is_in_progress()
+    }
+
+    pub fn into_inner(self) -> C {
+        self.inner.into_inner()
+    }
+}
+
+/// Handles an `AUTHINFO SASL` command on the server side, creating servers
+/// with a `ServerDispatcher`.
+pub struct NntpServer {
+    inner: LineServer,
+}
+
+impl NntpServer {
+    /// Handles an `AUTHINFO SASL` command line. The exchange continues with
+    /// `response` as long as the reply is a challenge.
+    pub fn start(dispatcher: &ServerDispatcher, conn: &ConnectionContext, command: &str) -> (Self, ServerReply) {
+        let mut server = Self {
+            inner: LineServer::new(CONTINUATION).with_success_data(),
+        };
+        let reply = match parse_command(command) {
+            Ok((mechanism, ir)) => reply(server.inner.start(dispatcher, conn, mechanism, ir.as_deref())),
+            Err(err) => ServerReply::Failure("501 Syntax error in AUTHINFO SASL command".to_string(), err),
+        };
+        (server, reply)
+    }
+
+    /// Handles a client response line.
+    pub fn response(&mut self, line: &str) -> ServerReply {
+        reply(self.inner.response(line))
+    }
+
+    /// Returns the exchange's mechanism, if it started.
+    pub fn mechanism_name(&self) -> Option<&str> {
+        self.inner.mechanism_name()
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.inner.is_done()
+    }
+}
+
+/// Parses an `AUTHINFO SASL <mechanism> [initial-response]` command.
+pub fn parse_command(command: &str) -> Result<(&str, Option<Vec<u8>>)> {
+    let mut args = command.trim_end_matches(['\r', '\n']).split(' ');
+    if !args.next().is_some_and(|verb| verb.eq_ignore_ascii_case("AUTHINFO")) {
+        bail!(ERR_INVALID_COMMAND);
+    }
+    if !args.next().is_some_and(|verb| verb.eq_ignore_ascii_case("SASL")) {
+        bail!(ERR_INVALID_COMMAND);
+    }
+    let mechanism = args.next().filter(|m| !m.is_empty()).ok_or_else(|| anyhow!(ERR_INVALID_COMMAND))?;
+    let ir = framing::decode_initial_response(args.next())?;
+    if args.next().is_some() {
+        bail!(ERR_INVALID_COMMAND);
+    }
+    Ok((mechanism, ir))
+}
+
+fn reply(outcome: LineOutcome) -> ServerReply {
+    let (line, err) = match outcome {
+        LineOutcome::Challenge(challenge) => return ServerReply::Challenge(challenge),
+        LineOutcome::Success(None) => return ServerReply::Success("281 Authentication accepted".to_string()),
+        LineOutcome::Success(Some(data)) => return ServerReply::Success(framing::encode_challenge(SUCCESS_WITH_DATA, &data)),
+        LineOutcome::Malformed(err) => ("482 SASL protocol error", err),
+        LineOutcome::Failure(err) if err.to_string() == dispatcher::ERR_UNKNOWN_MECHANISM => ("503 Mechanism not recognized", err),
+        LineOutcome::Failure(err) if err.to_string() == ERR_MECHANISM_FORBIDDEN => ("483 Encryption or stronger authentication required", err),
+        LineOutcome::Failure(err) => ("481 Authentication failed", err),
+    };
+    ServerReply::Failure(line.to_string(), err)
+}
+
+#[cfg(feature = "plain")]
+#[test]
+fn test_nntp_exchange() -> Result<()> {
+    use crate::plain::{PlainClient, PlainServer, PLAIN};
+    use crate::registry::Registry;
+
+    let mut registry = Registry::new();
+    registry.register_server(PLAIN, || Box::new(PlainServer::new(Box::new(|_, _, _| Ok(())))));
+    let dispatcher = ServerDispatcher::new(registry);
+    let conn = ConnectionContext {
+        tls: true,
+        ..Default::default()
+    };
+
+    let mut client = NntpClient::new(PlainClient::new(String::new(), "username".to_string(), "password".to_string()));
+    let command = client.command()?;
+    let (_, reply) = NntpServer::start(&dispatcher, &conn, &command);
+    if command != "AUTHINFO SASL PLAIN AHVzZXJuYW1lAHBhc3N3b3Jk" || client.reply(reply.line())? != ClientStep::Done {
+        bail!("Unexpected exchange: {} {:?}", command, reply);
+    }
+
+    let mut client = NntpClient::new(PlainClient::new(String::new(), "username".to_string(), "p".repeat(500)));
+    let (mut server, reply) = NntpServer::start(&dispatcher, &conn, &client.command()?);
+    if reply.line() != "383 " || !matches!(client.reply(reply.line())?, ClientStep::Send(_)) {
+        bail!("Expected the initial response to be deferred");
+    }
+    match server.response(&client.cancel()) {
+        ServerReply::Failure(line, _) if line.starts_with("481 ") => match client.reply(&line) {
+            Err(err) if matches!(err.downcast_ref(), Some(sasl::Error::Canceled)) => {}
+            _ => bail!("Expected the client to report the cancellation"),
+        },
+        reply => bail!("Unexpected reply: {:?}", reply),
+    }
+
+    if NntpServer::start(&dispatcher, &conn, "AUTHINFO SASL CRAM-MD5").1.line() != "503 Mechanism not recognized" {
+        bail!("Expected an unsupported mechanism");
+    }
+
+    Ok(())
+}
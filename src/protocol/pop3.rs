@@ -0,0 +1,199 @@
+//! POP3 `AUTH` exchanges (RFC 5034).
+
+use super::{ClientStep, LineClient, LineOutcome, LineServer, ServerReply};
+use crate::dispatcher::{self, ServerDispatcher};
+use crate::framing::{self, IMAP_CONTINUATION};
+use crate::policy::ConnectionContext;
+use crate::replay::ReplayGuard;
+use crate::sasl;
+
+use anyhow::{anyhow, bail, Result};
+use std::sync::Arc;
+
+pub const ERR_UNEXPECTED_RESPONSE: &str = "sasl: pop3: unexpected response";
+pub const ERR_INVALID_COMMAND: &str = "sasl: pop3: invalid AUTH command";
+
+/// The maximum length of a command line, including its CRLF terminator
+/// (RFC 2449 section 4). Initial responses which don't fit are sent in
+/// reply to the first challenge instead.
+pub const MAX_COMMAND_LENGTH: usize = 255;
+
+/// Drives a client through an `AUTH` command.
+pub struct Pop3Client<C> {
+    inner: LineClient<C>,
+}
+
+impl<C: sasl::Client> Pop3Client<C> {
+    pub fn new(client: C) -> Self {
+        Self {
+            inner: LineClient::new(client),
+        }
+    }
+
+    /// Refuses challenges replayed from previous exchanges sharing the same
+    /// guard with `sasl::Error::ReplayDetected`, e.g. when authentication
+    /// is retried.
+    pub fn with_replay_guard(mut self, guard: Arc<ReplayGuard>) -> Self {
+        self.inner.set_replay_guard(guard);
+        self
+    }
+
+    /// Starts the client and returns the `AUTH` command, with the initial
+    /// response if the mechanism has one and it fits on the line.
+    pub fn command(&mut self) -> Result<String> {
+        let (mechanism, ir) = self.inner.start()?;
+        let mut command = format!("AUTH {}", mechanism);
+        if let Some(ir) = ir {
+            let encoded = framing::encode_initial_response(Some(&ir)).unwrap_or_default();
+            if command.len() + 1 + encoded.len() + 2 <= MAX_COMMAND_LENGTH {
+                command.push(' ');
+                command.push_str(&encoded);
+            } else {
+                self.inner.defer(ir);
+            }
+        }
+        Ok(command)
+    }
+
+    /// Handles a server response line. On error while the exchange is in
+    /// progress, the exchange must be aborted with `cancel`.
+    pub fn reply(&mut self, line: &str) -> Result<ClientStep> {
+        let line = line.trim_end_matches(['\r', '\n']);
+        if line.starts_with("+OK") && self.is_in_progress() {
+            self.inner.success(None)
+        } else if line.starts_with('+') && self.is_in_progress() {
+            self.inner.challenge(IMAP_CONTINUATION, line)
+        } else if line.starts_with("-ERR") && self.inner.is_canceled() {
+            self.inner.fail(sasl::Error::Canceled.into())
+        } else if line.starts_with("-ERR") {
+            self.inner.fail(anyhow!(sasl::ERR_AUTHENTICATION_FAILED))
+        } else {
+            self.inner.fail(anyhow!(ERR_UNEXPECTED_RESPONSE))
+        }
+    }
+
+    /// Aborts the exchange, returning the line to send instead of a
+    /// response: the mechanism's own cancel response if it has one, `*`
+    /// otherwise.
+    pub fn cancel(&mut self) -> String {
+        self.inner.cancel()
+    }
+
+    /// Reports whether the command was sent and the server hasn't replied
+    /// with the outcome yet.
+    pub fn is_in_progress(&self) -> bool {
+        self.inner.is_in_progress()
+    }
+
+    pub fn into_inner(self) -> C {
+        self.inner.into_inner()
+    }
+}
+
+/// Handles an `AUTH` command on the server side, creating servers with a
+/// `ServerDispatcher`.
+pub struct Pop3Server {
+    inner: LineServer,
+}
+
+impl Pop3Server {
+    /// Handles an `AUTH` command line. The exchange continues with
+    /// `response` as long as the reply is a challenge.
+    pub fn start(dispatcher: &ServerDispatcher, conn: &ConnectionContext, command: &str) -> (Self, ServerReply) {
+        let mut server = Self {
+            inner: LineServer::new(IMAP_CONTINUATION),
+        };
+        let reply = match parse_command(command) {
+            Ok((mechanism, ir)) => reply(server.inner.start(dispatcher, conn, mechanism, ir.as_deref())),
+            Err(err) => ServerReply::Failure("-ERR Invalid AUTH command".to_string(), err),
+        };
+        (server, reply)
+    }
+
+    /// Handles a client response line.
+    pub fn response(&mut self, line: &str) -> ServerReply {
+        reply(self.inner.response(line))
+    }
+
+    /// Returns the exchange's mechanism, if it started.
+    pub fn mechanism_name(&self) -> Option<&str> {
+        self.inner.mechanism_name()
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.inner.is_done()
+    }
+}
+
+/// Parses an `AUTH <mechanism> [initial-response]` command. The `AUTH`
+/// command without arguments, listing mechanisms, isn't part of RFC 5034
+/// and is rejected.
+pub fn parse_command(command: &str) -> Result<(&str, Option<Vec<u8>>)> {
+    let mut args = command.trim_end_matches(['\r', '\n']).split(' ');
+    if !args.next().is_some_and(|verb| verb.eq_ignore_ascii_case("AUTH")) {
+        bail!(ERR_INVALID_COMMAND);
+    }
+    let mechanism = args.next().filter(|m| !m.is_empty()).ok_or_else(|| anyhow!(ERR_INVALID_COMMAND))?;
+    let ir = framing::decode_initial_response(args.next())?;
+    if args.next().is_some() {
+        bail!(ERR_INVALID_COMMAND);
+    }
+    Ok((mechanism, ir))
+}
+
+fn reply(outcome: LineOutcome) -> ServerReply {
+    let (line, err) = match outcome {
+        LineOutcome::Challenge(challenge) => return ServerReply::Challenge(challenge),
+        LineOutcome::Success(_) => return ServerReply::Success("+OK Authentication successful".to_string()),
+        LineOutcome::Malformed(err) => ("-ERR Invalid response", err),
+        LineOutcome::Failure(err) if err.to_string() == dispatcher::ERR_UNKNOWN_MECHANISM => ("-ERR Unsupported authentication mechanism", err),
+        LineOutcome::Failure(err) if matches!(err.downcast_ref(), Some(sasl::Error::Canceled)) => ("-ERR Authentication canceled", err),
+        LineOutcome::Failure(err) if matches!(err.downcast_ref(), Some(sasl::Error::RateLimited)) => ("-ERR [SYS/TEMP] Too many failed attempts, try again later", err),
+        // The AUTH response code is defined in RFC 3206.
+        LineOutcome::Failure(err) => ("-ERR [AUTH] Authentication failed", err),
+    };
+    ServerReply::Failure(line.to_string(), err)
+}
+
+#[cfg(feature = "plain")]
+#[test]
+fn test_pop3_exchange() -> Result<()> {
+    use crate::plain::{PlainClient, PlainServer, PLAIN};
+    use crate::registry::Registry;
+
+    let mut registry = Registry::new();
+    registry.register_server(PLAIN, || Box::new(PlainServer::new(Box::new(|_, _, _| Ok(())))));
+    let dispatcher = ServerDispatcher::new(registry);
+    let conn = ConnectionContext {
+        tls: true,
+        ..Default::default()
+    };
+
+    for (password, expected_lines) in [("password".to_string(), 1), ("p".repeat(200), 2)] {
+        let mut client = Pop3Client::new(PlainClient::new(String::new(), "username".to_string(), password));
+        let mut lines = vec![client.command()?];
+        let (mut server, mut reply) = Pop3Server::start(&dispatcher, &conn, &lines[0]);
+        while let ClientStep::Send(line) = client.reply(reply.line())? {
+            reply = server.response(&line);
+            lines.push(line);
+        }
+        if reply.line() != "+OK Authentication successful" || lines.len() != expected_lines {
+            bail!("Unexpected exchange: {:?}", lines);
+        }
+    }
+
+    let mut client = Pop3Client::new(PlainClient::new(String::new(), "username".to_string(), "p".repeat(200)));
+    let (mut server, reply) = Pop3Server::start(&dispatcher, &conn, &client.command()?);
+    if reply.line() != "+ " {
+        bail!("Expected an empty challenge");
+    }
+    match server.response(&client.cancel()) {
+        ServerReply::Failure(line, _) => match client.reply(&line) {
+            Err(err) if matches!(err.downcast_ref(), Some(sasl::Error::Canceled)) => {}
+            _ => bail!("Expected the client to report the cancellation"),
+        },
+        reply => bail!("Unexpected reply: {:?}", reply),
+    }
+
+    Ok(())
+}
@@ -0,0 +1,272 @@
+//! PostgreSQL SASL authentication (protocol 3.0, section 53.3 of the
+//! PostgreSQL documentation). Adapters handle the bodies of messages, after
+//! their type byte and length: `Authentication` ('R') messages sent by the
+//! server, and `SASLInitialResponse` and `SASLResponse` ('p') messages sent
+//! by the client.
+//!
+//! PostgreSQL only defines SCRAM-SHA-256 and SCRAM-SHA-256-PLUS, but any
+//! mechanism can be carried.
+
+use crate::dispatcher::{self, Exchange, ServerDispatcher};
+use crate::policy::{ConnectionContext, ERR_MECHANISM_FORBIDDEN};
+use crate::sasl;
+
+use anyhow::{anyhow, bail, Result};
+
+pub const ERR_MALFORMED_MESSAGE: &str = "sasl: postgres: malformed message";
+pub const ERR_UNEXPECTED_MESSAGE: &str = "sasl: postgres: unexpected message";
+pub const ERR_MECHANISM_UNSUPPORTED: &str = "sasl: postgres: mechanism not supported by the server";
+
+/// The codes of `Authentication` messages.
+pub const AUTHENTICATION_OK: i32 = 0;
+pub const AUTHENTICATION_SASL: i32 = 10;
+pub const AUTHENTICATION_SASL_CONTINUE: i32 = 11;
+pub const AUTHENTICATION_SASL_FINAL: i32 = 12;
+
+/// The SQLSTATE codes of `ErrorResponse` messages reporting failures.
+pub const PROTOCOL_VIOLATION: &str = "08P01";
+pub const INVALID_AUTHORIZATION_SPECIFICATION: &str = "28000";
+pub const INVALID_PASSWORD: &str = "28P01";
+
+/// Parses the mechanisms listed in an `AuthenticationSASL` message body,
+/// e.g. to select one with a `Negotiator`.
+pub fn parse_mechanisms(body: &[u8]) -> Result<Vec<String>> {
+    let (code, mut data) = parse_authentication(body)?;
+    if code != AUTHENTICATION_SASL {
+        bail!(ERR_UNEXPECTED_MESSAGE);
+    }
+    let mut mechanisms = Vec::new();
+    loop {
+        let (mechanism, rest) = cstring(data)?;
+        if mechanism.is_empty() {
+            return Ok(mechanisms);
+        }
+        mechanisms.push(mechanism.to_string());
+        data = rest;
+    }
+}
+
+/// Encodes the body of an `AuthenticationSASL` message.
+pub fn encode_mechanisms(mechanisms: &[&str]) -> Vec<u8> {
+    let mut body = AUTHENTICATION_SASL.to_be_bytes().to_vec();
+    for mechanism in mechanisms {
+        body.extend_from_slice(mechanism.as_bytes());
+        body.push(0);
+    }
+    body.push(0);
+    body
+}
+
+fn parse_authentication(body: &[u8]) -> Result<(i32, &[u8])> {
+    let code = body.get(..4).ok_or_else(|| anyhow!(ERR_MALFORMED_MESSAGE))?;
+    Ok((i32::from_be_bytes(code.try_into()?), &body[4..]))
+}
+
+fn encode_authentication(code: i32, data: &[u8]) -> Vec<u8> {
+    let mut body = code.to_be_bytes().to_vec();
+    body.extend_from_slice(data);
+    body
+}
+
+fn cstring(data: &[u8]) -> Result<(&str, &[u8])> {
+    let end = data.iter().position(|&b| b == 0).ok_or_else(|| anyhow!(ERR_MALFORMED_MESSAGE))?;
+    Ok((std::str::from_utf8(&data[..end])?, &data[end + 1..]))
+}
+
+/// The next step of a client exchange.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PostgresStep {
+    /// Send a 'p' message with this body.
+    Send(Vec<u8>),
+    /// Nothing to send, pass the next `Authentication` message.
+    Wait,
+    /// Authentication succeeded.
+    Done,
+}
+
+/// Drives a client through SASL authentication.
+pub struct PostgresClient<C> {
+    client: C,
+    started: bool,
+    finished: bool,
+}
+
+impl<C: sasl::Client> PostgresClient<C> {
+    pub fn new(client: C) -> Self {
+        Self {
+            client,
+            started: false,
+            finished: false,
+        }
+    }
+
+    /// Handles the body of an `Authentication` message.
+    pub fn authentication(&mut self, body: &[u8]) -> Result<PostgresStep> {
+        let (code, data) = parse_authentication(body)?;
+        match code {
+            AUTHENTICATION_SASL if !self.started => {
+                if !parse_mechanisms(body)?.iter().any(|m| m == self.client.mechanism_name()) {
+                    bail!(ERR_MECHANISM_UNSUPPORTED);
+                }
+                let (mechanism, ir) = self.client.start()?;
+                self.started = true;
+                let mut response = mechanism.into_bytes();
+                response.push(0);
+                match ir {
+                    Some(ir) => {
+                        response.extend_from_slice(&(ir.len() as i32).to_be_bytes());
+                        response.extend_from_slice(&ir);
+                    }
+                    None => response.extend_from_slice(&(-1i32).to_be_bytes()),
+                }
+                Ok(PostgresStep::Send(response))
+            }
+            AUTHENTICATION_SASL_CONTINUE if self.started && !self.finished => Ok(PostgresStep::Send(self.client.next(data)?)),
+            AUTHENTICATION_SASL_FINAL if self.started && !self.finished => {
+                self.finished = true;
+                self.client.finish(Some(data))?;
+                Ok(PostgresStep::Wait)
+            }
+            AUTHENTICATION_OK if self.started => {
+                if !self.finished {
+                    self.finished = true;
+                    self.client.finish(None)?;
+                }
+                Ok(PostgresStep::Done)
+            }
+            _ => bail!(ERR_UNEXPECTED_MESSAGE),
+        }
+    }
+
+    pub fn into_inner(self) -> C {
+        self.client
+    }
+}
+
+/// The reply of a server to a 'p' message.
+#[derive(Debug)]
+pub enum PostgresReply {
+    /// Send an `Authentication` message with this body.
+    Send(Vec<u8>),
+    /// Authentication succeeded, send `Authentication` messages with these
+    /// bodies: `AuthenticationSASLFinal` if the mechanism sent additional
+    /// data, and `AuthenticationOk`.
+    Success(Vec<Vec<u8>>),
+    /// Send an `ErrorResponse` with the SQLSTATE code. The error describes
+    /// the failure, e.g. for auditing, and must not be disclosed to the
+    /// client.
+    Failure(&'static str, anyhow::Error),
+}
+
+/// Handles SASL authentication on the server side, e.g. in a proxy,
+/// creating servers with a `ServerDispatcher`. The exchange starts by
+/// sending `encode_mechanisms`.
+#[derive(Default)]
+pub struct PostgresServer {
+    exchange: Option<Exchange>,
+}
+
+impl PostgresServer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Handles the body of a 'p' message: a `SASLInitialResponse` first,
+    /// `SASLResponse` messages then.
+    pub fn message(&mut self, dispatcher: &ServerDispatcher, conn: &ConnectionContext, body: &[u8]) -> PostgresReply {
+        let step = match &mut self.exchange {
+            Some(exchange) => exchange.next(body),
+            None => match parse_initial_response(body) {
+                Ok((mechanism, ir)) => match dispatcher.start(conn, mechanism, ir) {
+                    Ok((exchange, step)) => {
+                        self.exchange = Some(exchange);
+                        Ok(step)
+                    }
+                    Err(err) => Err(err),
+                },
+                Err(err) => return PostgresReply::Failure(PROTOCOL_VIOLATION, err),
+            },
+        };
+        let reply = match step {
+            Ok(sasl::ServerStep::Challenge(challenge)) => {
+                return PostgresReply::Send(encode_authentication(AUTHENTICATION_SASL_CONTINUE, &challenge));
+            }
+            Ok(sasl::ServerStep::Done { additional_data }) => {
+                let mut messages = Vec::new();
+                if let Some(data) = additional_data {
+                    messages.push(encode_authentication(AUTHENTICATION_SASL_FINAL, &data));
+                }
+                messages.push(encode_authentication(AUTHENTICATION_OK, &[]));
+                PostgresReply::Success(messages)
+            }
+            Err(err) if err.to_string() == dispatcher::ERR_UNKNOWN_MECHANISM => PostgresReply::Failure(PROTOCOL_VIOLATION, err),
+            Err(err) if err.to_string() == ERR_MECHANISM_FORBIDDEN => PostgresReply::Failure(INVALID_AUTHORIZATION_SPECIFICATION, err),
+            Err(err) => PostgresReply::Failure(INVALID_PASSWORD, err),
+        };
+        self.exchange = None;
+        reply
+    }
+}
+
+/// Parses the body of a `SASLInitialResponse` message.
+fn parse_initial_response(body: &[u8]) -> Result<(&str, Option<&[u8]>)> {
+    let (mechanism, rest) = cstring(body)?;
+    let len = rest.get(..4).ok_or_else(|| anyhow!(ERR_MALFORMED_MESSAGE))?;
+    let ir = match i32::from_be_bytes(len.try_into()?) {
+        -1 if rest.len() == 4 => None,
+        len if len >= 0 && rest.len() == 4 + len as usize => Some(&rest[4..]),
+        _ => bail!(ERR_MALFORMED_MESSAGE),
+    };
+    Ok((mechanism, ir))
+}
+
+#[cfg(feature = "plain")]
+#[test]
+fn test_postgres_exchange() -> Result<()> {
+    use crate::plain::{PlainClient, PlainServer, PLAIN};
+    use crate::registry::Registry;
+
+    let mut registry = Registry::new();
+    registry.register_server(PLAIN, || {
+        Box::new(PlainServer::new(Box::new(|_, _, p| match p {
+            "password" => Ok(()),
+            _ => bail!("invalid password"),
+        })))
+    });
+    let dispatcher = ServerDispatcher::new(registry);
+    let conn = ConnectionContext {
+        tls: true,
+        ..Default::default()
+    };
+
+    let mechanisms = encode_mechanisms(&["SCRAM-SHA-256", PLAIN]);
+    if parse_mechanisms(&mechanisms)? != ["SCRAM-SHA-256", PLAIN] {
+        bail!("Unexpected mechanisms");
+    }
+
+    for (password, success) in [("password", true), ("wrong", false)] {
+        let mut client = PostgresClient::new(PlainClient::new(String::new(), "username".to_string(), password.to_string()));
+        let mut server = PostgresServer::new();
+        let PostgresStep::Send(response) = client.authentication(&mechanisms)? else {
+            bail!("Expected a SASLInitialResponse");
+        };
+        if response != b"PLAIN\x00\x00\x00\x00\x12\x00username\x00password"[..] && success {
+            bail!("Unexpected SASLInitialResponse: {:?}", response);
+        }
+        match server.message(&dispatcher, &conn, &response) {
+            PostgresReply::Success(messages) if success => {
+                if messages.len() != 1 || client.authentication(&messages[0])? != PostgresStep::Done {
+                    bail!("Expected authentication to succeed");
+                }
+            }
+            PostgresReply::Failure(code, _) if !success && code == INVALID_PASSWORD => {}
+            reply => bail!("Unexpected reply: {:?}", reply),
+        }
+    }
+
+    if !matches!(PostgresServer::new().message(&dispatcher, &conn, b"PLAIN\x00\x00\x00"), PostgresReply::Failure(PROTOCOL_VIOLATION, _)) {
+        bail!("Expected a malformed message to be rejected");
+    }
+
+    Ok(())
+}
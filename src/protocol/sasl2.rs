@@ -0,0 +1,394 @@
+//! XMPP Extensible SASL Profile exchanges (SASL2, XEP-0388). Messages are
+//! modeled after the elements of the `urn:xmpp:sasl:2` namespace, their
+//! XML encoding and the inline features negotiated alongside them (e.g.
+//! resource binding) being left to the XMPP implementation.
+//!
+//! Once the mechanism completed, servers may require further tasks, such
+//! as a second factor, before reporting success. Tasks are driven like
+//! mechanisms: client and server implementations of `sasl::Client` and
+//! `sasl::Server`, named after the task.
+
+use super::authentication_failed;
+use crate::dispatcher::{self, Exchange, ServerDispatcher};
+use crate::policy::{ConnectionContext, ERR_MECHANISM_FORBIDDEN};
+use crate::sasl;
+
+use anyhow::{anyhow, bail, Result};
+
+pub const ERR_UNEXPECTED_MESSAGE: &str = "sasl: sasl2: unexpected message";
+pub const ERR_MECHANISM_UNSUPPORTED: &str = "sasl: sasl2: mechanism not supported by the server";
+pub const ERR_NO_SUPPORTED_TASK: &str = "sasl: sasl2: none of the tasks required by the server is supported";
+pub const ERR_UNKNOWN_TASK: &str = "sasl: sasl2: unknown task";
+
+/// Failure conditions (RFC 6120 section 6.5).
+pub const ABORTED: &str = "aborted";
+pub const ENCRYPTION_REQUIRED: &str = "encryption-required";
+pub const INVALID_MECHANISM: &str = "invalid-mechanism";
+pub const MALFORMED_REQUEST: &str = "malformed-request";
+pub const MECHANISM_TOO_WEAK: &str = "mechanism-too-weak";
+pub const NOT_AUTHORIZED: &str = "not-authorized";
+
+/// The `<user-agent/>` element of an `<authenticate/>` request.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct UserAgent {
+    /// A stable identifier of the client installation, e.g. a UUID.
+    pub id: Option<String>,
+    pub software: Option<String>,
+    pub device: Option<String>,
+}
+
+/// A message sent by clients.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ClientMessage {
+    /// `<authenticate mechanism='...'/>`
+    Authenticate {
+        mechanism: String,
+        initial_response: Option<Vec<u8>>,
+        user_agent: Option<UserAgent>,
+    },
+    /// `<response/>`
+    Response(Vec<u8>),
+    /// `<next task='...'/>`, selecting a task required by the server.
+    Next { task: String, data: Option<Vec<u8>> },
+    /// `<task-data/>`
+    TaskData(Vec<u8>),
+    /// `<abort/>`
+    Abort,
+}
+
+/// A message sent by servers.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ServerMessage {
+    /// `<challenge/>`
+    Challenge(Vec<u8>),
+    /// `<continue/>`, listing the tasks the client must choose from once
+    /// the mechanism completed.
+    Continue {
+        additional_data: Option<Vec<u8>>,
+        tasks: Vec<String>,
+        text: Option<String>,
+    },
+    /// `<task-data/>`
+    TaskData(Vec<u8>),
+    /// `<success/>`
+    Success {
+        additional_data: Option<Vec<u8>>,
+        authorization_identifier: String,
+    },
+    /// `<failure/>`, with one of the failure conditions.
+    Failure { condition: String, text: Option<String> },
+}
+
+/// The next step of a client exchange.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Sasl2Step {
+    Send(ClientMessage),
+    /// Authentication succeeded, with the JID the client is authorized as.
+    Done { authorization_identifier: String },
+}
+
+/// Drives a client through a SASL2 exchange.
+pub struct Sasl2Client<C> {
+    client: C,
+    user_agent: Option<UserAgent>,
+    tasks: Vec<sasl::BoxClient>,
+    task: Option<usize>,
+    started: bool,
+    done: bool,
+    canceled: bool,
+}
+
+impl<C: sasl::Client> Sasl2Client<C> {
+    pub fn new(client: C) -> Self {
+        Self {
+            client,
+            user_agent: None,
+            tasks: Vec::new(),
+            task: None,
+            started: false,
+            done: false,
+            canceled: false,
+        }
+    }
+
+    pub fn with_user_agent(mut self, user_agent: UserAgent) -> Self {
+        self.user_agent = Some(user_agent);
+        self
+    }
+
+    /// Adds a task the client can perform if the server requires it.
+    pub fn with_task(mut self, task: sasl::BoxClient) -> Self {
+        self.tasks.push(task);
+        self
+    }
+
+    /// Starts the client and returns the `<authenticate/>` request.
+    pub fn authenticate(&mut self) -> Result<ClientMessage> {
+        let (mechanism, initial_response) = self.client.start()?;
+        self.started = true;
+        Ok(ClientMessage::Authenticate {
+            mechanism,
+            initial_response,
+            user_agent: self.user_agent.clone(),
+        })
+    }
+
+    /// Handles a server message. On error while the exchange is in
+    /// progress, it must be aborted with `abort`.
+    pub fn message(&mut self, message: &ServerMessage) -> Result<Sasl2Step> {
+        if !self.is_in_progress() {
+            bail!(ERR_UNEXPECTED_MESSAGE);
+        }
+        match (message, self.task) {
+            (ServerMessage::Challenge(challenge), None) => Ok(Sasl2Step::Send(ClientMessage::Response(self.client.next(challenge)?))),
+            (ServerMessage::Continue { additional_data, tasks, .. }, None) => {
+                self.client.finish(additional_data.as_deref())?;
+                let i = self
+                    .tasks
+                    .iter()
+                    .position(|task| tasks.iter().any(|name| name == task.mechanism_name()))
+                    .ok_or_else(|| anyhow!(ERR_NO_SUPPORTED_TASK))?;
+                self.task = Some(i);
+                let (task, data) = self.tasks[i].start()?;
+                Ok(Sasl2Step::Send(ClientMessage::Next { task, data }))
+            }
+            (ServerMessage::TaskData(data), Some(i)) => Ok(Sasl2Step::Send(ClientMessage::TaskData(self.tasks[i].next(data)?))),
+            (ServerMessage::Success { additional_data, authorization_identifier }, task) => {
+                self.done = true;
+                match task {
+                    Some(i) => self.tasks[i].finish(additional_data.as_deref())?,
+                    None => self.client.finish(additional_data.as_deref())?,
+                }
+                Ok(Sasl2Step::Done {
+                    authorization_identifier: authorization_identifier.clone(),
+                })
+            }
+            (ServerMessage::Failure { condition, .. }, _) => {
+                self.done = true;
+                match condition.as_str() {
+                    ABORTED if self.canceled => bail!(sasl::Error::Canceled),
+                    INVALID_MECHANISM => bail!(ERR_MECHANISM_UNSUPPORTED),
+                    _ => Err(authentication_failed(&mut self.client)),
+                }
+            }
+            _ => bail!(ERR_UNEXPECTED_MESSAGE),
+        }
+    }
+
+    /// Aborts the exchange, returning the message to send: the mechanism's
+    /// own cancel response if it has one, `<abort/>` otherwise.
+    pub fn abort(&mut self) -> ClientMessage {
+        self.canceled = true;
+        let response = match self.task {
+            Some(i) => self.tasks[i].cancel().map(ClientMessage::TaskData),
+            None => self.client.cancel().map(ClientMessage::Response),
+        };
+        response.unwrap_or(ClientMessage::Abort)
+    }
+
+    /// Reports whether the exchange started and the server hasn't reported
+    /// the outcome yet.
+    pub fn is_in_progress(&self) -> bool {
+        self.started && !self.done
+    }
+
+    pub fn into_inner(self) -> C {
+        self.client
+    }
+}
+
+/// The reply of a server to a client message.
+#[derive(Debug)]
+pub enum Sasl2Reply {
+    Send(ServerMessage),
+    /// The mechanism and all tasks completed. The application sends a
+    /// `ServerMessage::Success` with the additional data, the JID the
+    /// client is authorized as, and the results of inline features.
+    Success(Option<Vec<u8>>),
+    /// Send the failure. The error describes it, e.g. for auditing, and
+    /// must not be disclosed to the client.
+    Failure(ServerMessage, anyhow::Error),
+}
+
+enum State {
+    Idle,
+    Mechanism(Exchange),
+    /// The mechanism completed, waiting for the client to select a task.
+    Continue,
+    Task(usize),
+}
+
+/// Handles SASL2 exchanges on the server side, creating servers with a
+/// `ServerDispatcher`. One must be used per authentication attempt.
+pub struct Sasl2Server {
+    tasks: Vec<sasl::BoxServer>,
+    state: State,
+}
+
+impl Default for Sasl2Server {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Sasl2Server {
+    pub fn new() -> Self {
+        Self {
+            tasks: Vec::new(),
+            state: State::Idle,
+        }
+    }
+
+    /// Requires a task once the mechanism completed. When several tasks
+    /// are added, clients must complete one of them.
+    pub fn with_task(mut self, task: sasl::BoxServer) -> Self {
+        self.tasks.push(task);
+        self
+    }
+
+    /// Handles a client message.
+    pub fn message(&mut self, dispatcher: &ServerDispatcher, conn: &ConnectionContext, message: &ClientMessage) -> Sasl2Reply {
+        let state = std::mem::replace(&mut self.state, State::Idle);
+        match (message, state) {
+            (ClientMessage::Authenticate { mechanism, initial_response, .. }, State::Idle) => {
+                match dispatcher.start(conn, mechanism, initial_response.as_deref()) {
+                    Ok((exchange, step)) => self.mechanism_step(exchange, Ok(step)),
+                    Err(err) if err.to_string() == dispatcher::ERR_UNKNOWN_MECHANISM => failure(INVALID_MECHANISM, err),
+                    Err(err) if err.to_string() == ERR_MECHANISM_FORBIDDEN && !conn.tls => failure(ENCRYPTION_REQUIRED, err),
+                    Err(err) if err.to_string() == ERR_MECHANISM_FORBIDDEN => failure(MECHANISM_TOO_WEAK, err),
+                    Err(err) => failure(NOT_AUTHORIZED, err),
+                }
+            }
+            (ClientMessage::Response(response), State::Mechanism(mut exchange)) => {
+                let step = exchange.next(response);
+                self.mechanism_step(exchange, step)
+            }
+            (ClientMessage::Next { task, data }, State::Continue) => match self.tasks.iter().position(|t| t.mechanism_name() == task) {
+                Some(i) => {
+                    let step = self.tasks[i].next(data.as_deref());
+                    self.task_step(i, step)
+                }
+                None => failure(MALFORMED_REQUEST, anyhow!(ERR_UNKNOWN_TASK)),
+            },
+            (ClientMessage::TaskData(data), State::Task(i)) => {
+                let step = self.tasks[i].next(Some(data));
+                self.task_step(i, step)
+            }
+            (ClientMessage::Abort, _) => failure(ABORTED, sasl::Error::Canceled.into()),
+            _ => failure(MALFORMED_REQUEST, anyhow!(sasl::ERR_UNEXPECTED_CLIENT_RESPONSE)),
+        }
+    }
+
+    fn mechanism_step(&mut self, exchange: Exchange, step: Result<sasl::ServerStep>) -> Sasl2Reply {
+        match step {
+            Ok(sasl::ServerStep::Challenge(challenge)) => {
+                self.state = State::Mechanism(exchange);
+                Sasl2Reply::Send(ServerMessage::Challenge(challenge))
+            }
+            Ok(sasl::ServerStep::Done { additional_data }) if !self.tasks.is_empty() => {
+                self.state = State::Continue;
+                Sasl2Reply::Send(ServerMessage::Continue {
+                    additional_data,
+                    tasks: self.tasks.iter().map(|task| task.mechanism_name().to_string()).collect(),
+                    text: None,
+                })
+            }
+            Ok(sasl::ServerStep::Done { additional_data }) => Sasl2Reply::Success(additional_data),
+            Err(err) => failure(NOT_AUTHORIZED, err),
+        }
+    }
+
+    fn task_step(&mut self, i: usize, step: Result<sasl::ServerStep>) -> Sasl2Reply {
+        match step {
+            Ok(sasl::ServerStep::Challenge(data)) => {
+                self.state = State::Task(i);
+                Sasl2Reply::Send(ServerMessage::TaskData(data))
+            }
+            Ok(sasl::ServerStep::Done { additional_data }) => Sasl2Reply::Success(additional_data),
+            Err(err) => failure(NOT_AUTHORIZED, err),
+        }
+    }
+
+    /// Reports whether an exchange is in progress.
+    pub fn is_in_progress(&self) -> bool {
+        !matches!(self.state, State::Idle)
+    }
+}
+
+fn failure(condition: &str, err: anyhow::Error) -> Sasl2Reply {
+    let message = ServerMessage::Failure {
+        condition: condition.to_string(),
+        text: None,
+    };
+    Sasl2Reply::Failure(message, err)
+}
+
+#[cfg(all(feature = "login", feature = "plain"))]
+#[test]
+fn test_sasl2_tasks() -> Result<()> {
+    use crate::login::{LoginClient, LoginServer};
+    use crate::plain::{PlainClient, PlainServer, PLAIN};
+    use crate::registry::Registry;
+
+    let mut registry = Registry::new();
+    registry.register_server(PLAIN, || Box::new(PlainServer::new(Box::new(|_, _, _| Ok(())))));
+    let dispatcher = ServerDispatcher::new(registry);
+    let conn = ConnectionContext {
+        tls: true,
+        ..Default::default()
+    };
+
+    // LOGIN stands in for a second factor task, asking for a one-time
+    // password.
+    let otp = || LoginServer::new(Box::new(|_, otp| if otp == "123456" { Ok(()) } else { bail!("invalid code") }));
+    let new_client = |code: &str| {
+        Sasl2Client::new(PlainClient::new(String::new(), "user".to_string(), "password".to_string()))
+            .with_task(Box::new(LoginClient::new("user".to_string(), code.to_string())))
+    };
+    let run = |code: &str| -> Result<(Vec<ClientMessage>, Result<Sasl2Step>)> {
+        let mut client = new_client(code);
+        let mut server = Sasl2Server::new().with_task(Box::new(otp()));
+        let mut messages = vec![client.authenticate()?];
+        loop {
+            let reply = server.message(&dispatcher, &conn, messages.last().unwrap());
+            let message = match reply {
+                Sasl2Reply::Send(message) | Sasl2Reply::Failure(message, _) => message,
+                Sasl2Reply::Success(additional_data) => ServerMessage::Success {
+                    additional_data,
+                    authorization_identifier: "user@example.org".to_string(),
+                },
+            };
+            match client.message(&message) {
+                Ok(Sasl2Step::Send(message)) => messages.push(message),
+                outcome => return Ok((messages, outcome)),
+            }
+        }
+    };
+    let (messages, outcome) = run("123456")?;
+    match outcome? {
+        Sasl2Step::Done { authorization_identifier } if authorization_identifier == "user@example.org" => {}
+        step => bail!("Unexpected step: {:?}", step),
+    }
+    if !matches!(&messages[1], ClientMessage::Next { task, .. } if task == "LOGIN") || messages.len() != 3 {
+        bail!("Unexpected messages: {:?}", messages);
+    }
+
+    match run("654321")?.1 {
+        Err(err) if err.to_string() == sasl::ERR_AUTHENTICATION_FAILED => {}
+        _ => bail!("Expected the task to fail"),
+    }
+
+    let mut client = new_client("123456");
+    let mut server = Sasl2Server::new();
+    client.authenticate()?;
+    let reply = server.message(&dispatcher, &conn, &client.abort());
+    match reply {
+        Sasl2Reply::Failure(message, err) if matches!(err.downcast_ref(), Some(sasl::Error::Canceled)) => match client.message(&message) {
+            Err(err) if matches!(err.downcast_ref(), Some(sasl::Error::Canceled)) => {}
+            _ => bail!("Expected the client to report the cancellation"),
+        },
+        reply => bail!("Unexpected reply: {:?}", reply),
+    }
+
+    Ok(())
+}
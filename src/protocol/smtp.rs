@@ -0,0 +1,266 @@
+//! SMTP `AUTH` exchanges (RFC 4954).
+
+use super::{ClientStep, LineClient, LineOutcome, LineServer, ServerReply};
+use crate::dispatcher::{self, ServerDispatcher};
+use crate::framing::{self, SMTP_CONTINUATION};
+use crate::policy::{ConnectionContext, ERR_MECHANISM_FORBIDDEN};
+use crate::replay::ReplayGuard;
+use crate::sasl;
+
+use anyhow::{anyhow, bail, Result};
+use std::sync::Arc;
+
+pub const ERR_MECHANISM_UNSUPPORTED: &str = "sasl: smtp: mechanism not supported by the server";
+pub const ERR_UNEXPECTED_REPLY: &str = "sasl: smtp: unexpected reply";
+pub const ERR_INVALID_COMMAND: &str = "sasl: smtp: invalid AUTH command";
+pub const ERR_LINE_TOO_LONG: &str = "sasl: smtp: line too long";
+
+/// The maximum length of a command line, without its CRLF terminator (RFC
+/// 5321 section 4.5.3.1.4). Initial responses which don't fit are sent
+/// after an empty challenge instead.
+pub const MAX_LINE_LENGTH: usize = 998;
+
+/// The maximum length of a response line accepted by servers, without its
+/// CRLF terminator (RFC 4954 section 4).
+pub const MAX_RESPONSE_LENGTH: usize = 12288;
+
+/// Drives a client through an `AUTH` exchange:
+///
+/// ```ignore
+/// let mut auth = SmtpClient::new(client);
+/// let mut line = auth.command()?;
+/// loop {
+///     send(&line)?;
+///     match auth.reply(&receive()?) {
+///         Ok(ClientStep::Send(response)) => line = response,
+///         Ok(ClientStep::Done) => break,
+///         Err(err) if auth.is_in_progress() => line = auth.cancel(),
+///         Err(err) => return Err(err),
+///     }
+/// }
+/// ```
+pub struct SmtpClient<C> {
+    inner: LineClient<C>,
+}
+
+impl<C: sasl::Client> SmtpClient<C> {
+    pub fn new(client: C) -> Self {
+        Self {
+            inner: LineClient::new(client),
+        }
+    }
+
+    /// Refuses challenges replayed from previous exchanges sharing the same
+    /// guard with `sasl::Error::ReplayDetected`, e.g. when authentication
+    /// is retried.
+    pub fn with_replay_guard(mut self, guard: Arc<ReplayGuard>) -> Self {
+        self.inner.set_replay_guard(guard);
+        self
+    }
+
+    /// Starts the client and returns the `AUTH` command, with the initial
+    /// response if the mechanism has one and it fits on the line.
+    pub fn command(&mut self) -> Result<String> {
+        let (mechanism, ir) = self.inner.start()?;
+        let mut command = format!("AUTH {}", mechanism);
+        if let Some(ir) = ir {
+            let encoded = framing::encode_initial_response(Some(&ir)).unwrap_or_default();
+            if command.len() + 1 + encoded.len() <= MAX_LINE_LENGTH {
+                command.push(' ');
+                command.push_str(&encoded);
+            } else {
+                self.inner.defer(ir);
+            }
+        }
+        Ok(command)
+    }
+
+    /// Handles a server reply line. On error while the exchange is in
+    /// progress, the exchange must be aborted with `cancel`.
+    pub fn reply(&mut self, line: &str) -> Result<ClientStep> {
+        let line = line.trim_end_matches(['\r', '\n']);
+        if line.as_bytes().get(3) == Some(&b'-') {
+            return Ok(ClientStep::Wait);
+        }
+        match line.get(..3) {
+            Some("334") if self.is_in_progress() => self.inner.challenge(SMTP_CONTINUATION, line),
+            Some("235") if self.is_in_progress() => self.inner.success(None),
+            Some("501") if self.inner.is_canceled() => self.inner.fail(sasl::Error::Canceled.into()),
+            Some("504") => self.inner.fail(anyhow!(ERR_MECHANISM_UNSUPPORTED)),
+            Some("535") => self.inner.fail(anyhow!(sasl::ERR_AUTHENTICATION_FAILED)),
+            _ => self.inner.fail(anyhow!(ERR_UNEXPECTED_REPLY)),
+        }
+    }
+
+    /// Aborts the exchange, returning the line to send instead of a
+    /// response: the mechanism's own cancel response if it has one, `*`
+    /// otherwise.
+    pub fn cancel(&mut self) -> String {
+        self.inner.cancel()
+    }
+
+    /// Reports whether the command was sent and the server hasn't replied
+    /// with the outcome yet.
+    pub fn is_in_progress(&self) -> bool {
+        self.inner.is_in_progress()
+    }
+
+    pub fn into_inner(self) -> C {
+        self.inner.into_inner()
+    }
+}
+
+/// Handles an `AUTH` command on the server side, creating servers with a
+/// `ServerDispatcher`.
+pub struct SmtpServer {
+    inner: LineServer,
+}
+
+impl SmtpServer {
+    /// Handles an `AUTH` command line. The exchange continues with
+    /// `response` as long as the reply is a challenge.
+    pub fn start(dispatcher: &ServerDispatcher, conn: &ConnectionContext, command: &str) -> (Self, ServerReply) {
+        let mut server = Self {
+            inner: LineServer::new(SMTP_CONTINUATION),
+        };
+        let reply = match parse_command(command) {
+            Ok((mechanism, ir)) => reply(server.inner.start(dispatcher, conn, mechanism, ir.as_deref())),
+            Err(err) => ServerReply::Failure("501 5.5.2 Syntax error in AUTH command".to_string(), err),
+        };
+        (server, reply)
+    }
+
+    /// Handles a client response line.
+    pub fn response(&mut self, line: &str) -> ServerReply {
+        if line.trim_end_matches(['\r', '\n']).len() > MAX_RESPONSE_LENGTH {
+            self.inner.abort();
+            return ServerReply::Failure("500 5.5.6 Authentication Exchange line is too long".to_string(), anyhow!(ERR_LINE_TOO_LONG));
+        }
+        reply(self.inner.response(line))
+    }
+
+    /// Returns the exchange's mechanism, if it started.
+    pub fn mechanism_name(&self) -> Option<&str> {
+        self.inner.mechanism_name()
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.inner.is_done()
+    }
+}
+
+/// Parses an `AUTH <mechanism> [initial-response]` command.
+pub fn parse_command(command: &str) -> Result<(&str, Option<Vec<u8>>)> {
+    let mut args = command.trim_end_matches(['\r', '\n']).split(' ');
+    if !args.next().is_some_and(|verb| verb.eq_ignore_ascii_case("AUTH")) {
+        bail!(ERR_INVALID_COMMAND);
+    }
+    let mechanism = args.next().filter(|m| !m.is_empty()).ok_or_else(|| anyhow!(ERR_INVALID_COMMAND))?;
+    let ir = framing::decode_initial_response(args.next())?;
+    if args.next().is_some() {
+        bail!(ERR_INVALID_COMMAND);
+    }
+    Ok((mechanism, ir))
+}
+
+fn reply(outcome: LineOutcome) -> ServerReply {
+    let (line, err) = match outcome {
+        LineOutcome::Challenge(challenge) => return ServerReply::Challenge(challenge),
+        LineOutcome::Success(_) => return ServerReply::Success("235 2.7.0 Authentication successful".to_string()),
+        LineOutcome::Malformed(err) => ("501 5.5.2 Cannot decode response", err),
+        LineOutcome::Failure(err) if err.to_string() == dispatcher::ERR_UNKNOWN_MECHANISM => ("504 5.5.4 Unrecognized authentication type", err),
+        LineOutcome::Failure(err) if err.to_string() == ERR_MECHANISM_FORBIDDEN => ("534 5.7.9 Authentication mechanism is too weak", err),
+        LineOutcome::Failure(err) if matches!(err.downcast_ref(), Some(sasl::Error::Canceled)) => ("501 5.7.0 Authentication canceled", err),
+        LineOutcome::Failure(err) if matches!(err.downcast_ref(), Some(sasl::Error::RateLimited)) => ("454 4.7.0 Temporary authentication failure", err),
+        LineOutcome::Failure(err) => ("535 5.7.8 Authentication credentials invalid", err),
+    };
+    ServerReply::Failure(line.to_string(), err)
+}
+
+#[cfg(all(feature = "login", feature = "plain"))]
+#[test]
+fn test_smtp_exchange() -> Result<()> {
+    use crate::login::{LoginClient, LoginServer, LOGIN};
+    use crate::plain::{PlainClient, PlainServer, PLAIN};
+    use crate::registry::Registry;
+
+    let mut registry = Registry::new();
+    registry
+        .register_server(PLAIN, || {
+            Box::new(PlainServer::new(Box::new(|_, u, p| {
+                if u != "username" || p != "password" {
+                    bail!("invalid credentials");
+                }
+                Ok(())
+            })))
+        })
+        .register_server(LOGIN, || Box::new(LoginServer::new(Box::new(|_, _| Ok(())))));
+    let dispatcher = ServerDispatcher::new(registry);
+    let conn = ConnectionContext {
+        tls: true,
+        ..Default::default()
+    };
+
+    // Runs an exchange, returning the lines sent by the client and the
+    // server, and the client's outcome.
+    let run = |client: PlainClient| -> Result<(Vec<String>, Result<ClientStep>)> {
+        let mut client = SmtpClient::new(client);
+        let mut lines = vec![client.command()?];
+        let (mut server, mut reply) = SmtpServer::start(&dispatcher, &conn, &lines[0]);
+        loop {
+            lines.push(reply.line().to_string());
+            match client.reply(reply.line()) {
+                Ok(ClientStep::Send(line)) => {
+                    reply = server.response(&line);
+                    lines.push(line);
+                }
+                outcome => return Ok((lines, outcome)),
+            }
+        }
+    };
+
+    let (lines, outcome) = run(PlainClient::new(String::new(), "username".to_string(), "password".to_string()))?;
+    if lines != ["AUTH PLAIN AHVzZXJuYW1lAHBhc3N3b3Jk", "235 2.7.0 Authentication successful"] || outcome? != ClientStep::Done {
+        bail!("Unexpected exchange: {:?}", lines);
+    }
+
+    // Initial responses which don't fit on the command line are sent after
+    // an empty challenge.
+    let (lines, outcome) = run(PlainClient::new(String::new(), "username".to_string(), "p".repeat(1000)))?;
+    if lines[..2] != ["AUTH PLAIN", "334 "] || lines.len() != 4 {
+        bail!("Expected the initial response to be deferred: {:?}", lines);
+    }
+    match outcome {
+        Err(err) if err.to_string() == sasl::ERR_AUTHENTICATION_FAILED => {}
+        _ => bail!("Expected authentication to fail"),
+    }
+
+    match SmtpServer::start(&dispatcher, &conn, "AUTH CRAM-MD5").1 {
+        ServerReply::Failure(line, _) if line.starts_with("504 ") => {}
+        reply => bail!("Unexpected reply: {:?}", reply),
+    }
+    if SmtpServer::start(&dispatcher, &conn, "AUTH PLAIN =").1.line() != "535 5.7.8 Authentication credentials invalid" {
+        bail!("Expected an empty initial response to be rejected");
+    }
+
+    let mut client = SmtpClient::new(LoginClient::new("username".to_string(), "password".to_string()));
+    let command = client.command()?;
+    let (mut server, reply) = SmtpServer::start(&dispatcher, &conn, &command);
+    if reply.line() != "334 UGFzc3dvcmQ6" || server.mechanism_name() != Some(LOGIN) {
+        bail!("Expected a password challenge");
+    }
+    match server.response(&client.cancel()) {
+        ServerReply::Failure(line, err) if line.starts_with("501 ") && matches!(err.downcast_ref(), Some(sasl::Error::Canceled)) => {
+            match client.reply(&line) {
+                Err(err) if matches!(err.downcast_ref(), Some(sasl::Error::Canceled)) => {}
+                _ => bail!("Expected the client to report the cancellation"),
+            }
+        }
+        reply => bail!("Unexpected reply: {:?}", reply),
+    }
+    if !server.is_done() || client.is_in_progress() {
+        bail!("Expected the exchange to be over");
+    }
+
+    Ok(())
+}
@@ -0,0 +1,251 @@
+//! Rate limiting of failed authentication attempts per user, to lock
+//! accounts out of online brute forcing. Failures are counted in a
+//! pluggable store, which clustered deployments can share. Stores which
+//! need to await I/O, like `store::redis::RedisStore`, implement
+//! `AsyncAttemptStore` and are used through `AsyncRateLimitServer`.
+
+#[cfg(feature = "tokio")]
+use crate::async_sasl::{AsyncServer, BoxFuture};
+use crate::master::MasterUsers;
+use crate::sasl;
+use crate::security_layer::BoxSecurityLayer;
+
+use anyhow::Result;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Counts failed attempts per key, e.g. per username.
+pub trait AttemptStore: Send + Sync {
+    /// Counts a failure and returns the number of failures within the
+    /// window, which starts with the first one.
+    fn record_failure(&self, key: &str, window: Duration) -> Result<u64>;
+
+    /// Returns the number of failures within the current window.
+    fn failures(&self, key: &str) -> Result<u64>;
+
+    /// Forgets the failures of a key, e.g. after a success.
+    fn clear(&self, key: &str) -> Result<()>;
+}
+
+/// Asynchronous counterpart of `AttemptStore`, for stores which need to
+/// await I/O.
+#[cfg(feature = "tokio")]
+pub trait AsyncAttemptStore: Send + Sync {
+    /// See `AttemptStore::record_failure`.
+    fn record_failure<'a>(&'a self, key: &'a str, window: Duration) -> BoxFuture<'a, Result<u64>>;
+
+    /// See `AttemptStore::failures`.
+    fn failures<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<u64>>;
+
+    /// See `AttemptStore::clear`.
+    fn clear<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<()>>;
+}
+
+/// An in-memory `AttemptStore`, for a single process.
+#[derive(Default)]
+pub struct MemoryAttemptStore {
+    attempts: Mutex<HashMap<String, (Instant, Duration, u64)>>,
+}
+
+impl MemoryAttemptStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl AttemptStore for MemoryAttemptStore {
+    fn record_failure(&self, key: &str, window: Duration) -> Result<u64> {
+        let mut attempts = self.attempts.lock().unwrap();
+        attempts.retain(|_, (start, window, _)| start.elapsed() < *window);
+        let (_, _, count) = attempts.entry(key.to_string()).or_insert((Instant::now(), window, 0));
+        *count += 1;
+        Ok(*count)
+    }
+
+    fn failures(&self, key: &str) -> Result<u64> {
+        Ok(match self.attempts.lock().unwrap().get(key) {
+            Some((start, window, count)) if start.elapsed() < *window => *count,
+            _ => 0,
+        })
+    }
+
+    fn clear(&self, key: &str) -> Result<()> {
+        self.attempts.lock().unwrap().remove(key);
+        Ok(())
+    }
+}
+
+/// How many failures are allowed within a window.
+#[derive(Clone, Copy, Debug)]
+pub struct RateLimit {
+    pub max_failures: u64,
+    pub window: Duration,
+}
+
+/// A server wrapper rejecting the attempts of users with too many recent
+/// failures with `sasl::Error::RateLimited`, even with valid credentials.
+/// Users are identified by `sasl::Server::authentication_id`, so the inner
+/// server still verifies their credentials: attempts with mechanisms which
+/// don't report it aren't limited.
+pub struct RateLimitServer<S> {
+    inner: S,
+    store: Arc<dyn AttemptStore>,
+    limit: RateLimit,
+}
+
+impl<S: sasl::Server> RateLimitServer<S> {
+    pub fn new(inner: S, store: Arc<dyn AttemptStore>, limit: RateLimit) -> Self {
+        Self { inner, store, limit }
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<S: sasl::Server> sasl::Server for RateLimitServer<S> {
+    fn mechanism_name(&self) -> &str {
+        self.inner.mechanism_name()
+    }
+
+    fn next(&mut self, response: Option<&[u8]>) -> Result<sasl::ServerStep> {
+        let step = self.inner.next(response);
+        if matches!(step, Ok(sasl::ServerStep::Challenge(_))) {
+            return step;
+        }
+        let Some(username) = self.inner.authentication_id() else {
+            return step;
+        };
+        if self.store.failures(username)? >= self.limit.max_failures {
+            return Err(sasl::Error::RateLimited.into());
+        }
+        match step {
+            Ok(_) => self.store.clear(username)?,
+            Err(_) => {
+                self.store.record_failure(username, self.limit.window)?;
+            }
+        }
+        step
+    }
+
+    fn is_done(&self) -> bool {
+        self.inner.is_done()
+    }
+
+    fn reset(&mut self) -> Result<()> {
+        self.inner.reset()
+    }
+
+    fn security_layer(&mut self) -> Option<BoxSecurityLayer> {
+        self.inner.security_layer()
+    }
+
+    fn authentication_id(&self) -> Option<&str> {
+        self.inner.authentication_id()
+    }
+
+    fn authorization_id(&self) -> Option<&str> {
+        self.inner.authorization_id()
+    }
+
+    fn set_master_users(&mut self, masters: Arc<MasterUsers>) -> bool {
+        self.inner.set_master_users(masters)
+    }
+}
+
+/// `RateLimitServer` for an `AsyncAttemptStore`: the inner server runs
+/// inline, like with `async_sasl::Inline`, and the store is awaited.
+#[cfg(feature = "tokio")]
+pub struct AsyncRateLimitServer<S> {
+    inner: S,
+    store: Arc<dyn AsyncAttemptStore>,
+    limit: RateLimit,
+}
+
+#[cfg(feature = "tokio")]
+impl<S: sasl::Server> AsyncRateLimitServer<S> {
+    pub fn new(inner: S, store: Arc<dyn AsyncAttemptStore>, limit: RateLimit) -> Self {
+        Self { inner, store, limit }
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl<S: sasl::Server> AsyncServer for AsyncRateLimitServer<S> {
+    fn mechanism_name(&self) -> &str {
+        self.inner.mechanism_name()
+    }
+
+    async fn next(&mut self, response: Option<&[u8]>) -> Result<sasl::ServerStep> {
+        let step = self.inner.next(response);
+        if matches!(step, Ok(sasl::ServerStep::Challenge(_))) {
+            return step;
+        }
+        let Some(username) = self.inner.authentication_id() else {
+            return step;
+        };
+        if self.store.failures(username).await? >= self.limit.max_failures {
+            return Err(sasl::Error::RateLimited.into());
+        }
+        match step {
+            Ok(_) => self.store.clear(username).await?,
+            Err(_) => {
+                self.store.record_failure(username, self.limit.window).await?;
+            }
+        }
+        step
+    }
+
+    fn is_done(&self) -> bool {
+        self.inner.is_done()
+    }
+
+    fn reset(&mut self) -> Result<()> {
+        self.inner.reset()
+    }
+
+    fn security_layer(&mut self) -> Option<BoxSecurityLayer> {
+        self.inner.security_layer()
+    }
+}
+
+#[cfg(feature = "plain")]
+#[test]
+fn test_rate_limit() -> Result<()> {
+    use crate::plain::PlainServer;
+    use crate::sasl::Server;
+    use anyhow::bail;
+
+    let plain = PlainServer::new(Box::new(|_, _, password| match password {
+        "password" => Ok(()),
+        _ => bail!("Invalid credentials"),
+    }));
+    let limit = RateLimit {
+        max_failures: 2,
+        window: Duration::from_secs(60),
+    };
+    let store = Arc::new(MemoryAttemptStore::new());
+    let mut server = RateLimitServer::new(plain, store.clone(), limit);
+    for (response, rate_limited) in [(&b"\x00username\x00wrong"[..], false), (b"\x00username\x00wrong", false), (b"\x00username\x00password", true)] {
+        server.reset()?;
+        match server.next(Some(response)) {
+            Err(err) if matches!(err.downcast_ref(), Some(sasl::Error::RateLimited)) == rate_limited => {}
+            _ => bail!("Unexpected outcome, expected rate limiting: {}", rate_limited),
+        }
+    }
+
+    server.reset()?;
+    if !server.next(Some(b"\x00other\x00password"))?.is_done() {
+        bail!("Expected other users not to be limited");
+    }
+    store.clear("username")?;
+    server.reset()?;
+    if !server.next(Some(b"\x00username\x00password"))?.is_done() || store.failures("username")? != 0 {
+        bail!("Expected the user to be unlocked");
+    }
+    Ok(())
+}
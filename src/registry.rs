@@ -0,0 +1,234 @@
+#[cfg(feature = "anonymous")]
+use crate::anonymous;
+#[cfg(feature = "external")]
+use crate::external;
+#[cfg(feature = "login")]
+use crate::login;
+#[cfg(feature = "oauthbearer")]
+use crate::oauthbearer;
+#[cfg(feature = "plain")]
+use crate::plain;
+use crate::sasl;
+
+/// Creates a new client for a mechanism.
+pub type ClientFactory = Box<dyn Fn() -> sasl::BoxClient + Send + Sync>;
+
+/// Creates a new server for a mechanism.
+pub type ServerFactory = Box<dyn Fn() -> sasl::BoxServer + Send + Sync>;
+
+struct Entry {
+    name: String,
+    client: Option<ClientFactory>,
+    server: Option<ServerFactory>,
+    properties: sasl::Properties,
+}
+
+/// The properties assumed for mechanisms which don't have any set: the most
+/// restrictive ones, so that security policies don't allow them by mistake.
+pub const UNKNOWN_PROPERTIES: sasl::Properties = sasl::Properties {
+    plaintext: true,
+    anonymous: false,
+    channel_binding: false,
+    mutual_auth: false,
+    security_layer: false,
+    ssf: 0,
+};
+
+/// Returns the properties of built-in mechanisms, or `UNKNOWN_PROPERTIES`.
+pub(crate) fn builtin_properties(name: &str) -> sasl::Properties {
+    match name {
+        #[cfg(feature = "anonymous")]
+        anonymous::ANONYMOUS => anonymous::PROPERTIES,
+        #[cfg(feature = "external")]
+        external::EXTERNAL => external::PROPERTIES,
+        #[cfg(feature = "login")]
+        login::LOGIN => login::PROPERTIES,
+        #[cfg(feature = "oauthbearer")]
+        oauthbearer::OAUTHBEARER => oauthbearer::PROPERTIES,
+        #[cfg(feature = "plain")]
+        plain::PLAIN => plain::PROPERTIES,
+        _ => UNKNOWN_PROPERTIES,
+    }
+}
+
+/// A set of mechanisms, built-in or user-defined, which can be instantiated
+/// by name. Mechanism names are case-insensitive, as per RFC 4422 section
+/// 3.1, and are listed in the order they were first registered.
+///
+/// Mechanisms can also be looked up by aliases, for servers advertising
+/// non-standard names (e.g. `X-OAUTH2` for `XOAUTH2`).
+#[derive(Default)]
+pub struct Registry {
+    entries: Vec<Entry>,
+    aliases: Vec<(String, String)>,
+}
+
+/// Normalizes a mechanism name as advertised by a peer.
+fn normalize(name: &str) -> String {
+    name.trim().to_ascii_uppercase()
+}
+
+impl Registry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the name of the mechanism an alias refers to, or the name
+    /// itself if it isn't an alias.
+    pub fn resolve(&self, name: &str) -> String {
+        let name = normalize(name);
+        match self.aliases.iter().find(|(alias, _)| *alias == name) {
+            Some((_, target)) => target.clone(),
+            None => name,
+        }
+    }
+
+    fn entry(&mut self, name: &str) -> &mut Entry {
+        let name = self.resolve(name);
+        let i = match self.entries.iter().position(|e| e.name == name) {
+            Some(i) => i,
+            None => {
+                self.entries.push(Entry {
+                    properties: builtin_properties(&name),
+                    name,
+                    client: None,
+                    server: None,
+                });
+                self.entries.len() - 1
+            }
+        };
+        &mut self.entries[i]
+    }
+
+    fn get(&self, name: &str) -> Option<&Entry> {
+        let name = self.resolve(name);
+        self.entries.iter().find(|e| e.name == name)
+    }
+
+    /// Registers an alias for a mechanism. Aliases are only used for
+    /// lookups, mechanisms are listed under their own name.
+    pub fn register_alias(&mut self, alias: &str, name: &str) -> &mut Self {
+        let (alias, name) = (normalize(alias), self.resolve(name));
+        self.aliases.retain(|(a, _)| *a != alias);
+        self.aliases.push((alias, name));
+        self
+    }
+
+    /// Registers a client factory, replacing any previous one for the same
+    /// mechanism.
+    pub fn register_client<F>(&mut self, name: &str, factory: F) -> &mut Self
+    where
+        F: Fn() -> sasl::BoxClient + Send + Sync + 'static,
+    {
+        self.entry(name).client = Some(Box::new(factory));
+        self
+    }
+
+    /// Registers a server factory, replacing any previous one for the same
+    /// mechanism.
+    pub fn register_server<F>(&mut self, name: &str, factory: F) -> &mut Self
+    where
+        F: Fn() -> sasl::BoxServer + Send + Sync + 'static,
+    {
+        self.entry(name).server = Some(Box::new(factory));
+        self
+    }
+
+    /// Sets the properties of a mechanism. Built-in mechanisms have their
+    /// properties set on registration, others have `UNKNOWN_PROPERTIES`
+    /// until this is called.
+    pub fn set_properties(&mut self, name: &str, properties: sasl::Properties) -> &mut Self {
+        self.entry(name).properties = properties;
+        self
+    }
+
+    /// Returns the properties of a mechanism, or `None` if it isn't
+    /// registered.
+    pub fn properties(&self, name: &str) -> Option<sasl::Properties> {
+        self.get(name).map(|e| e.properties)
+    }
+
+    /// Removes a mechanism. Returns false if it wasn't registered.
+    pub fn unregister(&mut self, name: &str) -> bool {
+        let name = self.resolve(name);
+        let len = self.entries.len();
+        self.entries.retain(|e| e.name != name);
+        self.aliases.retain(|(_, target)| *target != name);
+        self.entries.len() != len
+    }
+
+    /// Lists the mechanisms with a client factory.
+    pub fn client_mechanisms(&self) -> impl Iterator<Item = &str> {
+        self.entries
+            .iter()
+            .filter(|e| e.client.is_some())
+            .map(|e| e.name.as_str())
+    }
+
+    /// Lists the mechanisms with a server factory, e.g. to advertise them.
+    pub fn server_mechanisms(&self) -> impl Iterator<Item = &str> {
+        self.entries
+            .iter()
+            .filter(|e| e.server.is_some())
+            .map(|e| e.name.as_str())
+    }
+
+    /// Creates a client for a mechanism, or returns `None` if no client
+    /// factory is registered for it.
+    pub fn new_client(&self, name: &str) -> Option<sasl::BoxClient> {
+        self.get(name)?.client.as_ref().map(|factory| factory())
+    }
+
+    /// Creates a server for a mechanism, or returns `None` if no server
+    /// factory is registered for it.
+    pub fn new_server(&self, name: &str) -> Option<sasl::BoxServer> {
+        self.get(name)?.server.as_ref().map(|factory| factory())
+    }
+}
+
+#[cfg(all(feature = "anonymous", feature = "plain"))]
+#[test]
+fn test_registry() -> anyhow::Result<()> {
+    use crate::anonymous::{AnonymousClient, AnonymousServer, ANONYMOUS};
+    use crate::plain::{PlainServer, PLAIN};
+    use anyhow::bail;
+
+    let mut registry = Registry::new();
+    registry
+        .register_server(PLAIN, || Box::new(PlainServer::new(Box::new(|_, _, _| Ok(())))))
+        .register_server(ANONYMOUS, || Box::new(AnonymousServer::new(Box::new(|_| Ok(())))))
+        .register_client("anonymous", || Box::new(AnonymousClient::new("trace".to_string())));
+
+    if registry.server_mechanisms().collect::<Vec<_>>() != [PLAIN, ANONYMOUS] {
+        bail!("Unexpected server mechanisms");
+    }
+    if registry.client_mechanisms().collect::<Vec<_>>() != [ANONYMOUS] {
+        bail!("Unexpected client mechanisms");
+    }
+    if registry.properties("Plain").is_none_or(|p| !p.plaintext) || registry.properties("LOGIN").is_some() {
+        bail!("Unexpected properties");
+    }
+    registry.register_server("X-CUSTOM", || Box::new(PlainServer::new(Box::new(|_, _, _| Ok(())))));
+    if registry.properties("X-CUSTOM") != Some(UNKNOWN_PROPERTIES) {
+        bail!("Expected custom mechanisms to have the most restrictive properties");
+    }
+    registry.unregister("X-CUSTOM");
+
+    let server = registry.new_server("plain");
+    if server.as_ref().map(|s| s.mechanism_name()) != Some(PLAIN) {
+        bail!("Expected a PLAIN server");
+    }
+    registry.register_alias("x-plain", PLAIN);
+    if registry.new_server(" X-Plain ").is_none() || registry.server_mechanisms().any(|m| m == "X-PLAIN") {
+        bail!("Expected the alias to be usable for lookups only");
+    }
+    if registry.new_server("LOGIN").is_some() || registry.new_client(PLAIN).is_some() {
+        bail!("Expected unregistered mechanisms to be missing");
+    }
+
+    if !registry.unregister(PLAIN) || registry.new_server(PLAIN).is_some() || registry.new_server("X-PLAIN").is_some() {
+        bail!("Expected PLAIN to be unregistered");
+    }
+
+    Ok(())
+}
@@ -0,0 +1,178 @@
+use crate::sasl;
+use crate::security_layer::BoxSecurityLayer;
+
+use anyhow::{bail, Result};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+/// The number of challenges remembered by default.
+pub const DEFAULT_CAPACITY: usize = 1024;
+
+/// Mechanisms whose challenges contain a server nonce, and must therefore
+/// never repeat. Other mechanisms, such as LOGIN, send the same challenges
+/// in every exchange.
+const NONCE_MECHANISMS: &[&str] = &["SCRAM-", "CRAM-MD5", "DIGEST-MD5"];
+
+/// Remembers the challenges received by clients, to refuse servers which
+/// replay one, e.g. when a protocol adapter retries authentication. It is
+/// meant to be shared between the exchanges of a client: pass it to the
+/// `with_replay_guard` method of the line-based protocol adapters and of
+/// `interop::async_imap::ImapAuthenticator`, or wrap clients in a
+/// `GuardedClient` for the drivers and other adapters.
+pub struct ReplayGuard {
+    capacity: usize,
+    seen: Mutex<VecDeque<Vec<u8>>>,
+}
+
+impl Default for ReplayGuard {
+    fn default() -> Self {
+        Self::new(DEFAULT_CAPACITY)
+    }
+}
+
+impl ReplayGuard {
+    /// Creates a guard remembering up to `capacity` challenges and nonces.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            seen: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    fn remember(&self, seen: &mut VecDeque<Vec<u8>>, value: Vec<u8>) -> Result<()> {
+        if seen.contains(&value) {
+            bail!(sasl::Error::ReplayDetected);
+        }
+        if seen.len() == self.capacity {
+            seen.pop_front();
+        }
+        seen.push_back(value);
+        Ok(())
+    }
+
+    /// Checks a challenge received for a mechanism, and remembers it.
+    /// Returns `sasl::Error::ReplayDetected` if it was already received.
+    pub fn check(&self, mechanism: &str, challenge: &[u8]) -> Result<()> {
+        let mechanism = mechanism.to_ascii_uppercase();
+        if challenge.is_empty() || !NONCE_MECHANISMS.iter().any(|m| mechanism.starts_with(m)) {
+            return Ok(());
+        }
+
+        let mut seen = self.seen.lock().unwrap_or_else(|err| err.into_inner());
+        let mut value = mechanism.clone().into_bytes();
+        value.push(b'\x00');
+        value.extend_from_slice(challenge);
+        self.remember(&mut seen, value)?;
+
+        // A SCRAM server-first-message with a known nonce is a replay, even
+        // if the salt or iteration count changed.
+        if mechanism.starts_with("SCRAM-") {
+            let nonce = challenge.split(|&b| b == b',').find(|attr| attr.starts_with(b"r="));
+            if let Some(nonce) = nonce {
+                self.remember(&mut seen, nonce.to_vec())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A client wrapper checking every challenge with a `ReplayGuard` before
+/// passing it to the inner client.
+pub struct GuardedClient<C> {
+    inner: C,
+    guard: Arc<ReplayGuard>,
+}
+
+impl<C: sasl::Client> GuardedClient<C> {
+    pub fn new(inner: C, guard: Arc<ReplayGuard>) -> Self {
+        Self { inner, guard }
+    }
+
+    pub fn into_inner(self) -> C {
+        self.inner
+    }
+}
+
+impl<C: sasl::Client> sasl::Client for GuardedClient<C> {
+    fn mechanism_name(&self) -> &str {
+        self.inner.mechanism_name()
+    }
+
+    fn start(&mut self) -> Result<(String, Option<Vec<u8>>)> {
+        self.inner.start()
+    }
+
+    fn next(&mut self, challenge: &[u8]) -> Result<Vec<u8>> {
+        self.guard.check(self.inner.mechanism_name(), challenge)?;
+        self.inner.next(challenge)
+    }
+
+    fn finish(&mut self, data: Option<&[u8]>) -> Result<()> {
+        self.inner.finish(data)
+    }
+
+    fn cancel(&mut self) -> Option<Vec<u8>> {
+        self.inner.cancel()
+    }
+
+    fn failure(&mut self) -> Option<anyhow::Error> {
+        self.inner.failure()
+    }
+
+    fn security_layer(&mut self) -> Option<BoxSecurityLayer> {
+        self.inner.security_layer()
+    }
+}
+
+/// A client of a nonce-based mechanism, for tests: it answers every
+/// challenge with an empty response.
+#[cfg(test)]
+pub(crate) struct NonceClient;
+
+#[cfg(test)]
+impl sasl::Client for NonceClient {
+    fn mechanism_name(&self) -> &str {
+        "CRAM-MD5"
+    }
+
+    fn start(&mut self) -> Result<(String, Option<Vec<u8>>)> {
+        Ok(("CRAM-MD5".to_string(), None))
+    }
+
+    fn next(&mut self, _challenge: &[u8]) -> Result<Vec<u8>> {
+        Ok(Vec::new())
+    }
+}
+
+#[test]
+fn test_replay_guard() -> Result<()> {
+    let guard = ReplayGuard::new(8);
+
+    for _ in 0..2 {
+        guard.check("LOGIN", b"Password:")?;
+    }
+
+    guard.check("SCRAM-SHA-256", b"r=clientserver,s=c2FsdA==,i=4096")?;
+    let err = guard.check("SCRAM-SHA-256", b"r=clientserver,s=b3RoZXI=,i=4096").unwrap_err();
+    if err.downcast_ref() != Some(&sasl::Error::ReplayDetected) {
+        bail!("Expected a replayed nonce to be detected, got: {}", err);
+    }
+
+    guard.check("CRAM-MD5", b"<1896.697170952@example.org>")?;
+    if guard.check("cram-md5", b"<1896.697170952@example.org>").is_ok() {
+        bail!("Expected a replayed challenge to be detected");
+    }
+
+    let guard = Arc::new(ReplayGuard::default());
+    for replayed in [false, true] {
+        let mut client = GuardedClient::new(NonceClient, guard.clone());
+        let mut challenges = vec![crate::driver::ServerMessage::Challenge(b"<1@example.org>".to_vec())].into_iter();
+        let outcome = crate::driver::authenticate_client(&mut client, |_| Ok(()), || Ok(challenges.next().unwrap_or(crate::driver::ServerMessage::Failure)))?;
+        match outcome {
+            crate::driver::Outcome::Failure(err) if replayed == (err.downcast_ref() == Some(&sasl::Error::ReplayDetected)) => {}
+            _ => bail!("Unexpected outcome, expected a replay: {}", replayed),
+        }
+    }
+
+    Ok(())
+}
@@ -1,33 +1,414 @@
-use anyhow::{Result};
+use crate::master::MasterUsers;
+use crate::security_layer::BoxSecurityLayer;
+
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+pub use crate::selftest::self_test;
 
 pub const ERR_UNEXPECTED_CLIENT_RESPONSE: &str = "sasl: unexpected client response";
 pub const ERR_UNEXPECTED_SERVER_CHALLENGE: &str = "sasl: unexpected server challenge";
+pub const ERR_UNEXPECTED_SUCCESS_DATA: &str = "sasl: unexpected additional data with success";
+pub const ERR_AUTHENTICATION_FAILED: &str = "sasl: authentication failed";
+pub const ERR_RESET_UNSUPPORTED: &str = "sasl: server cannot be reset";
+
+/// The reason credentials were rejected. Authenticators may return it as
+/// their error (e.g. `bail!(FailureReason::UnknownUser)`) so that it can be
+/// reported to audit facilities without being disclosed to the client.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+#[non_exhaustive]
+pub enum FailureReason {
+    /// The user does not exist.
+    UnknownUser,
+    /// The user exists, but the credentials are invalid.
+    InvalidCredentials,
+    /// The credentials are valid, but the account cannot be used.
+    AccountDisabled,
+    /// The authenticated user is not allowed to act as the requested
+    /// authorization identity.
+    AuthorizationDenied,
+    /// The client response could not be parsed.
+    MalformedResponse,
+    /// Any other failure.
+    Other,
+}
+
+impl FailureReason {
+    /// Returns the reason carried by an error, or `Other` if it doesn't
+    /// carry any.
+    pub fn of(err: &anyhow::Error) -> Self {
+        err.downcast_ref::<FailureReason>().copied().unwrap_or(FailureReason::Other)
+    }
+}
+
+impl std::fmt::Display for FailureReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let descr = match self {
+            FailureReason::UnknownUser => "unknown user",
+            FailureReason::InvalidCredentials => "invalid credentials",
+            FailureReason::AccountDisabled => "account disabled",
+            FailureReason::AuthorizationDenied => "authorization denied",
+            FailureReason::MalformedResponse => "malformed response",
+            FailureReason::Other => "authentication failed",
+        };
+        write!(f, "sasl: {}", descr)
+    }
+}
+
+impl std::error::Error for FailureReason {}
+
+/// Typed errors, for conditions callers may need to handle specifically.
+/// They are returned wrapped in `anyhow::Error` and can be retrieved with
+/// `downcast_ref`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Error {
+    /// The server sent a challenge it already sent before, e.g. a SCRAM
+    /// nonce, which suggests a man-in-the-middle replaying an exchange.
+    ReplayDetected,
+    /// The exchange was aborted by either side before completing.
+    Canceled,
+    /// Too many authentication attempts failed recently for the user, who
+    /// must retry later. Protocols should report a temporary failure.
+    RateLimited,
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let descr = match self {
+            Error::ReplayDetected => "replayed server challenge",
+            Error::Canceled => "authentication canceled",
+            Error::RateLimited => "too many failed attempts",
+        };
+        write!(f, "sasl: {}", descr)
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// The security properties of a mechanism, for applications to decide which
+/// mechanisms to offer or accept.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Properties {
+    /// Credentials are sent in a form which can be replayed, e.g. a
+    /// password or a bearer token. Only use over an encrypted connection.
+    pub plaintext: bool,
+    /// Clients don't authenticate.
+    pub anonymous: bool,
+    /// The exchange can be bound to the underlying TLS session.
+    pub channel_binding: bool,
+    /// The server authenticates to the client.
+    pub mutual_auth: bool,
+    /// The mechanism negotiates a security layer, which must be applied to
+    /// the connection after authentication.
+    pub security_layer: bool,
+    /// The security strength factor of the security layer, roughly its key
+    /// size in bits as in Cyrus SASL. 0 if there is none.
+    pub ssf: u32,
+}
 
 /// Client interface to perform challenge-response authentication.
 pub trait Client {
+    /// Returns the name of the authentication mechanism, as returned by
+    /// `start`.
+    fn mechanism_name(&self) -> &str;
+
     /// Begins SASL authentication with the server. It returns the
     /// authentication mechanism name and "initial response" data (if required
-    /// by the selected mechanism). A non-nil error causes the client to abort
-    /// the authentication attempt.
-    /// 
-    /// A nil ir value is different from a zero-length value. The nil value
+    /// by the selected mechanism). An error causes the client to abort the
+    /// authentication attempt.
+    ///
+    /// A `None` initial response is different from an empty one. `None`
     /// indicates that the selected mechanism does not use an initial response,
-    /// while a zero-length value indicates an empty initial respons
-    /// e, which must
-    /// be sent to the server.
-    fn start(&mut self) -> Result<(String, Vec<u8>)>;
-	
-    /// Continues challenge-response authentication. A non-nil error causes
-    /// the client to abort the authentication attempt.
+    /// while `Some` with a zero-length value indicates an empty initial
+    /// response, which must be sent to the server.
+    fn start(&mut self) -> Result<(String, Option<Vec<u8>>)>;
+
+    /// Continues challenge-response authentication. An error causes the
+    /// client to abort the authentication attempt.
     fn next(&mut self, challenge: &[u8]) -> Result<Vec<u8>>;
+
+    /// Completes authentication after the server reported success. Data is
+    /// the "additional data with success" sent along with the outcome, if
+    /// any. Mechanisms providing mutual authentication must verify it here;
+    /// an error means the server could not be authenticated and the session
+    /// must not be trusted.
+    ///
+    /// The default implementation accepts no or empty additional data, and
+    /// rejects anything else.
+    fn finish(&mut self, data: Option<&[u8]>) -> Result<()> {
+        match data {
+            Some(data) if !data.is_empty() => bail!(ERR_UNEXPECTED_SUCCESS_DATA),
+            _ => Ok(()),
+        }
+    }
+
+    /// Aborts the exchange. It returns the response to send instead of the
+    /// next one for mechanisms defining an in-band way to cancel, such as
+    /// the GS2 `0x01` response of OAUTHBEARER, or `None` if the protocol's
+    /// own cancellation (e.g. a `*` line in SMTP and IMAP) must be used.
+    fn cancel(&mut self) -> Option<Vec<u8>> {
+        None
+    }
+
+    /// Returns the error a mechanism received from the server during the
+    /// exchange, once the server reported failure, e.g. the error challenge
+    /// of OAUTHBEARER. `None` if it didn't receive any.
+    fn failure(&mut self) -> Option<anyhow::Error> {
+        None
+    }
+
+    /// Returns the security layer negotiated by the mechanism, once
+    /// authentication succeeded, or `None` if it didn't negotiate any.
+    fn security_layer(&mut self) -> Option<BoxSecurityLayer> {
+        None
+    }
+}
+
+impl<C: Client + ?Sized> Client for Box<C> {
+    fn mechanism_name(&self) -> &str {
+        (**self).mechanism_name()
+    }
+
+    fn start(&mut self) -> Result<(String, Option<Vec<u8>>)> {
+        (**self).start()
+    }
+
+    fn next(&mut self, challenge: &[u8]) -> Result<Vec<u8>> {
+        (**self).next(challenge)
+    }
+
+    fn finish(&mut self, data: Option<&[u8]>) -> Result<()> {
+        (**self).finish(data)
+    }
+
+    fn cancel(&mut self) -> Option<Vec<u8>> {
+        (**self).cancel()
+    }
+
+    fn failure(&mut self) -> Option<anyhow::Error> {
+        (**self).failure()
+    }
+
+    fn security_layer(&mut self) -> Option<BoxSecurityLayer> {
+        (**self).security_layer()
+    }
+}
+
+/// A boxed client which can be moved across threads.
+pub type BoxClient = Box<dyn Client + Send>;
+
+/// The outcome of a successful server authentication step.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ServerStep {
+    /// Authentication continues, and the challenge must be sent to the
+    /// client.
+    Challenge(Vec<u8>),
+    /// Authentication is finished. Additional data, if any, must be delivered
+    /// to the client along with the success outcome (or as a final challenge
+    /// if the protocol cannot carry it).
+    Done { additional_data: Option<Vec<u8>> },
+}
+
+impl ServerStep {
+    pub fn is_done(&self) -> bool {
+        matches!(self, ServerStep::Done { .. })
+    }
 }
 
 /// Server interface to perform challenge-response authentication.
 pub trait Server: Send {
+    /// Returns the name of the authentication mechanism.
+    fn mechanism_name(&self) -> &str;
+
     /// Begins or continues challenge-response authentication. If the client
-    /// supplies an initial response, response is non-nil.
-    /// 
-    /// If the authentication is finished, done is set to true. If the
-    /// authentication has failed, an error is returned.
-    fn next(&mut self, response: Option<&[u8]>) -> Result<(Vec<u8>, bool)>;
-}
\ No newline at end of file
+    /// supplies an initial response, response is `Some`.
+    ///
+    /// If the authentication is finished, `ServerStep::Done` is returned. If
+    /// the authentication has failed, an error is returned.
+    fn next(&mut self, response: Option<&[u8]>) -> Result<ServerStep>;
+
+    /// Reports whether the exchange is over, either because authentication
+    /// succeeded or because it failed. A server which was started but isn't
+    /// done has a half-finished exchange.
+    fn is_done(&self) -> bool {
+        false
+    }
+
+    /// Resets the server to its initial state, so that it can be reused for
+    /// another authentication attempt. Servers which can't be reused return
+    /// an error.
+    fn reset(&mut self) -> Result<()> {
+        bail!(ERR_RESET_UNSUPPORTED)
+    }
+
+    /// Returns the security layer negotiated by the mechanism, once
+    /// authentication succeeded. See `Client::security_layer`.
+    fn security_layer(&mut self) -> Option<BoxSecurityLayer> {
+        None
+    }
+
+    /// Returns the authentication identity sent by the client, once the
+    /// mechanism received it, whether or not authentication succeeded. It
+    /// is in canonical form if the server has a canonicalizer, see
+    /// `identity::IdentityMapper`. `None` if the mechanism doesn't have one
+    /// or doesn't report it.
+    fn authentication_id(&self) -> Option<&str> {
+        None
+    }
+
+    /// Returns the authorization identity requested by the client, if any
+    /// and different from the authentication identity. See
+    /// `authentication_id`.
+    fn authorization_id(&self) -> Option<&str> {
+        None
+    }
+
+    /// Lets master users log in as other users, once usernames were parsed
+    /// and prepared like those of regular users. Returns whether the
+    /// mechanism carries such usernames, see `master::MasterUsers`.
+    fn set_master_users(&mut self, _masters: Arc<MasterUsers>) -> bool {
+        false
+    }
+}
+
+/// A boxed server.
+pub type BoxServer = Box<dyn Server>;
+
+impl<S: Server + ?Sized> Server for Box<S> {
+    fn mechanism_name(&self) -> &str {
+        (**self).mechanism_name()
+    }
+
+    fn next(&mut self, response: Option<&[u8]>) -> Result<ServerStep> {
+        (**self).next(response)
+    }
+
+    fn is_done(&self) -> bool {
+        (**self).is_done()
+    }
+
+    fn reset(&mut self) -> Result<()> {
+        (**self).reset()
+    }
+
+    fn security_layer(&mut self) -> Option<BoxSecurityLayer> {
+        (**self).security_layer()
+    }
+
+    fn authentication_id(&self) -> Option<&str> {
+        (**self).authentication_id()
+    }
+
+    fn authorization_id(&self) -> Option<&str> {
+        (**self).authorization_id()
+    }
+
+    fn set_master_users(&mut self, masters: Arc<MasterUsers>) -> bool {
+        (**self).set_master_users(masters)
+    }
+}
+
+#[cfg(feature = "plain")]
+#[test]
+fn test_client_finish_default() -> Result<()> {
+    let mut c = crate::plain::PlainClient::new(String::new(), "username".to_string(), "password".to_string());
+
+    c.finish(None)?;
+    c.finish(Some(b""))?;
+    if c.finish(Some(b"rspauth=1234")).is_ok() {
+        bail!("Unexpected success data was accepted");
+    }
+
+    Ok(())
+}
+
+// Servers are embedded in tokio tasks and tower services, which require
+// Send + 'static: this fails to compile if a type loses these bounds.
+#[test]
+fn test_mechanisms_are_send_sync() {
+    use crate::doc_examples::{ExampleTokenClient, ExampleTokenServer};
+
+    fn assert_send_sync<T: Send + Sync + 'static>() {}
+    fn assert_send<T: Send + 'static>() {}
+
+    #[cfg(feature = "anonymous")]
+    {
+        assert_send_sync::<crate::anonymous::AnonymousClient>();
+        assert_send_sync::<crate::anonymous::AnonymousServer>();
+        assert_send_sync::<crate::anonymous::AnonymousAuthenticator>();
+    }
+    #[cfg(feature = "external")]
+    {
+        assert_send_sync::<crate::external::ExternalClient>();
+        assert_send_sync::<crate::external::ExternalServer>();
+        assert_send_sync::<crate::external::ExternalAuthenticator>();
+        assert_send_sync::<crate::external::ExternalAuthorizer>();
+        #[cfg(feature = "x509")]
+        assert_send_sync::<crate::external::CertificateIdentityResolver>();
+    }
+    #[cfg(feature = "login")]
+    {
+        assert_send_sync::<crate::login::LoginClient>();
+        assert_send_sync::<crate::login::LoginServer>();
+        assert_send_sync::<crate::login::LoginAuthenticator>();
+        #[cfg(feature = "tokio")]
+        assert_send_sync::<crate::login::AsyncLoginServer>();
+    }
+    #[cfg(feature = "oauthbearer")]
+    {
+        assert_send_sync::<crate::oauthbearer::OAuthBearerClient>();
+        assert_send_sync::<crate::oauthbearer::OAuthBearerServer>();
+        assert_send_sync::<crate::oauthbearer::OAuthBearerAuthenticator>();
+        #[cfg(feature = "tokio")]
+        assert_send_sync::<crate::oauthbearer::AsyncOAuthBearerServer>();
+        #[cfg(feature = "tokio")]
+        assert_send_sync::<crate::oauthbearer::OAuthTokenProvider>();
+    }
+    #[cfg(feature = "plain")]
+    {
+        assert_send_sync::<crate::plain::PlainClient>();
+        assert_send_sync::<crate::plain::PlainServer>();
+        assert_send_sync::<crate::plain::PlainAuthenticator>();
+        #[cfg(feature = "tokio")]
+        assert_send_sync::<crate::plain::AsyncPlainServer>();
+    }
+    assert_send_sync::<ExampleTokenClient>();
+    assert_send_sync::<ExampleTokenServer>();
+
+    assert_send::<BoxClient>();
+    assert_send::<BoxServer>();
+    assert_send_sync::<crate::failure::FailurePolicyServer<ExampleTokenServer>>();
+    assert_send_sync::<crate::registry::ClientFactory>();
+    assert_send_sync::<crate::registry::ServerFactory>();
+    assert_send_sync::<crate::registry::Registry>();
+    assert_send_sync::<crate::dispatcher::ServerDispatcher>();
+    assert_send::<crate::dispatcher::Exchange>();
+    assert_send_sync::<crate::negotiator::Negotiator>();
+    assert_send_sync::<crate::identity::IdentityMapper>();
+    assert_send_sync::<crate::replay::ReplayGuard>();
+    assert_send_sync::<crate::downgrade::DowngradeGuard>();
+    assert_send_sync::<crate::nonce::NonceGenerator>();
+    assert_send_sync::<Box<dyn crate::store::CredentialStore>>();
+    assert_send_sync::<crate::typestate::InProgress<ExampleTokenClient>>();
+
+    #[cfg(feature = "api-v2")]
+    {
+        assert_send_sync::<crate::v2::V1Server<ExampleTokenServer>>();
+        assert_send_sync::<crate::v2::V2Server<crate::v2::V1Server<ExampleTokenServer>>>();
+    }
+    #[cfg(feature = "async-imap")]
+    assert_send_sync::<crate::interop::async_imap::ImapAuthenticator<ExampleTokenClient>>();
+
+    #[cfg(feature = "tokio")]
+    {
+        use crate::async_sasl::{BoxAsyncClient, BoxAsyncServer, Inline, SpawnBlocking};
+
+        assert_send::<BoxAsyncClient>();
+        assert_send::<BoxAsyncServer>();
+        assert_send_sync::<Inline<ExampleTokenServer>>();
+        assert_send_sync::<SpawnBlocking<ExampleTokenServer>>();
+    }
+}
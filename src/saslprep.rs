@@ -0,0 +1,61 @@
+//! SASLprep (RFC 4013), the preparation of usernames and passwords which
+//! makes strings entered in different Unicode forms compare equal, e.g.
+//! full-width or decomposed characters. PLAIN and LOGIN clients and servers
+//! apply it when built `with_saslprep`.
+
+use crate::sasl::FailureReason;
+
+use anyhow::Result;
+use std::borrow::Cow;
+
+pub const ERR_SASLPREP: &str = "sasl: saslprep: invalid string";
+
+/// Prepares a string with SASLprep. Strings with prohibited characters,
+/// e.g. control characters, fail with `FailureReason::MalformedResponse`.
+pub fn saslprep(s: &str) -> Result<Cow<'_, str>> {
+    stringprep::saslprep(s)
+        .map_err(|err| anyhow::Error::new(FailureReason::MalformedResponse).context(format!("{}: {}", ERR_SASLPREP, err)))
+}
+
+#[cfg(all(feature = "plain", feature = "login"))]
+#[test]
+fn test_saslprep() -> Result<()> {
+    use crate::login::{LoginClient, LoginServer};
+    use crate::plain::{PlainClient, PlainServer};
+    use crate::sasl::{Client, Server};
+    use anyhow::bail;
+
+    // U+2168 ROMAN NUMERAL NINE is mapped to "IX", and U+00AD SOFT HYPHEN
+    // to nothing (RFC 4013 section 3).
+    let (_, response) = PlainClient::new(String::new(), "user".to_string(), "\u{2168}".to_string()).with_saslprep().start()?;
+    if response.as_deref() != Some(&b"\x00user\x00IX"[..]) {
+        bail!("Unexpected response: {:?}", response);
+    }
+    let authenticator = || {
+        Box::new(|username: &str, password: &str| match (username, password) {
+            ("user", "IX") => Ok(()),
+            _ => bail!(FailureReason::InvalidCredentials),
+        })
+    };
+    let authenticator_plain = authenticator();
+    let mut s = PlainServer::new(Box::new(move |_, username, password| authenticator_plain(username, password))).with_saslprep();
+    s.next(Some("\x00us\u{00AD}er\x00I\u{00AD}X".as_bytes()))?;
+    s.reset()?;
+    match s.next(Some(b"\x00user\x00I\x07X")) {
+        Err(err) if FailureReason::of(&err) == FailureReason::MalformedResponse => {}
+        result => bail!("Expected a prohibited character to be rejected, got {:?}", result),
+    }
+
+    let mut c = LoginClient::new("user".to_string(), "\u{2168}".to_string()).with_saslprep();
+    let mut s = LoginServer::new(authenticator()).with_saslprep();
+    let (_, response) = c.start()?;
+    let challenge = match s.next(response.as_deref())? {
+        crate::sasl::ServerStep::Challenge(challenge) => challenge,
+        step => bail!("Unexpected step: {:?}", step),
+    };
+    if !s.next(Some(&c.next(&challenge)?))?.is_done() {
+        bail!("Expected the prepared password to be accepted");
+    }
+
+    Ok(())
+}
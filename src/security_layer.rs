@@ -0,0 +1,104 @@
+//! Security layers, providing per-message integrity and confidentiality
+//! after authentication for mechanisms negotiating them (e.g. GSSAPI and
+//! DIGEST-MD5). Once the exchange completed, protocols apply the layer to
+//! the rest of the stream, as a sequence of length-prefixed buffers (RFC
+//! 4422 section 3.7).
+
+use anyhow::{bail, Result};
+
+pub const ERR_BUFFER_TOO_LARGE: &str = "sasl: security layer buffer exceeds the maximum size";
+
+/// A security layer negotiated by a mechanism.
+pub trait SecurityLayer: Send {
+    /// Protects an outgoing message of at most `max_send_size` bytes.
+    fn wrap(&mut self, message: &[u8]) -> Result<Vec<u8>>;
+
+    /// Verifies and decodes an incoming protected message.
+    fn unwrap(&mut self, message: &[u8]) -> Result<Vec<u8>>;
+
+    /// Returns the maximum size of messages passed to `wrap`, so that
+    /// wrapped buffers don't exceed the size the peer accepts.
+    fn max_send_size(&self) -> usize;
+}
+
+pub type BoxSecurityLayer = Box<dyn SecurityLayer>;
+
+/// Protects outgoing data, split into buffers of at most `max_send_size`
+/// bytes, each prefixed with its length as a 4-byte big-endian integer.
+pub fn wrap_buffers(layer: &mut dyn SecurityLayer, data: &[u8]) -> Result<Vec<u8>> {
+    let mut out = Vec::new();
+    for chunk in data.chunks(layer.max_send_size().max(1)) {
+        let buffer = layer.wrap(chunk)?;
+        let len = u32::try_from(buffer.len()).or_else(|_| bail!(ERR_BUFFER_TOO_LARGE))?;
+        out.extend_from_slice(&len.to_be_bytes());
+        out.extend_from_slice(&buffer);
+    }
+    Ok(out)
+}
+
+/// Unwraps the complete buffers at the start of incoming data, returning
+/// the decoded data and the number of bytes consumed. Incomplete buffers
+/// are left for the next call, once more data has been received. Buffers
+/// larger than `max_buffer_size`, the size advertised to the peer, are
+/// rejected.
+pub fn unwrap_buffers(layer: &mut dyn SecurityLayer, input: &[u8], max_buffer_size: usize) -> Result<(Vec<u8>, usize)> {
+    let mut out = Vec::new();
+    let mut consumed = 0;
+    while let Some(header) = input.get(consumed..consumed + 4) {
+        let len = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+        if len > max_buffer_size {
+            bail!(ERR_BUFFER_TOO_LARGE);
+        }
+        let Some(buffer) = input.get(consumed + 4..consumed + 4 + len) else {
+            break;
+        };
+        out.extend(layer.unwrap(buffer)?);
+        consumed += 4 + len;
+    }
+    Ok((out, consumed))
+}
+
+#[test]
+fn test_security_layer_buffers() -> Result<()> {
+    // Appends a checksum byte to each message.
+    struct Checksum;
+
+    impl SecurityLayer for Checksum {
+        fn wrap(&mut self, message: &[u8]) -> Result<Vec<u8>> {
+            let sum = message.iter().fold(0u8, |acc, b| acc.wrapping_add(*b));
+            Ok([message, &[sum]].concat())
+        }
+
+        fn unwrap(&mut self, message: &[u8]) -> Result<Vec<u8>> {
+            let (sum, message) = message.split_last().ok_or_else(|| anyhow::anyhow!("empty buffer"))?;
+            if message.iter().fold(0u8, |acc, b| acc.wrapping_add(*b)) != *sum {
+                bail!("bad checksum");
+            }
+            Ok(message.to_vec())
+        }
+
+        fn max_send_size(&self) -> usize {
+            4
+        }
+    }
+
+    let wrapped = wrap_buffers(&mut Checksum, b"hello world")?;
+    if wrapped.len() != 11 + 3 * 5 {
+        bail!("Expected three buffers");
+    }
+
+    // The last buffer is incomplete.
+    let (data, consumed) = unwrap_buffers(&mut Checksum, &wrapped[..wrapped.len() - 1], 5)?;
+    if data != b"hello wo" || consumed != 18 {
+        bail!("Unexpected partial unwrap");
+    }
+    let (data, _) = unwrap_buffers(&mut Checksum, &wrapped[consumed..], 5)?;
+    if data != b"rld" {
+        bail!("Unexpected unwrap");
+    }
+
+    if unwrap_buffers(&mut Checksum, &wrapped, 4).is_ok() {
+        bail!("Expected oversized buffers to be rejected");
+    }
+    Ok(())
+}
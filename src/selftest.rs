@@ -0,0 +1,267 @@
+//! Power-on self tests, for daemons which must check that the primitives
+//! and mechanisms they rely on work before accepting connections.
+
+#[cfg(feature = "anonymous")]
+use crate::anonymous::{AnonymousClient, AnonymousServer};
+#[cfg(feature = "external")]
+use crate::external::{ExternalClient, ExternalServer};
+#[cfg(feature = "login")]
+use crate::login::{LoginClient, LoginServer};
+use crate::nonce::NonceGenerator;
+#[cfg(feature = "oauthbearer")]
+use crate::oauthbearer::{OAuthBearerClient, OAuthBearerError, OAuthBearerOptions, OAuthBearerServer};
+#[cfg(feature = "plain")]
+use crate::plain::{PlainClient, PlainServer};
+
+use anyhow::{anyhow, bail, Result};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+/// The outcome of a single check.
+#[derive(Clone, Debug, Serialize)]
+pub struct SelfTestResult {
+    pub name: &'static str,
+    pub passed: bool,
+    /// The error, if the check failed.
+    pub detail: Option<String>,
+}
+
+/// The outcome of `self_test`.
+#[derive(Clone, Debug, Serialize)]
+pub struct SelfTestReport {
+    pub results: Vec<SelfTestResult>,
+}
+
+impl SelfTestReport {
+    /// Reports whether all checks passed.
+    pub fn passed(&self) -> bool {
+        self.results.iter().all(|result| result.passed)
+    }
+
+    pub fn failures(&self) -> impl Iterator<Item = &SelfTestResult> {
+        self.results.iter().filter(|result| !result.passed)
+    }
+}
+
+impl std::fmt::Display for SelfTestReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for result in &self.results {
+            match &result.detail {
+                None => writeln!(f, "{}: ok", result.name)?,
+                Some(detail) => writeln!(f, "{}: FAILED: {}", result.name, detail)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+type Check = fn() -> Result<()>;
+
+/// Runs known-answer tests of SHA-256, and of HMAC-SHA-256 and
+/// PBKDF2-HMAC-SHA-256 with the `password` feature, checks of the random
+/// number generator and nonce generator, and authentication round-trips of
+/// every enabled built-in mechanism.
+pub fn self_test() -> SelfTestReport {
+    let checks: &[(&str, Check)] = &[
+        ("SHA-256", check_sha256),
+        #[cfg(feature = "password")]
+        ("HMAC-SHA-256", check_hmac_sha256),
+        #[cfg(feature = "password")]
+        ("PBKDF2-HMAC-SHA-256", check_pbkdf2_sha256),
+        ("entropy", check_entropy),
+        ("nonce", check_nonce),
+        #[cfg(feature = "anonymous")]
+        ("ANONYMOUS", check_anonymous),
+        #[cfg(feature = "external")]
+        ("EXTERNAL", check_external),
+        #[cfg(feature = "login")]
+        ("LOGIN", check_login),
+        #[cfg(feature = "oauthbearer")]
+        ("OAUTHBEARER", check_oauthbearer),
+        #[cfg(feature = "plain")]
+        ("PLAIN", check_plain),
+    ];
+
+    let results = checks
+        .iter()
+        .map(|(name, check)| {
+            let detail = check().err().map(|err| err.to_string());
+            SelfTestResult {
+                name,
+                passed: detail.is_none(),
+                detail,
+            }
+        })
+        .collect();
+    SelfTestReport { results }
+}
+
+/// Compares a digest to its expected value in hex.
+fn known_answer(digest: &[u8], expected: &str) -> Result<()> {
+    let hex: String = digest.iter().map(|b| format!("{:02x}", b)).collect();
+    if hex != expected {
+        bail!("unexpected output {}", hex);
+    }
+    Ok(())
+}
+
+/// FIPS 180-2 appendix B.1.
+fn check_sha256() -> Result<()> {
+    known_answer(&Sha256::digest(b"abc"), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad")
+}
+
+/// RFC 4231 section 4.3.
+#[cfg(feature = "password")]
+fn check_hmac_sha256() -> Result<()> {
+    use hmac::{Hmac, Mac};
+
+    let mut mac = Hmac::<Sha256>::new_from_slice(b"Jefe").map_err(|err| anyhow!("sasl: {}", err))?;
+    mac.update(b"what do ya want for nothing?");
+    known_answer(&mac.finalize().into_bytes(), "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843")
+}
+
+/// RFC 7914 section 11.
+#[cfg(feature = "password")]
+fn check_pbkdf2_sha256() -> Result<()> {
+    known_answer(
+        &pbkdf2::pbkdf2_hmac_array::<Sha256, 64>(b"passwd", b"salt", 1),
+        "55ac046e56e3089fec1691c22544b605f94185216dde0465e68b9d57c20dacbc49ca9cccf179b645991664b39d77ef317c71b845b1e30bd509112041d3a19783",
+    )
+}
+
+fn check_entropy() -> Result<()> {
+    let mut a = [0u8; 32];
+    let mut b = [0u8; 32];
+    getrandom::fill(&mut a).map_err(|err| anyhow!("sasl: {}", err))?;
+    getrandom::fill(&mut b).map_err(|err| anyhow!("sasl: {}", err))?;
+    if a == b || a == [0; 32] {
+        bail!("random number generator returned repeated output");
+    }
+    Ok(())
+}
+
+fn check_nonce() -> Result<()> {
+    let generator = NonceGenerator::new(0x0123456789abcdef);
+    let a = generator.generate()?;
+    let b = generator.generate()?;
+    if a == b {
+        bail!("duplicate nonce");
+    }
+    if !a.starts_with("0123456789abcdef") || a.len() != 64 || !a.bytes().all(|c| c.is_ascii_hexdigit()) {
+        bail!("malformed nonce: {}", a);
+    }
+    Ok(())
+}
+
+/// Runs an exchange between a client and a server, and checks that it
+/// succeeds or fails as expected.
+#[cfg(any(feature = "anonymous", feature = "external", feature = "login", feature = "oauthbearer", feature = "plain"))]
+fn round_trip(client: &mut dyn crate::sasl::Client, server: &mut dyn crate::sasl::Server, expect_success: bool) -> Result<()> {
+    let result = (|| {
+        let (_, ir) = client.start()?;
+        let mut step = server.next(ir.as_deref())?;
+        loop {
+            match step {
+                crate::sasl::ServerStep::Challenge(challenge) => {
+                    let response = client.next(&challenge)?;
+                    step = server.next(Some(&response))?;
+                }
+                crate::sasl::ServerStep::Done { additional_data } => return client.finish(additional_data.as_deref()),
+            }
+        }
+    })();
+
+    match (result, expect_success) {
+        (Ok(()), true) | (Err(_), false) => Ok(()),
+        (Ok(()), false) => bail!("invalid credentials were accepted"),
+        (Err(err), true) => bail!("valid credentials were rejected: {}", err),
+    }
+}
+
+#[cfg(feature = "anonymous")]
+fn check_anonymous() -> Result<()> {
+    let mut server = AnonymousServer::new(Box::new(|trace| {
+        if trace != "trace" {
+            bail!("invalid trace");
+        }
+        Ok(())
+    }));
+    round_trip(&mut AnonymousClient::new("trace".to_string()), &mut server, true)
+}
+
+#[cfg(feature = "external")]
+fn check_external() -> Result<()> {
+    let new_server = || {
+        ExternalServer::new(Box::new(|identity| {
+            if identity != "identity" {
+                bail!("invalid identity");
+            }
+            Ok(())
+        }))
+    };
+    round_trip(&mut ExternalClient::new("identity".to_string()), &mut new_server(), true)?;
+    round_trip(&mut ExternalClient::new("other".to_string()), &mut new_server(), false)
+}
+
+#[cfg(feature = "login")]
+fn check_login() -> Result<()> {
+    let new_server = || {
+        LoginServer::new(Box::new(|username, password| {
+            if username != "username" || password != "password" {
+                bail!("invalid credentials");
+            }
+            Ok(())
+        }))
+    };
+    let mut client = LoginClient::new("username".to_string(), "password".to_string());
+    round_trip(&mut client, &mut new_server(), true)?;
+    let mut client = LoginClient::new("username".to_string(), "wrong".to_string());
+    round_trip(&mut client, &mut new_server(), false)
+}
+
+#[cfg(feature = "oauthbearer")]
+fn check_oauthbearer() -> Result<()> {
+    let new_server = || {
+        OAuthBearerServer::new(Box::new(|opts| {
+            if opts.username != "username" || opts.token != "token" {
+                return Err(OAuthBearerError::invalid_token());
+            }
+            Ok(())
+        }))
+    };
+    let new_client = |token: &str| {
+        OAuthBearerClient::new(OAuthBearerOptions {
+            username: "username".to_string(),
+            token: token.to_string(),
+            host: "localhost".to_string(),
+            port: 143,
+            ..Default::default()
+        })
+    };
+    round_trip(&mut new_client("token"), &mut new_server(), true)?;
+    round_trip(&mut new_client("wrong"), &mut new_server(), false)
+}
+
+#[cfg(feature = "plain")]
+fn check_plain() -> Result<()> {
+    let new_server = || {
+        PlainServer::new(Box::new(|identity, username, password| {
+            if identity != "identity" || username != "username" || password != "password" {
+                bail!("invalid credentials");
+            }
+            Ok(())
+        }))
+    };
+    let new_client = |password: &str| PlainClient::new("identity".to_string(), "username".to_string(), password.to_string());
+    round_trip(&mut new_client("password"), &mut new_server(), true)?;
+    round_trip(&mut new_client("wrong"), &mut new_server(), false)
+}
+
+#[test]
+fn test_self_test() -> Result<()> {
+    let report = self_test();
+    if !report.passed() {
+        bail!("Self test failed:\n{}", report);
+    }
+    Ok(())
+}
@@ -0,0 +1,211 @@
+//! Credential storage, shared by the servers of password-based mechanisms.
+
+pub mod audit;
+#[cfg(feature = "tokio")]
+pub mod ldap;
+pub mod memory;
+#[cfg(feature = "pam")]
+pub mod pam;
+#[cfg(feature = "tokio")]
+pub mod redis;
+pub mod reloadable;
+#[cfg(feature = "password")]
+pub mod scram;
+#[cfg(feature = "sql")]
+pub mod sql;
+pub mod tenant;
+
+#[cfg(feature = "tokio")]
+use crate::async_sasl::BoxFuture;
+
+use crate::constant_time;
+use crate::sasl::FailureReason;
+
+use anyhow::{anyhow, bail, Result};
+use std::sync::Arc;
+
+pub const ERR_UNSUPPORTED_CREDENTIAL: &str = "sasl: store: credential can't verify a password";
+
+/// A user's credentials, as kept in a user database.
+#[derive(Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum StoredCredential {
+    /// A cleartext password.
+    Plaintext(String),
+    /// A password hash in PHC string format, e.g. `$argon2id$v=19$...`, or
+    /// a bcrypt hash, e.g. `$2b$12$...`. See `password::identify`.
+    Hash(String),
+    /// SCRAM keys, as per RFC 5802 section 3.
+    Scram {
+        /// The SCRAM mechanism, e.g. `SCRAM-SHA-256`.
+        mechanism: String,
+        iterations: u32,
+        salt: Vec<u8>,
+        stored_key: Vec<u8>,
+        server_key: Vec<u8>,
+    },
+}
+
+/// A user database.
+pub trait CredentialStore: Send + Sync {
+    /// Returns the credentials of a user, or `None` if the user doesn't
+    /// exist.
+    fn get(&self, username: &str) -> Result<Option<StoredCredential>>;
+
+    /// Lists all users.
+    fn usernames(&self) -> Result<Vec<String>>;
+}
+
+/// A user database storing SCRAM credentials, e.g. to upgrade users to
+/// them, see `scram::ScramUpgrade`.
+pub trait ScramCredentialStore: Send + Sync {
+    /// Returns the SCRAM credentials of a user for a mechanism, e.g.
+    /// `SCRAM-SHA-256`, or `None` if there are none.
+    fn scram_credential(&self, username: &str, mechanism: &str) -> Result<Option<StoredCredential>>;
+
+    /// Stores the SCRAM credentials of a user.
+    fn set_scram_credential(&self, username: &str, credential: StoredCredential) -> Result<()>;
+}
+
+/// The credential unknown users are verified against by stores holding no
+/// credentials, whose users are all unknown.
+static DEFAULT_DECOY: StoredCredential = StoredCredential::Plaintext(String::new());
+
+/// Returns a random password of `len` lowercase letters.
+fn random_password(len: usize) -> Result<String> {
+    let mut bytes = vec![0; len];
+    getrandom::fill(&mut bytes).map_err(|err| anyhow!("sasl: {}", err))?;
+    Ok(bytes.into_iter().map(|b| (b'a' + b % 26) as char).collect())
+}
+
+/// Creates a decoy of the kind and parameters of a stored credential, for
+/// a random password: a cleartext password of the same length, a hash with
+/// the same scheme and cost, or SCRAM credentials with the same iteration
+/// count. Verifying a password against it takes as long as against the
+/// credential.
+pub fn decoy_for(credential: &StoredCredential) -> Result<StoredCredential> {
+    match credential {
+        StoredCredential::Plaintext(password) => Ok(StoredCredential::Plaintext(random_password(password.len())?)),
+        #[cfg(feature = "password")]
+        StoredCredential::Hash(hash) if crate::password::identify(hash).is_some() => {
+            Ok(StoredCredential::Hash(crate::password::hash_like(&random_password(16)?, hash)?))
+        }
+        #[cfg(feature = "password")]
+        StoredCredential::Scram { mechanism, iterations, salt, .. } if mechanism == scram::SCRAM_SHA_256 => {
+            let mut salt = vec![0; salt.len()];
+            getrandom::fill(&mut salt).map_err(|err| anyhow!("sasl: {}", err))?;
+            scram::derive(&random_password(16)?, &salt, *iterations)
+        }
+        // Credentials which can't be verified fail alike for all users.
+        credential => Ok(credential.clone()),
+    }
+}
+
+/// Verifies a password against a credential, returning whether it matches.
+/// Mismatches of cleartext passwords don't allocate an error, so that their
+/// timing only depends on the comparison.
+fn verify_password(credential: &StoredCredential, password: &str) -> Result<bool> {
+    match credential {
+        StoredCredential::Plaintext(stored) => Ok(constant_time::eq(stored.as_bytes(), password.as_bytes())),
+        #[cfg(feature = "password")]
+        StoredCredential::Hash(hash) => mismatch_as_false(crate::password::verify(password, hash)),
+        #[cfg(feature = "password")]
+        credential @ StoredCredential::Scram { .. } => mismatch_as_false(scram::verify(password, credential)),
+        #[cfg(not(feature = "password"))]
+        _ => bail!(ERR_UNSUPPORTED_CREDENTIAL),
+    }
+}
+
+#[cfg(feature = "password")]
+fn mismatch_as_false(result: Result<()>) -> Result<bool> {
+    match result {
+        Ok(()) => Ok(true),
+        Err(err) if FailureReason::of(&err) == FailureReason::InvalidCredentials => Ok(false),
+        Err(err) => Err(err),
+    }
+}
+
+/// Verifies a password against the credentials of a user, if it exists.
+/// Hashes and SCRAM credentials are supported with the `password` feature,
+/// and users may only act as themselves.
+///
+/// Unknown users are verified against a decoy credential, which should be
+/// of the kind the store holds (e.g. a hash with the same parameters, see
+/// `decoy_for`), so that they can't be told apart from wrong passwords by
+/// timing.
+pub(crate) fn verify_credential(credential: Option<&StoredCredential>, decoy: Option<&StoredCredential>, identity: &str, username: &str, password: &str) -> Result<()> {
+    let unknown = credential.is_none();
+    let matches = verify_password(credential.unwrap_or(decoy.unwrap_or(&DEFAULT_DECOY)), password)?;
+    // A single failure path, whichever the reason.
+    if unknown | !matches {
+        bail!([FailureReason::InvalidCredentials, FailureReason::UnknownUser][unknown as usize]);
+    }
+    if !identity.is_empty() && identity != username {
+        bail!(FailureReason::AuthorizationDenied);
+    }
+    Ok(())
+}
+
+/// Verifies cleartext passwords, for the servers of PLAIN and LOGIN, so that
+/// both mechanisms share one backend. Failures should carry a
+/// `sasl::FailureReason`, e.g. `bail!(FailureReason::UnknownUser)`, and
+/// take as long for unknown users as for wrong passwords, e.g. by
+/// verifying the password against a decoy credential.
+pub trait CredentialVerifier: Send + Sync {
+    /// Verifies the password of a user, and that the user may act as
+    /// `identity`. An empty identity is the user itself, and is always
+    /// empty with LOGIN.
+    fn verify(&self, identity: &str, username: &str, password: &str) -> Result<()>;
+}
+
+impl<V: CredentialVerifier + ?Sized> CredentialVerifier for Arc<V> {
+    fn verify(&self, identity: &str, username: &str, password: &str) -> Result<()> {
+        (**self).verify(identity, username, password)
+    }
+}
+
+/// Verifies cleartext passwords asynchronously, e.g. against a directory.
+/// See `CredentialVerifier`.
+#[cfg(feature = "tokio")]
+pub trait AsyncCredentialVerifier: Send + Sync {
+    fn verify<'a>(&'a self, identity: &'a str, username: &'a str, password: &'a str) -> BoxFuture<'a, Result<()>>;
+}
+
+#[cfg(all(feature = "plain", feature = "login"))]
+#[test]
+fn test_credential_verifier() -> Result<()> {
+    use crate::login::LoginServer;
+    use crate::plain::PlainServer;
+    use crate::sasl::Server;
+
+    struct Verifier;
+
+    impl CredentialVerifier for Verifier {
+        fn verify(&self, identity: &str, username: &str, password: &str) -> Result<()> {
+            match (username, password) {
+                ("username", "password") if identity.is_empty() => Ok(()),
+                ("username", "password") => bail!(FailureReason::AuthorizationDenied),
+                ("username", _) => bail!(FailureReason::InvalidCredentials),
+                _ => bail!(FailureReason::UnknownUser),
+            }
+        }
+    }
+
+    let verifier: Arc<dyn CredentialVerifier> = Arc::new(Verifier);
+    let mut plain = PlainServer::from_verifier(verifier.clone());
+    plain.next(Some(b"\x00username\x00password"))?;
+    for (response, reason) in [(&b"admin\x00username\x00password"[..], FailureReason::AuthorizationDenied), (b"\x00nobody\x00password", FailureReason::UnknownUser)] {
+        plain.reset()?;
+        match plain.next(Some(response)) {
+            Err(err) if FailureReason::of(&err) == reason => {}
+            _ => bail!("Expected PLAIN to fail with {}", reason),
+        }
+    }
+
+    let mut login = LoginServer::from_verifier(verifier);
+    login.next(Some(b"username"))?;
+    match login.next(Some(b"wrong")) {
+        Err(err) if FailureReason::of(&err) == FailureReason::InvalidCredentials => Ok(()),
+        _ => bail!("Expected LOGIN to fail with invalid credentials"),
+    }
+}
@@ -0,0 +1,169 @@
+//! Offline audits of credential stores, reporting credentials which are
+//! stored with weak parameters.
+
+use super::{CredentialStore, StoredCredential};
+
+use anyhow::Result;
+use serde::Serialize;
+
+/// The minimum SCRAM iteration count recommended by RFC 7677.
+pub const MIN_SCRAM_ITERATIONS: u32 = 4096;
+
+/// Hash schemes considered broken, as crypt(3) or `{SCHEME}` prefixes.
+const WEAK_HASH_SCHEMES: &[&str] = &["$1$", "$apr1$", "{MD5}", "{SMD5}", "{SHA}", "{SSHA}", "{CRYPT}"];
+
+/// The thresholds of an audit.
+#[derive(Clone, Debug)]
+pub struct AuditPolicy {
+    pub min_scram_iterations: u32,
+    /// Whether to report cleartext passwords.
+    pub forbid_plaintext: bool,
+}
+
+impl Default for AuditPolicy {
+    fn default() -> Self {
+        Self {
+            min_scram_iterations: MIN_SCRAM_ITERATIONS,
+            forbid_plaintext: true,
+        }
+    }
+}
+
+/// A problem with a stored credential.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(tag = "weakness", rename_all = "snake_case")]
+#[non_exhaustive]
+pub enum Weakness {
+    EmptyPassword,
+    PlaintextPassword,
+    /// The password is hashed with a broken scheme, e.g. MD5-crypt.
+    WeakHash { scheme: String },
+    LowIterations { iterations: u32, minimum: u32 },
+    EmptySalt,
+}
+
+/// The weaknesses of a user's credentials.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Finding {
+    pub username: String,
+    pub weaknesses: Vec<Weakness>,
+}
+
+/// The outcome of an audit.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct AuditReport {
+    /// The number of users audited.
+    pub users: usize,
+    /// The users which could not be looked up, e.g. because they were
+    /// removed during the audit.
+    pub missing: Vec<String>,
+    pub findings: Vec<Finding>,
+}
+
+impl AuditReport {
+    /// Reports whether no weakness was found.
+    pub fn passed(&self) -> bool {
+        self.findings.is_empty()
+    }
+}
+
+/// Reports the weaknesses of a credential.
+pub fn check(credential: &StoredCredential, policy: &AuditPolicy) -> Vec<Weakness> {
+    let mut weaknesses = Vec::new();
+    match credential {
+        StoredCredential::Plaintext(password) => {
+            if password.is_empty() {
+                weaknesses.push(Weakness::EmptyPassword);
+            }
+            if policy.forbid_plaintext {
+                weaknesses.push(Weakness::PlaintextPassword);
+            }
+        }
+        StoredCredential::Hash(hash) => {
+            let upper = hash.to_ascii_uppercase();
+            if let Some(scheme) = WEAK_HASH_SCHEMES.iter().find(|s| upper.starts_with(&s.to_ascii_uppercase())) {
+                weaknesses.push(Weakness::WeakHash {
+                    scheme: scheme.to_string(),
+                });
+            } else if hash.len() == 13 && !hash.starts_with(['$', '{']) {
+                // Traditional DES-based crypt(3).
+                weaknesses.push(Weakness::WeakHash {
+                    scheme: "des-crypt".to_string(),
+                });
+            }
+        }
+        StoredCredential::Scram { iterations, salt, .. } => {
+            if *iterations < policy.min_scram_iterations {
+                weaknesses.push(Weakness::LowIterations {
+                    iterations: *iterations,
+                    minimum: policy.min_scram_iterations,
+                });
+            }
+            if salt.is_empty() {
+                weaknesses.push(Weakness::EmptySalt);
+            }
+        }
+    }
+    weaknesses
+}
+
+/// Checks the credentials of every user of a store.
+pub fn audit(store: &dyn CredentialStore, policy: &AuditPolicy) -> Result<AuditReport> {
+    let mut report = AuditReport::default();
+    for username in store.usernames()? {
+        report.users += 1;
+        let credential = match store.get(&username)? {
+            Some(credential) => credential,
+            None => {
+                report.missing.push(username);
+                continue;
+            }
+        };
+        let weaknesses = check(&credential, policy);
+        if !weaknesses.is_empty() {
+            report.findings.push(Finding { username, weaknesses });
+        }
+    }
+    Ok(report)
+}
+
+#[test]
+fn test_audit() -> Result<()> {
+    use anyhow::bail;
+
+    struct Store(Vec<(&'static str, StoredCredential)>);
+
+    impl CredentialStore for Store {
+        fn get(&self, username: &str) -> Result<Option<StoredCredential>> {
+            Ok(self.0.iter().find(|(u, _)| *u == username).map(|(_, c)| c.clone()))
+        }
+
+        fn usernames(&self) -> Result<Vec<String>> {
+            Ok(self.0.iter().map(|(u, _)| u.to_string()).collect())
+        }
+    }
+
+    let scram = |iterations| StoredCredential::Scram {
+        mechanism: "SCRAM-SHA-256".to_string(),
+        iterations,
+        salt: b"salt".to_vec(),
+        stored_key: Vec::new(),
+        server_key: Vec::new(),
+    };
+    let store = Store(vec![
+        ("alice", scram(4096)),
+        ("bob", scram(1000)),
+        ("carol", StoredCredential::Hash("$1$salt$hash".to_string())),
+        ("dave", StoredCredential::Hash("$argon2id$v=19$m=65536,t=3,p=4$salt$hash".to_string())),
+        ("eve", StoredCredential::Plaintext(String::new())),
+    ]);
+
+    let report = audit(&store, &AuditPolicy::default())?;
+    let json = serde_json::to_string(&report.findings)?;
+    let expected = r#"[{"username":"bob","weaknesses":[{"weakness":"low_iterations","iterations":1000,"minimum":4096}]},{"username":"carol","weaknesses":[{"weakness":"weak_hash","scheme":"$1$"}]},{"username":"eve","weaknesses":[{"weakness":"empty_password"},{"weakness":"plaintext_password"}]}]"#;
+    if report.users != 5 || json != expected {
+        bail!("Unexpected report: {}", json);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,192 @@
+//! An `AsyncCredentialVerifier` checking passwords with LDAP simple binds,
+//! e.g. against Active Directory or OpenLDAP. As in `protocol::ldap`, the
+//! LDAP client is left to the application: it's reached through the
+//! `LdapConnection` trait.
+
+use super::AsyncCredentialVerifier;
+use crate::async_sasl::BoxFuture;
+use crate::protocol::ldap::{INVALID_CREDENTIALS, SUCCESS};
+use crate::sasl::FailureReason;
+
+use anyhow::{anyhow, bail, Result};
+
+pub const ERR_BIND_FAILED: &str = "sasl: ldap: bind failed";
+pub const ERR_AMBIGUOUS_USER: &str = "sasl: ldap: several entries match the user";
+
+/// The placeholder replaced by the escaped username in DN templates and
+/// search filters.
+pub const USERNAME: &str = "{username}";
+
+/// A connection to an LDAP server.
+pub trait LdapConnection: Send {
+    /// Performs a simple bind, returning its result code.
+    fn simple_bind<'a>(&'a mut self, dn: &'a str, password: &'a str) -> BoxFuture<'a, Result<u32>>;
+
+    /// Searches the subtree of `base`, returning the DNs of the matching
+    /// entries.
+    fn search<'a>(&'a mut self, base: &'a str, filter: &'a str) -> BoxFuture<'a, Result<Vec<String>>>;
+}
+
+/// Opens a new connection. Each verification uses its own connection, since
+/// binds change its authorization state.
+pub type LdapConnector = Box<dyn Fn() -> BoxFuture<'static, Result<Box<dyn LdapConnection>>> + Send + Sync>;
+
+/// How to find the DN of a user.
+#[derive(Clone, Debug)]
+pub enum DnLookup {
+    /// A DN template, e.g. `uid={username},ou=people,dc=example,dc=com`.
+    Template(String),
+    /// A search of the subtree of `base` with a filter template, e.g.
+    /// `(sAMAccountName={username})`, bound as a service account first.
+    Search {
+        base: String,
+        filter: String,
+        bind_dn: String,
+        bind_password: String,
+    },
+}
+
+/// Escapes a DN attribute value (RFC 4514 section 2.4).
+fn escape_dn_value(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for (i, c) in value.chars().enumerate() {
+        match c {
+            ',' | '+' | '"' | '\\' | '<' | '>' | ';' | '=' => escaped.push('\\'),
+            '#' | ' ' if i == 0 => escaped.push('\\'),
+            ' ' if i == value.chars().count() - 1 => escaped.push('\\'),
+            '\0' => {
+                escaped.push_str("\\00");
+                continue;
+            }
+            _ => {}
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Escapes a search filter assertion value (RFC 4515 section 3).
+fn escape_filter_value(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '*' => escaped.push_str("\\2a"),
+            '(' => escaped.push_str("\\28"),
+            ')' => escaped.push_str("\\29"),
+            '\\' => escaped.push_str("\\5c"),
+            '\0' => escaped.push_str("\\00"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Verifies passwords by binding as the user. Users may only act as
+/// themselves.
+pub struct LdapVerifier {
+    connector: LdapConnector,
+    lookup: DnLookup,
+}
+
+impl LdapVerifier {
+    pub fn new(connector: LdapConnector, lookup: DnLookup) -> Self {
+        Self { connector, lookup }
+    }
+
+    async fn authenticate(&self, username: &str, password: &str) -> Result<()> {
+        // An empty password would make an unauthenticated bind, which
+        // servers accept for any DN (RFC 4513 section 5.1.2).
+        if username.is_empty() || password.is_empty() {
+            bail!(FailureReason::InvalidCredentials);
+        }
+        let mut conn = (self.connector)().await?;
+        let dn = match &self.lookup {
+            DnLookup::Template(template) => template.replace(USERNAME, &escape_dn_value(username)),
+            DnLookup::Search {
+                base,
+                filter,
+                bind_dn,
+                bind_password,
+            } => {
+                match conn.simple_bind(bind_dn, bind_password).await? {
+                    SUCCESS => {}
+                    code => bail!("{}: service account bind returned {}", ERR_BIND_FAILED, code),
+                }
+                let mut dns = conn.search(base, &filter.replace(USERNAME, &escape_filter_value(username))).await?;
+                match dns.len() {
+                    0 => bail!(FailureReason::UnknownUser),
+                    1 => dns.remove(0),
+                    _ => bail!(ERR_AMBIGUOUS_USER),
+                }
+            }
+        };
+        match conn.simple_bind(&dn, password).await? {
+            SUCCESS => Ok(()),
+            INVALID_CREDENTIALS => Err(anyhow!(FailureReason::InvalidCredentials)),
+            code => Err(anyhow!("{}: {}", ERR_BIND_FAILED, code)),
+        }
+    }
+}
+
+impl AsyncCredentialVerifier for LdapVerifier {
+    fn verify<'a>(&'a self, identity: &'a str, username: &'a str, password: &'a str) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            self.authenticate(username, password).await?;
+            if !identity.is_empty() && identity != username {
+                bail!(FailureReason::AuthorizationDenied);
+            }
+            Ok(())
+        })
+    }
+}
+
+#[test]
+fn test_ldap_verifier() -> Result<()> {
+    struct Directory;
+
+    impl LdapConnection for Directory {
+        fn simple_bind<'a>(&'a mut self, dn: &'a str, password: &'a str) -> BoxFuture<'a, Result<u32>> {
+            let valid = matches!((dn, password), ("cn=admin", "secret") | ("uid=j\\,doe,dc=example", "password"));
+            Box::pin(async move { Ok(if valid { SUCCESS } else { INVALID_CREDENTIALS }) })
+        }
+
+        fn search<'a>(&'a mut self, _base: &'a str, filter: &'a str) -> BoxFuture<'a, Result<Vec<String>>> {
+            let dns = match filter {
+                "(uid=j,doe)" => vec!["uid=j\\,doe,dc=example".to_string()],
+                _ => Vec::new(),
+            };
+            Box::pin(async move { Ok(dns) })
+        }
+    }
+
+    fn connect() -> BoxFuture<'static, Result<Box<dyn LdapConnection>>> {
+        Box::pin(async { Ok(Box::new(Directory) as Box<dyn LdapConnection>) })
+    }
+
+    let template = LdapVerifier::new(Box::new(connect), DnLookup::Template("uid={username},dc=example".to_string()));
+    let search = LdapVerifier::new(
+        Box::new(connect),
+        DnLookup::Search {
+            base: "dc=example".to_string(),
+            filter: "(uid={username})".to_string(),
+            bind_dn: "cn=admin".to_string(),
+            bind_password: "secret".to_string(),
+        },
+    );
+    let runtime = tokio::runtime::Builder::new_current_thread().build()?;
+    runtime.block_on(async {
+        for verifier in [&template, &search] {
+            verifier.verify("", "j,doe", "password").await?;
+            for (username, password, reason) in [("j,doe", "wrong", FailureReason::InvalidCredentials), ("j,doe", "", FailureReason::InvalidCredentials)] {
+                match verifier.verify("", username, password).await {
+                    Err(err) if FailureReason::of(&err) == reason => {}
+                    _ => bail!("Expected verification to fail with {}", reason),
+                }
+            }
+        }
+        match search.verify("", "*", "password").await {
+            Err(err) if FailureReason::of(&err) == FailureReason::UnknownUser => Ok(()),
+            _ => bail!("Expected an unknown user"),
+        }
+    })
+}
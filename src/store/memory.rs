@@ -0,0 +1,125 @@
+//! A thread-safe in-memory user database, for tests, examples and small
+//! deployments.
+
+use super::{decoy_for, verify_credential, CredentialStore, CredentialVerifier, ScramCredentialStore, StoredCredential};
+
+use anyhow::{anyhow, bail, Result};
+use std::collections::BTreeMap;
+use std::sync::{OnceLock, RwLock};
+
+pub const ERR_USER_EXISTS: &str = "sasl: store: user already exists";
+pub const ERR_UNKNOWN_USER: &str = "sasl: store: unknown user";
+
+/// An in-memory user database. Unless a decoy is set, unknown users are
+/// verified against a decoy of the kind of the first user added, see
+/// `decoy_for`.
+#[derive(Default)]
+pub struct MemoryStore {
+    users: RwLock<BTreeMap<String, StoredCredential>>,
+    decoy: OnceLock<StoredCredential>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the credential unknown users are verified against, see
+    /// `CredentialVerifier`.
+    pub fn with_decoy(self, decoy: StoredCredential) -> Self {
+        let _ = self.decoy.set(decoy);
+        self
+    }
+
+    /// Adds a user, failing if it already exists.
+    pub fn add(&self, username: &str, credential: StoredCredential) -> Result<()> {
+        if self.decoy.get().is_none() {
+            let _ = self.decoy.set(decoy_for(&credential)?);
+        }
+        let mut users = self.users.write().unwrap();
+        if users.contains_key(username) {
+            bail!(ERR_USER_EXISTS);
+        }
+        users.insert(username.to_string(), credential);
+        Ok(())
+    }
+
+    /// Replaces the credentials of a user, failing if it doesn't exist.
+    pub fn update(&self, username: &str, credential: StoredCredential) -> Result<()> {
+        let mut users = self.users.write().unwrap();
+        let stored = users.get_mut(username).ok_or_else(|| anyhow!(ERR_UNKNOWN_USER))?;
+        *stored = credential;
+        Ok(())
+    }
+
+    /// Removes a user, returning its credentials if it existed.
+    pub fn remove(&self, username: &str) -> Option<StoredCredential> {
+        self.users.write().unwrap().remove(username)
+    }
+}
+
+impl CredentialStore for MemoryStore {
+    fn get(&self, username: &str) -> Result<Option<StoredCredential>> {
+        Ok(self.users.read().unwrap().get(username).cloned())
+    }
+
+    fn usernames(&self) -> Result<Vec<String>> {
+        Ok(self.users.read().unwrap().keys().cloned().collect())
+    }
+}
+
+/// Replaces the credentials of users, so that SCRAM credentials supersede
+/// their password or hash.
+impl ScramCredentialStore for MemoryStore {
+    fn scram_credential(&self, username: &str, mechanism: &str) -> Result<Option<StoredCredential>> {
+        Ok(self.get(username)?.filter(|credential| matches!(credential, StoredCredential::Scram { mechanism: m, .. } if m == mechanism)))
+    }
+
+    fn set_scram_credential(&self, username: &str, credential: StoredCredential) -> Result<()> {
+        self.update(username, credential)
+    }
+}
+
+/// Verifies cleartext passwords, and hashes and SCRAM credentials with the
+/// `password` feature. Users may only act as themselves.
+impl CredentialVerifier for MemoryStore {
+    fn verify(&self, identity: &str, username: &str, password: &str) -> Result<()> {
+        verify_credential(self.users.read().unwrap().get(username), self.decoy.get(), identity, username, password)
+    }
+}
+
+#[test]
+fn test_memory_store() -> Result<()> {
+    use crate::sasl::FailureReason;
+
+    let store = MemoryStore::new();
+    store.add("username", StoredCredential::Plaintext("password".to_string()))?;
+    if store.add("username", StoredCredential::Plaintext("other".to_string())).is_ok() {
+        bail!("Expected adding an existing user to fail");
+    }
+    store.verify("", "username", "password")?;
+    for (identity, username, password, reason) in [
+        ("", "username", "wrong", FailureReason::InvalidCredentials),
+        ("", "nobody", "password", FailureReason::UnknownUser),
+        ("admin", "username", "password", FailureReason::AuthorizationDenied),
+    ] {
+        match store.verify(identity, username, password) {
+            Err(err) if FailureReason::of(&err) == reason => {}
+            _ => bail!("Expected verification to fail with {}", reason),
+        }
+    }
+
+    store.update("username", StoredCredential::Plaintext("new".to_string()))?;
+    store.verify("", "username", "new")?;
+    if store.update("nobody", StoredCredential::Plaintext("new".to_string())).is_ok() {
+        bail!("Expected updating an unknown user to fail");
+    }
+    match store.decoy.get() {
+        Some(StoredCredential::Plaintext(decoy)) if decoy.len() == "password".len() && decoy != "password" => {}
+        _ => bail!("Expected a decoy modeled on the first user"),
+    }
+    if store.remove("username").is_none() || !store.usernames()?.is_empty() {
+        bail!("Expected the user to be removed");
+    }
+    Ok(())
+}
@@ -0,0 +1,191 @@
+//! A `CredentialVerifier` authenticating system users through PAM (libpam),
+//! through FFI. The PAM conversation is answered with the username and
+//! password received by the server; other prompts, e.g. for a one-time
+//! password, fail the authentication.
+//!
+//! PAM modules may block, e.g. to delay failures: async servers should
+//! verify credentials on a blocking thread.
+
+use super::CredentialVerifier;
+use crate::sasl::FailureReason;
+
+use anyhow::{anyhow, bail, Result};
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int, c_void};
+use std::ptr;
+
+pub const ERR_LIBPAM: &str = "sasl: pam: libpam error";
+
+const PAM_SUCCESS: c_int = 0;
+const PAM_BUF_ERR: c_int = 5;
+const PAM_PERM_DENIED: c_int = 6;
+const PAM_AUTH_ERR: c_int = 7;
+const PAM_USER_UNKNOWN: c_int = 10;
+const PAM_MAXTRIES: c_int = 11;
+const PAM_NEW_AUTHTOK_REQD: c_int = 12;
+const PAM_ACCT_EXPIRED: c_int = 13;
+const PAM_CONV_ERR: c_int = 19;
+
+const PAM_PROMPT_ECHO_OFF: c_int = 1;
+const PAM_PROMPT_ECHO_ON: c_int = 2;
+const PAM_ERROR_MSG: c_int = 3;
+const PAM_TEXT_INFO: c_int = 4;
+
+const PAM_SILENT: c_int = 0x8000;
+const PAM_DISALLOW_NULL_AUTHTOK: c_int = 0x1;
+
+#[repr(C)]
+struct PamHandle {
+    _private: [u8; 0],
+}
+
+#[repr(C)]
+struct PamMessage {
+    msg_style: c_int,
+    msg: *const c_char,
+}
+
+#[repr(C)]
+struct PamResponse {
+    resp: *mut c_char,
+    resp_retcode: c_int,
+}
+
+type PamConvFn = unsafe extern "C" fn(num_msg: c_int, msg: *mut *const PamMessage, resp: *mut *mut PamResponse, appdata_ptr: *mut c_void) -> c_int;
+
+#[repr(C)]
+struct PamConv {
+    conv: PamConvFn,
+    appdata_ptr: *mut c_void,
+}
+
+#[link(name = "pam")]
+extern "C" {
+    fn pam_start(service_name: *const c_char, user: *const c_char, pam_conversation: *const PamConv, pamh: *mut *mut PamHandle) -> c_int;
+    fn pam_authenticate(pamh: *mut PamHandle, flags: c_int) -> c_int;
+    fn pam_acct_mgmt(pamh: *mut PamHandle, flags: c_int) -> c_int;
+    fn pam_end(pamh: *mut PamHandle, pam_status: c_int) -> c_int;
+    fn pam_strerror(pamh: *mut PamHandle, errnum: c_int) -> *const c_char;
+}
+
+// Responses are freed by libpam, so they must be allocated with malloc.
+extern "C" {
+    fn calloc(nmemb: usize, size: usize) -> *mut c_void;
+    fn strdup(s: *const c_char) -> *mut c_char;
+    fn free(ptr: *mut c_void);
+}
+
+/// The credentials answering the conversation.
+struct Credentials {
+    username: CString,
+    password: CString,
+}
+
+unsafe extern "C" fn conversation(num_msg: c_int, msg: *mut *const PamMessage, resp: *mut *mut PamResponse, appdata_ptr: *mut c_void) -> c_int {
+    let Ok(count) = usize::try_from(num_msg) else {
+        return PAM_CONV_ERR;
+    };
+    // SAFETY: the application data is the `Credentials` of `verify`, and
+    // Linux-PAM passes an array of `num_msg` message pointers.
+    unsafe {
+        let credentials = &*(appdata_ptr as *const Credentials);
+        let responses = calloc(count, std::mem::size_of::<PamResponse>()) as *mut PamResponse;
+        if responses.is_null() {
+            return PAM_BUF_ERR;
+        }
+        for i in 0..count {
+            let answer = match (**msg.add(i)).msg_style {
+                PAM_PROMPT_ECHO_OFF => credentials.password.as_ptr(),
+                PAM_PROMPT_ECHO_ON => credentials.username.as_ptr(),
+                PAM_ERROR_MSG | PAM_TEXT_INFO => continue,
+                _ => {
+                    for j in 0..i {
+                        free((*responses.add(j)).resp as *mut c_void);
+                    }
+                    free(responses as *mut c_void);
+                    return PAM_CONV_ERR;
+                }
+            };
+            let copy = strdup(answer);
+            if copy.is_null() {
+                for j in 0..i {
+                    free((*responses.add(j)).resp as *mut c_void);
+                }
+                free(responses as *mut c_void);
+                return PAM_BUF_ERR;
+            }
+            (*responses.add(i)).resp = copy;
+        }
+        *resp = responses;
+    }
+    PAM_SUCCESS
+}
+
+/// Verifies the credentials of system users with a PAM service, e.g.
+/// `imap`, configured in `/etc/pam.d`. Users may only act as themselves.
+pub struct PamVerifier {
+    service: CString,
+}
+
+impl PamVerifier {
+    pub fn new(service: &str) -> Result<Self> {
+        Ok(Self {
+            service: CString::new(service)?,
+        })
+    }
+}
+
+impl CredentialVerifier for PamVerifier {
+    fn verify(&self, identity: &str, username: &str, password: &str) -> Result<()> {
+        let credentials = Credentials {
+            username: CString::new(username).map_err(|_| anyhow!(FailureReason::MalformedResponse))?,
+            password: CString::new(password).map_err(|_| anyhow!(FailureReason::MalformedResponse))?,
+        };
+        let conv = PamConv {
+            conv: conversation,
+            appdata_ptr: &credentials as *const Credentials as *mut c_void,
+        };
+        let mut pamh = ptr::null_mut();
+        // SAFETY: the conversation and credentials outlive the handle, which
+        // is released before returning.
+        let rc = unsafe {
+            let rc = pam_start(self.service.as_ptr(), credentials.username.as_ptr(), &conv, &mut pamh);
+            if rc != PAM_SUCCESS {
+                return Err(anyhow!("{}: {}", ERR_LIBPAM, CStr::from_ptr(pam_strerror(pamh, rc)).to_string_lossy()));
+            }
+            let mut rc = pam_authenticate(pamh, PAM_SILENT | PAM_DISALLOW_NULL_AUTHTOK);
+            if rc == PAM_SUCCESS {
+                rc = pam_acct_mgmt(pamh, PAM_SILENT | PAM_DISALLOW_NULL_AUTHTOK);
+            }
+            let detail = CStr::from_ptr(pam_strerror(pamh, rc)).to_string_lossy().into_owned();
+            pam_end(pamh, rc);
+            (rc, detail)
+        };
+        match rc {
+            (PAM_SUCCESS, _) => {}
+            (PAM_USER_UNKNOWN, _) => bail!(FailureReason::UnknownUser),
+            (PAM_AUTH_ERR | PAM_MAXTRIES, _) => bail!(FailureReason::InvalidCredentials),
+            (PAM_PERM_DENIED | PAM_ACCT_EXPIRED | PAM_NEW_AUTHTOK_REQD, _) => bail!(FailureReason::AccountDisabled),
+            (_, detail) => bail!("{}: {}", ERR_LIBPAM, detail),
+        }
+        if !identity.is_empty() && identity != username {
+            bail!(FailureReason::AuthorizationDenied);
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_pam_verifier() -> Result<()> {
+    let verifier = PamVerifier::new("rs-sasl-test")?;
+    // The unconfigured service falls back to `/etc/pam.d/other`.
+    match verifier.verify("", "rs-sasl-nobody", "password") {
+        Err(err) if matches!(FailureReason::of(&err), FailureReason::UnknownUser | FailureReason::InvalidCredentials) => {}
+        other => bail!("Expected the unknown user to be rejected, got {:?}", other),
+    }
+    match verifier.verify("", "user\0name", "password") {
+        Err(err) if FailureReason::of(&err) == FailureReason::MalformedResponse => {}
+        other => bail!("Expected a malformed response, got {:?}", other),
+    }
+    Ok(())
+}
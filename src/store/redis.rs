@@ -0,0 +1,266 @@
+//! A store in Redis for state shared by the nodes of a cluster: a cache of
+//! credentials loaded from a slower backend, and counters of failed
+//! attempts, e.g. for lockouts. The Redis client is left to the
+//! application: it's reached through the `RedisConnection` trait.
+
+use super::StoredCredential;
+use crate::async_sasl::BoxFuture;
+use crate::ratelimit::AsyncAttemptStore;
+use crate::framing;
+
+use anyhow::{anyhow, bail, Result};
+use std::time::Duration;
+
+pub const ERR_MALFORMED_CREDENTIAL: &str = "sasl: redis: malformed cached credential";
+pub const ERR_PLAINTEXT_CREDENTIAL: &str = "sasl: redis: cleartext passwords can't be cached";
+
+/// The default prefix of the keys of the store.
+pub const DEFAULT_PREFIX: &str = "sasl:";
+
+/// The Redis commands used by the store.
+pub trait RedisConnection: Send + Sync {
+    /// `GET key`.
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Option<String>>>;
+
+    /// `SET key value EX ttl`.
+    fn set_ex<'a>(&'a self, key: &'a str, value: &'a str, ttl: Duration) -> BoxFuture<'a, Result<()>>;
+
+    /// `DEL key`.
+    fn del<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<()>>;
+
+    /// `INCR key`, then `EXPIRE key ttl NX`, atomically (e.g. in a
+    /// `MULTI` transaction), returning the incremented value.
+    fn incr_ex<'a>(&'a self, key: &'a str, ttl: Duration) -> BoxFuture<'a, Result<u64>>;
+}
+
+/// Encodes credentials as a cache value. Cleartext passwords are refused,
+/// so that they don't spread to a network cache.
+fn encode_credential(credential: &StoredCredential) -> Result<String> {
+    Ok(match credential {
+        StoredCredential::Plaintext(_) => bail!(ERR_PLAINTEXT_CREDENTIAL),
+        StoredCredential::Hash(hash) => format!("hash:{}", hash),
+        StoredCredential::Scram {
+            mechanism,
+            iterations,
+            salt,
+            stored_key,
+            server_key,
+        } => format!(
+            "scram:{}:{}:{}:{}:{}",
+            mechanism,
+            iterations,
+            framing::base64_encode(salt),
+            framing::base64_encode(stored_key),
+            framing::base64_encode(server_key)
+        ),
+    })
+}
+
+fn decode_credential(value: &str) -> Result<StoredCredential> {
+    let (kind, value) = value.split_once(':').ok_or_else(|| anyhow!(ERR_MALFORMED_CREDENTIAL))?;
+    match kind {
+        "hash" => Ok(StoredCredential::Hash(value.to_string())),
+        "scram" => {
+            let fields: Vec<&str> = value.split(':').collect();
+            let [mechanism, iterations, salt, stored_key, server_key] = fields[..] else {
+                bail!(ERR_MALFORMED_CREDENTIAL);
+            };
+            Ok(StoredCredential::Scram {
+                mechanism: mechanism.to_string(),
+                iterations: iterations.parse().map_err(|_| anyhow!(ERR_MALFORMED_CREDENTIAL))?,
+                salt: framing::base64_decode(salt)?,
+                stored_key: framing::base64_decode(stored_key)?,
+                server_key: framing::base64_decode(server_key)?,
+            })
+        }
+        _ => bail!(ERR_MALFORMED_CREDENTIAL),
+    }
+}
+
+/// Credentials and failure counters in Redis, under keys starting with a
+/// prefix: `<prefix>credential:<username>` and `<prefix>failures:<key>`.
+pub struct RedisStore<C> {
+    conn: C,
+    prefix: String,
+}
+
+impl<C: RedisConnection> RedisStore<C> {
+    pub fn new(conn: C) -> Self {
+        Self {
+            conn,
+            prefix: DEFAULT_PREFIX.to_string(),
+        }
+    }
+
+    pub fn with_prefix(mut self, prefix: &str) -> Self {
+        self.prefix = prefix.to_string();
+        self
+    }
+
+    /// Returns the cached credentials of a user, if any.
+    pub async fn cached_credential(&self, username: &str) -> Result<Option<StoredCredential>> {
+        let key = format!("{}credential:{}", self.prefix, username);
+        self.conn.get(&key).await?.map(|value| decode_credential(&value)).transpose()
+    }
+
+    /// Caches the credentials of a user for `ttl`. Cleartext passwords
+    /// can't be cached.
+    pub async fn cache_credential(&self, username: &str, credential: &StoredCredential, ttl: Duration) -> Result<()> {
+        let key = format!("{}credential:{}", self.prefix, username);
+        self.conn.set_ex(&key, &encode_credential(credential)?, ttl).await
+    }
+
+    /// Drops the cached credentials of a user, e.g. after a password change.
+    pub async fn invalidate(&self, username: &str) -> Result<()> {
+        self.conn.del(&format!("{}credential:{}", self.prefix, username)).await
+    }
+
+    /// Counts a failed attempt for a key (e.g. a username or an address)
+    /// and returns the number of failures since the first one, which
+    /// expires after `window`.
+    pub async fn record_failure(&self, key: &str, window: Duration) -> Result<u64> {
+        self.conn.incr_ex(&format!("{}failures:{}", self.prefix, key), window).await
+    }
+
+    /// Returns the number of failed attempts counted for a key.
+    pub async fn failures(&self, key: &str) -> Result<u64> {
+        match self.conn.get(&format!("{}failures:{}", self.prefix, key)).await? {
+            Some(count) => Ok(count.parse()?),
+            None => Ok(0),
+        }
+    }
+
+    /// Resets the failed attempts of a key, e.g. after a success.
+    pub async fn clear_failures(&self, key: &str) -> Result<()> {
+        self.conn.del(&format!("{}failures:{}", self.prefix, key)).await
+    }
+}
+
+impl<C: RedisConnection> AsyncAttemptStore for RedisStore<C> {
+    fn record_failure<'a>(&'a self, key: &'a str, window: Duration) -> BoxFuture<'a, Result<u64>> {
+        Box::pin(RedisStore::record_failure(self, key, window))
+    }
+
+    fn failures<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<u64>> {
+        Box::pin(RedisStore::failures(self, key))
+    }
+
+    fn clear<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<()>> {
+        Box::pin(RedisStore::clear_failures(self, key))
+    }
+}
+
+#[cfg(test)]
+#[derive(Default)]
+struct Redis(std::sync::Mutex<std::collections::HashMap<String, String>>);
+
+#[cfg(test)]
+impl RedisConnection for Redis {
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Option<String>>> {
+        Box::pin(async move { Ok(self.0.lock().unwrap().get(key).cloned()) })
+    }
+
+    fn set_ex<'a>(&'a self, key: &'a str, value: &'a str, _ttl: Duration) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            self.0.lock().unwrap().insert(key.to_string(), value.to_string());
+            Ok(())
+        })
+    }
+
+    fn del<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            self.0.lock().unwrap().remove(key);
+            Ok(())
+        })
+    }
+
+    fn incr_ex<'a>(&'a self, key: &'a str, _ttl: Duration) -> BoxFuture<'a, Result<u64>> {
+        Box::pin(async move {
+            let mut values = self.0.lock().unwrap();
+            let count = values.get(key).map_or(Ok(0), |count| count.parse::<u64>())? + 1;
+            values.insert(key.to_string(), count.to_string());
+            Ok(count)
+        })
+    }
+}
+
+#[test]
+fn test_redis_store() -> Result<()> {
+
+    let store = RedisStore::new(Redis::default()).with_prefix("test:");
+    let credential = StoredCredential::Scram {
+        mechanism: "SCRAM-SHA-256".to_string(),
+        iterations: 4096,
+        salt: b"salt".to_vec(),
+        stored_key: b"stored".to_vec(),
+        server_key: b"server".to_vec(),
+    };
+    let runtime = tokio::runtime::Builder::new_current_thread().build()?;
+    runtime.block_on(async {
+        store.cache_credential("username", &credential, Duration::from_secs(60)).await?;
+        if store.cached_credential("username").await? != Some(credential) {
+            bail!("Expected the credential to be cached");
+        }
+        store.invalidate("username").await?;
+        if store.cached_credential("username").await?.is_some() {
+            bail!("Expected the credential to be invalidated");
+        }
+        let plaintext = StoredCredential::Plaintext("password".to_string());
+        if store.cache_credential("username", &plaintext, Duration::from_secs(60)).await.is_ok() || !store.conn.0.lock().unwrap().is_empty() {
+            bail!("Expected cleartext passwords not to be cached");
+        }
+
+        for _ in 0..3 {
+            store.record_failure("username", Duration::from_secs(60)).await?;
+        }
+        if store.failures("username").await? != 3 || !store.conn.0.lock().unwrap().contains_key("test:failures:username") {
+            bail!("Expected 3 failures");
+        }
+        store.clear_failures("username").await?;
+        if store.failures("username").await? != 0 {
+            bail!("Expected failures to be cleared");
+        }
+        Ok(())
+    })
+}
+
+#[cfg(feature = "plain")]
+#[test]
+fn test_redis_rate_limit() -> Result<()> {
+    use crate::async_sasl::AsyncServer;
+    use crate::plain::PlainServer;
+    use crate::ratelimit::{AsyncRateLimitServer, RateLimit};
+    use crate::sasl;
+    use std::sync::Arc;
+
+    let plain = PlainServer::new(Box::new(|_, _, password| match password {
+        "password" => Ok(()),
+        _ => bail!("Invalid credentials"),
+    }));
+    let limit = RateLimit {
+        max_failures: 2,
+        window: Duration::from_secs(60),
+    };
+    let store = Arc::new(RedisStore::new(Redis::default()));
+    let mut server = AsyncRateLimitServer::new(plain, store.clone(), limit);
+    let runtime = tokio::runtime::Builder::new_current_thread().build()?;
+    runtime.block_on(async {
+        for (response, rate_limited) in [(&b"\x00username\x00wrong"[..], false), (b"\x00username\x00wrong", false), (b"\x00username\x00password", true)] {
+            server.reset()?;
+            match server.next(Some(response)).await {
+                Err(err) if matches!(err.downcast_ref(), Some(sasl::Error::RateLimited)) == rate_limited => {}
+                _ => bail!("Unexpected outcome, expected rate limiting: {}", rate_limited),
+            }
+        }
+        if store.failures("username").await? != 2 {
+            bail!("Expected the failures to be counted in Redis");
+        }
+
+        store.clear_failures("username").await?;
+        server.reset()?;
+        if !server.next(Some(b"\x00username\x00password")).await?.is_done() {
+            bail!("Expected the user to be unlocked");
+        }
+        Ok(())
+    })
+}
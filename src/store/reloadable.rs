@@ -0,0 +1,101 @@
+//! Backends which can be replaced while the server runs, e.g. when a
+//! credential file changes or on SIGHUP. Each verification uses the backend
+//! which was current when it started, so exchanges in flight during a
+//! reload complete against the old one.
+
+use super::{CredentialStore, CredentialVerifier, ScramCredentialStore, StoredCredential};
+#[cfg(feature = "tokio")]
+use super::AsyncCredentialVerifier;
+#[cfg(feature = "tokio")]
+use crate::async_sasl::BoxFuture;
+
+use anyhow::Result;
+use std::sync::{Arc, RwLock};
+
+/// A backend which can be swapped atomically.
+pub struct Reloadable<T: ?Sized> {
+    current: RwLock<Arc<T>>,
+}
+
+impl<T: ?Sized> Reloadable<T> {
+    pub fn new(backend: Arc<T>) -> Self {
+        Self {
+            current: RwLock::new(backend),
+        }
+    }
+
+    /// Returns the current backend.
+    pub fn current(&self) -> Arc<T> {
+        self.current.read().unwrap().clone()
+    }
+
+    /// Replaces the backend.
+    pub fn reload(&self, backend: Arc<T>) {
+        *self.current.write().unwrap() = backend;
+    }
+
+    /// Replaces the backend with a new one, keeping the current one if
+    /// loading it fails.
+    pub fn reload_with(&self, load: impl FnOnce() -> Result<Arc<T>>) -> Result<()> {
+        self.reload(load()?);
+        Ok(())
+    }
+}
+
+impl<T: CredentialVerifier + ?Sized> CredentialVerifier for Reloadable<T> {
+    fn verify(&self, identity: &str, username: &str, password: &str) -> Result<()> {
+        self.current().verify(identity, username, password)
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl<T: AsyncCredentialVerifier + ?Sized + 'static> AsyncCredentialVerifier for Reloadable<T> {
+    fn verify<'a>(&'a self, identity: &'a str, username: &'a str, password: &'a str) -> BoxFuture<'a, Result<()>> {
+        let current = self.current();
+        Box::pin(async move { current.verify(identity, username, password).await })
+    }
+}
+
+impl<T: CredentialStore + ?Sized> CredentialStore for Reloadable<T> {
+    fn get(&self, username: &str) -> Result<Option<StoredCredential>> {
+        self.current().get(username)
+    }
+
+    fn usernames(&self) -> Result<Vec<String>> {
+        self.current().usernames()
+    }
+}
+
+impl<T: ScramCredentialStore + ?Sized> ScramCredentialStore for Reloadable<T> {
+    fn scram_credential(&self, username: &str, mechanism: &str) -> Result<Option<StoredCredential>> {
+        self.current().scram_credential(username, mechanism)
+    }
+
+    fn set_scram_credential(&self, username: &str, credential: StoredCredential) -> Result<()> {
+        self.current().set_scram_credential(username, credential)
+    }
+}
+
+#[test]
+fn test_reloadable() -> Result<()> {
+    use super::memory::MemoryStore;
+    use anyhow::{anyhow, bail};
+
+    let store = |password: &str| -> Result<Arc<MemoryStore>> {
+        let store = MemoryStore::new();
+        store.add("username", StoredCredential::Plaintext(password.to_string()))?;
+        Ok(Arc::new(store))
+    };
+    let verifier: Reloadable<dyn CredentialVerifier> = Reloadable::new(store("old")?);
+    let in_flight = verifier.current();
+    verifier.verify("", "username", "old")?;
+
+    verifier.reload_with(|| Ok(store("new")?))?;
+    if verifier.verify("", "username", "old").is_ok() || in_flight.verify("", "username", "old").is_err() {
+        bail!("Expected new verifications only to use the new backend");
+    }
+    if verifier.reload_with(|| Err(anyhow!("malformed credential file"))).is_ok() {
+        bail!("Expected the reload to fail");
+    }
+    verifier.verify("", "username", "new")
+}
@@ -0,0 +1,189 @@
+//! SCRAM-SHA-256 credentials (RFC 5802 section 3, RFC 7677): deriving them
+//! from a password, verifying a password against them, and upgrading users
+//! to them when they authenticate with a cleartext password, so that stores
+//! can migrate away from plaintext-verifiable credentials gradually.
+
+use super::{CredentialVerifier, ScramCredentialStore, StoredCredential};
+use crate::constant_time;
+use crate::sasl::FailureReason;
+
+use anyhow::{anyhow, bail, Result};
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::sync::Arc;
+
+pub const SCRAM_SHA_256: &str = "SCRAM-SHA-256";
+
+/// The iteration count of upgraded credentials by default, as recommended
+/// by OWASP for PBKDF2-HMAC-SHA-256 rather than the minimum of RFC 7677.
+pub const DEFAULT_ITERATIONS: u32 = 600_000;
+
+pub const SALT_LENGTH: usize = 16;
+
+pub const ERR_SASLPREP_UNAVAILABLE: &str = "sasl: scram: SASLprep is needed for passwords with non-ASCII or control characters";
+
+/// Receives the username and the error of every failed upgrade, e.g. to log
+/// it.
+pub type UpgradeReporter = Box<dyn Fn(&str, &anyhow::Error) + Send + Sync>;
+
+fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Prepares a password with SASLprep, as SCRAM clients do (RFC 5802
+/// section 2.2). Without the `saslprep` feature, only passwords which it
+/// leaves unchanged are accepted.
+fn prepare(password: &str) -> Result<Cow<'_, str>> {
+    #[cfg(feature = "saslprep")]
+    return crate::saslprep::saslprep(password);
+    #[cfg(not(feature = "saslprep"))]
+    {
+        if !password.bytes().all(|b| b == b' ' || b.is_ascii_graphic()) {
+            bail!(ERR_SASLPREP_UNAVAILABLE);
+        }
+        Ok(Cow::Borrowed(password))
+    }
+}
+
+/// Returns the stored key and server key of a password.
+fn keys(password: &str, salt: &[u8], iterations: u32) -> Result<(Vec<u8>, Vec<u8>)> {
+    let salted_password = pbkdf2::pbkdf2_hmac_array::<Sha256, 32>(prepare(password)?.as_bytes(), salt, iterations);
+    let client_key = hmac(&salted_password, b"Client Key");
+    Ok((Sha256::digest(client_key).to_vec(), hmac(&salted_password, b"Server Key")))
+}
+
+/// Derives the SCRAM-SHA-256 credentials of a password, after preparing it
+/// with SASLprep.
+pub fn derive(password: &str, salt: &[u8], iterations: u32) -> Result<StoredCredential> {
+    let (stored_key, server_key) = keys(password, salt, iterations)?;
+    Ok(StoredCredential::Scram {
+        mechanism: SCRAM_SHA_256.to_string(),
+        iterations,
+        salt: salt.to_vec(),
+        stored_key,
+        server_key,
+    })
+}
+
+/// Verifies a cleartext password against SCRAM-SHA-256 credentials, after
+/// preparing it with SASLprep, failing with
+/// `FailureReason::InvalidCredentials` if it doesn't match.
+pub fn verify(password: &str, credential: &StoredCredential) -> Result<()> {
+    let StoredCredential::Scram {
+        mechanism,
+        iterations,
+        salt,
+        stored_key,
+        ..
+    } = credential
+    else {
+        bail!(super::ERR_UNSUPPORTED_CREDENTIAL);
+    };
+    if mechanism != SCRAM_SHA_256 {
+        bail!(super::ERR_UNSUPPORTED_CREDENTIAL);
+    }
+    if !constant_time::eq(&keys(password, salt, *iterations)?.0, stored_key) {
+        bail!(FailureReason::InvalidCredentials);
+    }
+    Ok(())
+}
+
+/// A verifier persisting SCRAM-SHA-256 credentials for users who don't have
+/// any yet, after they authenticate with another verifier. Failing to
+/// persist them doesn't fail the authentication: the error is passed to the
+/// reporter, if any, and the upgrade is retried on the next one.
+pub struct ScramUpgrade<V> {
+    verifier: V,
+    store: Arc<dyn ScramCredentialStore>,
+    iterations: u32,
+    reporter: Option<UpgradeReporter>,
+}
+
+impl<V: CredentialVerifier> ScramUpgrade<V> {
+    pub fn new(verifier: V, store: Arc<dyn ScramCredentialStore>) -> Self {
+        Self {
+            verifier,
+            store,
+            iterations: DEFAULT_ITERATIONS,
+            reporter: None,
+        }
+    }
+
+    pub fn with_iterations(mut self, iterations: u32) -> Self {
+        self.iterations = iterations;
+        self
+    }
+
+    pub fn with_reporter(mut self, reporter: UpgradeReporter) -> Self {
+        self.reporter = Some(reporter);
+        self
+    }
+
+    fn upgrade(&self, username: &str, password: &str) -> Result<()> {
+        if self.store.scram_credential(username, SCRAM_SHA_256)?.is_some() {
+            return Ok(());
+        }
+        let mut salt = [0; SALT_LENGTH];
+        getrandom::fill(&mut salt).map_err(|err| anyhow!("sasl: {}", err))?;
+        self.store.set_scram_credential(username, derive(password, &salt, self.iterations)?)
+    }
+}
+
+impl<V: CredentialVerifier> CredentialVerifier for ScramUpgrade<V> {
+    fn verify(&self, identity: &str, username: &str, password: &str) -> Result<()> {
+        self.verifier.verify(identity, username, password)?;
+        if let Err(err) = self.upgrade(username, password) {
+            if let Some(reporter) = &self.reporter {
+                reporter(username, &err);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_scram_upgrade() -> Result<()> {
+    use super::memory::MemoryStore;
+    use super::CredentialStore;
+
+    // RFC 7677 section 3.
+    let StoredCredential::Scram { stored_key, server_key, .. } = derive("pencil", &crate::framing::base64_decode("W22ZaJ0SNY7soEsUEjb6gQ==")?, 4096)? else {
+        bail!("Expected SCRAM credentials");
+    };
+    let keys = (crate::framing::base64_encode(&stored_key), crate::framing::base64_encode(&server_key));
+    if keys != ("WG5d8oPm3OtcPnkdi4Uo7BkeZkBFzpcXkuLmtbsT4qY=".to_string(), "wfPLwcE6nTWhTAmQ7tl2KeoiWGPlZqQxSrmfPwDl2dU=".to_string()) {
+        bail!("Unexpected SCRAM keys: {:?}", keys);
+    }
+
+    let store = Arc::new(MemoryStore::new());
+    store.add("username", StoredCredential::Plaintext("password".to_string()))?;
+    let verifier = ScramUpgrade::new(store.clone(), store.clone()).with_iterations(4096);
+    verifier.verify("", "username", "password")?;
+    match store.get("username")? {
+        Some(credential) => verify("password", &credential)?,
+        None => bail!("Expected the user to exist"),
+    }
+    verifier.verify("", "username", "password")?;
+    if verifier.verify("", "username", "wrong").is_ok() {
+        bail!("Expected a wrong password to fail after the upgrade");
+    }
+
+    // Control characters are prohibited by SASLprep.
+    let failures = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let reported = failures.clone();
+    let verifier = ScramUpgrade::new(store.clone(), store.clone()).with_reporter(Box::new(move |username, _| reported.lock().unwrap().push(username.to_string())));
+    store.add("other", StoredCredential::Plaintext("pass\u{7}word".to_string()))?;
+    verifier.verify("", "other", "pass\u{7}word")?;
+    if *failures.lock().unwrap() != ["other"] || !matches!(store.get("other")?, Some(StoredCredential::Plaintext(_))) {
+        bail!("Expected the failed upgrade to be reported");
+    }
+
+    #[cfg(feature = "saslprep")]
+    if derive("I\u{AD}X", b"salt", 1)? != derive("IX", b"salt", 1)? {
+        bail!("Expected passwords to be prepared with SASLprep");
+    }
+    Ok(())
+}
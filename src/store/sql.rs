@@ -0,0 +1,210 @@
+//! A user database backed by an SQL query, e.g. against the users table of a
+//! mail server, with a cache of the credentials it loads.
+//!
+//! Queries are run by a `SqlExecutor`, which receives the query text and its
+//! parameters separately: the username is never written into the query, and
+//! the executor must bind it with the driver, never by formatting it into
+//! the text. `SqlxExecutor` runs them with sqlx, as persistent (prepared
+//! and reused) statements on a pool of any database.
+
+use super::{verify_credential, AsyncCredentialVerifier, StoredCredential};
+use crate::async_sasl::BoxFuture;
+
+use anyhow::Result;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How long loaded credentials are cached by default.
+pub const DEFAULT_CACHE_TTL: Duration = Duration::from_secs(60);
+
+/// Runs a credential query.
+pub trait SqlExecutor: Send + Sync {
+    /// Runs `query` with `params` bound to its placeholders, in order, as
+    /// parameters of the statement, returning the credentials of the first
+    /// row, or `None` if there is none.
+    fn fetch_credential<'a>(&'a self, query: &'a str, params: &'a [&'a str]) -> BoxFuture<'a, Result<Option<StoredCredential>>>;
+}
+
+/// A `SqlExecutor` running queries on a sqlx pool, with the username bound
+/// as a parameter of a persistent statement, prepared once per connection.
+/// The first column of the row is the password: hashes recognized by
+/// `password::identify` with the `password` feature, cleartext otherwise.
+pub struct SqlxExecutor<DB: sqlx::Database> {
+    pool: sqlx::Pool<DB>,
+}
+
+impl<DB: sqlx::Database> SqlxExecutor<DB> {
+    pub fn new(pool: sqlx::Pool<DB>) -> Self {
+        Self { pool }
+    }
+}
+
+impl<DB> SqlExecutor for SqlxExecutor<DB>
+where
+    DB: sqlx::Database + sqlx::database::HasStatementCache,
+    for<'q> &'q str: sqlx::Encode<'q, DB> + sqlx::Type<DB>,
+    for<'q> DB::Arguments<'q>: sqlx::IntoArguments<'q, DB>,
+    for<'c> &'c mut DB::Connection: sqlx::Executor<'c, Database = DB>,
+    for<'r> String: sqlx::Decode<'r, DB> + sqlx::Type<DB>,
+    usize: sqlx::ColumnIndex<DB::Row>,
+{
+    fn fetch_credential<'a>(&'a self, query: &'a str, params: &'a [&'a str]) -> BoxFuture<'a, Result<Option<StoredCredential>>> {
+        Box::pin(async move {
+            let mut query = sqlx::query_as::<DB, (String,)>(query).persistent(true);
+            for param in params {
+                query = query.bind(*param);
+            }
+            let row = query.fetch_optional(&self.pool).await?;
+            Ok(row.map(|(password,)| credential_of(password)))
+        })
+    }
+}
+
+/// Returns the credential of a password column.
+fn credential_of(password: String) -> StoredCredential {
+    #[cfg(feature = "password")]
+    if crate::password::identify(&password).is_some() {
+        return StoredCredential::Hash(password);
+    }
+    StoredCredential::Plaintext(password)
+}
+
+/// A user database loading credentials with an SQL query, e.g.
+/// `SELECT password FROM users WHERE name = $1 AND active`, where the
+/// username is the only parameter.
+pub struct SqlStore<E> {
+    executor: E,
+    query: String,
+    ttl: Duration,
+    decoy: StoredCredential,
+    cache: Mutex<HashMap<String, (Instant, Option<StoredCredential>)>>,
+}
+
+impl<E: SqlExecutor> SqlStore<E> {
+    /// Creates a store running `query`. Unknown users are verified against
+    /// `decoy`, which should be of the kind and parameters of the stored
+    /// credentials, e.g. `decoy_for` one of them, see `CredentialVerifier`.
+    pub fn new(executor: E, query: &str, decoy: StoredCredential) -> Self {
+        Self {
+            executor,
+            query: query.to_string(),
+            ttl: DEFAULT_CACHE_TTL,
+            decoy,
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Sets how long credentials are cached, including the absence of a
+    /// user. A zero duration disables the cache.
+    pub fn with_cache_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    /// Returns the credentials of a user, or `None` if the user doesn't
+    /// exist.
+    pub async fn get(&self, username: &str) -> Result<Option<StoredCredential>> {
+        if let Some((loaded, credential)) = self.cache.lock().unwrap().get(username) {
+            if loaded.elapsed() < self.ttl {
+                return Ok(credential.clone());
+            }
+        }
+        let credential = self.executor.fetch_credential(&self.query, &[username]).await?;
+        if !self.ttl.is_zero() {
+            let mut cache = self.cache.lock().unwrap();
+            cache.retain(|_, (loaded, _)| loaded.elapsed() < self.ttl);
+            cache.insert(username.to_string(), (Instant::now(), credential.clone()));
+        }
+        Ok(credential)
+    }
+
+    /// Drops the cached credentials of a user, e.g. after a password change.
+    pub fn invalidate(&self, username: &str) {
+        self.cache.lock().unwrap().remove(username);
+    }
+}
+
+/// Verifies cleartext passwords, and hashes and SCRAM credentials with the
+/// `password` feature. Users may only act as themselves.
+impl<E: SqlExecutor> AsyncCredentialVerifier for SqlStore<E> {
+    fn verify<'a>(&'a self, identity: &'a str, username: &'a str, password: &'a str) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move { verify_credential(self.get(username).await?.as_ref(), Some(&self.decoy), identity, username, password) })
+    }
+}
+
+#[test]
+fn test_sql_store() -> Result<()> {
+    use crate::sasl::FailureReason;
+    use anyhow::bail;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    struct Users(AtomicUsize);
+
+    impl SqlExecutor for Users {
+        fn fetch_credential<'a>(&'a self, query: &'a str, params: &'a [&'a str]) -> BoxFuture<'a, Result<Option<StoredCredential>>> {
+            Box::pin(async move {
+                self.0.fetch_add(1, Ordering::SeqCst);
+                if query != "SELECT password FROM users WHERE name = $1" {
+                    bail!("Unexpected query: {}", query);
+                }
+                match params {
+                    [username] => Ok((*username == "username").then(|| StoredCredential::Plaintext("password".to_string()))),
+                    _ => bail!("Unexpected parameters: {:?}", params),
+                }
+            })
+        }
+    }
+
+    let decoy = super::decoy_for(&StoredCredential::Plaintext("password".to_string()))?;
+    let store = SqlStore::new(Users(AtomicUsize::new(0)), "SELECT password FROM users WHERE name = $1", decoy);
+    let runtime = tokio::runtime::Builder::new_current_thread().build()?;
+    runtime.block_on(async {
+        store.verify("", "username", "password").await?;
+        match store.verify("", "username", "wrong").await {
+            Err(err) if FailureReason::of(&err) == FailureReason::InvalidCredentials => {}
+            _ => bail!("Expected invalid credentials"),
+        }
+        if store.executor.0.load(Ordering::SeqCst) != 1 {
+            bail!("Expected the credentials to be cached");
+        }
+        store.invalidate("username");
+        match store.verify("", "nobody", "password").await {
+            Err(err) if FailureReason::of(&err) == FailureReason::UnknownUser => {}
+            _ => bail!("Expected an unknown user"),
+        }
+        match store.verify("", "nobody' OR '1'='1", "password").await {
+            Err(err) if FailureReason::of(&err) == FailureReason::UnknownUser => {}
+            _ => bail!("Expected the username to be bound as a parameter"),
+        }
+        store.get("username").await?;
+        if store.executor.0.load(Ordering::SeqCst) != 4 {
+            bail!("Expected the cache to be invalidated");
+        }
+        Ok(())
+    })
+}
+
+#[test]
+fn test_sqlx_executor() -> Result<()> {
+    use crate::sasl::FailureReason;
+    use anyhow::bail;
+
+    let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+    runtime.block_on(async {
+        let pool = sqlx::sqlite::SqlitePoolOptions::new().max_connections(1).connect("sqlite::memory:").await?;
+        sqlx::query("CREATE TABLE users (name TEXT, password TEXT)").execute(&pool).await?;
+        sqlx::query("INSERT INTO users VALUES ('username', 'password')").execute(&pool).await?;
+
+        let decoy = super::decoy_for(&StoredCredential::Plaintext("password".to_string()))?;
+        let store = SqlStore::new(SqlxExecutor::new(pool), "SELECT password FROM users WHERE name = $1", decoy).with_cache_ttl(Duration::ZERO);
+        store.verify("", "username", "password").await?;
+        for (username, reason) in [("username", FailureReason::InvalidCredentials), ("nobody' OR '1'='1", FailureReason::UnknownUser)] {
+            match store.verify("", username, "wrong").await {
+                Err(err) if FailureReason::of(&err) == reason => {}
+                result => bail!("Expected {} for {}, got {:?}", reason, username, result),
+            }
+        }
+        Ok(())
+    })
+}
@@ -0,0 +1,145 @@
+//! Routing of users to the credential backend of their tenant, by the
+//! domain of their username, e.g. for a mail server hosting many domains.
+
+use super::CredentialVerifier;
+use crate::sasl::FailureReason;
+
+use anyhow::{bail, Result};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+/// A verifier routing `user@domain` usernames to the verifier of their
+/// domain. Usernames without a domain, or of an unknown domain, go to the
+/// default verifier, and are unknown users without one.
+#[derive(Default)]
+pub struct TenantVerifier {
+    domains: BTreeMap<String, Arc<dyn CredentialVerifier>>,
+    default: Option<Arc<dyn CredentialVerifier>>,
+}
+
+impl TenantVerifier {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Routes the users of a domain to a verifier. Domains are compared
+    /// case-insensitively.
+    pub fn with_domain(mut self, domain: &str, verifier: Arc<dyn CredentialVerifier>) -> Self {
+        self.domains.insert(domain.to_ascii_lowercase(), verifier);
+        self
+    }
+
+    pub fn with_default(mut self, verifier: Arc<dyn CredentialVerifier>) -> Self {
+        self.default = Some(verifier);
+        self
+    }
+
+    /// Returns the verifier of a username.
+    pub fn verifier(&self, username: &str) -> Option<&Arc<dyn CredentialVerifier>> {
+        let domain = username.rsplit_once('@').map(|(_, domain)| domain.to_ascii_lowercase());
+        domain.and_then(|domain| self.domains.get(&domain)).or(self.default.as_ref())
+    }
+}
+
+/// Verifies full usernames, including their domain, with the verifier of
+/// their tenant.
+impl CredentialVerifier for TenantVerifier {
+    fn verify(&self, identity: &str, username: &str, password: &str) -> Result<()> {
+        match self.verifier(username) {
+            Some(verifier) => verifier.verify(identity, username, password),
+            None => bail!(FailureReason::UnknownUser),
+        }
+    }
+}
+
+#[cfg(feature = "plain")]
+#[test]
+fn test_tenants() -> Result<()> {
+    use super::memory::MemoryStore;
+    use super::StoredCredential;
+    use crate::audit::{AuditSink, AuthEvent};
+    use crate::dispatcher::ServerDispatcher;
+    use crate::master::MasterUsers;
+    use crate::plain::{PlainServer, PLAIN};
+    use crate::policy::{ConnectionContext, SecurityPolicy};
+    use crate::registry::Registry;
+    use std::sync::Mutex;
+
+    #[derive(Default)]
+    struct Sink(Mutex<Vec<AuthEvent>>);
+
+    impl AuditSink for Sink {
+        fn record(&self, event: &AuthEvent) {
+            self.0.lock().unwrap().push(event.clone());
+        }
+    }
+
+    let tenant = |username: &str| -> Result<Arc<MemoryStore>> {
+        let store = MemoryStore::new();
+        store.add(username, StoredCredential::Plaintext("password".to_string()))?;
+        Ok(Arc::new(store))
+    };
+    let verifier: Arc<dyn CredentialVerifier> = Arc::new(
+        TenantVerifier::new()
+            .with_domain("example.org", tenant("john@example.org")?)
+            .with_domain("example.com", tenant("john@Example.com")?),
+    );
+
+    let new_dispatcher = |policy: SecurityPolicy| {
+        let mut registry = Registry::new();
+        let verifier = verifier.clone();
+        registry.register_server(PLAIN, move || Box::new(PlainServer::from_verifier(verifier.clone())));
+        ServerDispatcher::new(registry).with_policy(policy)
+    };
+    let dispatcher = new_dispatcher(SecurityPolicy::default()).with_tenant(
+        "example.net",
+        new_dispatcher(SecurityPolicy {
+            min_ssf: 128,
+            ..Default::default()
+        }),
+    );
+    let conn = ConnectionContext {
+        tls: true,
+        ..Default::default()
+    };
+
+    dispatcher.start(&conn, PLAIN, Some(b"\x00john@example.org\x00password"))?;
+    dispatcher.start(&conn, PLAIN, Some(b"\x00john@Example.com\x00password"))?;
+    for username in ["john@example.com", "john@example.net", "john"] {
+        match dispatcher.start(&conn, PLAIN, Some(format!("\x00{}\x00password", username).as_bytes())) {
+            Err(err) if FailureReason::of(&err) == FailureReason::UnknownUser => {}
+            _ => bail!("Expected {} to be unknown", username),
+        }
+    }
+
+    let conn = ConnectionContext {
+        server_name: Some("mail.Example.net".to_string()),
+        ..conn
+    };
+    if !dispatcher.mechanisms(&conn).is_empty() || dispatcher.start(&conn, PLAIN, Some(b"\x00john@example.org\x00password")).is_ok() {
+        bail!("Expected the tenant of the SNI hostname to require encryption");
+    }
+
+    // The tenant inherits the audit sink of the parent, and its master users
+    // only once it opted in.
+    let sink = Arc::new(Sink::default());
+    let masters = Arc::new(MasterUsers::new(tenant("admin")?, tenant("john@example.org")?));
+    let dispatcher = dispatcher.with_audit_sink(sink.clone()).with_master_users(masters.clone());
+    let conn = ConnectionContext { ssf: 256, ..conn };
+    if dispatcher.start(&conn, PLAIN, Some(b"\x00john@example.org*admin\x00password")).is_ok() || sink.0.lock().unwrap().len() != 1 {
+        bail!("Expected the tenant to reject the master users of the parent");
+    }
+    let strict = SecurityPolicy {
+        min_ssf: 128,
+        ..Default::default()
+    };
+    let dispatcher = new_dispatcher(SecurityPolicy::default())
+        .with_master_users(masters)
+        .with_tenant("example.net", new_dispatcher(strict).with_inherited_masters());
+    let (exchange, _) = dispatcher.start(&conn, PLAIN, Some(b"\x00john@example.org*admin\x00password"))?;
+    if !exchange.is_done() {
+        bail!("Expected the tenant to use the master users of the parent");
+    }
+
+    Ok(())
+}
@@ -0,0 +1,148 @@
+//! Minimal SMTP and IMAP servers used to exercise SASL exchanges over real
+//! sockets in tests. They only speak enough of each protocol to authenticate
+//! a single connection.
+
+pub mod imap;
+pub mod smtp;
+
+use crate::framing;
+use crate::sasl;
+
+use anyhow::{anyhow, bail, Result};
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::thread::JoinHandle;
+
+/// Creates a server for the requested mechanism, or returns `None` if the
+/// mechanism is not supported.
+pub type ServerFactory = Box<dyn Fn(&str) -> Option<Box<dyn sasl::Server>> + Send>;
+
+/// A test server accepting a single connection on the loopback interface.
+pub struct TestServer {
+    addr: SocketAddr,
+    handle: JoinHandle<Result<bool>>,
+}
+
+impl TestServer {
+    fn spawn<F>(serve: F) -> Result<Self>
+    where
+        F: FnOnce(&mut Conn) -> Result<bool> + Send + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        let handle = std::thread::spawn(move || {
+            let (stream, _) = listener.accept()?;
+            let mut conn = Conn::new(stream)?;
+            serve(&mut conn)
+        });
+
+        Ok(Self { addr, handle })
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Waits for the connection to be closed, and reports whether the client
+    /// authenticated successfully.
+    pub fn join(self) -> Result<bool> {
+        self.handle
+            .join()
+            .map_err(|_| anyhow!("test server panicked"))?
+    }
+}
+
+/// A line-oriented connection.
+pub struct Conn {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl Conn {
+    pub fn new(stream: TcpStream) -> Result<Self> {
+        Ok(Self {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: stream,
+        })
+    }
+
+    /// Reads a line without its CRLF terminator. Returns `None` once the peer
+    /// closed the connection.
+    pub fn read_line(&mut self) -> Result<Option<String>> {
+        let mut line = String::new();
+        if self.reader.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        Ok(Some(line.trim_end_matches(['\r', '\n']).to_string()))
+    }
+
+    pub fn write_line(&mut self, line: &str) -> Result<()> {
+        self.writer.write_all(line.as_bytes())?;
+        self.writer.write_all(b"\r\n")?;
+        Ok(())
+    }
+}
+
+/// The result of a SASL exchange run by a test server.
+#[derive(Debug, PartialEq, Eq)]
+enum Outcome {
+    Success,
+    Failure,
+    Canceled,
+}
+
+/// Runs a SASL exchange once the mechanism has been selected. Challenges are
+/// sent base64-encoded after `prefix`, and a `*` response cancels the
+/// exchange.
+fn exchange(conn: &mut Conn, server: &mut dyn sasl::Server, ir: Option<Vec<u8>>, prefix: &str) -> Result<Outcome> {
+    let mut response = ir;
+    loop {
+        let challenge = match server.next(response.as_deref()) {
+            Ok(sasl::ServerStep::Challenge(challenge)) => challenge,
+            Ok(sasl::ServerStep::Done { additional_data: None }) => return Ok(Outcome::Success),
+            // Neither SMTP nor IMAP can carry additional data with success,
+            // send it as a final challenge expecting an empty response.
+            Ok(sasl::ServerStep::Done { additional_data: Some(data) }) => {
+                return match send_challenge(conn, &data, prefix)? {
+                    Some(response) if response.is_empty() => Ok(Outcome::Success),
+                    Some(_) => Ok(Outcome::Failure),
+                    None => Ok(Outcome::Canceled),
+                };
+            }
+            Err(_) => return Ok(Outcome::Failure),
+        };
+
+        response = match send_challenge(conn, &challenge, prefix) {
+            Ok(Some(response)) => Some(response),
+            Ok(None) => return Ok(Outcome::Canceled),
+            Err(_) => return Ok(Outcome::Failure),
+        };
+    }
+}
+
+/// Sends a challenge and returns the decoded response, or `None` if the
+/// client canceled.
+fn send_challenge(conn: &mut Conn, challenge: &[u8], prefix: &str) -> Result<Option<Vec<u8>>> {
+    conn.write_line(&framing::encode_challenge(prefix, challenge))?;
+    let line = conn.read_line()?.ok_or_else(|| anyhow!("connection closed during authentication"))?;
+    framing::decode_response(&line)
+}
+
+/// Returns a factory creating PLAIN servers accepting a single set of
+/// credentials.
+pub fn plain_factory(username: &'static str, password: &'static str) -> ServerFactory {
+    use crate::plain::{PlainServer, PLAIN};
+
+    Box::new(move |mech| {
+        if mech != PLAIN {
+            return None;
+        }
+        let server = PlainServer::new(Box::new(move |_, u, p| {
+            if u != username || p != password {
+                bail!("invalid credentials");
+            }
+            Ok(())
+        }));
+        Some(Box::new(server))
+    })
+}
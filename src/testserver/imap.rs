@@ -0,0 +1,104 @@
+use super::{exchange, Conn, Outcome, ServerFactory, TestServer};
+use crate::framing::{decode_initial_response, IMAP_CONTINUATION};
+
+use anyhow::Result;
+
+/// Spawns an IMAP server advertising `mechanisms` as AUTH= capabilities,
+/// along with SASL-IR, and authenticating with the servers created by
+/// `factory`.
+pub fn spawn(mechanisms: &[&str], factory: ServerFactory) -> Result<TestServer> {
+    let capabilities = mechanisms
+        .iter()
+        .map(|mech| format!(" AUTH={}", mech))
+        .collect::<String>();
+    TestServer::spawn(move |conn| serve(conn, &capabilities, &factory))
+}
+
+fn serve(conn: &mut Conn, capabilities: &str, factory: &ServerFactory) -> Result<bool> {
+    let mut authenticated = false;
+
+    conn.write_line(&format!("* OK [CAPABILITY IMAP4rev1 SASL-IR{}] test server ready", capabilities))?;
+    while let Some(line) = conn.read_line()? {
+        let mut args = line.split(' ');
+        let tag = args.next().unwrap_or_default();
+        let command = args.next().unwrap_or_default().to_uppercase();
+        match command.as_str() {
+            "CAPABILITY" => {
+                conn.write_line(&format!("* CAPABILITY IMAP4rev1 SASL-IR{}", capabilities))?;
+                conn.write_line(&format!("{} OK CAPABILITY completed", tag))?;
+            }
+            "AUTHENTICATE" if authenticated => {
+                conn.write_line(&format!("{} BAD Already authenticated", tag))?;
+            }
+            "AUTHENTICATE" => {
+                let mech = args.next().unwrap_or_default().to_uppercase();
+                let mut server = match factory(&mech) {
+                    Some(server) => server,
+                    None => {
+                        conn.write_line(&format!("{} NO Unsupported authentication mechanism", tag))?;
+                        continue;
+                    }
+                };
+                let ir = match decode_initial_response(args.next()) {
+                    Ok(ir) => ir,
+                    Err(_) => {
+                        conn.write_line(&format!("{} BAD Cannot decode initial response", tag))?;
+                        continue;
+                    }
+                };
+
+                match exchange(conn, server.as_mut(), ir, IMAP_CONTINUATION)? {
+                    Outcome::Success => {
+                        authenticated = true;
+                        conn.write_line(&format!("{} OK AUTHENTICATE completed", tag))?;
+                    }
+                    Outcome::Failure => conn.write_line(&format!("{} NO [AUTHENTICATIONFAILED] Authentication failed", tag))?,
+                    Outcome::Canceled => conn.write_line(&format!("{} BAD AUTHENTICATE canceled", tag))?,
+                }
+            }
+            "LOGOUT" => {
+                conn.write_line("* BYE")?;
+                conn.write_line(&format!("{} OK LOGOUT completed", tag))?;
+                break;
+            }
+            _ => conn.write_line(&format!("{} BAD Command not implemented", tag))?,
+        }
+    }
+
+    Ok(authenticated)
+}
+
+#[test]
+fn test_imap_plain_continuation() -> Result<()> {
+    use super::plain_factory;
+    use crate::framing::base64_encode;
+    use crate::plain::{PlainClient, PLAIN};
+    use crate::sasl::Client;
+    use anyhow::bail;
+
+    let server = spawn(&[PLAIN], plain_factory("username", "password"))?;
+    let mut conn = Conn::new(std::net::TcpStream::connect(server.addr())?)?;
+    if !conn.read_line()?.unwrap_or_default().contains("AUTH=PLAIN") {
+        bail!("PLAIN is not advertised");
+    }
+
+    let mut c = PlainClient::new(String::new(), "username".to_string(), "wrong".to_string());
+    let (mech, ir) = c.start()?;
+    conn.write_line(&format!("a1 AUTHENTICATE {}", mech))?;
+    if conn.read_line()?.as_deref() != Some("+ ") {
+        bail!("Expected an empty continuation request");
+    }
+    conn.write_line(&base64_encode(&ir.unwrap_or_default()))?;
+    if !conn.read_line()?.unwrap_or_default().starts_with("a1 NO ") {
+        bail!("Invalid credentials were accepted");
+    }
+
+    conn.write_line("a2 LOGOUT")?;
+    conn.read_line()?;
+    conn.read_line()?;
+
+    if server.join()? {
+        bail!("Server reported a successful authentication");
+    }
+    Ok(())
+}
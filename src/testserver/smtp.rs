@@ -0,0 +1,100 @@
+use super::{exchange, Conn, Outcome, ServerFactory, TestServer};
+use crate::framing::{decode_initial_response, SMTP_CONTINUATION};
+
+use anyhow::Result;
+
+/// Spawns an SMTP server advertising `mechanisms` in its EHLO response and
+/// authenticating with the servers created by `factory`.
+pub fn spawn(mechanisms: &[&str], factory: ServerFactory) -> Result<TestServer> {
+    let mechanisms = mechanisms.join(" ");
+    TestServer::spawn(move |conn| serve(conn, &mechanisms, &factory))
+}
+
+fn serve(conn: &mut Conn, mechanisms: &str, factory: &ServerFactory) -> Result<bool> {
+    let mut authenticated = false;
+
+    conn.write_line("220 localhost ESMTP test server")?;
+    while let Some(line) = conn.read_line()? {
+        let mut args = line.split(' ');
+        let verb = args.next().unwrap_or_default().to_uppercase();
+        match verb.as_str() {
+            "EHLO" => {
+                conn.write_line("250-localhost")?;
+                conn.write_line(&format!("250 AUTH {}", mechanisms))?;
+            }
+            "AUTH" if authenticated => {
+                conn.write_line("503 5.5.1 Already authenticated")?;
+            }
+            "AUTH" => {
+                let mech = args.next().unwrap_or_default().to_uppercase();
+                let mut server = match factory(&mech) {
+                    Some(server) => server,
+                    None => {
+                        conn.write_line("504 5.5.4 Unrecognized authentication type")?;
+                        continue;
+                    }
+                };
+                let ir = match decode_initial_response(args.next()) {
+                    Ok(ir) => ir,
+                    Err(_) => {
+                        conn.write_line("501 5.5.2 Cannot decode initial response")?;
+                        continue;
+                    }
+                };
+
+                match exchange(conn, server.as_mut(), ir, SMTP_CONTINUATION)? {
+                    Outcome::Success => {
+                        authenticated = true;
+                        conn.write_line("235 2.7.0 Authentication successful")?;
+                    }
+                    Outcome::Failure => conn.write_line("535 5.7.8 Authentication credentials invalid")?,
+                    Outcome::Canceled => conn.write_line("501 5.0.0 Authentication canceled")?,
+                }
+            }
+            "QUIT" => {
+                conn.write_line("221 2.0.0 Bye")?;
+                break;
+            }
+            _ => conn.write_line("502 5.5.1 Command not implemented")?,
+        }
+    }
+
+    Ok(authenticated)
+}
+
+#[test]
+fn test_smtp_plain() -> Result<()> {
+    use super::plain_factory;
+    use crate::framing::base64_encode;
+    use crate::plain::{PlainClient, PLAIN};
+    use crate::sasl::Client;
+    use anyhow::bail;
+
+    let server = spawn(&[PLAIN], plain_factory("username", "password"))?;
+    let mut conn = Conn::new(std::net::TcpStream::connect(server.addr())?)?;
+    conn.read_line()?;
+    conn.write_line("EHLO client")?;
+    conn.read_line()?;
+    if conn.read_line()?.as_deref() != Some("250 AUTH PLAIN") {
+        bail!("PLAIN is not advertised");
+    }
+
+    conn.write_line("AUTH LOGIN")?;
+    if !conn.read_line()?.unwrap_or_default().starts_with("504 ") {
+        bail!("Unsupported mechanism was accepted");
+    }
+
+    let mut c = PlainClient::new(String::new(), "username".to_string(), "password".to_string());
+    let (mech, ir) = c.start()?;
+    conn.write_line(&format!("AUTH {} {}", mech, base64_encode(&ir.unwrap_or_default())))?;
+    if !conn.read_line()?.unwrap_or_default().starts_with("235 ") {
+        bail!("Authentication failed");
+    }
+    conn.write_line("QUIT")?;
+    conn.read_line()?;
+
+    if !server.join()? {
+        bail!("Server did not report a successful authentication");
+    }
+    Ok(())
+}
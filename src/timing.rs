@@ -0,0 +1,178 @@
+//! A statistical timing-test harness for verification paths, after dudect
+//! ("dude, is my code constant time?"). Inputs of two classes, usually a
+//! fixed one and random ones, are run interleaved in random order, and
+//! Welch's t-test tells whether their execution times differ.
+//!
+//! Timings are noisy in debug builds and on loaded machines, so the tests
+//! are ignored by default. Run them with:
+//!
+//! ```text
+//! cargo test --release -- --ignored timing
+//! ```
+
+use anyhow::{anyhow, bail, Result};
+use std::hint::black_box;
+use std::time::Instant;
+
+/// The |t| value above which timings are considered to leak, as in dudect.
+pub const THRESHOLD: f64 = 4.5;
+
+/// Running mean and variance, using Welford's algorithm.
+#[derive(Default)]
+struct Stats {
+    n: f64,
+    mean: f64,
+    m2: f64,
+}
+
+impl Stats {
+    fn push(&mut self, x: f64) {
+        self.n += 1.0;
+        let delta = x - self.mean;
+        self.mean += delta / self.n;
+        self.m2 += delta * (x - self.mean);
+    }
+
+    fn variance(&self) -> f64 {
+        if self.n < 2.0 {
+            return 0.0;
+        }
+        self.m2 / (self.n - 1.0)
+    }
+}
+
+/// Runs `samples` operations, each on an input of a random class prepared
+/// beforehand, and returns Welch's t statistic between both classes.
+/// Measurements above the 90th percentile (e.g. interrupted runs) are
+/// discarded.
+pub fn t_statistic<I, R>(
+    samples: usize,
+    mut prepare: impl FnMut(bool) -> Result<I>,
+    mut run: impl FnMut(&I) -> R,
+) -> Result<f64> {
+    let mut classes = vec![0u8; samples];
+    getrandom::fill(&mut classes).map_err(|err| anyhow!("sasl: {}", err))?;
+    let inputs = classes
+        .iter()
+        .map(|c| prepare(c & 1 == 1).map(|input| (c & 1 == 1, input)))
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut timings = Vec::with_capacity(samples);
+    for (class, input) in &inputs {
+        let start = Instant::now();
+        black_box(run(black_box(input)));
+        timings.push((*class, start.elapsed().as_nanos() as f64));
+    }
+
+    let mut sorted: Vec<f64> = timings.iter().map(|(_, t)| *t).collect();
+    sorted.sort_by(f64::total_cmp);
+    let cutoff = sorted[sorted.len() * 9 / 10];
+    let mut stats = [Stats::default(), Stats::default()];
+    for (class, t) in timings.into_iter().filter(|(_, t)| *t <= cutoff) {
+        stats[class as usize].push(t);
+    }
+
+    let [a, b] = stats;
+    let se = (a.variance() / a.n + b.variance() / b.n).sqrt();
+    if !se.is_normal() {
+        return Ok(0.0);
+    }
+    Ok((a.mean - b.mean) / se)
+}
+
+/// Fails if the timings of both classes differ significantly.
+pub fn check<I, R>(
+    name: &str,
+    samples: usize,
+    prepare: impl FnMut(bool) -> Result<I>,
+    run: impl FnMut(&I) -> R,
+) -> Result<()> {
+    let t = t_statistic(samples, prepare, run)?;
+    if t.abs() > THRESHOLD {
+        bail!("{}: timing leak detected (t = {:.2})", name, t);
+    }
+    Ok(())
+}
+
+fn random_bytes(len: usize) -> Result<Vec<u8>> {
+    let mut buf = vec![0u8; len];
+    getrandom::fill(&mut buf).map_err(|err| anyhow!("sasl: {}", err))?;
+    Ok(buf)
+}
+
+fn random_text(len: usize) -> Result<String> {
+    Ok(random_bytes(len)?.into_iter().map(|b| (b'a' + b % 26) as char).collect())
+}
+
+#[test]
+#[ignore = "timing-sensitive"]
+fn test_timing_detects_leaks() -> Result<()> {
+    // Slice equality returns at the first difference.
+    let secret = vec![0x5a; 1 << 16];
+    let result = check(
+        "slice equality",
+        10_000,
+        |fixed| if fixed { Ok(secret.clone()) } else { random_bytes(secret.len()) },
+        |input| *input == secret,
+    );
+    if result.is_ok() {
+        bail!("Expected a timing leak to be detected");
+    }
+    Ok(())
+}
+
+#[test]
+#[ignore = "timing-sensitive"]
+fn test_timing_channel_binding_compare() -> Result<()> {
+    let secret = random_bytes(32)?;
+    check(
+        "channel binding compare",
+        200_000,
+        |fixed| if fixed { Ok(secret.clone()) } else { random_bytes(secret.len()) },
+        |input| crate::constant_time::eq(input, &secret),
+    )
+}
+
+#[test]
+#[ignore = "timing-sensitive"]
+fn test_timing_unknown_users() -> Result<()> {
+    use crate::store::{decoy_for, verify_credential, StoredCredential};
+
+    // Unknown users against known ones, with a wrong password.
+    let credential = StoredCredential::Plaintext(random_text(32)?);
+    let decoy = decoy_for(&credential)?;
+    let password = random_text(32)?;
+    check(
+        "unknown users",
+        200_000,
+        |known| Ok(known.then(|| credential.clone())),
+        |input| verify_credential(input.as_ref(), Some(&decoy), "", "username", &password).is_ok(),
+    )
+}
+
+#[cfg(feature = "password")]
+#[test]
+#[ignore = "timing-sensitive"]
+fn test_timing_password_hash() -> Result<()> {
+    // A password differing in its last character, against random ones.
+    let hash = bcrypt::hash_with_salt("password", 4, [0; 16])?.to_string();
+    check(
+        "password hash",
+        5_000,
+        |fixed| if fixed { Ok("passwore".to_string()) } else { random_text(8) },
+        |input| crate::password::verify(input, &hash).is_ok(),
+    )
+}
+
+#[cfg(feature = "password")]
+#[test]
+#[ignore = "timing-sensitive"]
+fn test_timing_scram_verify() -> Result<()> {
+    let credential = crate::store::scram::derive("password", &random_bytes(16)?, 16)?;
+    check(
+        "SCRAM verify",
+        50_000,
+        |fixed| if fixed { Ok("passwore".to_string()) } else { random_text(8) },
+        |input| crate::store::scram::verify(input, &credential).is_ok(),
+    )
+}
@@ -0,0 +1,100 @@
+//! A typestate wrapper around `sasl::Client`, for embedding clients into
+//! protocol state machines. Each stage of the exchange is a distinct type,
+//! so that steps can't be taken out of order:
+//!
+//! ```compile_fail
+//! use rs_sasl::plain::PlainClient;
+//! use rs_sasl::typestate::Unstarted;
+//!
+//! let client = Unstarted::new(PlainClient::new(String::new(), "username".to_string(), "password".to_string()));
+//! client.next(b""); // next() is only available once started
+//! ```
+
+use crate::sasl;
+
+use anyhow::Result;
+
+/// A client which hasn't started authenticating yet.
+pub struct Unstarted<C> {
+    client: C,
+}
+
+impl<C: sasl::Client> Unstarted<C> {
+    pub fn new(client: C) -> Self {
+        Self { client }
+    }
+
+    pub fn mechanism_name(&self) -> &str {
+        self.client.mechanism_name()
+    }
+
+    /// Begins authentication, returning the in-progress client and the
+    /// initial response, if any. See `sasl::Client::start`.
+    pub fn start(mut self) -> Result<(InProgress<C>, Option<Vec<u8>>)> {
+        let (_, ir) = self.client.start()?;
+        Ok((InProgress { client: self.client }, ir))
+    }
+}
+
+/// A client in the middle of an exchange.
+pub struct InProgress<C> {
+    client: C,
+}
+
+impl<C: sasl::Client> InProgress<C> {
+    pub fn mechanism_name(&self) -> &str {
+        self.client.mechanism_name()
+    }
+
+    /// Answers a server challenge. See `sasl::Client::next`.
+    pub fn next(&mut self, challenge: &[u8]) -> Result<Vec<u8>> {
+        self.client.next(challenge)
+    }
+
+    /// Completes the exchange once the server reported success, verifying
+    /// the additional data sent with it. See `sasl::Client::finish`.
+    pub fn finish(mut self, data: Option<&[u8]>) -> Result<Finished<C>> {
+        self.client.finish(data)?;
+        Ok(Finished { client: self.client })
+    }
+
+    /// Aborts the exchange, returning the in-band cancel response to send,
+    /// if the mechanism has one. See `sasl::Client::cancel`.
+    pub fn cancel(mut self) -> Option<Vec<u8>> {
+        self.client.cancel()
+    }
+}
+
+/// A client which completed authentication successfully.
+pub struct Finished<C> {
+    client: C,
+}
+
+impl<C: sasl::Client> Finished<C> {
+    pub fn mechanism_name(&self) -> &str {
+        self.client.mechanism_name()
+    }
+
+    pub fn into_inner(self) -> C {
+        self.client
+    }
+}
+
+#[cfg(feature = "login")]
+#[test]
+fn test_typestate_login() -> Result<()> {
+    use crate::login::LoginClient;
+    use anyhow::bail;
+
+    let client = Unstarted::new(LoginClient::new("username".to_string(), "password".to_string()));
+    let (mut client, ir) = client.start()?;
+    if ir.as_deref() != Some(&b"username"[..]) {
+        bail!("Invalid initial response: {:?}", ir);
+    }
+    if client.next(b"Password:")? != b"password" {
+        bail!("Invalid password response");
+    }
+    client.finish(None)?;
+
+    Ok(())
+}
@@ -0,0 +1,296 @@
+//! A preview of the next major version of the core API, enabled with the
+//! `api-v2` feature. It is subject to change until it replaces the traits in
+//! `sasl`.
+//!
+//! Compared to the current API, it offers:
+//!
+//! - a single `Mechanism` trait for both sides of the exchange,
+//! - typed errors instead of error strings,
+//! - an explicit `InitialResponse` instead of an `Option`,
+//! - a `SaslContext` carrying connection and identity information across
+//!   steps.
+//!
+//! `V1Client`, `V1Server`, `V2Client` and `V2Server` bridge mechanisms
+//! between both APIs so that code can be migrated incrementally.
+
+use crate::channel_binding::ChannelBinding;
+use crate::sasl;
+
+/// The error type of the v2 API.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// The server sent a challenge the client didn't expect.
+    UnexpectedChallenge,
+    /// The client sent a response the server didn't expect.
+    UnexpectedResponse,
+    /// The server sent additional data with success that the client didn't
+    /// expect or couldn't verify.
+    UnexpectedSuccessData,
+    /// The credentials were rejected.
+    AuthenticationFailed(sasl::FailureReason),
+    /// Any other error.
+    Other(anyhow::Error),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::UnexpectedChallenge => f.write_str(sasl::ERR_UNEXPECTED_SERVER_CHALLENGE),
+            Error::UnexpectedResponse => f.write_str(sasl::ERR_UNEXPECTED_CLIENT_RESPONSE),
+            Error::UnexpectedSuccessData => f.write_str(sasl::ERR_UNEXPECTED_SUCCESS_DATA),
+            Error::AuthenticationFailed(reason) => write!(f, "{}", reason),
+            Error::Other(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<anyhow::Error> for Error {
+    fn from(err: anyhow::Error) -> Self {
+        if let Some(reason) = err.downcast_ref::<sasl::FailureReason>() {
+            return Error::AuthenticationFailed(*reason);
+        }
+        match err.to_string().as_str() {
+            sasl::ERR_UNEXPECTED_SERVER_CHALLENGE => Error::UnexpectedChallenge,
+            sasl::ERR_UNEXPECTED_CLIENT_RESPONSE => Error::UnexpectedResponse,
+            sasl::ERR_UNEXPECTED_SUCCESS_DATA => Error::UnexpectedSuccessData,
+            _ => Error::Other(err),
+        }
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// The initial response sent by a client along with the mechanism name.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum InitialResponse {
+    /// The mechanism doesn't use an initial response.
+    None,
+    /// The mechanism sends an empty initial response.
+    Empty,
+    Data(Vec<u8>),
+}
+
+impl From<Option<Vec<u8>>> for InitialResponse {
+    fn from(ir: Option<Vec<u8>>) -> Self {
+        match ir {
+            None => InitialResponse::None,
+            Some(data) if data.is_empty() => InitialResponse::Empty,
+            Some(data) => InitialResponse::Data(data),
+        }
+    }
+}
+
+impl From<InitialResponse> for Option<Vec<u8>> {
+    fn from(ir: InitialResponse) -> Self {
+        match ir {
+            InitialResponse::None => None,
+            InitialResponse::Empty => Some(Vec::new()),
+            InitialResponse::Data(data) => Some(data),
+        }
+    }
+}
+
+/// The result of a successful step.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Step {
+    /// The message must be sent to the peer, and the exchange continues.
+    Continue(Vec<u8>),
+    /// Authentication succeeded (server side). Additional data, if any, must
+    /// be delivered to the client along with the outcome.
+    Success { additional_data: Option<Vec<u8>> },
+}
+
+/// State shared by the application and a mechanism across an exchange.
+#[derive(Clone, Debug, Default)]
+#[non_exhaustive]
+pub struct SaslContext {
+    /// Whether the connection is protected by TLS.
+    pub tls: bool,
+    /// The channel binding data of the connection, if any.
+    pub channel_binding: ChannelBinding,
+    /// The authentication identity, once known.
+    pub authcid: Option<String>,
+    /// The authorization identity requested by the client, if any.
+    pub authzid: Option<String>,
+}
+
+/// A SASL mechanism, client or server side.
+pub trait Mechanism: Send {
+    /// Returns the name of the mechanism.
+    fn name(&self) -> &str;
+
+    /// Begins the exchange on the client side, returning the initial
+    /// response. Servers don't need to implement it.
+    fn start(&mut self, ctx: &mut SaslContext) -> Result<InitialResponse> {
+        let _ = ctx;
+        Ok(InitialResponse::None)
+    }
+
+    /// Processes a message from the peer: a challenge on the client side,
+    /// or a response on the server side (`None` if the client didn't send
+    /// an initial response).
+    fn step(&mut self, ctx: &mut SaslContext, input: Option<&[u8]>) -> Result<Step>;
+
+    /// Completes the exchange on the client side, verifying the additional
+    /// data sent with success.
+    fn finish(&mut self, ctx: &mut SaslContext, additional_data: Option<&[u8]>) -> Result<()> {
+        let _ = ctx;
+        match additional_data {
+            Some(data) if !data.is_empty() => Err(Error::UnexpectedSuccessData),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Exposes a `sasl::Client` as a v2 mechanism.
+pub struct V1Client<C>(pub C);
+
+impl<C: sasl::Client + Send> Mechanism for V1Client<C> {
+    fn name(&self) -> &str {
+        self.0.mechanism_name()
+    }
+
+    fn start(&mut self, _ctx: &mut SaslContext) -> Result<InitialResponse> {
+        let (_, ir) = self.0.start()?;
+        Ok(ir.into())
+    }
+
+    fn step(&mut self, _ctx: &mut SaslContext, input: Option<&[u8]>) -> Result<Step> {
+        Ok(Step::Continue(self.0.next(input.unwrap_or_default())?))
+    }
+
+    fn finish(&mut self, _ctx: &mut SaslContext, additional_data: Option<&[u8]>) -> Result<()> {
+        Ok(self.0.finish(additional_data)?)
+    }
+}
+
+/// Exposes a `sasl::Server` as a v2 mechanism.
+pub struct V1Server<S>(pub S);
+
+impl<S: sasl::Server> Mechanism for V1Server<S> {
+    fn name(&self) -> &str {
+        self.0.mechanism_name()
+    }
+
+    fn step(&mut self, _ctx: &mut SaslContext, input: Option<&[u8]>) -> Result<Step> {
+        match self.0.next(input)? {
+            sasl::ServerStep::Challenge(challenge) => Ok(Step::Continue(challenge)),
+            sasl::ServerStep::Done { additional_data } => Ok(Step::Success { additional_data }),
+        }
+    }
+}
+
+/// Exposes a v2 client mechanism as a `sasl::Client`.
+pub struct V2Client<M> {
+    mechanism: M,
+    ctx: SaslContext,
+}
+
+impl<M: Mechanism> V2Client<M> {
+    pub fn new(mechanism: M, ctx: SaslContext) -> Self {
+        Self { mechanism, ctx }
+    }
+
+    pub fn context(&self) -> &SaslContext {
+        &self.ctx
+    }
+}
+
+impl<M: Mechanism> sasl::Client for V2Client<M> {
+    fn mechanism_name(&self) -> &str {
+        self.mechanism.name()
+    }
+
+    fn start(&mut self) -> anyhow::Result<(String, Option<Vec<u8>>)> {
+        let ir = self.mechanism.start(&mut self.ctx)?;
+        Ok((self.mechanism.name().to_string(), ir.into()))
+    }
+
+    fn next(&mut self, challenge: &[u8]) -> anyhow::Result<Vec<u8>> {
+        match self.mechanism.step(&mut self.ctx, Some(challenge))? {
+            Step::Continue(response) => Ok(response),
+            Step::Success { .. } => Err(Error::UnexpectedChallenge.into()),
+        }
+    }
+
+    fn finish(&mut self, data: Option<&[u8]>) -> anyhow::Result<()> {
+        Ok(self.mechanism.finish(&mut self.ctx, data)?)
+    }
+}
+
+/// Exposes a v2 server mechanism as a `sasl::Server`.
+pub struct V2Server<M> {
+    mechanism: M,
+    ctx: SaslContext,
+    done: bool,
+}
+
+impl<M: Mechanism> V2Server<M> {
+    pub fn new(mechanism: M, ctx: SaslContext) -> Self {
+        Self {
+            mechanism,
+            ctx,
+            done: false,
+        }
+    }
+
+    pub fn context(&self) -> &SaslContext {
+        &self.ctx
+    }
+}
+
+impl<M: Mechanism> sasl::Server for V2Server<M> {
+    fn mechanism_name(&self) -> &str {
+        self.mechanism.name()
+    }
+
+    fn next(&mut self, response: Option<&[u8]>) -> anyhow::Result<sasl::ServerStep> {
+        if self.done {
+            return Err(Error::UnexpectedResponse.into());
+        }
+        let step = self.mechanism.step(&mut self.ctx, response);
+        self.done = !matches!(step, Ok(Step::Continue(_)));
+        match step? {
+            Step::Continue(challenge) => Ok(sasl::ServerStep::Challenge(challenge)),
+            Step::Success { additional_data } => Ok(sasl::ServerStep::Done { additional_data }),
+        }
+    }
+
+    fn is_done(&self) -> bool {
+        self.done
+    }
+}
+
+#[cfg(feature = "plain")]
+#[test]
+fn test_v1_v2_round_trip() -> anyhow::Result<()> {
+    use crate::plain::{PlainClient, PlainServer};
+    use crate::sasl::{Client, FailureReason};
+    use anyhow::bail;
+
+    let mut client = V2Client::new(
+        V1Client(PlainClient::new(String::new(), "username".to_string(), "password".to_string())),
+        SaslContext::default(),
+    );
+    let mut server = V1Server(PlainServer::new(Box::new(|_, _, password| {
+        if password != "secret" {
+            bail!(FailureReason::InvalidCredentials);
+        }
+        Ok(())
+    })));
+
+    let (_, ir) = client.start()?;
+    let mut ctx = SaslContext::default();
+    match server.step(&mut ctx, ir.as_deref()) {
+        Err(Error::AuthenticationFailed(FailureReason::InvalidCredentials)) => {}
+        result => bail!("Expected invalid credentials, got {:?}", result),
+    }
+    if !matches!(client.next(b"challenge").map_err(Error::from), Err(Error::UnexpectedChallenge)) {
+        bail!("Expected an unexpected challenge error");
+    }
+
+    Ok(())
+}